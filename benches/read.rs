@@ -0,0 +1,35 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use taxbit_export_rec::{
+    read_tb_export_rec_file, read_tb_export_rec_file_parallel, test_support::sample_recs_all_types,
+    write_tb_export_rec_file, TaxBitExportRec,
+};
+
+const ROWS: usize = 200_000;
+
+fn read_benchmark(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("bench.csv");
+    let samples = sample_recs_all_types();
+    let recs: Vec<TaxBitExportRec> = (0..ROWS)
+        .map(|i| {
+            let mut rec = samples[i % samples.len()].clone();
+            rec.time += i as i64;
+            rec.external_id = format!("id-{i}");
+            rec
+        })
+        .collect();
+    write_tb_export_rec_file(&path, &recs).unwrap();
+
+    let mut group = c.benchmark_group("read");
+    group.sample_size(10);
+    group.bench_function("sequential", |b| {
+        b.iter(|| read_tb_export_rec_file(&path).unwrap())
+    });
+    group.bench_function("parallel", |b| {
+        b.iter(|| read_tb_export_rec_file_parallel(&path, 10_000).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, read_benchmark);
+criterion_main!(benches);
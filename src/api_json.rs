@@ -0,0 +1,226 @@
+use rust_decimal::Decimal;
+use serde_json::{Map, Value};
+
+use crate::{
+    de_taxbit_rec_type_lenient, decimal_str_to_decimal_flexible, dt_str_to_utc_time_ms_flexible,
+    TaxBitExportRec,
+};
+
+// The text of a string or number field, empty if it's missing or null
+fn text(obj: &Map<String, Value>, key: &str) -> Result<String, String> {
+    match obj.get(key) {
+        None | Some(Value::Null) => Ok(String::new()),
+        Some(Value::String(s)) => Ok(s.clone()),
+        Some(Value::Number(n)) => Ok(n.to_string()),
+        Some(v) => Err(format!("{key} is {v}, expecting a string")),
+    }
+}
+
+// A decimal given as a string or number, None if it's missing, null or
+// an empty string
+fn decimal(obj: &Map<String, Value>, key: &str) -> Result<Option<Decimal>, String> {
+    let s = text(obj, key)?;
+    if s.trim().is_empty() {
+        return Ok(None);
+    }
+    decimal_str_to_decimal_flexible(&s)
+        .map(Some)
+        .map_err(|e| format!("{key}: {e}"))
+}
+
+impl TaxBitExportRec {
+    /// Convert a transaction returned by TaxBit's API, an object with
+    /// camelCase keys, to a record.
+    ///
+    /// dateTime, in a format dt_str_to_utc_time_ms_flexible accepts or
+    /// as milliseconds since the epoch, and transactionType, as
+    /// de_taxbit_rec_type_lenient accepts it, are required. The
+    /// quantities, fee and market value may be strings or numbers, a
+    /// missing or null field is None or empty as in a CSV, and
+    /// internalTransfer is a boolean or "true" or "false". externalId
+    /// falls back to id. Other keys are ignored.
+    pub fn from_api_json(value: &Value) -> Result<TaxBitExportRec, String> {
+        let obj = value
+            .as_object()
+            .ok_or_else(|| format!("Expecting a transaction object, found {value}"))?;
+
+        let time = match obj.get("dateTime") {
+            Some(Value::String(s)) => dt_str_to_utc_time_ms_flexible(s)?,
+            Some(Value::Number(n)) => n
+                .as_i64()
+                .ok_or_else(|| format!("dateTime {n} isn't milliseconds since the epoch"))?,
+            _ => return Err("dateTime is missing".to_owned()),
+        };
+        let type_txs = match obj.get("transactionType") {
+            Some(v) => de_taxbit_rec_type_lenient(v).map_err(|e| e.to_string())?,
+            None => return Err("transactionType is missing".to_owned()),
+        };
+        let internal_transfer = match obj.get("internalTransfer") {
+            None | Some(Value::Null) => false,
+            Some(Value::Bool(b)) => *b,
+            Some(Value::String(s)) if s.eq_ignore_ascii_case("true") => true,
+            Some(Value::String(s)) if s.eq_ignore_ascii_case("false") => false,
+            Some(v) => return Err(format!("internalTransfer is {v}, expecting true or false")),
+        };
+        let external_id = match text(obj, "externalId")? {
+            id if id.is_empty() => text(obj, "id")?,
+            id => id,
+        };
+
+        let mut rec = TaxBitExportRec::new();
+        rec.time = time;
+        rec.type_txs = type_txs;
+        rec.received_quantity = decimal(obj, "receivedQuantity")?;
+        rec.received_currency = text(obj, "receivedCurrency")?;
+        rec.sent_quantity = decimal(obj, "sentQuantity")?;
+        rec.sent_currency = text(obj, "sentCurrency")?;
+        rec.fee_amount = decimal(obj, "feeAmount")?;
+        rec.fee_currency = text(obj, "feeCurrency")?;
+        rec.market_value = decimal(obj, "marketValue")?;
+        rec.source = text(obj, "source")?;
+        rec.internal_transfer = internal_transfer;
+        rec.external_id = external_id;
+
+        Ok(rec)
+    }
+
+    /// Convert an array of transactions returned by TaxBit's API, or an
+    /// object with the array as its "data", see from_api_json. The error
+    /// names the index of the first transaction which can't be converted.
+    pub fn from_api_json_array(value: &Value) -> Result<Vec<TaxBitExportRec>, String> {
+        let transactions = match value {
+            Value::Array(transactions) => transactions,
+            Value::Object(obj) => match obj.get("data") {
+                Some(Value::Array(transactions)) => transactions,
+                _ => return Err("Expecting an array of transactions or a data array".to_owned()),
+            },
+            _ => return Err(format!("Expecting an array of transactions, found {value}")),
+        };
+
+        transactions
+            .iter()
+            .enumerate()
+            .map(|(i, t)| {
+                TaxBitExportRec::from_api_json(t).map_err(|e| format!("Transaction {i}: {e}"))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal_macros::dec;
+    use serde_json::json;
+    use taxbitrec::TaxBitRecType;
+
+    use super::*;
+
+    fn response() -> Value {
+        json!({
+            "data": [
+                {
+                    "id": "9f1c2b",
+                    "dateTime": "2022-03-01T14:35:06.123Z",
+                    "transactionType": "buy",
+                    "receivedQuantity": "0.5",
+                    "receivedCurrency": "BTC",
+                    "sentQuantity": "20000.00",
+                    "sentCurrency": "USD",
+                    "feeAmount": 1.25,
+                    "feeCurrency": "USD",
+                    "marketValue": "20000.00",
+                    "source": "Coinbase",
+                    "accountId": "acc-1",
+                    "metadata": { "tags": ["dca"] }
+                },
+                {
+                    "dateTime": "2022-03-02T00:00:00Z",
+                    "transactionType": "transfer-out",
+                    "sentQuantity": "1.2E-3",
+                    "sentCurrency": "BTC",
+                    "feeAmount": null,
+                    "feeCurrency": null,
+                    "marketValue": null,
+                    "source": "Coinbase",
+                    "internalTransfer": true,
+                    "externalId": "tx-2",
+                    "id": "ignored"
+                },
+                {
+                    "dateTime": 1646265600000_i64,
+                    "transactionType": "Gift Received",
+                    "receivedQuantity": 3,
+                    "receivedCurrency": "ETH",
+                    "source": "Metamask",
+                    "internalTransfer": "FALSE"
+                }
+            ],
+            "nextPageToken": null
+        })
+    }
+
+    #[test]
+    fn test_from_api_json_array() {
+        let recs = TaxBitExportRec::from_api_json_array(&response()).unwrap();
+        assert_eq!(recs.len(), 3);
+
+        let mut buy = TaxBitExportRec::new();
+        buy.time = 1646145306123;
+        buy.type_txs = TaxBitRecType::Buy;
+        buy.received_quantity = Some(dec!(0.5));
+        buy.received_currency = "BTC".to_owned();
+        buy.sent_quantity = Some(dec!(20000.00));
+        buy.sent_currency = "USD".to_owned();
+        buy.fee_amount = Some(dec!(1.25));
+        buy.fee_currency = "USD".to_owned();
+        buy.market_value = Some(dec!(20000.00));
+        buy.source = "Coinbase".to_owned();
+        buy.external_id = "9f1c2b".to_owned();
+        assert!(recs[0].eq_strict(&buy));
+
+        assert_eq!(recs[1].type_txs, TaxBitRecType::TransferOut);
+        assert_eq!(recs[1].sent_quantity, Some(dec!(0.0012)));
+        assert_eq!(recs[1].fee_amount, None);
+        assert_eq!(recs[1].fee_currency, "");
+        assert_eq!(recs[1].market_value, None);
+        assert!(recs[1].internal_transfer);
+        assert_eq!(recs[1].external_id, "tx-2");
+
+        assert_eq!(recs[2].time, 1646265600000);
+        assert_eq!(recs[2].type_txs, TaxBitRecType::GiftReceived);
+        assert_eq!(recs[2].received_quantity, Some(dec!(3)));
+        assert!(!recs[2].internal_transfer);
+        assert_eq!(recs[2].external_id, "");
+        assert!(recs.iter().all(|r| r.validate().is_ok()));
+
+        // A bare array is accepted too
+        let array = response()["data"].clone();
+        assert_eq!(TaxBitExportRec::from_api_json_array(&array).unwrap(), recs);
+    }
+
+    #[test]
+    fn test_from_api_json_errors() {
+        let err = |value: Value| TaxBitExportRec::from_api_json_array(&value).unwrap_err();
+        assert_eq!(
+            err(json!([{ "transactionType": "buy" }])),
+            "Transaction 0: dateTime is missing"
+        );
+        assert!(err(json!([
+            { "dateTime": "2022-03-01T00:00:00Z", "transactionType": "buy" },
+            { "dateTime": "2022-03-01T00:00:00Z", "transactionType": "swap" }
+        ]))
+        .starts_with("Transaction 1: Unknown Transaction Type 'swap'"));
+        assert_eq!(
+            err(json!([{
+                "dateTime": "2022-03-01T00:00:00Z",
+                "transactionType": "buy",
+                "receivedQuantity": "lots"
+            }])),
+            "Transaction 0: receivedQuantity: 'lots' isn't a number"
+        );
+        assert_eq!(
+            err(json!({ "transactions": [] })),
+            "Expecting an array of transactions or a data array"
+        );
+    }
+}
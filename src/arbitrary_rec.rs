@@ -0,0 +1,234 @@
+//! Random TaxBitExportRec generation for fuzzing and property tests,
+//! enabled with the `arbitrary` feature.
+//!
+//! [any_valid_rec] and the [arbitrary::Arbitrary] implementation produce
+//! records which pass validate(), [any_invalid_rec] produces records which
+//! don't.
+
+use arbitrary::{Arbitrary, Unstructured};
+use proptest::prelude::*;
+use rust_decimal::Decimal;
+use taxbitrec::TaxBitRecType;
+
+use crate::TaxBitExportRec;
+
+/// 2009-01-03T00:00:00Z, the Bitcoin genesis block
+pub const TIME_MIN: i64 = 1230940800000;
+
+/// 2030-01-01T00:00:00Z
+pub const TIME_MAX: i64 = 1893456000000;
+
+pub const CURRENCIES: &[&str] = &["BTC", "ETH", "USD", "USDC", "ADA", "SOL", "XRP", "BNB"];
+
+pub const SOURCES: &[&str] = &["BinanceUS", "Coinbase", "Kraken", "Ledger"];
+
+const VALID_TYPES: [TaxBitRecType; 9] = [
+    TaxBitRecType::Buy,
+    TaxBitRecType::Sale,
+    TaxBitRecType::Trade,
+    TaxBitRecType::TransferIn,
+    TaxBitRecType::TransferOut,
+    TaxBitRecType::Income,
+    TaxBitRecType::Expense,
+    TaxBitRecType::GiftReceived,
+    TaxBitRecType::GiftSent,
+];
+
+// The random choices a valid record is built from
+struct Parts {
+    time: i64,
+    type_index: usize,
+    received: (usize, Decimal),
+    sent: (usize, Decimal),
+    fee: Option<(usize, Decimal)>,
+    market_value: Option<Decimal>,
+    source_index: usize,
+    internal_transfer: bool,
+    external_id: String,
+}
+
+fn build_valid_rec(parts: Parts) -> TaxBitExportRec {
+    let type_txs = VALID_TYPES[parts.type_index % VALID_TYPES.len()].clone();
+    let (has_received, has_sent) = match type_txs {
+        TaxBitRecType::Buy | TaxBitRecType::Sale | TaxBitRecType::Trade => (true, true),
+        TaxBitRecType::TransferIn | TaxBitRecType::Income | TaxBitRecType::GiftReceived => {
+            (true, false)
+        }
+        _ => (false, true),
+    };
+
+    let mut rec = TaxBitExportRec::new();
+    rec.time = parts.time;
+    rec.type_txs = type_txs;
+    if has_received {
+        rec.received_currency = CURRENCIES[parts.received.0 % CURRENCIES.len()].to_owned();
+        rec.received_quantity = Some(parts.received.1);
+    }
+    if has_sent {
+        rec.sent_currency = CURRENCIES[parts.sent.0 % CURRENCIES.len()].to_owned();
+        rec.sent_quantity = Some(parts.sent.1);
+    }
+    if let Some((currency, amount)) = parts.fee {
+        rec.fee_currency = CURRENCIES[currency % CURRENCIES.len()].to_owned();
+        rec.fee_amount = Some(amount);
+    }
+    rec.market_value = parts.market_value;
+    rec.source = SOURCES[parts.source_index % SOURCES.len()].to_owned();
+    rec.internal_transfer = parts.internal_transfer;
+    rec.external_id = parts.external_id;
+
+    rec
+}
+
+/// A non-negative Decimal with up to 12 integer and 12 fractional digits
+pub fn any_quantity() -> impl Strategy<Value = Decimal> {
+    (0i64..1_000_000_000_000, 0u32..=12).prop_map(|(m, scale)| Decimal::new(m, scale))
+}
+
+/// Records which pass validate()
+pub fn any_valid_rec() -> impl Strategy<Value = TaxBitExportRec> {
+    (
+        TIME_MIN..TIME_MAX,
+        0..VALID_TYPES.len(),
+        (0..CURRENCIES.len(), any_quantity()),
+        (0..CURRENCIES.len(), any_quantity()),
+        proptest::option::of((0..CURRENCIES.len(), any_quantity())),
+        proptest::option::of(any_quantity()),
+        0..SOURCES.len(),
+        any::<bool>(),
+        "[0-9a-f]{8}-[0-9a-f]{4}",
+    )
+        .prop_map(
+            |(
+                time,
+                type_index,
+                received,
+                sent,
+                fee,
+                market_value,
+                source_index,
+                internal_transfer,
+                external_id,
+            )| {
+                build_valid_rec(Parts {
+                    time,
+                    type_index,
+                    received,
+                    sent,
+                    fee,
+                    market_value,
+                    source_index,
+                    internal_transfer,
+                    external_id,
+                })
+            },
+        )
+}
+
+/// Records which fail validate(), each is a valid record with one defect
+pub fn any_invalid_rec() -> impl Strategy<Value = TaxBitExportRec> {
+    (any_valid_rec(), 0..5usize).prop_map(|(mut rec, defect)| {
+        match defect {
+            0 => rec.type_txs = TaxBitRecType::Unknown,
+            1 => rec.market_value = Some(Decimal::NEGATIVE_ONE),
+            2 => {
+                rec.fee_amount = Some(Decimal::ONE);
+                rec.fee_currency = "".to_owned();
+            }
+            3 => {
+                // Negate whichever quantity is present
+                if let Some(q) = rec.received_quantity {
+                    rec.received_quantity = Some(-q - Decimal::ONE);
+                } else if let Some(q) = rec.sent_quantity {
+                    rec.sent_quantity = Some(-q - Decimal::ONE);
+                }
+            }
+            _ => {
+                // Remove the currency of a required side
+                if rec.received_quantity.is_some() {
+                    rec.received_currency = "".to_owned();
+                } else {
+                    rec.sent_currency = "".to_owned();
+                }
+            }
+        }
+        rec
+    })
+}
+
+fn arbitrary_quantity(u: &mut Unstructured<'_>) -> arbitrary::Result<Decimal> {
+    Ok(Decimal::new(
+        u.int_in_range(0..=999_999_999_999)?,
+        u.int_in_range(0..=12)?,
+    ))
+}
+
+impl<'a> Arbitrary<'a> for TaxBitExportRec {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        let fee = if u.arbitrary()? {
+            Some((u.arbitrary()?, arbitrary_quantity(u)?))
+        } else {
+            None
+        };
+        let market_value = if u.arbitrary()? {
+            Some(arbitrary_quantity(u)?)
+        } else {
+            None
+        };
+
+        Ok(build_valid_rec(Parts {
+            time: u.int_in_range(TIME_MIN..=TIME_MAX - 1)?,
+            type_index: u.arbitrary()?,
+            received: (u.arbitrary()?, arbitrary_quantity(u)?),
+            sent: (u.arbitrary()?, arbitrary_quantity(u)?),
+            fee,
+            market_value,
+            source_index: u.arbitrary()?,
+            internal_transfer: u.arbitrary()?,
+            external_id: format!("{:08x}", u.arbitrary::<u32>()?),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{TaxBitExportRecReader, TaxBitExportRecWriter};
+
+    proptest! {
+        #[test]
+        fn prop_valid_recs_validate(rec in any_valid_rec()) {
+            prop_assert_eq!(rec.validate(), Ok(()));
+        }
+
+        #[test]
+        fn prop_invalid_recs_dont_validate(rec in any_invalid_rec()) {
+            prop_assert!(rec.validate().is_err());
+        }
+
+        #[test]
+        fn prop_csv_round_trip(recs in proptest::collection::vec(any_valid_rec(), 0..20)) {
+            let mut writer = TaxBitExportRecWriter::new(vec![]);
+            for rec in &recs {
+                writer.write_rec(rec).unwrap();
+            }
+            let csv = writer.into_inner().unwrap();
+
+            let reader = TaxBitExportRecReader::new(csv.as_slice()).unwrap();
+            let recs_read: Vec<TaxBitExportRec> = reader.map(|r| r.unwrap()).collect();
+            prop_assert_eq!(recs, recs_read);
+        }
+    }
+
+    #[test]
+    fn test_arbitrary_is_valid() {
+        let data: Vec<u8> = (0..4096u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 7) as u8)
+            .collect();
+        let mut u = Unstructured::new(&data);
+        for _ in 0..20 {
+            let rec = TaxBitExportRec::arbitrary(&mut u).unwrap();
+            assert_eq!(rec.validate(), Ok(()));
+        }
+    }
+}
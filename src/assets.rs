@@ -0,0 +1,248 @@
+use std::collections::BTreeMap;
+
+use crate::TaxBitExportRec;
+
+// The currency codes known by default
+const DEFAULT_ASSETS: [&str; 62] = [
+    "1INCH", "AAVE", "ADA", "ALGO", "APE", "ARB", "ATOM", "AUD", "AVAX", "AXS", "BAT", "BCH",
+    "BNB", "BTC", "BUSD", "CAD", "CHF", "COMP", "CRV", "DAI", "DASH", "DOGE", "DOT", "EOS", "ETC",
+    "ETH", "EUR", "FIL", "FTM", "GBP", "GRT", "HBAR", "ICP", "JPY", "LINK", "LTC", "MANA", "MATIC",
+    "MKR", "NEAR", "OP", "PAXG", "POL", "SAND", "SHIB", "SNX", "SOL", "SUSHI", "TRX", "UNI", "USD",
+    "USDC", "USDP", "USDT", "VET", "XLM", "XMR", "XRP", "XTZ", "YFI", "ZEC", "ZRX",
+];
+
+// The aliases known by default and the codes they stand for
+const DEFAULT_ALIASES: [(&str, &str); 11] = [
+    ("XBT", "BTC"),
+    ("XXBT", "BTC"),
+    ("XETH", "ETH"),
+    ("BETH", "ETH"),
+    ("XDG", "DOGE"),
+    ("XXDG", "DOGE"),
+    ("XXRP", "XRP"),
+    ("XXLM", "XLM"),
+    ("ZUSD", "USD"),
+    ("ZEUR", "EUR"),
+    ("ZGBP", "GBP"),
+];
+
+/// A currency code used by records which isn't in an AssetRegistry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownAsset {
+    /// The code as the first record using it spells it
+    pub code: String,
+
+    /// Indices of the records using the code in ascending order
+    pub rows: Vec<usize>,
+}
+
+/// The known currency codes and aliases for them, for instance "XBT"
+/// for "BTC". Codes are matched ignoring case but kept as they were
+/// added, so "btc" matches "BTC" and normalizes to it.
+///
+/// The default registry has common crypto and fiat codes and the
+/// aliases of some exchanges, add_asset and add_alias add to or
+/// override them.
+#[derive(Debug, Clone)]
+pub struct AssetRegistry {
+    // The codes keyed by their upper case
+    assets: BTreeMap<String, String>,
+
+    // The codes the aliases stand for keyed by the alias's upper case
+    aliases: BTreeMap<String, String>,
+}
+
+impl Default for AssetRegistry {
+    fn default() -> Self {
+        let mut registry = AssetRegistry::empty();
+        for code in DEFAULT_ASSETS {
+            registry.add_asset(code);
+        }
+        for (alias, code) in DEFAULT_ALIASES {
+            registry.add_alias(alias, code);
+        }
+
+        registry
+    }
+}
+
+impl AssetRegistry {
+    /// The default registry
+    pub fn new() -> AssetRegistry {
+        AssetRegistry::default()
+    }
+
+    /// A registry without any codes
+    pub fn empty() -> AssetRegistry {
+        AssetRegistry {
+            assets: BTreeMap::new(),
+            aliases: BTreeMap::new(),
+        }
+    }
+
+    /// Add code as a known asset, replacing an alias of the same name
+    /// and the spelling of the same code if it was already added
+    pub fn add_asset(&mut self, code: &str) {
+        let key = code.to_uppercase();
+        self.aliases.remove(&key);
+        self.assets.insert(key, code.to_owned());
+    }
+
+    /// Add alias for code, adding code if it isn't known. Replaces an
+    /// asset or alias of the same name.
+    pub fn add_alias(&mut self, alias: &str, code: &str) {
+        let code = match self.assets.get(&code.to_uppercase()) {
+            Some(known) => known.clone(),
+            None => {
+                self.add_asset(code);
+                code.to_owned()
+            }
+        };
+        let key = alias.to_uppercase();
+        self.assets.remove(&key);
+        self.aliases.insert(key, code);
+    }
+
+    /// The code as it was added of a known code or of the code an alias
+    /// stands for, None if code is unknown
+    pub fn lookup(&self, code: &str) -> Option<&str> {
+        let key = code.to_uppercase();
+        self.assets
+            .get(&key)
+            .or_else(|| self.aliases.get(&key))
+            .map(|c| c.as_str())
+    }
+
+    /// The unknown codes in the received, sent and fee currencies of the
+    /// records ordered by code, empty currencies are ignored
+    pub fn check(&self, recs: &[TaxBitExportRec]) -> Vec<UnknownAsset> {
+        let mut unknown: BTreeMap<String, UnknownAsset> = BTreeMap::new();
+        for (i, rec) in recs.iter().enumerate() {
+            for code in [
+                &rec.received_currency,
+                &rec.sent_currency,
+                &rec.fee_currency,
+            ] {
+                if code.is_empty() || self.lookup(code).is_some() {
+                    continue;
+                }
+                let asset = unknown
+                    .entry(code.to_uppercase())
+                    .or_insert_with(|| UnknownAsset {
+                        code: code.clone(),
+                        rows: vec![],
+                    });
+                if asset.rows.last() != Some(&i) {
+                    asset.rows.push(i);
+                }
+            }
+        }
+
+        unknown.into_values().collect()
+    }
+
+    /// Replace the received, sent and fee currencies which are aliases,
+    /// or known codes spelled differently, with the code as it was
+    /// added. Returns the number of currencies replaced, unknown codes
+    /// are unchanged.
+    pub fn normalize(&self, recs: &mut [TaxBitExportRec]) -> usize {
+        let mut replaced = 0;
+        for rec in recs {
+            for code in [
+                &mut rec.received_currency,
+                &mut rec.sent_currency,
+                &mut rec.fee_currency,
+            ] {
+                if let Some(known) = self.lookup(code) {
+                    if known != code.as_str() {
+                        *code = known.to_owned();
+                        replaced += 1;
+                    }
+                }
+            }
+        }
+
+        replaced
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal_macros::dec;
+    use taxbitrec::TaxBitRecType;
+
+    use super::*;
+
+    fn trade(received: &str, sent: &str, fee: &str) -> TaxBitExportRec {
+        let mut rec = TaxBitExportRec::new();
+        rec.type_txs = TaxBitRecType::Trade;
+        rec.received_quantity = Some(dec!(1));
+        rec.received_currency = received.to_owned();
+        rec.sent_quantity = Some(dec!(2));
+        rec.sent_currency = sent.to_owned();
+        if !fee.is_empty() {
+            rec.fee_amount = Some(dec!(0.01));
+            rec.fee_currency = fee.to_owned();
+        }
+        rec
+    }
+
+    #[test]
+    fn test_asset_registry_normalize() {
+        let registry = AssetRegistry::new();
+        let mut recs = vec![trade("XBT", "xbt", "Xbt"), trade("ETH", "btc", "")];
+        assert_eq!(registry.check(&recs), vec![]);
+        assert_eq!(registry.normalize(&mut recs), 4);
+        assert_eq!(recs[0], trade("BTC", "BTC", "BTC"));
+        assert_eq!(recs[1], trade("ETH", "BTC", ""));
+        assert_eq!(registry.normalize(&mut recs), 0);
+    }
+
+    #[test]
+    fn test_asset_registry_check() {
+        let registry = AssetRegistry::new();
+        let recs = vec![
+            trade("BTCC", "USD", ""),
+            trade("ETH", "USD", "USD"),
+            trade("btcc", "ZZZ", "ZZZ"),
+        ];
+        assert_eq!(
+            registry.check(&recs),
+            vec![
+                UnknownAsset {
+                    code: "BTCC".to_owned(),
+                    rows: vec![0, 2],
+                },
+                UnknownAsset {
+                    code: "ZZZ".to_owned(),
+                    rows: vec![2],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_asset_registry_overrides() {
+        let mut registry = AssetRegistry::new();
+        assert_eq!(registry.lookup("beth"), Some("ETH"));
+        assert_eq!(registry.lookup("BTCC"), None);
+
+        // BETH is its own asset and XBT stands for a new code
+        registry.add_asset("BETH");
+        registry.add_alias("XBT", "wBTC");
+        registry.add_alias("BTCC", "btc");
+        assert_eq!(registry.lookup("BETH"), Some("BETH"));
+        assert_eq!(registry.lookup("xbt"), Some("wBTC"));
+        assert_eq!(registry.lookup("WBTC"), Some("wBTC"));
+        assert_eq!(registry.lookup("BTCC"), Some("BTC"));
+
+        let mut recs = vec![trade("BETH", "XBT", "BTCC")];
+        assert_eq!(registry.normalize(&mut recs), 2);
+        assert_eq!(recs[0], trade("BETH", "wBTC", "BTC"));
+
+        let mut empty = AssetRegistry::empty();
+        assert_eq!(empty.lookup("BTC"), None);
+        empty.add_asset("BTC");
+        assert_eq!(empty.check(&[trade("BTC", "btc", "")]), vec![]);
+    }
+}
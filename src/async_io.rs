@@ -0,0 +1,538 @@
+use std::{
+    collections::VecDeque,
+    error::Error,
+    io::{self, Read, Write},
+    pin::Pin,
+    sync::{Arc, Mutex, MutexGuard},
+    task::{ready, Context, Poll},
+};
+
+use futures_core::Stream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::{
+    ReaderConfig, TaxBitExportLayout, TaxBitExportRec, TaxBitExportRecReader,
+    TaxBitExportRecWriter, WriterConfig,
+};
+
+// Size of the reads from the AsyncRead and of the output buffered before
+// it's written to the AsyncWrite
+const BUF_SIZE: usize = 8 * 1024;
+
+// Input read from the AsyncRead waiting to be parsed
+#[derive(Default)]
+struct Pipe {
+    buf: VecDeque<u8>,
+
+    // The error reading the input, returned once buf is empty
+    error: Option<io::Error>,
+
+    eof: bool,
+}
+
+// The input of the sync reader which parses the records, it only reads
+// from the pipe once a whole record has been buffered or the input has
+// ended so it never runs out of input mid-record
+#[derive(Clone, Default)]
+struct PipeReader(Arc<Mutex<Pipe>>);
+
+impl PipeReader {
+    fn lock(&self) -> MutexGuard<'_, Pipe> {
+        self.0.lock().expect("SNH")
+    }
+}
+
+impl Read for PipeReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let mut pipe = self.lock();
+        if !pipe.buf.is_empty() {
+            return pipe.buf.read(out);
+        }
+        if let Some(e) = pipe.error.take() {
+            return Err(e);
+        }
+        if pipe.eof {
+            Ok(0)
+        } else {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ScanState {
+    FieldStart,
+    Unquoted,
+    Quoted,
+    QuoteInQuoted,
+}
+
+// Finds the ends of records in the input, quoting as the csv crate does
+// so a newline in a quoted field doesn't end a record. Blank lines,
+// which the csv crate skips, aren't records. Until the delimiter is known
+// any of the delimiters ReaderConfig supports starts a field.
+struct RecordScanner {
+    state: ScanState,
+    blank: bool,
+    delimiter: Option<u8>,
+}
+
+impl RecordScanner {
+    fn new(delimiter: Option<u8>) -> RecordScanner {
+        RecordScanner {
+            state: ScanState::FieldStart,
+            blank: true,
+            delimiter,
+        }
+    }
+
+    // Scan bytes until max records end, returns the number of records
+    // which ended and the number of bytes scanned
+    fn scan(&mut self, bytes: &[u8], max: usize) -> (usize, usize) {
+        let mut records = 0;
+        for (i, b) in bytes.iter().enumerate() {
+            if records == max {
+                return (records, i);
+            }
+            if *b == b'\n' && self.state != ScanState::Quoted {
+                if !self.blank {
+                    records += 1;
+                }
+                self.state = ScanState::FieldStart;
+                self.blank = true;
+                continue;
+            }
+            if *b != b'\r' {
+                self.blank = false;
+            }
+            let is_delimiter = match self.delimiter {
+                Some(delimiter) => *b == delimiter,
+                None => matches!(b, b',' | b';' | b'\t'),
+            };
+            self.state = match (self.state, b) {
+                (ScanState::Quoted, b'"') => ScanState::QuoteInQuoted,
+                (ScanState::Quoted, _) => ScanState::Quoted,
+                (ScanState::FieldStart | ScanState::QuoteInQuoted, b'"') => ScanState::Quoted,
+                _ if is_delimiter => ScanState::FieldStart,
+                _ => ScanState::Unquoted,
+            };
+        }
+
+        (records, bytes.len())
+    }
+}
+
+/// Streaming reader of TaxBit export records from an AsyncRead, enabled
+/// by the async feature.
+///
+/// The records, and the errors including RowErrors, are those
+/// TaxBitExportRecReader returns for the same input, the records are
+/// parsed by a TaxBitExportRecReader as each is read.
+pub struct AsyncTaxBitExportRecReader<R: AsyncRead + Unpin> {
+    rdr: R,
+    pipe: PipeReader,
+    scanner: RecordScanner,
+    reader: TaxBitExportRecReader<PipeReader>,
+
+    // Whole records in the pipe the reader hasn't read
+    records: usize,
+
+    buf: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncTaxBitExportRecReader<R> {
+    /// Create a reader, the header is read and verified immediately
+    pub async fn new(rdr: R) -> Result<AsyncTaxBitExportRecReader<R>, Box<dyn Error>> {
+        AsyncTaxBitExportRecReader::new_with_config(rdr, ReaderConfig::default()).await
+    }
+
+    /// Create a reader using config, see new
+    pub async fn new_with_config(
+        mut rdr: R,
+        config: ReaderConfig,
+    ) -> Result<AsyncTaxBitExportRecReader<R>, Box<dyn Error>> {
+        let pipe = PipeReader::default();
+        let mut scanner = RecordScanner::new(config.delimiter);
+        let mut buf = vec![0; BUF_SIZE];
+
+        // Read until the header is buffered, the rest is scanned once the
+        // delimiter is known
+        let mut rest: Vec<u8> = vec![];
+        loop {
+            match rdr.read(&mut buf).await {
+                Ok(0) => {
+                    pipe.lock().eof = true;
+                    break;
+                }
+                Ok(n) => {
+                    pipe.lock().buf.extend(&buf[..n]);
+                    let (records, end) = scanner.scan(&buf[..n], 1);
+                    if records == 1 {
+                        rest = buf[end..n].to_vec();
+                        break;
+                    }
+                }
+                Err(e) => {
+                    let mut pipe = pipe.lock();
+                    pipe.error = Some(e);
+                    pipe.eof = true;
+                    break;
+                }
+            }
+        }
+
+        let reader = TaxBitExportRecReader::new_with_config(pipe.clone(), config)?;
+        scanner.delimiter = Some(reader.delimiter());
+        let (records, _) = scanner.scan(&rest, usize::MAX);
+
+        Ok(AsyncTaxBitExportRecReader {
+            rdr,
+            pipe,
+            scanner,
+            reader,
+            records,
+            buf,
+        })
+    }
+
+    /// The field delimiter, see ReaderConfig::delimiter
+    pub fn delimiter(&self) -> u8 {
+        self.reader.delimiter()
+    }
+
+    /// The layout found in the header
+    pub fn layout(&self) -> TaxBitExportLayout {
+        self.reader.layout()
+    }
+
+    /// Names of the columns which will be captured in TaxBitExportRec::extras
+    pub fn extra_columns(&self) -> Vec<String> {
+        self.reader.extra_columns()
+    }
+}
+
+impl<R: AsyncRead + Unpin> Stream for AsyncTaxBitExportRecReader<R> {
+    type Item = Result<TaxBitExportRec, Box<dyn Error>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            let eof = this.pipe.lock().eof;
+            if this.records > 0 || eof {
+                this.records = this.records.saturating_sub(1);
+                return Poll::Ready(this.reader.next());
+            }
+
+            let mut read_buf = ReadBuf::new(&mut this.buf);
+            match ready!(Pin::new(&mut this.rdr).poll_read(cx, &mut read_buf)) {
+                Ok(()) if read_buf.filled().is_empty() => this.pipe.lock().eof = true,
+                Ok(()) => {
+                    let filled = read_buf.filled();
+                    this.records += this.scanner.scan(filled, usize::MAX).0;
+                    this.pipe.lock().buf.extend(filled);
+                }
+                Err(e) => {
+                    // Returned by the reader after the buffered records
+                    let mut pipe = this.pipe.lock();
+                    pipe.error = Some(e);
+                    pipe.eof = true;
+                }
+            }
+        }
+    }
+}
+
+// The output of the sync writer waiting to be written to the AsyncWrite
+#[derive(Clone, Default)]
+struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuf {
+    fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.0.lock().expect("SNH"))
+    }
+
+    fn len(&self) -> usize {
+        self.0.lock().expect("SNH").len()
+    }
+}
+
+impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().expect("SNH").extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writer of TaxBit export records to an AsyncWrite, enabled by the
+/// async feature.
+///
+/// The output and errors are those of TaxBitExportRecWriter, which
+/// formats the records. Use flush or into_inner to write the buffered
+/// output.
+pub struct AsyncTaxBitExportRecWriter<W: AsyncWrite + Unpin> {
+    wtr: W,
+    buf: SharedBuf,
+    writer: TaxBitExportRecWriter<SharedBuf>,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncTaxBitExportRecWriter<W> {
+    pub fn new(wtr: W) -> AsyncTaxBitExportRecWriter<W> {
+        let buf = SharedBuf::default();
+        AsyncTaxBitExportRecWriter {
+            wtr,
+            writer: TaxBitExportRecWriter::new(buf.clone()),
+            buf,
+        }
+    }
+
+    /// See TaxBitExportRecWriter::with_config
+    pub fn with_config(mut self, config: WriterConfig) -> AsyncTaxBitExportRecWriter<W> {
+        self.writer = self.writer.with_config(config);
+        self
+    }
+
+    /// See TaxBitExportRecWriter::with_lot_id_column
+    pub fn with_lot_id_column(mut self, enabled: bool) -> AsyncTaxBitExportRecWriter<W> {
+        self.writer = self.writer.with_lot_id_column(enabled);
+        self
+    }
+
+    /// See TaxBitExportRecWriter::with_extra_columns
+    pub fn with_extra_columns(mut self, columns: &[String]) -> AsyncTaxBitExportRecWriter<W> {
+        self.writer = self.writer.with_extra_columns(columns);
+        self
+    }
+
+    pub async fn write_rec(&mut self, rec: &TaxBitExportRec) -> Result<(), Box<dyn Error>> {
+        self.writer.write_rec(rec)?;
+        self.writer.flush()?;
+        if self.buf.len() >= BUF_SIZE {
+            self.wtr.write_all(&self.buf.take()).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the buffered output and flush the AsyncWrite, writing the
+    /// header if it hasn't been written yet
+    pub async fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        self.writer.flush()?;
+        self.wtr.write_all(&self.buf.take()).await?;
+        self.wtr.flush().await?;
+
+        Ok(())
+    }
+
+    /// Flush and return the underlying AsyncWrite
+    pub async fn into_inner(mut self) -> Result<W, Box<dyn Error>> {
+        self.flush().await?;
+
+        Ok(self.wtr)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::future::poll_fn;
+
+    use super::*;
+    use crate::{test_support::sample_recs_all_types, RowError};
+
+    // Returns the input a few bytes at a time, Pending every other poll
+    struct Trickle {
+        data: Vec<u8>,
+        pos: usize,
+        chunk: usize,
+        pending: bool,
+    }
+
+    impl Trickle {
+        fn new(data: &[u8], chunk: usize) -> Trickle {
+            Trickle {
+                data: data.to_vec(),
+                pos: 0,
+                chunk,
+                pending: false,
+            }
+        }
+    }
+
+    impl AsyncRead for Trickle {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            self.pending = !self.pending;
+            if self.pending {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            let end = (self.pos + self.chunk)
+                .min(self.data.len())
+                .min(self.pos + buf.remaining());
+            buf.put_slice(&self.data[self.pos..end]);
+            self.pos = end;
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    // Fails after returning the input
+    struct Failing(Vec<u8>);
+
+    impl AsyncRead for Failing {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            if self.0.is_empty() {
+                return Poll::Ready(Err(io::Error::other("connection reset")));
+            }
+            let data = std::mem::take(&mut self.0);
+            buf.put_slice(&data);
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    // Each record, or the error as a string
+    type Entries = Vec<Result<TaxBitExportRec, String>>;
+
+    fn read_sync(csv: &[u8]) -> Result<Entries, String> {
+        let reader = TaxBitExportRecReader::new(csv).map_err(|e| e.to_string())?;
+        Ok(reader.map(|e| e.map_err(|e| e.to_string())).collect())
+    }
+
+    async fn read_async<R: AsyncRead + Unpin>(rdr: R) -> Result<Entries, String> {
+        let mut reader = AsyncTaxBitExportRecReader::new(rdr)
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut entries: Entries = vec![];
+        while let Some(entry) = poll_fn(|cx| Pin::new(&mut reader).poll_next(cx)).await {
+            entries.push(entry.map_err(|e| e.to_string()));
+        }
+        Ok(entries)
+    }
+
+    fn fixture() -> Vec<u8> {
+        let mut recs = sample_recs_all_types();
+        recs[2].source = "Coinbase\nPro, \"quoted\"".to_owned();
+        let mut writer = TaxBitExportRecWriter::new(vec![]);
+        for rec in &recs {
+            writer.write_rec(rec).unwrap();
+        }
+        writer.into_inner().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_read_matches_sync() {
+        let csv = fixture();
+        let mut inputs: Vec<Vec<u8>> = vec![csv.clone()];
+
+        // A row which doesn't parse, a blank line, CRLF line ends and no
+        // final newline
+        let text = String::from_utf8(csv.clone()).unwrap();
+        let mut lines: Vec<String> = text.lines().map(|l| l.to_owned()).collect();
+        lines[1] = lines[1].replacen("Z,", "Z,Bought,", 1);
+        lines.insert(5, "".to_owned());
+        inputs.push(lines.join("\n").into_bytes());
+        inputs.push(lines.join("\r\n").into_bytes());
+
+        // Semicolon delimited
+        let mut writer = TaxBitExportRecWriter::new(vec![]).with_config(WriterConfig {
+            delimiter: b';',
+            ..WriterConfig::default()
+        });
+        for rec in sample_recs_all_types() {
+            writer.write_rec(&rec).unwrap();
+        }
+        inputs.push(writer.into_inner().unwrap());
+
+        // Bad headers
+        inputs.push(b"Date,Transaction Type\n".to_vec());
+        inputs.push(vec![]);
+
+        for input in inputs {
+            let expected = read_sync(&input);
+            assert_eq!(read_async(input.as_slice()).await, expected);
+            for chunk in [1, 2, 7, 100] {
+                assert_eq!(read_async(Trickle::new(&input, chunk)).await, expected);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_row_error() {
+        let text = String::from_utf8(fixture()).unwrap();
+        let bad = text.replacen("1012.55", "lots", 1);
+        let mut reader = AsyncTaxBitExportRecReader::new(Trickle::new(bad.as_bytes(), 5))
+            .await
+            .unwrap();
+        assert_eq!(reader.layout(), TaxBitExportLayout::Current);
+        let mut row_errors: Vec<RowError> = vec![];
+        while let Some(entry) = poll_fn(|cx| Pin::new(&mut reader).poll_next(cx)).await {
+            if let Err(e) = entry {
+                row_errors.push(*e.downcast::<RowError>().unwrap());
+            }
+        }
+        let (_, expected) = TaxBitExportRecReader::new(bad.as_bytes())
+            .unwrap()
+            .read_lenient()
+            .unwrap();
+        assert_eq!(row_errors.len(), 1);
+        assert_eq!(row_errors[0].row, expected[0].row);
+        assert_eq!(row_errors[0].to_string(), expected[0].to_string());
+    }
+
+    #[tokio::test]
+    async fn test_read_io_error() {
+        let entries = read_async(Failing(fixture())).await.unwrap();
+        assert_eq!(entries.len(), 10);
+        assert!(entries[..9].iter().all(|e| e.is_ok()));
+        assert_eq!(entries[9], Err("connection reset".to_owned()));
+    }
+
+    #[tokio::test]
+    async fn test_write_matches_sync() {
+        let mut recs = vec![sample_recs_all_types(); 100].concat();
+        recs[0] = recs[0].with_lot_id("lot-1");
+        let config = WriterConfig {
+            reject_duplicate_ids: true,
+            ..WriterConfig::default()
+        };
+
+        let mut writer = TaxBitExportRecWriter::new(vec![])
+            .with_config(config.clone())
+            .with_lot_id_column(true);
+        let mut async_writer = AsyncTaxBitExportRecWriter::new(vec![])
+            .with_config(config)
+            .with_lot_id_column(true);
+        // All but the first 9 are rejected as duplicates
+        for (i, rec) in recs.iter().enumerate() {
+            let expected = writer.write_rec(rec).map_err(|e| e.to_string());
+            let found = async_writer.write_rec(rec).await.map_err(|e| e.to_string());
+            assert_eq!(found, expected, "record {i}");
+        }
+        let expected = writer.into_inner().unwrap();
+        let found = async_writer.into_inner().await.unwrap();
+        assert_eq!(found, expected);
+
+        let empty = AsyncTaxBitExportRecWriter::new(vec![]);
+        let header = TaxBitExportRecWriter::new(vec![]).into_inner().unwrap();
+        assert_eq!(empty.into_inner().await.unwrap(), header);
+    }
+
+    #[test]
+    fn test_futures_are_send() {
+        fn assert_send<T: Send>(_: T) {}
+        let csv = fixture();
+        assert_send(AsyncTaxBitExportRecReader::new(csv.as_slice()));
+        let mut writer = AsyncTaxBitExportRecWriter::new(vec![]);
+        let rec = TaxBitExportRec::new();
+        assert_send(writer.write_rec(&rec));
+    }
+}
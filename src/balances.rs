@@ -0,0 +1,415 @@
+use std::collections::BTreeMap;
+
+use rust_decimal::Decimal;
+use taxbitrec::TaxBitRecType;
+
+use crate::{TaxBitExportRec, TimeRange};
+
+/// Options for reconcile_balances_with_opts
+#[derive(Debug, Clone, Default)]
+pub struct BalanceOpts {
+    /// Include BalanceReport::series
+    pub include_series: bool,
+
+    /// Track one balance per asset across all sources, the source of
+    /// every balance is "", so an internal transfer between two sources
+    /// nets out leaving only what was lost to its network fee
+    pub net_internal_transfers: bool,
+}
+
+/// The balance of an asset at a source after a record changed it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BalancePoint {
+    /// Index of the record which changed the balance
+    pub index: usize,
+    pub time: i64,
+    pub source: String,
+    pub asset: String,
+    pub balance: Decimal,
+}
+
+/// The result of reconcile_balances
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BalanceReport {
+    /// The balance of each (source, asset) after the last record
+    pub balances: BTreeMap<(String, String), Decimal>,
+
+    /// Every change of a balance in record order, empty unless
+    /// BalanceOpts::include_series
+    pub series: Vec<BalancePoint>,
+
+    /// Each change which took a balance from zero or more to less than
+    /// zero, usually a deposit or earlier trade is missing
+    pub negatives: Vec<BalancePoint>,
+}
+
+/// Replay the records in order tracking the balance of each asset at each
+/// source, see reconcile_balances_with_opts
+///
+/// # Panics
+///
+/// In debug builds if recs isn't sorted by time
+pub fn reconcile_balances(recs: &[TaxBitExportRec]) -> BalanceReport {
+    reconcile_balances_with_opts(recs, &BalanceOpts::default())
+}
+
+/// Replay the records in order tracking the balance of each asset at each
+/// source. The received quantity is added to the received currency, the
+/// sent quantity and the fee amount are subtracted from the sent and fee
+/// currencies, records with an Unknown or Invalid type are skipped.
+///
+/// # Panics
+///
+/// In debug builds if recs isn't sorted by time
+pub fn reconcile_balances_with_opts(recs: &[TaxBitExportRec], opts: &BalanceOpts) -> BalanceReport {
+    debug_assert!(
+        recs.windows(2).all(|w| w[0].time <= w[1].time),
+        "reconcile_balances requires records sorted by time"
+    );
+
+    replay(recs.iter().enumerate(), opts)
+}
+
+// Replay the records, with their indices, in the order given, see
+// reconcile_balances_with_opts
+fn replay<'a>(
+    recs: impl Iterator<Item = (usize, &'a TaxBitExportRec)>,
+    opts: &BalanceOpts,
+) -> BalanceReport {
+    let mut report = BalanceReport::default();
+    for (index, rec) in recs {
+        if matches!(
+            rec.type_txs,
+            TaxBitRecType::Unknown | TaxBitRecType::Invalid
+        ) {
+            continue;
+        }
+        let source = if opts.net_internal_transfers {
+            ""
+        } else {
+            rec.source.as_str()
+        };
+
+        // The change of each asset, a fee paid in a traded asset is
+        // combined with that leg
+        let mut changes: BTreeMap<&str, Decimal> = BTreeMap::new();
+        let legs = [
+            (rec.received_quantity, &rec.received_currency, Decimal::ONE),
+            (rec.sent_quantity, &rec.sent_currency, Decimal::NEGATIVE_ONE),
+            (rec.fee_amount, &rec.fee_currency, Decimal::NEGATIVE_ONE),
+        ];
+        for (quantity, currency, sign) in legs {
+            if let Some(quantity) = quantity {
+                if !currency.is_empty() {
+                    *changes.entry(currency.as_str()).or_default() += sign * quantity;
+                }
+            }
+        }
+
+        for (asset, change) in changes {
+            let balance = report
+                .balances
+                .entry((source.to_owned(), asset.to_owned()))
+                .or_default();
+            let before = *balance;
+            *balance += change;
+            let point = BalancePoint {
+                index,
+                time: rec.time,
+                source: source.to_owned(),
+                asset: asset.to_owned(),
+                balance: *balance,
+            };
+            if before >= Decimal::ZERO && point.balance < Decimal::ZERO {
+                report.negatives.push(point.clone());
+            }
+            if opts.include_series {
+                report.series.push(point);
+            }
+        }
+    }
+
+    report
+}
+
+/// The balances at an instant, see holdings_at_with_opts
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Holdings {
+    pub as_of_ms: i64,
+
+    /// The balance of each (source, asset), the source is "" with
+    /// BalanceOpts::net_internal_transfers
+    pub balances: BTreeMap<(String, String), Decimal>,
+
+    /// The (source, asset) of the balances which are negative, usually a
+    /// deposit or earlier trade is missing
+    pub negative: Vec<(String, String)>,
+}
+
+/// The balance of each (source, asset) at as_of_ms, see
+/// holdings_at_with_opts
+pub fn holdings_at(recs: &[TaxBitExportRec], as_of_ms: i64) -> BTreeMap<(String, String), Decimal> {
+    holdings_at_with_opts(recs, as_of_ms, &BalanceOpts::default()).balances
+}
+
+/// Replay the records at or before as_of_ms in time order, recs needn't
+/// be sorted, as reconcile_balances_with_opts does and return the
+/// balances. Negative balances are kept as they are and listed in
+/// Holdings::negative, opts.include_series is ignored.
+pub fn holdings_at_with_opts(
+    recs: &[TaxBitExportRec],
+    as_of_ms: i64,
+    opts: &BalanceOpts,
+) -> Holdings {
+    let mut order: Vec<usize> = (0..recs.len())
+        .filter(|i| recs[*i].time <= as_of_ms)
+        .collect();
+    order.sort_by_key(|i| recs[*i].time);
+    let opts = BalanceOpts {
+        include_series: false,
+        ..opts.clone()
+    };
+    let report = replay(order.into_iter().map(|i| (i, &recs[i])), &opts);
+
+    Holdings {
+        as_of_ms,
+        negative: report
+            .balances
+            .iter()
+            .filter(|(_, balance)| **balance < Decimal::ZERO)
+            .map(|(key, _)| key.clone())
+            .collect(),
+        balances: report.balances,
+    }
+}
+
+/// The holdings at the last millisecond of the UTC calendar year, see
+/// holdings_at_with_opts, an error if the year is out of range
+pub fn holdings_at_year_end(
+    recs: &[TaxBitExportRec],
+    year: u32,
+    opts: &BalanceOpts,
+) -> Result<Holdings, String> {
+    let range = TimeRange::utc_year(year).ok_or_else(|| format!("Year {year} is out of range"))?;
+
+    Ok(holdings_at_with_opts(recs, range.end - 1, opts))
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    // 2022-01-01T00:00:00Z
+    const T: i64 = 1640995200000;
+    const DAY: i64 = 24 * 60 * 60 * 1000;
+
+    fn rec(
+        time: i64,
+        type_txs: TaxBitRecType,
+        received: Option<(Decimal, &str)>,
+        sent: Option<(Decimal, &str)>,
+        source: &str,
+    ) -> TaxBitExportRec {
+        let mut rec = TaxBitExportRec::new();
+        rec.time = time;
+        rec.type_txs = type_txs;
+        if let Some((q, c)) = received {
+            rec.received_quantity = Some(q);
+            rec.received_currency = c.to_owned();
+        }
+        if let Some((q, c)) = sent {
+            rec.sent_quantity = Some(q);
+            rec.sent_currency = c.to_owned();
+        }
+        rec.source = source.to_owned();
+        rec
+    }
+
+    fn history() -> Vec<TaxBitExportRec> {
+        let mut buy = rec(
+            T + DAY,
+            TaxBitRecType::Buy,
+            Some((dec!(0.1), "BTC")),
+            Some((dec!(4000), "USD")),
+            "Coinbase",
+        );
+        buy.fee_amount = Some(dec!(15.50));
+        buy.fee_currency = "USD".to_owned();
+        let mut out = rec(
+            T + 2 * DAY,
+            TaxBitRecType::TransferOut,
+            None,
+            Some((dec!(0.05), "BTC")),
+            "Coinbase",
+        );
+        out.internal_transfer = true;
+        let mut inn = rec(
+            T + 2 * DAY + 1000,
+            TaxBitRecType::TransferIn,
+            Some((dec!(0.0499), "BTC")),
+            None,
+            "Kraken",
+        );
+        inn.internal_transfer = true;
+        let mut sale = rec(
+            T + 3 * DAY,
+            TaxBitRecType::Sale,
+            Some((dec!(2100), "USD")),
+            Some((dec!(0.0498), "BTC")),
+            "Kraken",
+        );
+        sale.fee_amount = Some(dec!(0.0000001));
+        sale.fee_currency = "BTC".to_owned();
+
+        vec![
+            rec(
+                T,
+                TaxBitRecType::TransferIn,
+                Some((dec!(5000), "USD")),
+                None,
+                "Coinbase",
+            ),
+            buy,
+            out,
+            inn,
+            sale,
+        ]
+    }
+
+    #[test]
+    fn test_reconcile_balances_clean() {
+        let report = reconcile_balances(&history());
+        assert!(report.negatives.is_empty(), "{:?}", report.negatives);
+        assert!(report.series.is_empty());
+        let key = |s: &str, a: &str| (s.to_owned(), a.to_owned());
+        assert_eq!(
+            report.balances,
+            BTreeMap::from([
+                (key("Coinbase", "BTC"), dec!(0.05)),
+                (key("Coinbase", "USD"), dec!(984.50)),
+                (key("Kraken", "BTC"), dec!(0.0000999)),
+                (key("Kraken", "USD"), dec!(2100)),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_reconcile_balances_missing_deposit() {
+        let mut recs = history();
+        recs.remove(0);
+        let report = reconcile_balances(&recs);
+        assert_eq!(
+            report.negatives,
+            vec![BalancePoint {
+                index: 0,
+                time: T + DAY,
+                source: "Coinbase".to_owned(),
+                asset: "USD".to_owned(),
+                balance: dec!(-4015.50),
+            }]
+        );
+
+        // Without the TransferIn the sale at Kraken is of BTC it doesn't
+        // have
+        let mut recs = history();
+        recs.remove(3);
+        let report = reconcile_balances(&recs);
+        assert_eq!(report.negatives.len(), 1);
+        assert_eq!(report.negatives[0].index, 3);
+        assert_eq!(report.negatives[0].time, T + 3 * DAY);
+        assert_eq!(report.negatives[0].source, "Kraken");
+        assert_eq!(report.negatives[0].balance, dec!(-0.0498001));
+    }
+
+    #[test]
+    fn test_reconcile_balances_net_internal_transfers() {
+        let opts = BalanceOpts {
+            include_series: true,
+            net_internal_transfers: true,
+        };
+        let mut recs = history();
+        recs[4].sent_quantity = Some(dec!(0.02));
+        let report = reconcile_balances_with_opts(&recs, &opts);
+        assert!(report.negatives.is_empty());
+        assert_eq!(
+            report.balances[&(String::new(), "BTC".to_owned())],
+            dec!(0.0798999)
+        );
+
+        let btc: Vec<Decimal> = report
+            .series
+            .iter()
+            .filter(|p| p.asset == "BTC")
+            .map(|p| p.balance)
+            .collect();
+        assert_eq!(
+            btc,
+            vec![dec!(0.1), dec!(0.05), dec!(0.0999), dec!(0.0798999)]
+        );
+        assert_eq!(report.series.len(), 7);
+        assert!(report.series.windows(2).all(|w| w[0].index <= w[1].index));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "sorted by time")]
+    fn test_reconcile_balances_unsorted() {
+        let mut recs = history();
+        recs.swap(0, 1);
+        reconcile_balances(&recs);
+    }
+
+    #[test]
+    fn test_holdings_at_withdrawal() {
+        let mut recs = history();
+        recs[2].fee_amount = Some(dec!(0.0001));
+        recs[2].fee_currency = "BTC".to_owned();
+        // Unsorted is fine
+        recs.swap(0, 4);
+        let coinbase_btc = ("Coinbase".to_owned(), "BTC".to_owned());
+
+        let before = holdings_at(&recs, T + 2 * DAY - 1);
+        let after = holdings_at(&recs, T + 2 * DAY);
+        assert_eq!(before[&coinbase_btc], dec!(0.1));
+        assert_eq!(
+            before[&coinbase_btc] - after[&coinbase_btc],
+            dec!(0.05) + dec!(0.0001)
+        );
+        assert!(!after.contains_key(&("Kraken".to_owned(), "BTC".to_owned())));
+        assert!(holdings_at(&recs, T - 1).is_empty());
+
+        // Netted across sources only the network and withdrawal fees
+        // are lost by the internal transfer
+        let opts = BalanceOpts {
+            net_internal_transfers: true,
+            ..BalanceOpts::default()
+        };
+        let btc = (String::new(), "BTC".to_owned());
+        let netted = holdings_at_with_opts(&recs, T + 2 * DAY + 1000, &opts);
+        assert_eq!(netted.balances[&btc], dec!(0.0999) - dec!(0.0001));
+        assert_eq!(netted.as_of_ms, T + 2 * DAY + 1000);
+    }
+
+    #[test]
+    fn test_holdings_at_year_end() {
+        let mut recs = history();
+        recs.remove(3);
+        let mut next_year = recs[0].clone();
+        next_year.time = T + 400 * DAY;
+        recs.push(next_year);
+
+        // The sale at Kraken without the TransferIn is kept negative
+        let holdings = holdings_at_year_end(&recs, 2022, &BalanceOpts::default()).unwrap();
+        let kraken_btc = ("Kraken".to_owned(), "BTC".to_owned());
+        assert_eq!(holdings.balances[&kraken_btc], dec!(-0.0498001));
+        assert_eq!(holdings.negative, vec![kraken_btc]);
+        assert_eq!(holdings.as_of_ms, 1672531199999);
+        assert_eq!(
+            holdings.balances[&("Coinbase".to_owned(), "USD".to_owned())],
+            dec!(984.50)
+        );
+        assert!(holdings_at_year_end(&recs, u32::MAX, &BalanceOpts::default()).is_err());
+    }
+}
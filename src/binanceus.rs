@@ -0,0 +1,278 @@
+//! Convert Binance.US trade and distribution history CSVs to TaxBit
+//! export records
+//!
+//! Trade history columns used are Date(UTC), Market, Type, Price, Amount,
+//! Total, Fee and Fee Coin. Amount is of the base asset and Total of the
+//! quote asset, which are split from the Market symbol, see split_market.
+//! A trade quoted in USD is a Buy or Sale with Total as the market value,
+//! otherwise it's a Trade with no market value.
+//!
+//! Distribution history columns used are Date(UTC), Coin, Amount and
+//! Category. Staking and referral distributions are Income, others are
+//! rejected.
+use std::{error::Error, io::Read};
+
+use taxbitrec::TaxBitRecType;
+
+use crate::{
+    convert::{exchange_type, parse_decimal_opt, Columns},
+    dt_str_to_utc_time_ms_flexible, RejectedRow, TaxBitExportRec,
+};
+
+/// Source of the converted records
+pub const BINANCEUS_SOURCE: &str = "BinanceUS";
+
+/// Quote assets traded on Binance.US in priority order, "BTCUSDT" is
+/// BTC quoted in USDT not BTCUSD quoted in T
+pub const BINANCEUS_QUOTE_ASSETS: [&str; 8] =
+    ["USDT", "USDC", "BUSD", "USD", "BTC", "ETH", "BNB", "DAI"];
+
+const TRADE_COLUMNS: [&str; 8] = [
+    "Date(UTC)",
+    "Market",
+    "Type",
+    "Price",
+    "Amount",
+    "Total",
+    "Fee",
+    "Fee Coin",
+];
+
+const DISTRIBUTION_COLUMNS: [&str; 4] = ["Date(UTC)", "Coin", "Amount", "Category"];
+
+/// Split a Market symbol into (base, quote) using the first of
+/// quote_assets the symbol ends with, "ADABTC" is ("ADA", "BTC").
+/// Returns an error if none match leaving a non-empty base.
+pub fn split_market(market: &str, quote_assets: &[&str]) -> Result<(String, String), String> {
+    quote_assets
+        .iter()
+        .find_map(|quote| match market.strip_suffix(quote) {
+            Some(base) if !base.is_empty() => Some((base.to_owned(), quote.to_string())),
+            _ => None,
+        })
+        .ok_or_else(|| {
+            format!(
+                "Unable to split Market '{market}' using quote assets {}",
+                quote_assets.join(", ")
+            )
+        })
+}
+
+fn trade_record_to_rec(
+    columns: &Columns,
+    record: &csv::StringRecord,
+    quote_assets: &[&str],
+) -> Result<TaxBitExportRec, String> {
+    let (base, quote) = split_market(columns.get(record, "Market"), quote_assets)?;
+    let amount = parse_decimal_opt("Amount", columns.get(record, "Amount"))?;
+    let total = parse_decimal_opt("Total", columns.get(record, "Total"))?;
+
+    let mut rec = TaxBitExportRec::new();
+    rec.time = dt_str_to_utc_time_ms_flexible(columns.get(record, "Date(UTC)"))?;
+    rec.source = BINANCEUS_SOURCE.to_owned();
+    rec.fee_amount = parse_decimal_opt("Fee", columns.get(record, "Fee"))?;
+    rec.fee_currency = columns.get(record, "Fee Coin").to_owned();
+    match columns.get(record, "Type").to_uppercase().as_str() {
+        "BUY" => {
+            rec.received_quantity = amount;
+            rec.received_currency = base;
+            rec.sent_quantity = total;
+            rec.sent_currency = quote;
+        }
+        "SELL" => {
+            rec.sent_quantity = amount;
+            rec.sent_currency = base;
+            rec.received_quantity = total;
+            rec.received_currency = quote;
+        }
+        _ => {
+            return Err(format!(
+                "Unsupported Type '{}'",
+                columns.get(record, "Type")
+            ))
+        }
+    }
+    rec.type_txs = exchange_type(&rec);
+    if rec.type_txs != TaxBitRecType::Trade {
+        rec.market_value = total;
+    }
+
+    Ok(rec)
+}
+
+/// Read a Binance.US trade history CSV returning the converted records
+/// and the rows which couldn't be converted, including those with a
+/// Market which can't be split using quote_assets, see split_market.
+/// An error is returned only if the input isn't a readable trade history.
+pub fn read_binanceus_trades<R: Read>(
+    rdr: R,
+    quote_assets: &[&str],
+) -> Result<(Vec<TaxBitExportRec>, Vec<RejectedRow>), Box<dyn Error>> {
+    let mut reader = csv::Reader::from_reader(rdr);
+    let columns = Columns::new(
+        reader.headers()?,
+        &TRADE_COLUMNS,
+        "Binance.US trade history",
+    )?;
+
+    let mut recs: Vec<TaxBitExportRec> = vec![];
+    let mut rejects: Vec<RejectedRow> = vec![];
+    for record in reader.records() {
+        let record = record?;
+        match trade_record_to_rec(&columns, &record, quote_assets) {
+            Ok(rec) => recs.push(rec),
+            Err(reason) => rejects.push(RejectedRow::new(&record, reason)),
+        }
+    }
+
+    Ok((recs, rejects))
+}
+
+fn distribution_record_to_rec(
+    columns: &Columns,
+    record: &csv::StringRecord,
+) -> Result<TaxBitExportRec, String> {
+    let category = columns.get(record, "Category");
+    let lower = category.to_lowercase();
+    if !lower.contains("staking") && !lower.contains("referral") {
+        return Err(format!("Unsupported Category '{category}'"));
+    }
+
+    let mut rec = TaxBitExportRec::new();
+    rec.time = dt_str_to_utc_time_ms_flexible(columns.get(record, "Date(UTC)"))?;
+    rec.type_txs = TaxBitRecType::Income;
+    rec.received_quantity = parse_decimal_opt("Amount", columns.get(record, "Amount"))?;
+    rec.received_currency = columns.get(record, "Coin").to_owned();
+    rec.source = BINANCEUS_SOURCE.to_owned();
+
+    Ok(rec)
+}
+
+/// Read a Binance.US distribution history CSV returning the converted
+/// records and the rows which couldn't be converted. An error is returned
+/// only if the input isn't a readable distribution history.
+pub fn read_binanceus_distributions<R: Read>(
+    rdr: R,
+) -> Result<(Vec<TaxBitExportRec>, Vec<RejectedRow>), Box<dyn Error>> {
+    let mut reader = csv::Reader::from_reader(rdr);
+    let columns = Columns::new(
+        reader.headers()?,
+        &DISTRIBUTION_COLUMNS,
+        "Binance.US distribution history",
+    )?;
+
+    let mut recs: Vec<TaxBitExportRec> = vec![];
+    let mut rejects: Vec<RejectedRow> = vec![];
+    for record in reader.records() {
+        let record = record?;
+        match distribution_record_to_rec(&columns, &record) {
+            Ok(rec) => recs.push(rec),
+            Err(reason) => rejects.push(RejectedRow::new(&record, reason)),
+        }
+    }
+
+    Ok((recs, rejects))
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    const TRADES_CSV: &str = r#"Date(UTC),Market,Type,Price,Amount,Total,Fee,Fee Coin
+2021-01-02 10:00:00,BTCUSD,BUY,33000,0.03,990,0.00003,BTC
+2021-01-03 11:00:00,BTCUSD,SELL,35000,0.01,350,0.35,USD
+2021-01-04 12:00:00,ADABTC,BUY,0.00001,1000,0.01,0.75,ADA
+2021-01-05 13:00:00,ETHBTC,SELL,0.03,0.5,0.015,0.0001,BNB
+2021-01-06 14:00:00,BTCUSDT,BUY,36000,0.01,360,0.36,USDT
+2021-01-07 15:00:00,XYZ,BUY,1,1,1,,
+"#;
+
+    const DISTRIBUTIONS_CSV: &str = r#"Date(UTC),Coin,Amount,Category
+2021-02-01 00:00:00,ADA,1.25,Staking Rewards
+2021-02-02 00:00:00,BTC,0.00001,Referral Commission
+2021-02-03 00:00:00,XRP,10,Airdrop
+"#;
+
+    #[test]
+    fn test_split_market() {
+        assert_eq!(
+            split_market("BTCUSD", &BINANCEUS_QUOTE_ASSETS).unwrap(),
+            ("BTC".to_owned(), "USD".to_owned())
+        );
+        assert_eq!(
+            split_market("BTCUSDT", &BINANCEUS_QUOTE_ASSETS).unwrap(),
+            ("BTC".to_owned(), "USDT".to_owned())
+        );
+        assert_eq!(
+            split_market("ADABTC", &BINANCEUS_QUOTE_ASSETS).unwrap(),
+            ("ADA".to_owned(), "BTC".to_owned())
+        );
+        // Only symbols ending in one of the quote assets can be split
+        assert!(split_market("BTCUSDT", &["USD"]).is_err());
+        assert!(split_market("USD", &["USD"]).is_err());
+    }
+
+    #[test]
+    fn test_read_binanceus_trades() {
+        let (recs, rejects) =
+            read_binanceus_trades(TRADES_CSV.as_bytes(), &BINANCEUS_QUOTE_ASSETS).unwrap();
+        assert_eq!(recs.len(), 5);
+
+        // USD quoted
+        let buy = &recs[0];
+        assert_eq!(buy.type_txs, TaxBitRecType::Buy);
+        assert_eq!(buy.time, 1609581600000);
+        assert_eq!(buy.received_quantity, Some(dec!(0.03)));
+        assert_eq!(buy.received_currency, "BTC");
+        assert_eq!(buy.sent_quantity, Some(dec!(990)));
+        assert_eq!(buy.sent_currency, "USD");
+        assert_eq!(buy.market_value, Some(dec!(990)));
+        assert_eq!(buy.source, BINANCEUS_SOURCE);
+        let sale = &recs[1];
+        assert_eq!(sale.type_txs, TaxBitRecType::Sale);
+        assert_eq!(sale.sent_currency, "BTC");
+        assert_eq!(sale.received_quantity, Some(dec!(350)));
+        assert_eq!(sale.fee_currency, "USD");
+
+        // Crypto to crypto
+        let trade = &recs[2];
+        assert_eq!(trade.type_txs, TaxBitRecType::Trade);
+        assert_eq!(trade.received_quantity, Some(dec!(1000)));
+        assert_eq!(trade.received_currency, "ADA");
+        assert_eq!(trade.sent_quantity, Some(dec!(0.01)));
+        assert_eq!(trade.sent_currency, "BTC");
+        assert_eq!(trade.market_value, None);
+
+        // Fee in a third coin
+        let trade = &recs[3];
+        assert_eq!(trade.type_txs, TaxBitRecType::Trade);
+        assert_eq!(trade.sent_currency, "ETH");
+        assert_eq!(trade.received_currency, "BTC");
+        assert_eq!(trade.fee_amount, Some(dec!(0.0001)));
+        assert_eq!(trade.fee_currency, "BNB");
+
+        assert_eq!(recs[4].type_txs, TaxBitRecType::Trade);
+        assert_eq!(recs[4].sent_currency, "USDT");
+
+        assert_eq!(rejects.len(), 1);
+        assert!(rejects[0]
+            .reason
+            .starts_with("Unable to split Market 'XYZ'"));
+    }
+
+    #[test]
+    fn test_read_binanceus_distributions() {
+        let (recs, rejects) = read_binanceus_distributions(DISTRIBUTIONS_CSV.as_bytes()).unwrap();
+        assert_eq!(recs.len(), 2);
+        assert!(recs.iter().all(|r| r.type_txs == TaxBitRecType::Income));
+        assert_eq!(recs[0].received_quantity, Some(dec!(1.25)));
+        assert_eq!(recs[0].received_currency, "ADA");
+        assert_eq!(recs[0].time, 1612137600000);
+        assert_eq!(recs[1].received_currency, "BTC");
+
+        assert_eq!(rejects.len(), 1);
+        assert_eq!(rejects[0].reason, "Unsupported Category 'Airdrop'");
+    }
+}
@@ -0,0 +1,265 @@
+use chrono::{Datelike, Duration, Months, NaiveDate};
+
+use crate::TaxBitExportRec;
+
+/// The length of the buckets of bucket_by, all are UTC calendar periods
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BucketPeriod {
+    Day,
+    /// Starting on Monday
+    Week,
+    Month,
+    Year,
+}
+
+impl BucketPeriod {
+    /// The start of the period containing date
+    fn start_of(&self, date: NaiveDate) -> NaiveDate {
+        match self {
+            BucketPeriod::Day => date,
+            BucketPeriod::Week => date
+                .checked_sub_signed(Duration::days(date.weekday().num_days_from_monday() as i64))
+                .unwrap_or(date),
+            BucketPeriod::Month => date.with_day(1).expect("SNH"),
+            BucketPeriod::Year => date.with_ordinal(1).expect("SNH"),
+        }
+    }
+
+    /// The start of the period following the one starting at start, None
+    /// if it's after the last date chrono supports
+    fn next_start(&self, start: NaiveDate) -> Option<NaiveDate> {
+        match self {
+            BucketPeriod::Day => start.checked_add_signed(Duration::days(1)),
+            BucketPeriod::Week => start.checked_add_signed(Duration::days(7)),
+            BucketPeriod::Month => start.checked_add_months(Months::new(1)),
+            BucketPeriod::Year => start.checked_add_months(Months::new(12)),
+        }
+    }
+}
+
+fn date_to_time_ms(date: NaiveDate) -> i64 {
+    date.and_hms_opt(0, 0, 0)
+        .expect("SNH")
+        .and_utc()
+        .timestamp_millis()
+}
+
+/// Options for bucket_by_with_opts
+#[derive(Debug, Clone, Default)]
+pub struct BucketOpts {
+    /// Also return the buckets with no records between the first and
+    /// last record so the buckets are contiguous
+    pub include_empty: bool,
+}
+
+/// The records in [start_ms, end_ms)
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimeBucket<'a> {
+    pub start_ms: i64,
+
+    /// The start of the next bucket
+    pub end_ms: i64,
+
+    pub recs: &'a [TaxBitExportRec],
+}
+
+struct BucketIter<'a> {
+    recs: &'a [TaxBitExportRec],
+    period: BucketPeriod,
+    include_empty: bool,
+    next: usize,
+    next_start: Option<NaiveDate>,
+}
+
+impl<'a> Iterator for BucketIter<'a> {
+    type Item = TimeBucket<'a>;
+
+    fn next(&mut self) -> Option<TimeBucket<'a>> {
+        let rest = &self.recs[self.next..];
+        let first = rest.first()?;
+        let start = match self.next_start {
+            Some(start) if self.include_empty => start,
+            _ => self.period.start_of(first.date_utc()),
+        };
+        // The last period chrono supports holds the rest of the records
+        let end = self.period.next_start(start);
+        let end_ms = end.map_or(i64::MAX, date_to_time_ms);
+        let len = match end {
+            Some(_) => rest.iter().take_while(|r| r.time < end_ms).count(),
+            None => rest.len(),
+        };
+
+        self.next += len;
+        self.next_start = end;
+        Some(TimeBucket {
+            start_ms: date_to_time_ms(start),
+            end_ms,
+            recs: &rest[..len],
+        })
+    }
+}
+
+/// Iterate over the records one UTC calendar period at a time, only
+/// buckets with records are returned.
+///
+/// # Panics
+///
+/// In debug builds if recs isn't sorted by time
+pub fn bucket_by(
+    recs: &[TaxBitExportRec],
+    period: BucketPeriod,
+) -> impl Iterator<Item = TimeBucket<'_>> {
+    bucket_by_with_opts(recs, period, BucketOpts::default())
+}
+
+/// Iterate over the records one UTC calendar period at a time as
+/// bucket_by does, with opts.include_empty the buckets with no records
+/// are also returned.
+///
+/// # Panics
+///
+/// In debug builds if recs isn't sorted by time
+pub fn bucket_by_with_opts(
+    recs: &[TaxBitExportRec],
+    period: BucketPeriod,
+    opts: BucketOpts,
+) -> impl Iterator<Item = TimeBucket<'_>> {
+    debug_assert!(
+        recs.windows(2).all(|w| w[0].time <= w[1].time),
+        "bucket_by requires records sorted by time"
+    );
+
+    BucketIter {
+        recs,
+        period,
+        include_empty: opts.include_empty,
+        next: 0,
+        next_start: None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ms(y: i32, m: u32, d: u32) -> i64 {
+        date_to_time_ms(NaiveDate::from_ymd_opt(y, m, d).unwrap())
+    }
+
+    fn recs(times: &[i64]) -> Vec<TaxBitExportRec> {
+        times
+            .iter()
+            .map(|t| {
+                let mut rec = TaxBitExportRec::new();
+                rec.time = *t;
+                rec
+            })
+            .collect()
+    }
+
+    // (start_ms, end_ms, number of records) of each bucket
+    fn summary<'a>(buckets: impl Iterator<Item = TimeBucket<'a>>) -> Vec<(i64, i64, usize)> {
+        buckets
+            .map(|b| (b.start_ms, b.end_ms, b.recs.len()))
+            .collect()
+    }
+
+    fn leap_year_fixture() -> Vec<TaxBitExportRec> {
+        recs(&[
+            ms(2024, 1, 31) + 1,
+            ms(2024, 2, 1),
+            ms(2024, 2, 28) + 1000,
+            ms(2024, 2, 29),
+            ms(2024, 3, 1) - 1,
+            ms(2024, 3, 1),
+            ms(2024, 5, 31),
+        ])
+    }
+
+    #[test]
+    fn test_bucket_by_month() {
+        let recs = &leap_year_fixture();
+        assert_eq!(
+            summary(bucket_by(recs, BucketPeriod::Month)),
+            vec![
+                (ms(2024, 1, 1), ms(2024, 2, 1), 1),
+                (ms(2024, 2, 1), ms(2024, 3, 1), 4),
+                (ms(2024, 3, 1), ms(2024, 4, 1), 1),
+                (ms(2024, 5, 1), ms(2024, 6, 1), 1),
+            ]
+        );
+
+        let opts = BucketOpts {
+            include_empty: true,
+        };
+        let buckets = summary(bucket_by_with_opts(recs, BucketPeriod::Month, opts));
+        assert_eq!(buckets.len(), 5);
+        assert_eq!(buckets[3], (ms(2024, 4, 1), ms(2024, 5, 1), 0));
+        let days: Vec<i64> = buckets
+            .iter()
+            .map(|(start, end, _)| (end - start) / (24 * 60 * 60 * 1000))
+            .collect();
+        assert_eq!(days, vec![31, 29, 31, 30, 31]);
+    }
+
+    #[test]
+    fn test_bucket_by_day() {
+        let recs = &leap_year_fixture()[2..6];
+        let opts = BucketOpts {
+            include_empty: true,
+        };
+        assert_eq!(
+            summary(bucket_by_with_opts(recs, BucketPeriod::Day, opts)),
+            vec![
+                (ms(2024, 2, 28), ms(2024, 2, 29), 1),
+                (ms(2024, 2, 29), ms(2024, 3, 1), 2),
+                (ms(2024, 3, 1), ms(2024, 3, 2), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bucket_by_week_and_year() {
+        let recs = &leap_year_fixture();
+        // 2024-02-26 is a Monday
+        let weeks = summary(bucket_by(recs, BucketPeriod::Week));
+        assert_eq!(weeks[0], (ms(2024, 1, 29), ms(2024, 2, 5), 2));
+        assert_eq!(weeks[1], (ms(2024, 2, 26), ms(2024, 3, 4), 4));
+
+        assert_eq!(
+            summary(bucket_by(recs, BucketPeriod::Year)),
+            vec![(ms(2024, 1, 1), ms(2025, 1, 1), 7)]
+        );
+        assert_eq!(ms(2025, 1, 1) - ms(2024, 1, 1), 366 * 24 * 60 * 60 * 1000);
+    }
+
+    #[test]
+    fn test_bucket_by_empty() {
+        assert_eq!(bucket_by(&[], BucketPeriod::Day).count(), 0);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "sorted by time")]
+    fn test_bucket_by_unsorted() {
+        let recs = recs(&[ms(2024, 2, 1), ms(2024, 1, 1)]);
+        let _ = bucket_by(&recs, BucketPeriod::Day);
+    }
+
+    #[test]
+    fn test_bucket_by_last_date() {
+        let recs = recs(&[ms(2024, 1, 1), i64::MAX - 1, i64::MAX]);
+        for period in [
+            BucketPeriod::Day,
+            BucketPeriod::Week,
+            BucketPeriod::Month,
+            BucketPeriod::Year,
+        ] {
+            let buckets = summary(bucket_by(&recs, period));
+            assert_eq!(buckets.len(), 2, "{period:?}");
+            assert_eq!(buckets[0].2, 1);
+            assert_eq!(buckets[1].1, i64::MAX);
+            assert_eq!(buckets[1].2, 2);
+        }
+    }
+}
@@ -0,0 +1,277 @@
+//! Convert a Coinbase transaction history report to TaxBit export records
+//!
+//! The columns used are Timestamp, Transaction Type, Asset, Quantity
+//! Transacted, Spot Price at Transaction, Subtotal, Total, Fees and Notes,
+//! any others are ignored, as are the lines before the header. Prices,
+//! Subtotal and Fees are assumed to be USD and may have a "$" prefix.
+//!
+//! | Transaction Type | TaxBitRecType | Sides                               |
+//! |------------------|---------------|-------------------------------------|
+//! | Buy              | Buy           | received Asset, sent USD Subtotal   |
+//! | Sell             | Sale          | sent Asset, received USD Subtotal   |
+//! | Send             | TransferOut   | sent Asset                          |
+//! | Receive          | TransferIn    | received Asset                      |
+//! | Convert          | Trade         | both parsed from Notes              |
+//! | Rewards Income   | Income        | received Asset                      |
+//! | Learning Reward  | Income        | received Asset                      |
+//!
+//! market_value is the Subtotal or, if it's empty, Quantity Transacted
+//! times Spot Price at Transaction. Other transaction types are rejected.
+use std::{error::Error, io::Read};
+
+use rust_decimal::Decimal;
+use taxbitrec::TaxBitRecType;
+
+use crate::{
+    convert::{parse_decimal_opt, Columns},
+    dt_str_to_utc_time_ms_flexible, RejectedRow, TaxBitExportRec,
+};
+
+/// Source of the converted records
+pub const COINBASE_SOURCE: &str = "Coinbase";
+
+const COLUMNS: [&str; 9] = [
+    "Timestamp",
+    "Transaction Type",
+    "Asset",
+    "Quantity Transacted",
+    "Spot Price at Transaction",
+    "Subtotal",
+    "Total",
+    "Fees",
+    "Notes",
+];
+
+// A USD amount, "$1,234.56" or "1234.56"
+fn parse_usd_opt(column: &str, s: &str) -> Result<Option<Decimal>, String> {
+    parse_decimal_opt(column, &s.trim().replacen('$', "", 1).replace(',', ""))
+}
+
+// Coinbase writes "2021-01-02T10:00:00Z" or "2021-01-02 10:00:00 UTC"
+fn parse_coinbase_timestamp(s: &str) -> Result<i64, String> {
+    dt_str_to_utc_time_ms_flexible(s.trim_end_matches("UTC").trim())
+}
+
+// Set the sent and received sides from "Converted 0.5 ETH to 800 USDC"
+fn set_sides_from_convert_notes(rec: &mut TaxBitExportRec, notes: &str) -> Result<(), String> {
+    let qty = |s: &str| {
+        parse_decimal_opt("Notes", &s.replace(',', ""))
+            .ok()
+            .flatten()
+    };
+
+    let words: Vec<&str> = notes.split_whitespace().collect();
+    if let ["Converted", sent_qty, sent_asset, "to", received_qty, received_asset] =
+        words.as_slice()
+    {
+        if let (Some(sent_qty), Some(received_qty)) = (qty(sent_qty), qty(received_qty)) {
+            rec.sent_quantity = Some(sent_qty);
+            rec.sent_currency = sent_asset.to_string();
+            rec.received_quantity = Some(received_qty);
+            rec.received_currency = received_asset.to_string();
+            return Ok(());
+        }
+    }
+
+    Err(format!("Unable to parse Convert Notes '{notes}'"))
+}
+
+fn coinbase_record_to_rec(
+    columns: &Columns,
+    record: &csv::StringRecord,
+) -> Result<TaxBitExportRec, String> {
+    let asset = columns.get(record, "Asset");
+    let quantity = parse_decimal_opt(
+        "Quantity Transacted",
+        columns.get(record, "Quantity Transacted"),
+    )?;
+    let spot_price = parse_usd_opt(
+        "Spot Price at Transaction",
+        columns.get(record, "Spot Price at Transaction"),
+    )?;
+    let subtotal = parse_usd_opt("Subtotal", columns.get(record, "Subtotal"))?;
+
+    let mut rec = TaxBitExportRec::new();
+    rec.time = parse_coinbase_timestamp(columns.get(record, "Timestamp"))?;
+    rec.source = COINBASE_SOURCE.to_owned();
+    rec.fee_amount = parse_usd_opt("Fees", columns.get(record, "Fees"))?;
+    if rec.fee_amount.is_some() {
+        rec.fee_currency = "USD".to_owned();
+    }
+    rec.market_value = match (subtotal, quantity, spot_price) {
+        (Some(subtotal), _, _) => Some(subtotal),
+        (None, Some(quantity), Some(spot_price)) => Some(quantity * spot_price),
+        _ => None,
+    };
+
+    let received = |rec: &mut TaxBitExportRec| {
+        rec.received_quantity = quantity;
+        rec.received_currency = asset.to_owned();
+    };
+    match columns.get(record, "Transaction Type") {
+        "Buy" => {
+            rec.type_txs = TaxBitRecType::Buy;
+            received(&mut rec);
+            rec.sent_quantity = subtotal;
+            rec.sent_currency = "USD".to_owned();
+        }
+        "Sell" => {
+            rec.type_txs = TaxBitRecType::Sale;
+            rec.sent_quantity = quantity;
+            rec.sent_currency = asset.to_owned();
+            rec.received_quantity = subtotal;
+            rec.received_currency = "USD".to_owned();
+        }
+        "Send" => {
+            rec.type_txs = TaxBitRecType::TransferOut;
+            rec.sent_quantity = quantity;
+            rec.sent_currency = asset.to_owned();
+        }
+        "Receive" => {
+            rec.type_txs = TaxBitRecType::TransferIn;
+            received(&mut rec);
+        }
+        "Convert" => {
+            rec.type_txs = TaxBitRecType::Trade;
+            set_sides_from_convert_notes(&mut rec, columns.get(record, "Notes"))?;
+        }
+        "Rewards Income" | "Learning Reward" => {
+            rec.type_txs = TaxBitRecType::Income;
+            received(&mut rec);
+        }
+        txs_type => return Err(format!("Unsupported Transaction Type '{txs_type}'")),
+    }
+
+    Ok(rec)
+}
+
+/// Read a Coinbase transaction history report returning the converted
+/// records and the rows which couldn't be converted. An error is returned
+/// only if the input isn't a readable Coinbase transaction history report.
+pub fn read_coinbase_csv<R: Read>(
+    rdr: R,
+) -> Result<(Vec<TaxBitExportRec>, Vec<RejectedRow>), Box<dyn Error>> {
+    // The report has a preamble of a varying number of lines before the header
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(rdr);
+    let mut records = reader.records();
+    let columns = loop {
+        match records.next() {
+            Some(record) => {
+                let record = record?;
+                if record.iter().any(|f| f.trim() == "Timestamp") {
+                    break Columns::new(&record, &COLUMNS, "Coinbase transaction history")?;
+                }
+            }
+            None => return Err("Not a Coinbase transaction history CSV, no header".into()),
+        }
+    };
+
+    let mut recs: Vec<TaxBitExportRec> = vec![];
+    let mut rejects: Vec<RejectedRow> = vec![];
+    for record in records {
+        let record = record?;
+        match coinbase_record_to_rec(&columns, &record) {
+            Ok(rec) => recs.push(rec),
+            Err(reason) => rejects.push(RejectedRow::new(&record, reason)),
+        }
+    }
+
+    Ok((recs, rejects))
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    const COINBASE_CSV: &str = r#"You can use this transaction report to inform your likely tax obligations.
+Transactions
+User,someone@example.com,abc123
+Timestamp,Transaction Type,Asset,Quantity Transacted,Spot Price Currency,Spot Price at Transaction,Subtotal,Total (inclusive of fees and/or spread),Total,Fees,Notes
+2021-01-02T10:00:00Z,Buy,BTC,0.03,USD,"$33,000.00","$990.00",$1000.00,$1000.00,$10.00,Bought 0.03 BTC for $1000.00 USD
+2021-01-03T11:00:00Z,Sell,BTC,0.01,USD,35000,350,345,345,5,Sold 0.01 BTC for $345.00 USD
+2021-01-04 12:00:00 UTC,Send,BTC,0.005,USD,36000,,,,,Sent 0.005 BTC to 1abc
+2021-01-05T13:00:00Z,Receive,ETH,0.5,USD,1100,,,,,Received 0.5 ETH from an external account
+2021-01-06T14:00:00Z,Convert,ETH,0.5,USD,1600,800,810,810,10,"Converted 0.5 ETH to 1,600 USDC"
+2021-01-07T15:00:00Z,Rewards Income,ALGO,1.25,USD,0.40,0.50,0.50,0.50,,Received 1.25 ALGO from Coinbase Rewards
+2021-01-08T16:00:00Z,Learning Reward,GRT,3,USD,0.6,1.8,1.8,1.8,,Received 3 GRT from Coinbase Earn
+2021-01-09T17:00:00Z,Advanced Trade Buy,BTC,0.01,USD,37000,370,370,370,,Bought 0.01 BTC
+2021-01-10T18:00:00Z,Convert,ETH,0.5,USD,1600,800,810,810,10,Converted some ETH
+"#;
+
+    #[test]
+    fn test_read_coinbase_csv() {
+        let (recs, rejects) = read_coinbase_csv(COINBASE_CSV.as_bytes()).unwrap();
+        let types: Vec<TaxBitRecType> = recs.iter().map(|r| r.type_txs.clone()).collect();
+        assert_eq!(
+            types,
+            vec![
+                TaxBitRecType::Buy,
+                TaxBitRecType::Sale,
+                TaxBitRecType::TransferOut,
+                TaxBitRecType::TransferIn,
+                TaxBitRecType::Trade,
+                TaxBitRecType::Income,
+                TaxBitRecType::Income,
+            ]
+        );
+        assert!(recs.iter().all(|r| r.source == COINBASE_SOURCE));
+
+        let buy = &recs[0];
+        assert_eq!(buy.time, 1609581600000);
+        assert_eq!(buy.received_quantity, Some(dec!(0.03)));
+        assert_eq!(buy.received_currency, "BTC");
+        assert_eq!(buy.sent_quantity, Some(dec!(990.00)));
+        assert_eq!(buy.sent_currency, "USD");
+        assert_eq!(buy.fee_amount, Some(dec!(10.00)));
+        assert_eq!(buy.fee_currency, "USD");
+        assert_eq!(buy.market_value, Some(dec!(990.00)));
+
+        let sale = &recs[1];
+        assert_eq!(sale.sent_quantity, Some(dec!(0.01)));
+        assert_eq!(sale.sent_currency, "BTC");
+        assert_eq!(sale.received_quantity, Some(dec!(350)));
+        assert_eq!(sale.received_currency, "USD");
+
+        // No Subtotal, market value is quantity times spot price
+        let send = &recs[2];
+        assert_eq!(send.time, 1609761600000);
+        assert_eq!(send.sent_quantity, Some(dec!(0.005)));
+        assert_eq!(send.market_value, Some(dec!(180)));
+        assert_eq!(send.fee_currency, "");
+
+        let convert = &recs[4];
+        assert_eq!(convert.sent_quantity, Some(dec!(0.5)));
+        assert_eq!(convert.sent_currency, "ETH");
+        assert_eq!(convert.received_quantity, Some(dec!(1600)));
+        assert_eq!(convert.received_currency, "USDC");
+        assert_eq!(convert.market_value, Some(dec!(800)));
+
+        assert_eq!(recs[5].received_currency, "ALGO");
+        assert_eq!(recs[6].received_quantity, Some(dec!(3)));
+
+        assert_eq!(rejects.len(), 2);
+        assert_eq!(rejects[0].line, 12);
+        assert_eq!(
+            rejects[0].reason,
+            "Unsupported Transaction Type 'Advanced Trade Buy'"
+        );
+        assert_eq!(
+            rejects[0].row,
+            "2021-01-09T17:00:00Z,Advanced Trade Buy,BTC,0.01,USD,37000,370,370,370,,Bought 0.01 BTC"
+        );
+        assert_eq!(
+            rejects[1].reason,
+            "Unable to parse Convert Notes 'Converted some ETH'"
+        );
+    }
+
+    #[test]
+    fn test_read_coinbase_csv_no_header() {
+        assert!(read_coinbase_csv("a,b\n1,2\n".as_bytes()).is_err());
+    }
+}
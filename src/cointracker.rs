@@ -0,0 +1,227 @@
+//! Convert a CoinTracker CSV export to TaxBit export records
+//!
+//! The columns used are Date, Received Quantity, Received Currency,
+//! Sent Quantity, Sent Currency, Fee Amount, Fee Currency and Tag, any
+//! others are ignored. CoinTracker has no market value or transaction
+//! id so market_value is None and external_id is empty.
+//!
+//! The TaxBitRecType is inferred from the Tag, case insensitive, and
+//! which sides, received and sent, are populated:
+//!
+//! | Tag      | Sides          | TaxBitRecType                                    |
+//! |----------|----------------|--------------------------------------------------|
+//! | staked   | received       | Income                                           |
+//! | gift     | received       | GiftReceived                                     |
+//! | gift     | sent           | GiftSent                                         |
+//! | payment  | sent           | Expense                                          |
+//! | transfer | received       | TransferIn, internal_transfer true               |
+//! | transfer | sent           | TransferOut, internal_transfer true              |
+//! |          | received, sent | Buy if USD sent, Sale if USD received else Trade |
+//! |          | received       | TransferIn                                       |
+//! |          | sent           | TransferOut                                      |
+//!
+//! Any other combination, including an unsupported Tag, is rejected.
+use std::{error::Error, io::Read};
+
+use chrono::NaiveDateTime;
+use taxbitrec::TaxBitRecType;
+use time_ms_conversions::{dt_str_to_utc_time_ms, TzMassaging};
+
+use crate::{
+    convert::{exchange_type, parse_decimal_opt, Columns},
+    RejectedRow, TaxBitExportRec,
+};
+
+/// Source of the converted records
+pub const COINTRACKER_SOURCE: &str = "CoinTracker";
+
+const COLUMNS: [&str; 8] = [
+    "Date",
+    "Received Quantity",
+    "Received Currency",
+    "Sent Quantity",
+    "Sent Currency",
+    "Fee Amount",
+    "Fee Currency",
+    "Tag",
+];
+
+// TzMassaging isn't Copy, make a copy for each row
+fn copy_tz(tz: &TzMassaging) -> TzMassaging {
+    match tz {
+        TzMassaging::HasTz => TzMassaging::HasTz,
+        TzMassaging::CondAddTzUtc => TzMassaging::CondAddTzUtc,
+        TzMassaging::LocalTz => TzMassaging::LocalTz,
+    }
+}
+
+// CoinTracker writes naive dates, "01/02/2021 10:00:00", they are
+// normalized to "2021-01-02 10:00:00" and tz decides the time zone.
+fn parse_cointracker_date(s: &str, tz: &TzMassaging) -> Result<i64, String> {
+    let normalized = ["%m/%d/%Y %H:%M:%S", "%m/%d/%Y %H:%M", "%Y-%m-%d %H:%M:%S"]
+        .iter()
+        .find_map(|fmt| NaiveDateTime::parse_from_str(s, fmt).ok())
+        .map(|ndt| ndt.format("%Y-%m-%d %H:%M:%S").to_string());
+
+    let dt_str = normalized.as_deref().unwrap_or(s);
+    dt_str_to_utc_time_ms(dt_str, copy_tz(tz))
+        .map_err(|e| format!("Unable to parse Date '{s}': {e}"))
+}
+
+fn type_from_tag(tag: &str, rec: &mut TaxBitExportRec) -> Result<TaxBitRecType, String> {
+    let has_received = !rec.received_currency.is_empty();
+    let has_sent = !rec.sent_currency.is_empty();
+
+    let tag = tag.to_lowercase();
+    Ok(match (tag.as_str(), has_received, has_sent) {
+        ("staked", true, false) => TaxBitRecType::Income,
+        ("gift", true, false) => TaxBitRecType::GiftReceived,
+        ("gift", false, true) => TaxBitRecType::GiftSent,
+        ("payment", false, true) => TaxBitRecType::Expense,
+        ("transfer", true, false) => {
+            rec.internal_transfer = true;
+            TaxBitRecType::TransferIn
+        }
+        ("transfer", false, true) => {
+            rec.internal_transfer = true;
+            TaxBitRecType::TransferOut
+        }
+        ("", true, true) => exchange_type(rec),
+        ("", true, false) => TaxBitRecType::TransferIn,
+        ("", false, true) => TaxBitRecType::TransferOut,
+        ("staked" | "gift" | "payment" | "transfer" | "", _, _) => {
+            return Err(format!(
+                "Tag '{tag}' with received {} and sent {} is not supported",
+                if has_received { "present" } else { "empty" },
+                if has_sent { "present" } else { "empty" },
+            ))
+        }
+        (tag, _, _) => return Err(format!("Unsupported Tag '{tag}'")),
+    })
+}
+
+fn cointracker_record_to_rec(
+    columns: &Columns,
+    record: &csv::StringRecord,
+    tz: &TzMassaging,
+) -> Result<TaxBitExportRec, String> {
+    let mut rec = TaxBitExportRec::new();
+    rec.time = parse_cointracker_date(columns.get(record, "Date"), tz)?;
+    rec.received_quantity = parse_decimal_opt(
+        "Received Quantity",
+        columns.get(record, "Received Quantity"),
+    )?;
+    rec.received_currency = columns.get(record, "Received Currency").to_owned();
+    rec.sent_quantity = parse_decimal_opt("Sent Quantity", columns.get(record, "Sent Quantity"))?;
+    rec.sent_currency = columns.get(record, "Sent Currency").to_owned();
+    rec.fee_amount = parse_decimal_opt("Fee Amount", columns.get(record, "Fee Amount"))?;
+    rec.fee_currency = columns.get(record, "Fee Currency").to_owned();
+    rec.source = COINTRACKER_SOURCE.to_owned();
+    rec.type_txs = type_from_tag(columns.get(record, "Tag"), &mut rec)?;
+
+    Ok(rec)
+}
+
+/// Read a CoinTracker CSV returning the converted records and the rows
+/// which couldn't be converted. The dates have no time zone, tz decides
+/// how they're interpreted. An error is returned only if the input isn't
+/// a readable CoinTracker CSV.
+pub fn read_cointracker_csv<R: Read>(
+    rdr: R,
+    tz: TzMassaging,
+) -> Result<(Vec<TaxBitExportRec>, Vec<RejectedRow>), Box<dyn Error>> {
+    let mut reader = csv::Reader::from_reader(rdr);
+    let columns = Columns::new(reader.headers()?, &COLUMNS, "CoinTracker")?;
+
+    let mut recs: Vec<TaxBitExportRec> = vec![];
+    let mut rejects: Vec<RejectedRow> = vec![];
+    for record in reader.records() {
+        let record = record?;
+        match cointracker_record_to_rec(&columns, &record, &tz) {
+            Ok(rec) => recs.push(rec),
+            Err(reason) => rejects.push(RejectedRow::new(&record, reason)),
+        }
+    }
+
+    Ok((recs, rejects))
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    const COINTRACKER_CSV: &str = r#"Date,Received Quantity,Received Currency,Sent Quantity,Sent Currency,Fee Amount,Fee Currency,Tag
+01/02/2021 10:00:00,1.5,ADA,,,,,staked
+01/03/2021 11:00:00,0.1,ETH,,,,,gift
+01/04/2021 12:00:00,,,0.1,ETH,,,Gift
+01/05/2021 13:00:00,,,0.001,BTC,0.00001,BTC,payment
+01/06/2021 14:00:00,0.5,BTC,,,,,transfer
+01/07/2021 15:00:00,,,0.5,BTC,0.0001,BTC,transfer
+01/08/2021 16:00:00,0.03,BTC,1000,USD,1.5,USD,
+01/09/2021 17:00:00,350,USD,0.01,BTC,,,
+01/10/2021 18:00:00,0.3,ETH,0.01,BTC,,,
+01/11/2021 19:00:00,5,DOT,,,,,
+01/12/2021 20:00:00,,,5,DOT,,,
+01/13/2021 21:00:00,1,BTC,,,,,mined
+01/14/2021 22:00:00,1,BTC,1,ETH,,,staked
+"#;
+
+    #[test]
+    fn test_read_cointracker_csv() {
+        let (recs, rejects) =
+            read_cointracker_csv(COINTRACKER_CSV.as_bytes(), TzMassaging::CondAddTzUtc).unwrap();
+        let types: Vec<(TaxBitRecType, bool)> = recs
+            .iter()
+            .map(|r| (r.type_txs.clone(), r.internal_transfer))
+            .collect();
+        assert_eq!(
+            types,
+            vec![
+                (TaxBitRecType::Income, false),
+                (TaxBitRecType::GiftReceived, false),
+                (TaxBitRecType::GiftSent, false),
+                (TaxBitRecType::Expense, false),
+                (TaxBitRecType::TransferIn, true),
+                (TaxBitRecType::TransferOut, true),
+                (TaxBitRecType::Buy, false),
+                (TaxBitRecType::Sale, false),
+                (TaxBitRecType::Trade, false),
+                (TaxBitRecType::TransferIn, false),
+                (TaxBitRecType::TransferOut, false),
+            ]
+        );
+
+        let staked = &recs[0];
+        assert_eq!(staked.time, 1609581600000);
+        assert_eq!(staked.received_quantity, Some(dec!(1.5)));
+        assert_eq!(staked.received_currency, "ADA");
+        assert_eq!(staked.source, COINTRACKER_SOURCE);
+        let payment = &recs[3];
+        assert_eq!(payment.sent_quantity, Some(dec!(0.001)));
+        assert_eq!(payment.fee_amount, Some(dec!(0.00001)));
+        assert_eq!(payment.fee_currency, "BTC");
+
+        assert_eq!(rejects.len(), 2);
+        assert_eq!(rejects[0].reason, "Unsupported Tag 'mined'");
+        assert_eq!(
+            rejects[1].reason,
+            "Tag 'staked' with received present and sent present is not supported"
+        );
+    }
+
+    #[test]
+    fn test_read_cointracker_csv_bad_date() {
+        let csv = format!(
+            "{}\nnot a date,1,BTC,,,,,\n",
+            COINTRACKER_CSV.lines().next().unwrap()
+        );
+        let (recs, rejects) =
+            read_cointracker_csv(csv.as_bytes(), TzMassaging::CondAddTzUtc).unwrap();
+        assert!(recs.is_empty());
+        assert!(rejects[0]
+            .reason
+            .starts_with("Unable to parse Date 'not a date'"));
+    }
+}
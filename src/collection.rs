@@ -0,0 +1,521 @@
+use std::{collections::BTreeSet, fmt::Display, ops::RangeBounds};
+
+use taxbitrec::TaxBitRecType;
+use time_ms_conversions::time_ms_to_utc_string;
+
+use crate::{CostBasisEvent, TaxBitExportRec};
+
+/// An ordered collection of TaxBitExportRec's
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TaxBitExportRecCollection {
+    recs: Vec<TaxBitExportRec>,
+}
+
+/// Returned by sort_topologically when records with the same
+/// timestamp depend on each other in a cycle
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopologicalSortError {
+    /// The timestamp shared by the records
+    pub time: i64,
+
+    /// The records which could not be ordered
+    pub cycle: Vec<TaxBitExportRec>,
+}
+
+impl Display for TopologicalSortError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ids: Vec<&str> = self.cycle.iter().map(|r| r.external_id.as_str()).collect();
+        write!(
+            f,
+            "Cycle of {} records at {}, each disposes of an asset another acquires, External IDs: {}",
+            self.cycle.len(),
+            time_ms_to_utc_string(self.time),
+            ids.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for TopologicalSortError {}
+
+impl TaxBitExportRecCollection {
+    pub fn new() -> TaxBitExportRecCollection {
+        TaxBitExportRecCollection { recs: vec![] }
+    }
+
+    pub fn from_vec(recs: Vec<TaxBitExportRec>) -> TaxBitExportRecCollection {
+        TaxBitExportRecCollection { recs }
+    }
+
+    pub fn into_vec(self) -> Vec<TaxBitExportRec> {
+        self.recs
+    }
+
+    pub fn as_slice(&self) -> &[TaxBitExportRec] {
+        &self.recs
+    }
+
+    pub fn len(&self) -> usize {
+        self.recs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.recs.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&TaxBitExportRec> {
+        self.recs.get(index)
+    }
+
+    pub fn push(&mut self, rec: TaxBitExportRec) {
+        self.recs.push(rec);
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, TaxBitExportRec> {
+        self.recs.iter()
+    }
+
+    /// Remove the records in range returning them as an iterator, see Vec::drain.
+    ///
+    /// # Panics
+    ///
+    /// If the start of the range is greater than the end or the end
+    /// is greater than the length of the collection.
+    pub fn drain(
+        &mut self,
+        range: impl RangeBounds<usize>,
+    ) -> impl Iterator<Item = TaxBitExportRec> + '_ {
+        self.recs.drain(range)
+    }
+
+    /// A new collection with clones of the records in range.
+    ///
+    /// # Panics
+    ///
+    /// If the start of the range is greater than the end or the end
+    /// is greater than the length of the collection.
+    pub fn slice(&self, range: impl RangeBounds<usize>) -> TaxBitExportRecCollection {
+        let bounds = (range.start_bound().cloned(), range.end_bound().cloned());
+        TaxBitExportRecCollection::from_vec(self.recs[bounds].to_vec())
+    }
+
+    pub fn sort(&mut self) {
+        self.recs.sort();
+    }
+
+    /// The distinct assets, see TaxBitExportRec::get_asset, of all
+    /// records. Records with an Unknown type are skipped.
+    pub fn unique_assets(&self) -> BTreeSet<String> {
+        self.recs
+            .iter()
+            .filter(|r| r.type_txs != TaxBitRecType::Unknown)
+            .map(|r| r.get_asset().to_owned())
+            .collect()
+    }
+
+    /// The distinct assets of the records of type txs_type
+    pub fn unique_assets_for_type(&self, txs_type: TaxBitRecType) -> BTreeSet<String> {
+        self.recs
+            .iter()
+            .filter(|r| r.type_txs == txs_type && r.type_txs != TaxBitRecType::Unknown)
+            .map(|r| r.get_asset().to_owned())
+            .collect()
+    }
+
+    /// The CostBasisEvent's of the records, see TaxBitExportRec::to_cost_basis_events,
+    /// sorted by time_ms even if the collection isn't sorted. Events with the
+    /// same time_ms are in collection order, a Trade's Disposal before its
+    /// Acquisition.
+    pub fn to_cost_basis_events(&self) -> Vec<CostBasisEvent> {
+        let mut events: Vec<CostBasisEvent> = self
+            .recs
+            .iter()
+            .flat_map(|r| r.to_cost_basis_events())
+            .collect();
+        events.sort_by_key(|e| e.time_ms());
+
+        events
+    }
+
+    /// Sort the records by time and within the same timestamp order
+    /// acquisitions of an asset before disposals of that asset.
+    ///
+    /// Returns an error, leaving the collection unchanged, if records with
+    /// the same timestamp form a cycle, for instance two Trades where each
+    /// sends the asset the other receives.
+    pub fn sort_topologically(&mut self) -> Result<(), TopologicalSortError> {
+        let mut sorted: Vec<usize> = (0..self.recs.len()).collect();
+        sorted.sort_by(|a, b| self.recs[*a].cmp(&self.recs[*b]));
+
+        let mut order: Vec<usize> = Vec::with_capacity(sorted.len());
+        let mut start = 0;
+        while start < sorted.len() {
+            let time = self.recs[sorted[start]].time;
+            let mut end = start + 1;
+            while end < sorted.len() && self.recs[sorted[end]].time == time {
+                end += 1;
+            }
+            order.extend(self.order_same_time(&sorted[start..end])?);
+            start = end;
+        }
+
+        let mut recs: Vec<Option<TaxBitExportRec>> = std::mem::take(&mut self.recs)
+            .into_iter()
+            .map(Some)
+            .collect();
+        self.recs = order.iter().map(|i| recs[*i].take().unwrap()).collect();
+
+        Ok(())
+    }
+
+    // Kahn's algorithm over records sharing a timestamp, an edge from a
+    // record acquiring an asset to each record disposing of that asset.
+    // The earliest ready record in `group` order is always chosen so the
+    // result is deterministic.
+    fn order_same_time(&self, group: &[usize]) -> Result<Vec<usize>, TopologicalSortError> {
+        let n = group.len();
+        let mut in_degree = vec![0usize; n];
+        let mut edges: Vec<Vec<usize>> = vec![vec![]; n];
+        for (i, gi) in group.iter().enumerate() {
+            let acquired = match acquired_asset(&self.recs[*gi]) {
+                Some(asset) => asset,
+                None => continue,
+            };
+            for (j, gj) in group.iter().enumerate() {
+                if i != j && disposed_asset(&self.recs[*gj]) == Some(acquired) {
+                    edges[i].push(j);
+                    in_degree[j] += 1;
+                }
+            }
+        }
+
+        let mut done = vec![false; n];
+        let mut order = Vec::with_capacity(n);
+        while order.len() < n {
+            let next = (0..n).find(|i| !done[*i] && in_degree[*i] == 0);
+            match next {
+                Some(i) => {
+                    done[i] = true;
+                    order.push(group[i]);
+                    for j in &edges[i] {
+                        in_degree[*j] -= 1;
+                    }
+                }
+                None => {
+                    return Err(TopologicalSortError {
+                        time: self.recs[group[0]].time,
+                        cycle: (0..n)
+                            .filter(|i| !done[*i])
+                            .map(|i| self.recs[group[i]].clone())
+                            .collect(),
+                    });
+                }
+            }
+        }
+
+        Ok(order)
+    }
+}
+
+fn acquired_asset(rec: &TaxBitExportRec) -> Option<&str> {
+    match rec.type_txs {
+        TaxBitRecType::Buy
+        | TaxBitRecType::TransferIn
+        | TaxBitRecType::Income
+        | TaxBitRecType::GiftReceived
+        | TaxBitRecType::Trade
+            if !rec.received_currency.is_empty() =>
+        {
+            Some(rec.received_currency.as_str())
+        }
+        _ => None,
+    }
+}
+
+fn disposed_asset(rec: &TaxBitExportRec) -> Option<&str> {
+    match rec.type_txs {
+        TaxBitRecType::Sale
+        | TaxBitRecType::TransferOut
+        | TaxBitRecType::Expense
+        | TaxBitRecType::GiftSent
+        | TaxBitRecType::Trade
+            if !rec.sent_currency.is_empty() =>
+        {
+            Some(rec.sent_currency.as_str())
+        }
+        _ => None,
+    }
+}
+
+impl From<Vec<TaxBitExportRec>> for TaxBitExportRecCollection {
+    fn from(recs: Vec<TaxBitExportRec>) -> Self {
+        Self::from_vec(recs)
+    }
+}
+
+impl FromIterator<TaxBitExportRec> for TaxBitExportRecCollection {
+    fn from_iter<I: IntoIterator<Item = TaxBitExportRec>>(iter: I) -> Self {
+        Self::from_vec(iter.into_iter().collect())
+    }
+}
+
+impl IntoIterator for TaxBitExportRecCollection {
+    type Item = TaxBitExportRec;
+    type IntoIter = std::vec::IntoIter<TaxBitExportRec>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.recs.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a TaxBitExportRecCollection {
+    type Item = &'a TaxBitExportRec;
+    type IntoIter = std::slice::Iter<'a, TaxBitExportRec>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.recs.iter()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    fn rec(
+        time: i64,
+        type_txs: TaxBitRecType,
+        received: &str,
+        sent: &str,
+        id: &str,
+    ) -> TaxBitExportRec {
+        TaxBitExportRec {
+            time,
+            type_txs,
+            received_quantity: if received.is_empty() {
+                None
+            } else {
+                Some(dec!(1))
+            },
+            received_currency: received.to_owned(),
+            sent_quantity: if sent.is_empty() { None } else { Some(dec!(1)) },
+            sent_currency: sent.to_owned(),
+            external_id: id.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_basics() {
+        let mut c = TaxBitExportRecCollection::new();
+        assert!(c.is_empty());
+        c.push(rec(2, TaxBitRecType::Buy, "BTC", "USD", "b"));
+        c.push(rec(1, TaxBitRecType::Buy, "BTC", "USD", "a"));
+        assert_eq!(c.len(), 2);
+        assert_eq!(c.get(0).unwrap().external_id, "b");
+        assert!(c.get(2).is_none());
+
+        c.sort();
+        let ids: Vec<&str> = c.iter().map(|r| r.external_id.as_str()).collect();
+        assert_eq!(ids, vec!["a", "b"]);
+
+        let c2: TaxBitExportRecCollection = c.clone().into_iter().collect();
+        assert_eq!(c, c2);
+        assert_eq!(c2.into_vec().len(), 2);
+    }
+
+    #[test]
+    fn test_drain() {
+        let mut c: TaxBitExportRecCollection = (0..10)
+            .map(|t| rec(t, TaxBitRecType::Buy, "BTC", "USD", &t.to_string()))
+            .collect();
+
+        let drained: Vec<TaxBitExportRec> = c.drain(2..5).collect();
+        let drained_times: Vec<i64> = drained.iter().map(|r| r.time).collect();
+        assert_eq!(drained_times, vec![2, 3, 4]);
+
+        assert_eq!(c.len(), 7);
+        let times: Vec<i64> = c.iter().map(|r| r.time).collect();
+        assert_eq!(times, vec![0, 1, 5, 6, 7, 8, 9]);
+        assert_eq!(c.get(2).unwrap().time, 5);
+        assert!(c.get(7).is_none());
+
+        let rest: Vec<TaxBitExportRec> = c.drain(..).collect();
+        assert_eq!(rest.len(), 7);
+        assert!(c.is_empty());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_drain_out_of_bounds() {
+        let mut c = TaxBitExportRecCollection::new();
+        let _ = c.drain(0..1);
+    }
+
+    #[test]
+    fn test_sort_topologically() {
+        let mut c = TaxBitExportRecCollection::from_vec(vec![
+            rec(2, TaxBitRecType::Income, "ETH", "", "income"),
+            rec(1, TaxBitRecType::Sale, "USD", "BTC", "sale"),
+            rec(1, TaxBitRecType::Buy, "BTC", "USD", "buy"),
+            rec(0, TaxBitRecType::TransferOut, "", "BTC", "xfer-out"),
+            rec(
+                1,
+                TaxBitRecType::TransferOut,
+                "",
+                "BTC",
+                "xfer-out-same-time",
+            ),
+        ]);
+        c.sort_topologically().unwrap();
+
+        let ids: Vec<&str> = c.iter().map(|r| r.external_id.as_str()).collect();
+        assert_eq!(ids.len(), 5);
+        assert_eq!(ids[0], "xfer-out");
+        assert_eq!(ids[1], "buy");
+        assert_eq!(ids[4], "income");
+        assert!(c
+            .iter()
+            .zip(c.iter().skip(1))
+            .all(|(a, b)| a.time <= b.time));
+    }
+
+    #[test]
+    fn test_sort_topologically_trade_chain() {
+        // BTC -> ETH must come after USD -> BTC at the same timestamp
+        let mut c = TaxBitExportRecCollection::from_vec(vec![
+            rec(1, TaxBitRecType::Trade, "ETH", "BTC", "btc-eth"),
+            rec(1, TaxBitRecType::Trade, "BTC", "USDC", "usdc-btc"),
+        ]);
+        c.sort_topologically().unwrap();
+        assert_eq!(c.get(0).unwrap().external_id, "usdc-btc");
+        assert_eq!(c.get(1).unwrap().external_id, "btc-eth");
+    }
+
+    #[test]
+    fn test_sort_topologically_cycle() {
+        let original = TaxBitExportRecCollection::from_vec(vec![
+            rec(0, TaxBitRecType::Buy, "BTC", "USD", "before"),
+            rec(1, TaxBitRecType::Trade, "ETH", "BTC", "btc-eth"),
+            rec(1, TaxBitRecType::Trade, "BTC", "ETH", "eth-btc"),
+        ]);
+        let mut c = original.clone();
+
+        let err = c.sort_topologically().unwrap_err();
+        assert_eq!(err.time, 1);
+        assert_eq!(err.cycle.len(), 2);
+        assert!(err.to_string().contains("btc-eth"));
+        assert!(err.to_string().contains("eth-btc"));
+        assert_eq!(c, original);
+    }
+
+    #[test]
+    fn test_to_cost_basis_events() {
+        let mut c = TaxBitExportRecCollection::from_vec(vec![
+            rec(3, TaxBitRecType::Sale, "USD", "BTC", "sale"),
+            rec(1, TaxBitRecType::Buy, "BTC", "USD", "buy"),
+            rec(2, TaxBitRecType::TransferOut, "", "BTC", "xfer-out"),
+            rec(2, TaxBitRecType::Income, "ETH", "", "income"),
+            rec(0, TaxBitRecType::Income, "ETH", "", "no-market-value"),
+        ]);
+        for r in c.recs.iter_mut() {
+            r.market_value = Some(dec!(10));
+        }
+        c.recs[4].market_value = None;
+
+        let events = c.to_cost_basis_events();
+        assert_eq!(events.len(), 3);
+        let times: Vec<i64> = events.iter().map(|e| e.time_ms()).collect();
+        assert_eq!(times, vec![1, 2, 3]);
+        assert!(matches!(&events[0], CostBasisEvent::Acquisition { asset, .. } if asset == "BTC"));
+        assert!(matches!(&events[1], CostBasisEvent::Acquisition { asset, .. } if asset == "ETH"));
+        assert!(matches!(&events[2], CostBasisEvent::Disposal { asset, .. } if asset == "BTC"));
+    }
+
+    #[test]
+    fn test_to_cost_basis_events_trade() {
+        // The BTC lot bought is traded for ETH which is sold
+        let mut c = TaxBitExportRecCollection::from_vec(vec![
+            rec(3, TaxBitRecType::Sale, "USD", "ETH", "sale"),
+            rec(2, TaxBitRecType::Trade, "ETH", "BTC", "trade"),
+            rec(1, TaxBitRecType::Buy, "BTC", "USD", "buy"),
+        ]);
+        for r in c.recs.iter_mut() {
+            r.market_value = Some(dec!(10));
+        }
+
+        let events = c.to_cost_basis_events();
+        let legs: Vec<(i64, bool, &str)> = events
+            .iter()
+            .map(|e| match e {
+                CostBasisEvent::Acquisition { time_ms, asset, .. } => {
+                    (*time_ms, true, asset.as_str())
+                }
+                CostBasisEvent::Disposal { time_ms, asset, .. } => {
+                    (*time_ms, false, asset.as_str())
+                }
+            })
+            .collect();
+        assert_eq!(
+            legs,
+            vec![
+                (1, true, "BTC"),
+                (2, false, "BTC"),
+                (2, true, "ETH"),
+                (3, false, "ETH"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unique_assets() {
+        let c = TaxBitExportRecCollection::from_vec(vec![
+            rec(1, TaxBitRecType::Trade, "BTC", "USDC", "t1"),
+            rec(2, TaxBitRecType::Trade, "BTC", "ETH", "t2"),
+            rec(3, TaxBitRecType::Trade, "BTC", "USDC", "t3"),
+            rec(4, TaxBitRecType::Sale, "USD", "ETH", "s1"),
+            rec(5, TaxBitRecType::Sale, "USD", "ETH", "s2"),
+            rec(6, TaxBitRecType::Unknown, "", "", "u1"),
+        ]);
+
+        let expected: BTreeSet<String> = ["BTC", "ETH"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(c.unique_assets(), expected);
+        assert_eq!(
+            c.unique_assets().into_iter().collect::<Vec<String>>(),
+            vec!["BTC", "ETH"]
+        );
+
+        let trades: BTreeSet<String> = ["BTC"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(c.unique_assets_for_type(TaxBitRecType::Trade), trades);
+        let sales: BTreeSet<String> = ["ETH"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(c.unique_assets_for_type(TaxBitRecType::Sale), sales);
+        assert!(c.unique_assets_for_type(TaxBitRecType::Buy).is_empty());
+        assert!(c.unique_assets_for_type(TaxBitRecType::Unknown).is_empty());
+    }
+
+    #[test]
+    fn test_slice() {
+        let c: TaxBitExportRecCollection = (0..10)
+            .map(|t| rec(t, TaxBitRecType::Buy, "BTC", "USD", &t.to_string()))
+            .collect();
+
+        assert_eq!(c.slice(0..c.len()), c.clone());
+        assert_eq!(c.slice(..), c.clone());
+
+        let s = c.slice(2..5);
+        assert_eq!(s.len(), 3);
+        let times: Vec<i64> = s.iter().map(|r| r.time).collect();
+        assert_eq!(times, vec![2, 3, 4]);
+        assert_eq!(c.len(), 10);
+
+        assert_eq!(c.slice(8..=9).len(), 2);
+        assert!(c.slice(10..).is_empty());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_slice_out_of_bounds() {
+        let c = TaxBitExportRecCollection::new();
+        c.slice(0..1);
+    }
+}
@@ -0,0 +1,324 @@
+use std::{error::Error, fmt::Display};
+
+use rust_decimal::prelude::*;
+use taxbitrec::{TaxBitRec, TaxBitRecType};
+
+use crate::{normalize_column_name, TaxBitExportRec, ValidationError};
+
+/// Conversion of a third party record to a TaxBitExportRec
+pub trait ToTaxBitExportRec {
+    type Error;
+
+    fn to_taxbit_export_rec(&self) -> Result<TaxBitExportRec, Self::Error>;
+}
+
+/// Convert all of the items returning the converted records, in item
+/// order, and the errors paired with the index of the item which failed
+pub fn convert_all<T: ToTaxBitExportRec>(
+    items: &[T],
+) -> (Vec<TaxBitExportRec>, Vec<(usize, T::Error)>) {
+    let mut recs: Vec<TaxBitExportRec> = vec![];
+    let mut errors: Vec<(usize, T::Error)> = vec![];
+    for (i, item) in items.iter().enumerate() {
+        match item.to_taxbit_export_rec() {
+            Ok(rec) => recs.push(rec),
+            Err(e) => errors.push((i, e)),
+        }
+    }
+
+    (recs, errors)
+}
+
+/// The reference implementation. source is the sending_source, or the
+/// receiving_destination if it's empty, and external_id is the
+/// exchange_transaction_id, or the blockchain_transaction_hash if it's
+/// empty. TaxBitRec has no market value so market_value is None. The
+/// error is the result of TaxBitExportRec::validate on the converted record.
+impl ToTaxBitExportRec for TaxBitRec {
+    type Error = Vec<ValidationError>;
+
+    fn to_taxbit_export_rec(&self) -> Result<TaxBitExportRec, Self::Error> {
+        let or_else = |a: &String, b: &String| if a.is_empty() { b.clone() } else { a.clone() };
+
+        let mut rec = TaxBitExportRec::new();
+        rec.time = self.time;
+        rec.type_txs = self.type_txs.clone();
+        rec.sent_quantity = self.sent_quantity;
+        rec.sent_currency = self.sent_currency.clone();
+        rec.received_quantity = self.received_quantity;
+        rec.received_currency = self.received_currency.clone();
+        rec.fee_amount = self.fee_quantity;
+        rec.fee_currency = self.fee_currency.clone();
+        rec.source = or_else(&self.sending_source, &self.receiving_destination);
+        rec.external_id = or_else(
+            &self.exchange_transaction_id,
+            &self.blockchain_transaction_hash,
+        );
+        rec.validate()?;
+
+        Ok(rec)
+    }
+}
+
+/// A row of a foreign export which couldn't be converted to a TaxBitExportRec
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RejectedRow {
+    /// Line number of the row in the input, the header is line 1
+    pub line: u64,
+
+    /// The row as it appeared in the input
+    pub row: String,
+
+    pub reason: String,
+}
+
+impl Display for RejectedRow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}: {}", self.line, self.reason, self.row)
+    }
+}
+
+impl Error for RejectedRow {}
+
+impl RejectedRow {
+    pub(crate) fn new(record: &csv::StringRecord, reason: impl Into<String>) -> RejectedRow {
+        RejectedRow {
+            line: record.position().map_or(0, |p| p.line()),
+            row: record_to_line(record),
+            reason: reason.into(),
+        }
+    }
+}
+
+// Index in the header of each of the columns a converter uses
+pub(crate) struct Columns {
+    names: &'static [&'static str],
+    indices: Vec<usize>,
+}
+
+impl Columns {
+    /// Find each of names in the header as normalize_column_name matches
+    /// them, format is used in the error listing the missing columns
+    pub(crate) fn new(
+        header: &csv::StringRecord,
+        names: &'static [&'static str],
+        format: &str,
+    ) -> Result<Columns, Box<dyn Error>> {
+        let mut indices: Vec<usize> = vec![];
+        let mut missing: Vec<&str> = vec![];
+        for name in names {
+            let normalized = normalize_column_name(name);
+            match header
+                .iter()
+                .position(|h| normalize_column_name(h) == normalized)
+            {
+                Some(idx) => indices.push(idx),
+                None => missing.push(name),
+            }
+        }
+        if !missing.is_empty() {
+            return Err(format!(
+                "Not a {format} CSV, missing columns: {}",
+                missing.join(", ")
+            )
+            .into());
+        }
+
+        Ok(Columns { names, indices })
+    }
+
+    /// The trimmed value of the column named name
+    ///
+    /// # Panics
+    ///
+    /// If name isn't one of the names passed to new
+    pub(crate) fn get<'r>(&self, record: &'r csv::StringRecord, name: &str) -> &'r str {
+        let i = match self.names.iter().position(|n| *n == name) {
+            Some(i) => i,
+            None => panic!("{name} is not a known column"),
+        };
+        record.get(self.indices[i]).unwrap_or("").trim()
+    }
+}
+
+// The record as a CSV line, fields are quoted only when necessary
+fn record_to_line(record: &csv::StringRecord) -> String {
+    let mut wtr = csv::WriterBuilder::new()
+        .terminator(csv::Terminator::Any(b'\n'))
+        .from_writer(vec![]);
+    if wtr.write_record(record).is_err() {
+        return record.iter().collect::<Vec<&str>>().join(",");
+    }
+    match wtr.into_inner() {
+        Ok(bytes) => String::from_utf8_lossy(&bytes).trim_end().to_owned(),
+        Err(_) => record.iter().collect::<Vec<&str>>().join(","),
+    }
+}
+
+/// The type of an exchange of the sent for the received currency, Buy
+/// if USD was sent, Sale if USD was received otherwise Trade
+pub(crate) fn exchange_type(rec: &TaxBitExportRec) -> TaxBitRecType {
+    if rec.sent_currency == "USD" {
+        TaxBitRecType::Buy
+    } else if rec.received_currency == "USD" {
+        TaxBitRecType::Sale
+    } else {
+        TaxBitRecType::Trade
+    }
+}
+
+/// Parse an optional decimal, empty or whitespace is None. Plain and
+/// scientific notation are accepted.
+pub(crate) fn parse_decimal_opt(column: &str, s: &str) -> Result<Option<Decimal>, String> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Ok(None);
+    }
+
+    match Decimal::from_str(s).or_else(|_| Decimal::from_scientific(s)) {
+        Ok(d) => Ok(Some(d)),
+        Err(_) => Err(format!("{column} '{s}' is not a number")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_decimal_opt() {
+        assert_eq!(parse_decimal_opt("Amount", "").unwrap(), None);
+        assert_eq!(
+            parse_decimal_opt("Amount", " 1.5 ").unwrap(),
+            Some(dec!(1.5))
+        );
+        assert_eq!(
+            parse_decimal_opt("Amount", "3e-7").unwrap(),
+            Some(dec!(0.0000003))
+        );
+        assert_eq!(
+            parse_decimal_opt("Amount", "abc").unwrap_err(),
+            "Amount 'abc' is not a number"
+        );
+    }
+
+    #[test]
+    fn test_rejected_row() {
+        let mut rdr = csv::Reader::from_reader("a,b\n1,\"x,y\"\n".as_bytes());
+        let record = rdr.records().next().unwrap().unwrap();
+        let rejected = RejectedRow::new(&record, "bad");
+        assert_eq!(rejected.line, 2);
+        assert_eq!(rejected.row, "1,\"x,y\"");
+        assert_eq!(rejected.to_string(), "line 2: bad: 1,\"x,y\"");
+    }
+
+    fn taxbitrec(type_txs: TaxBitRecType, received: &str, id: &str) -> TaxBitRec {
+        TaxBitRec {
+            time: 1609581600000,
+            type_txs,
+            sent_quantity: None,
+            sent_currency: "".to_owned(),
+            sending_source: "".to_owned(),
+            received_quantity: Some(dec!(1)),
+            received_currency: received.to_owned(),
+            receiving_destination: "BinanceUS".to_owned(),
+            fee_quantity: None,
+            fee_currency: "".to_owned(),
+            exchange_transaction_id: id.to_owned(),
+            blockchain_transaction_hash: "0xabc".to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_taxbitrec_to_taxbit_export_rec() {
+        let rec = taxbitrec(TaxBitRecType::Income, "BTC", "id-1")
+            .to_taxbit_export_rec()
+            .unwrap();
+        assert_eq!(rec.time, 1609581600000);
+        assert_eq!(rec.type_txs, TaxBitRecType::Income);
+        assert_eq!(rec.received_quantity, Some(dec!(1)));
+        assert_eq!(rec.received_currency, "BTC");
+        assert_eq!(rec.source, "BinanceUS");
+        assert_eq!(rec.external_id, "id-1");
+        assert_eq!(rec.market_value, None);
+
+        let rec = taxbitrec(TaxBitRecType::Income, "BTC", "")
+            .to_taxbit_export_rec()
+            .unwrap();
+        assert_eq!(rec.external_id, "0xabc");
+    }
+
+    // An integration's own record type
+    struct Fill {
+        asset: &'static str,
+        quantity: i64,
+    }
+
+    impl ToTaxBitExportRec for Fill {
+        type Error = String;
+
+        fn to_taxbit_export_rec(&self) -> Result<TaxBitExportRec, Self::Error> {
+            if self.quantity <= 0 {
+                return Err(format!(
+                    "{} quantity {} is not positive",
+                    self.asset, self.quantity
+                ));
+            }
+            let mut rec = TaxBitExportRec::new();
+            rec.type_txs = TaxBitRecType::Income;
+            rec.received_quantity = Some(Decimal::from(self.quantity));
+            rec.received_currency = self.asset.to_owned();
+            Ok(rec)
+        }
+    }
+
+    #[test]
+    fn test_convert_all() {
+        let fills = [
+            Fill {
+                asset: "BTC",
+                quantity: 1,
+            },
+            Fill {
+                asset: "ETH",
+                quantity: 0,
+            },
+            Fill {
+                asset: "ADA",
+                quantity: 3,
+            },
+            Fill {
+                asset: "XRP",
+                quantity: -1,
+            },
+        ];
+        let (recs, errors) = convert_all(&fills);
+        assert_eq!(recs.len(), 2);
+        assert_eq!(recs[0].received_currency, "BTC");
+        assert_eq!(recs[1].received_currency, "ADA");
+        assert_eq!(
+            errors,
+            vec![
+                (1, "ETH quantity 0 is not positive".to_owned()),
+                (3, "XRP quantity -1 is not positive".to_owned()),
+            ]
+        );
+
+        let items = [
+            taxbitrec(TaxBitRecType::Income, "BTC", "id-1"),
+            taxbitrec(TaxBitRecType::Unknown, "BTC", "id-2"),
+            taxbitrec(TaxBitRecType::Income, "", "id-3"),
+        ];
+        let (recs, errors) = convert_all(&items);
+        assert_eq!(recs.len(), 1);
+        assert_eq!(
+            errors,
+            vec![
+                (1, vec![ValidationError::InvalidType]),
+                (2, vec![ValidationError::MissingReceivedSide]),
+            ]
+        );
+    }
+}
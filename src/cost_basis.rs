@@ -0,0 +1,611 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use rust_decimal::prelude::*;
+use taxbitrec::TaxBitRecType;
+
+use crate::TaxBitExportRec;
+
+/// A uniform event used as input to a cost basis engine
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CostBasisEvent {
+    Acquisition {
+        time_ms: i64,
+        asset: String,
+        quantity: Decimal,
+        price_per_unit: Decimal,
+    },
+    Disposal {
+        time_ms: i64,
+        asset: String,
+        quantity: Decimal,
+        proceeds_per_unit: Decimal,
+    },
+}
+
+impl CostBasisEvent {
+    pub fn time_ms(&self) -> i64 {
+        match self {
+            CostBasisEvent::Acquisition { time_ms, .. } => *time_ms,
+            CostBasisEvent::Disposal { time_ms, .. } => *time_ms,
+        }
+    }
+}
+
+impl TaxBitExportRec {
+    /// True if the record creates or consumes cost basis.
+    ///
+    /// Under US tax rules buying or receiving an asset as income or a gift
+    /// establishes its basis, and selling, trading, spending or gifting it
+    /// away is a disposal which consumes basis. Moving an asset between
+    /// your own wallets, TransferIn and TransferOut, isn't a taxable event
+    /// and the basis moves with the asset. Unknown and Invalid records
+    /// aren't cost basis events.
+    pub fn is_cost_basis_event(&self) -> bool {
+        match self.type_txs {
+            TaxBitRecType::Buy
+            | TaxBitRecType::Sale
+            | TaxBitRecType::Trade
+            | TaxBitRecType::Income
+            | TaxBitRecType::GiftReceived
+            | TaxBitRecType::GiftSent
+            | TaxBitRecType::Expense => true,
+            TaxBitRecType::TransferIn
+            | TaxBitRecType::TransferOut
+            | TaxBitRecType::Invalid
+            | TaxBitRecType::Unknown => false,
+        }
+    }
+
+    // The acquisition of the received currency
+    fn acquisition_event(&self) -> Option<CostBasisEvent> {
+        let quantity = self.received_quantity?;
+        Some(CostBasisEvent::Acquisition {
+            time_ms: self.time,
+            asset: self.received_currency.clone(),
+            quantity,
+            price_per_unit: self.market_value?.checked_div(quantity)?,
+        })
+    }
+
+    // The disposal of the sent currency
+    fn disposal_event(&self) -> Option<CostBasisEvent> {
+        let quantity = self.sent_quantity?;
+        Some(CostBasisEvent::Disposal {
+            time_ms: self.time,
+            asset: self.sent_currency.clone(),
+            quantity,
+            proceeds_per_unit: self.market_value?.checked_div(quantity)?,
+        })
+    }
+
+    /// Convert to a CostBasisEvent, the per unit values are
+    /// market_value divided by the quantity.
+    ///
+    /// Buy, Income and GiftReceived are acquisitions of the received
+    /// currency, Sale, Expense and GiftSent are disposals of the sent
+    /// currency.
+    ///
+    /// Returns None for:
+    ///   - TransferIn and TransferOut, they neither gain nor lose cost basis
+    ///   - Trade, it is both a disposal and an acquisition, use
+    ///     to_cost_basis_events
+    ///   - Unknown and Invalid
+    ///   - When the quantity is missing or zero or market_value is missing
+    pub fn to_cost_basis_event(&self) -> Option<CostBasisEvent> {
+        match self.type_txs {
+            TaxBitRecType::Buy | TaxBitRecType::Income | TaxBitRecType::GiftReceived => {
+                self.acquisition_event()
+            }
+            TaxBitRecType::Sale | TaxBitRecType::Expense | TaxBitRecType::GiftSent => {
+                self.disposal_event()
+            }
+            TaxBitRecType::TransferIn
+            | TaxBitRecType::TransferOut
+            | TaxBitRecType::Trade
+            | TaxBitRecType::Invalid
+            | TaxBitRecType::Unknown => None,
+        }
+    }
+
+    /// Convert to the CostBasisEvent's of the record, as
+    /// to_cost_basis_event but a Trade is a Disposal of the sent currency
+    /// followed by an Acquisition of the received currency, market_value
+    /// being both the proceeds and the cost. A leg whose quantity is
+    /// missing or zero is omitted.
+    pub fn to_cost_basis_events(&self) -> Vec<CostBasisEvent> {
+        match self.type_txs {
+            TaxBitRecType::Trade => [self.disposal_event(), self.acquisition_event()]
+                .into_iter()
+                .flatten()
+                .collect(),
+            _ => self.to_cost_basis_event().into_iter().collect(),
+        }
+    }
+}
+
+/// The FIFO coverage of an asset's disposals, see fifo_preview
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FifoAsset {
+    /// Sum of the quantities acquired
+    pub acquired: Decimal,
+
+    /// Sum of the quantities disposed of
+    pub disposed: Decimal,
+
+    /// The part of disposed not matched to an earlier acquisition
+    pub uncovered: Decimal,
+
+    /// The time and index of the first disposal not fully matched, None
+    /// if every disposal is covered
+    pub first_uncovered_time: Option<i64>,
+    pub first_uncovered_index: Option<usize>,
+
+    /// The quantity of the acquisitions not consumed
+    pub remaining: Decimal,
+}
+
+/// The result of fifo_preview
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FifoReport {
+    /// The coverage of each asset acquired or disposed of
+    pub assets: BTreeMap<String, FifoAsset>,
+
+    /// The ID of the lot each acquisition created, keyed by the index of
+    /// the record. The record's lot_id if it has one, otherwise the asset
+    /// and the 1-based number of the acquisition of it, "BTC-2", with the
+    /// number increased past any lot_id of the records.
+    pub acquired_lots: BTreeMap<usize, String>,
+
+    /// The IDs of the lots each disposal consumed, oldest first, keyed by
+    /// the index of the record. An uncovered disposal consumes none.
+    pub consumed_lots: BTreeMap<usize, Vec<String>>,
+}
+
+impl FifoReport {
+    /// True if every disposal is covered by earlier acquisitions
+    pub fn is_covered(&self) -> bool {
+        self.assets.values().all(|a| a.uncovered.is_zero())
+    }
+
+    /// A copy of the records the report is of with lot_id set to the lot
+    /// each acquisition created, a Trade's being that of its received
+    /// side, or to the lot a disposal consumed if it consumed just one.
+    /// Other records are unchanged.
+    pub fn with_lot_ids(&self, recs: &[TaxBitExportRec]) -> Vec<TaxBitExportRec> {
+        recs.iter()
+            .enumerate()
+            .map(|(index, rec)| {
+                let consumed = match self.consumed_lots.get(&index).map(|l| l.as_slice()) {
+                    Some([lot]) => Some(lot),
+                    _ => None,
+                };
+                match self.acquired_lots.get(&index).or(consumed) {
+                    Some(lot) => rec.with_lot_id(lot),
+                    None => rec.clone(),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Check the records have the acquisitions needed to compute the cost
+/// basis of their disposals. A diagnostic of quantities only, no gains
+/// are computed.
+///
+/// The records are replayed in time order, ties in record order. Buy,
+/// TransferIn, Income and GiftReceived and the received side of a Trade
+/// acquire an asset, Sale, Expense and GiftSent and the sent side of a
+/// Trade dispose of it, consuming the oldest acquisitions first. Fees,
+/// TransferOuts and non-positive quantities are ignored. Each acquisition
+/// creates a lot, see FifoReport::acquired_lots and
+/// FifoReport::with_lot_ids.
+pub fn fifo_preview(recs: &[TaxBitExportRec]) -> FifoReport {
+    let mut order: Vec<usize> = (0..recs.len()).collect();
+    order.sort_by_key(|i| recs[*i].time);
+
+    let mut report = FifoReport::default();
+    // The IDs and quantities of the unconsumed acquisitions of each
+    // asset, oldest first
+    let mut lots: BTreeMap<String, VecDeque<(String, Decimal)>> = BTreeMap::new();
+    // The number of acquisitions of each asset
+    let mut acquisitions: BTreeMap<String, usize> = BTreeMap::new();
+    // The lot IDs of the records, generated IDs skip them
+    let used_ids: BTreeSet<&String> = recs.iter().filter_map(|r| r.lot_id.as_ref()).collect();
+    for index in order {
+        let rec = &recs[index];
+        let (acquires, disposes) = match rec.type_txs {
+            TaxBitRecType::Buy
+            | TaxBitRecType::TransferIn
+            | TaxBitRecType::Income
+            | TaxBitRecType::GiftReceived => (true, false),
+            TaxBitRecType::Sale | TaxBitRecType::Expense | TaxBitRecType::GiftSent => (false, true),
+            TaxBitRecType::Trade => (true, true),
+            TaxBitRecType::TransferOut | TaxBitRecType::Invalid | TaxBitRecType::Unknown => {
+                (false, false)
+            }
+        };
+
+        if disposes {
+            if let Some(quantity) = rec.sent_quantity.filter(|q| *q > Decimal::ZERO) {
+                let asset = report.assets.entry(rec.sent_currency.clone()).or_default();
+                let asset_lots = lots.entry(rec.sent_currency.clone()).or_default();
+                asset.disposed += quantity;
+                let mut needed = quantity;
+                let mut consumed = vec![];
+                while let Some((id, lot)) = asset_lots.front_mut() {
+                    let used = needed.min(*lot);
+                    *lot -= used;
+                    needed -= used;
+                    consumed.push(id.clone());
+                    if lot.is_zero() {
+                        asset_lots.pop_front();
+                    }
+                    if needed.is_zero() {
+                        break;
+                    }
+                }
+                if !consumed.is_empty() {
+                    report.consumed_lots.insert(index, consumed);
+                }
+                if !needed.is_zero() {
+                    asset.uncovered += needed;
+                    if asset.first_uncovered_index.is_none() {
+                        asset.first_uncovered_time = Some(rec.time);
+                        asset.first_uncovered_index = Some(index);
+                    }
+                }
+            }
+        }
+
+        if acquires {
+            if let Some(quantity) = rec.received_quantity.filter(|q| *q > Decimal::ZERO) {
+                report
+                    .assets
+                    .entry(rec.received_currency.clone())
+                    .or_default()
+                    .acquired += quantity;
+                let count = acquisitions
+                    .entry(rec.received_currency.clone())
+                    .or_default();
+                *count += 1;
+                let id = match &rec.lot_id {
+                    Some(id) => id.clone(),
+                    None => loop {
+                        let id = format!("{}-{count}", rec.received_currency);
+                        if !used_ids.contains(&id) {
+                            break id;
+                        }
+                        *count += 1;
+                    },
+                };
+                report.acquired_lots.insert(index, id.clone());
+                lots.entry(rec.received_currency.clone())
+                    .or_default()
+                    .push_back((id, quantity));
+            }
+        }
+    }
+
+    for (asset, asset_lots) in lots {
+        if let Some(fifo) = report.assets.get_mut(&asset) {
+            fifo.remaining = asset_lots.iter().map(|(_, q)| q).sum();
+        }
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    fn rec(type_txs: TaxBitRecType) -> TaxBitExportRec {
+        TaxBitExportRec {
+            time: 1000,
+            type_txs,
+            received_quantity: Some(dec!(2)),
+            received_currency: "BTC".to_owned(),
+            sent_quantity: Some(dec!(4)),
+            sent_currency: "ETH".to_owned(),
+            market_value: Some(dec!(100)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_acquisition() {
+        for type_txs in [
+            TaxBitRecType::Buy,
+            TaxBitRecType::Income,
+            TaxBitRecType::GiftReceived,
+        ] {
+            let event = rec(type_txs).to_cost_basis_event().unwrap();
+            assert_eq!(event.time_ms(), 1000);
+            assert_eq!(
+                event,
+                CostBasisEvent::Acquisition {
+                    time_ms: 1000,
+                    asset: "BTC".to_owned(),
+                    quantity: dec!(2),
+                    price_per_unit: dec!(50),
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn test_disposal() {
+        for type_txs in [
+            TaxBitRecType::Sale,
+            TaxBitRecType::Expense,
+            TaxBitRecType::GiftSent,
+        ] {
+            let event = rec(type_txs).to_cost_basis_event().unwrap();
+            assert_eq!(
+                event,
+                CostBasisEvent::Disposal {
+                    time_ms: 1000,
+                    asset: "ETH".to_owned(),
+                    quantity: dec!(4),
+                    proceeds_per_unit: dec!(25),
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn test_none() {
+        for type_txs in [
+            TaxBitRecType::TransferIn,
+            TaxBitRecType::TransferOut,
+            TaxBitRecType::Trade,
+            TaxBitRecType::Invalid,
+            TaxBitRecType::Unknown,
+        ] {
+            assert_eq!(rec(type_txs).to_cost_basis_event(), None);
+        }
+
+        let mut r = rec(TaxBitRecType::Buy);
+        r.market_value = None;
+        assert_eq!(r.to_cost_basis_event(), None);
+
+        let mut r = rec(TaxBitRecType::Sale);
+        r.sent_quantity = Some(dec!(0));
+        assert_eq!(r.to_cost_basis_event(), None);
+
+        let mut r = rec(TaxBitRecType::Income);
+        r.received_quantity = None;
+        assert_eq!(r.to_cost_basis_event(), None);
+    }
+
+    #[test]
+    fn test_trade_events() {
+        let trade = rec(TaxBitRecType::Trade);
+        assert_eq!(
+            trade.to_cost_basis_events(),
+            vec![
+                CostBasisEvent::Disposal {
+                    time_ms: 1000,
+                    asset: "ETH".to_owned(),
+                    quantity: dec!(4),
+                    proceeds_per_unit: dec!(25),
+                },
+                CostBasisEvent::Acquisition {
+                    time_ms: 1000,
+                    asset: "BTC".to_owned(),
+                    quantity: dec!(2),
+                    price_per_unit: dec!(50),
+                },
+            ]
+        );
+
+        // Single leg records are the same as to_cost_basis_event
+        let buy = rec(TaxBitRecType::Buy);
+        assert_eq!(
+            buy.to_cost_basis_events(),
+            vec![buy.to_cost_basis_event().unwrap()]
+        );
+        assert_eq!(
+            rec(TaxBitRecType::TransferIn).to_cost_basis_events(),
+            vec![]
+        );
+
+        let mut trade = rec(TaxBitRecType::Trade);
+        trade.sent_quantity = None;
+        assert_eq!(trade.to_cost_basis_events().len(), 1);
+    }
+
+    #[test]
+    fn test_is_cost_basis_event() {
+        for (type_txs, expected) in [
+            (TaxBitRecType::Buy, true),
+            (TaxBitRecType::Sale, true),
+            (TaxBitRecType::Trade, true),
+            (TaxBitRecType::Income, true),
+            (TaxBitRecType::GiftReceived, true),
+            (TaxBitRecType::GiftSent, true),
+            (TaxBitRecType::Expense, true),
+            (TaxBitRecType::TransferIn, false),
+            (TaxBitRecType::TransferOut, false),
+            (TaxBitRecType::Invalid, false),
+            (TaxBitRecType::Unknown, false),
+        ] {
+            assert_eq!(
+                rec(type_txs.clone()).is_cost_basis_event(),
+                expected,
+                "{type_txs:?}"
+            );
+        }
+    }
+
+    fn fifo_rec(
+        time: i64,
+        type_txs: TaxBitRecType,
+        received: Option<(Decimal, &str)>,
+        sent: Option<(Decimal, &str)>,
+    ) -> TaxBitExportRec {
+        let mut rec = TaxBitExportRec::new();
+        rec.time = time;
+        rec.type_txs = type_txs;
+        if let Some((quantity, asset)) = received {
+            rec.received_quantity = Some(quantity);
+            rec.received_currency = asset.to_owned();
+        }
+        if let Some((quantity, asset)) = sent {
+            rec.sent_quantity = Some(quantity);
+            rec.sent_currency = asset.to_owned();
+        }
+        rec
+    }
+
+    #[test]
+    fn test_fifo_preview_covered() {
+        let recs = vec![
+            fifo_rec(
+                1,
+                TaxBitRecType::Buy,
+                Some((dec!(1.5), "BTC")),
+                Some((dec!(30000), "USD")),
+            ),
+            fifo_rec(2, TaxBitRecType::TransferIn, Some((dec!(0.5), "BTC")), None),
+            fifo_rec(
+                3,
+                TaxBitRecType::Trade,
+                Some((dec!(20), "ETH")),
+                Some((dec!(1.75), "BTC")),
+            ),
+            fifo_rec(4, TaxBitRecType::GiftSent, None, Some((dec!(5), "ETH"))),
+            fifo_rec(
+                5,
+                TaxBitRecType::TransferOut,
+                None,
+                Some((dec!(100), "ETH")),
+            ),
+        ];
+        let report = fifo_preview(&recs);
+        assert!(report.is_covered());
+        assert_eq!(
+            report.assets["BTC"],
+            FifoAsset {
+                acquired: dec!(2.0),
+                disposed: dec!(1.75),
+                uncovered: dec!(0),
+                first_uncovered_time: None,
+                first_uncovered_index: None,
+                remaining: dec!(0.25),
+            }
+        );
+        assert_eq!(report.assets["ETH"].remaining, dec!(15));
+        // The USD paid for the Buy isn't a disposal
+        assert!(!report.assets.contains_key("USD"));
+    }
+
+    #[test]
+    fn test_fifo_preview_uncovered() {
+        // Not in time order, the Sale at 1 is before any Buy
+        let recs = vec![
+            fifo_rec(2, TaxBitRecType::Buy, Some((dec!(0.3), "BTC")), None),
+            fifo_rec(
+                1,
+                TaxBitRecType::Sale,
+                Some((dec!(100), "USD")),
+                Some((dec!(0.1), "BTC")),
+            ),
+            fifo_rec(3, TaxBitRecType::Income, Some((dec!(0.05), "BTC")), None),
+            fifo_rec(4, TaxBitRecType::Sale, None, Some((dec!(0.4), "BTC"))),
+            fifo_rec(5, TaxBitRecType::Expense, None, Some((dec!(0.01), "BTC"))),
+        ];
+        let report = fifo_preview(&recs);
+        assert!(!report.is_covered());
+        let btc = &report.assets["BTC"];
+        assert_eq!(btc.disposed, dec!(0.51));
+        assert_eq!(btc.acquired, dec!(0.35));
+        // All of the first Sale, 0.05 of the second and the Expense
+        assert_eq!(btc.uncovered, dec!(0.16));
+        assert_eq!(btc.first_uncovered_time, Some(1));
+        assert_eq!(btc.first_uncovered_index, Some(1));
+        assert_eq!(btc.remaining, dec!(0));
+
+        // Partially covered reports the exact remainder
+        let report = fifo_preview(&recs[2..4]);
+        let btc = &report.assets["BTC"];
+        assert_eq!(btc.uncovered, dec!(0.35));
+        assert_eq!(btc.first_uncovered_time, Some(4));
+        assert_eq!(btc.first_uncovered_index, Some(1));
+    }
+
+    #[test]
+    fn test_fifo_preview_lot_ids() {
+        let recs = vec![
+            fifo_rec(1, TaxBitRecType::Buy, Some((dec!(1), "BTC")), None),
+            fifo_rec(2, TaxBitRecType::Buy, Some((dec!(1), "BTC")), None).with_lot_id("mine"),
+            fifo_rec(
+                3,
+                TaxBitRecType::Trade,
+                Some((dec!(20), "ETH")),
+                Some((dec!(1.5), "BTC")),
+            ),
+            fifo_rec(4, TaxBitRecType::Sale, None, Some((dec!(0.5), "BTC"))),
+            fifo_rec(5, TaxBitRecType::Sale, None, Some((dec!(1), "BTC"))),
+        ];
+        let report = fifo_preview(&recs);
+        assert_eq!(
+            report.acquired_lots,
+            BTreeMap::from([
+                (0, "BTC-1".to_owned()),
+                (1, "mine".to_owned()),
+                (2, "ETH-1".to_owned())
+            ])
+        );
+        assert_eq!(
+            report.consumed_lots,
+            BTreeMap::from([
+                (2, vec!["BTC-1".to_owned(), "mine".to_owned()]),
+                (3, vec!["mine".to_owned()]),
+            ])
+        );
+
+        let lot_ids: Vec<Option<String>> = report
+            .with_lot_ids(&recs)
+            .into_iter()
+            .map(|r| r.lot_id)
+            .collect();
+        assert_eq!(
+            lot_ids,
+            vec![
+                Some("BTC-1".to_owned()),
+                Some("mine".to_owned()),
+                Some("ETH-1".to_owned()),
+                Some("mine".to_owned()),
+                None,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fifo_preview_lot_id_collision() {
+        let recs = vec![
+            fifo_rec(1, TaxBitRecType::Buy, Some((dec!(1), "BTC")), None),
+            fifo_rec(2, TaxBitRecType::Buy, Some((dec!(1), "BTC")), None).with_lot_id("BTC-1"),
+            fifo_rec(3, TaxBitRecType::Buy, Some((dec!(1), "BTC")), None),
+            fifo_rec(4, TaxBitRecType::Buy, Some((dec!(1), "BTC")), None).with_lot_id("BTC-4"),
+            fifo_rec(5, TaxBitRecType::Sale, None, Some((dec!(1.5), "BTC"))),
+        ];
+        let report = fifo_preview(&recs);
+        assert_eq!(
+            report.acquired_lots,
+            BTreeMap::from([
+                (0, "BTC-2".to_owned()),
+                (1, "BTC-1".to_owned()),
+                (2, "BTC-5".to_owned()),
+                (3, "BTC-4".to_owned()),
+            ])
+        );
+        assert_eq!(
+            report.consumed_lots,
+            BTreeMap::from([(4, vec!["BTC-2".to_owned(), "BTC-1".to_owned()])])
+        );
+    }
+}
@@ -0,0 +1,125 @@
+use rust_decimal::{Decimal, RoundingStrategy};
+
+/// How format_decimal formats a Decimal, the default formats it as
+/// Decimal's Display does
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DecimalFormat {
+    /// Pad with trailing zeros to at least this many decimal places
+    pub min_scale: u32,
+
+    /// Round, midpoints away from zero, to at most this many decimal
+    /// places
+    pub max_scale: Option<u32>,
+
+    /// Remove trailing zeros, before padding to min_scale, so 1.500
+    /// is "1.5"
+    pub trim_zeros: bool,
+}
+
+impl DecimalFormat {
+    /// Exactly scale decimal places, DecimalFormat::fixed(8) formats 1.5
+    /// as "1.50000000"
+    pub fn fixed(scale: u32) -> DecimalFormat {
+        DecimalFormat {
+            min_scale: scale,
+            max_scale: Some(scale),
+            trim_zeros: false,
+        }
+    }
+}
+
+/// Format d as opts specifies, always in positional notation, "0.00000001"
+/// never "1E-8". The value is only changed when rounding to max_scale.
+pub fn format_decimal(d: Decimal, opts: &DecimalFormat) -> String {
+    let mut d = d;
+    if let Some(max_scale) = opts.max_scale {
+        d = d.round_dp_with_strategy(max_scale, RoundingStrategy::MidpointAwayFromZero);
+    }
+    if opts.trim_zeros {
+        d = d.normalize();
+    }
+    if d.scale() < opts.min_scale {
+        d.rescale(opts.min_scale);
+    }
+
+    // Built from the mantissa so the notation doesn't depend on Display
+    let scale = d.scale() as usize;
+    let mut digits = d.mantissa().unsigned_abs().to_string();
+    if digits.len() <= scale {
+        digits.insert_str(0, &"0".repeat(scale + 1 - digits.len()));
+    }
+    let sign = if d.mantissa() < 0 { "-" } else { "" };
+    if scale == 0 {
+        format!("{sign}{digits}")
+    } else {
+        let (int, frac) = digits.split_at(digits.len() - scale);
+        format!("{sign}{int}.{frac}")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    #[test]
+    fn test_format_decimal_default() {
+        let opts = DecimalFormat::default();
+        for d in [
+            dec!(0),
+            dec!(1),
+            dec!(-1),
+            dec!(1.500),
+            dec!(-0.0025979719720382955),
+            dec!(1012.55),
+            Decimal::MAX,
+            Decimal::MIN,
+        ] {
+            assert_eq!(format_decimal(d, &opts), d.to_string());
+        }
+    }
+
+    #[test]
+    fn test_format_decimal_tiny() {
+        let tiny = Decimal::from_scientific("1E-8").unwrap();
+        assert_eq!(format!("{tiny:e}"), "1e-8");
+        assert_eq!(
+            format_decimal(tiny, &DecimalFormat::default()),
+            "0.00000001"
+        );
+        assert_eq!(
+            format_decimal(-tiny, &DecimalFormat::default()),
+            "-0.00000001"
+        );
+
+        let tiniest = Decimal::from_i128_with_scale(1, 28);
+        assert_eq!(
+            format_decimal(tiniest, &DecimalFormat::default()),
+            format!("0.{}1", "0".repeat(27))
+        );
+        assert_eq!(
+            format_decimal(tiniest, &DecimalFormat::fixed(8)),
+            "0.00000000"
+        );
+    }
+
+    #[test]
+    fn test_format_decimal_scale() {
+        let fixed = DecimalFormat::fixed(8);
+        assert_eq!(format_decimal(dec!(1.5), &fixed), "1.50000000");
+        assert_eq!(format_decimal(dec!(12), &fixed), "12.00000000");
+        assert_eq!(format_decimal(dec!(0.123456785), &fixed), "0.12345679");
+        assert_eq!(format_decimal(dec!(-0.123456785), &fixed), "-0.12345679");
+
+        let money = DecimalFormat {
+            min_scale: 2,
+            max_scale: Some(4),
+            trim_zeros: true,
+        };
+        assert_eq!(format_decimal(dec!(3), &money), "3.00");
+        assert_eq!(format_decimal(dec!(3.10000), &money), "3.10");
+        assert_eq!(format_decimal(dec!(3.14159), &money), "3.1416");
+        assert_eq!(format_decimal(dec!(0.00001), &money), "0.00");
+    }
+}
@@ -0,0 +1,632 @@
+use std::collections::BTreeMap;
+
+use rust_decimal::Decimal;
+use taxbitrec::TaxBitRecType;
+
+use crate::TaxBitExportRec;
+
+/// Options for TaxBitExportRec::cmp_content_with_opts and
+/// eq_content_with_opts
+#[derive(Debug, Clone, Default)]
+pub struct ContentOpts {
+    pub ignore_source: bool,
+}
+
+impl TaxBitExportRec {
+    /// Ordering as Ord but ignoring external_id
+    pub fn cmp_content(&self, other: &Self) -> std::cmp::Ordering {
+        self.cmp_content_with_opts(other, &ContentOpts::default())
+    }
+
+    /// Ordering as Ord but ignoring external_id and the fields opts
+    /// ignores
+    pub fn cmp_content_with_opts(&self, other: &Self, opts: &ContentOpts) -> std::cmp::Ordering {
+        self.cmp_fields(other, opts.ignore_source, true)
+    }
+
+    /// Equality ignoring external_id, consistent with cmp_content
+    pub fn eq_content(&self, other: &Self) -> bool {
+        self.cmp_content(other).is_eq()
+    }
+
+    /// Equality ignoring external_id and the fields opts ignores,
+    /// consistent with cmp_content_with_opts
+    pub fn eq_content_with_opts(&self, other: &Self, opts: &ContentOpts) -> bool {
+        self.cmp_content_with_opts(other, opts).is_eq()
+    }
+}
+
+/// Remove runs of adjacent equal records keeping the first of each run,
+/// returns the number removed. Sort first to remove all duplicates.
+pub fn dedup_consecutive(recs: &mut Vec<TaxBitExportRec>) -> usize {
+    let len = recs.len();
+    recs.dedup();
+
+    len - recs.len()
+}
+
+/// Remove runs of adjacent records with equal keys keeping the first of
+/// each run, returns the number removed.
+pub fn dedup_consecutive_by<K, F>(recs: &mut Vec<TaxBitExportRec>, key_fn: F) -> usize
+where
+    K: PartialEq,
+    F: FnMut(&mut TaxBitExportRec) -> K,
+{
+    let len = recs.len();
+    recs.dedup_by_key(key_fn);
+
+    len - recs.len()
+}
+
+/// Remove the records eq_content to an earlier record, returns the
+/// number removed. The order of the remaining records is unchanged.
+pub fn dedup_by_content(recs: &mut Vec<TaxBitExportRec>) -> usize {
+    dedup_by_content_with_opts(recs, &ContentOpts::default())
+}
+
+/// Remove the records eq_content_with_opts to an earlier record, returns
+/// the number removed. The order of the remaining records is unchanged.
+pub fn dedup_by_content_with_opts(recs: &mut Vec<TaxBitExportRec>, opts: &ContentOpts) -> usize {
+    let mut order: Vec<usize> = (0..recs.len()).collect();
+    order.sort_by(|a, b| {
+        recs[*a]
+            .cmp_content_with_opts(&recs[*b], opts)
+            .then(a.cmp(b))
+    });
+    let mut keep = vec![true; recs.len()];
+    for w in order.windows(2) {
+        if recs[w[0]].eq_content_with_opts(&recs[w[1]], opts) {
+            keep[w[1]] = false;
+        }
+    }
+
+    let len = recs.len();
+    let mut keep = keep.into_iter();
+    recs.retain(|_| keep.next().expect("SNH"));
+
+    len - recs.len()
+}
+
+/// The (source, external_id) pairs used by more than one record with the
+/// indices of those records. Records with an empty external_id are ignored.
+pub fn find_duplicate_ids(recs: &[TaxBitExportRec]) -> BTreeMap<(String, String), Vec<usize>> {
+    let mut ids: BTreeMap<(String, String), Vec<usize>> = BTreeMap::new();
+    for (i, rec) in recs.iter().enumerate() {
+        if !rec.external_id.is_empty() {
+            ids.entry((rec.source.clone(), rec.external_id.clone()))
+                .or_default()
+                .push(i);
+        }
+    }
+    ids.retain(|_, indices| indices.len() > 1);
+
+    ids
+}
+
+/// Options for find_near_duplicates_with_opts
+#[derive(Debug, Clone, Default)]
+pub struct NearDupOpts {
+    /// Group records even if their external_ids differ
+    pub ignore_external_id: bool,
+}
+
+/// Records which are identical except for time, see find_near_duplicates
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NearDupGroup {
+    /// Indices of the records in time order, ties in index order
+    pub indices: Vec<usize>,
+
+    /// Time of the last record minus time of the first, i64::MAX if
+    /// it's larger
+    pub span_ms: i64,
+}
+
+/// Groups of records equal in every field but time whose times are
+/// within window_ms of the first record of the group, e.g. the same
+/// transaction exported twice with slightly different timestamps.
+/// Records are only proposed as duplicates, none are removed.
+pub fn find_near_duplicates(recs: &[TaxBitExportRec], window_ms: i64) -> Vec<NearDupGroup> {
+    find_near_duplicates_with_opts(recs, window_ms, &NearDupOpts::default())
+}
+
+/// Find near duplicates as find_near_duplicates does using opts. Records
+/// with different sources are never grouped.
+pub fn find_near_duplicates_with_opts(
+    recs: &[TaxBitExportRec],
+    window_ms: i64,
+    opts: &NearDupOpts,
+) -> Vec<NearDupGroup> {
+    let mut order: Vec<usize> = (0..recs.len()).collect();
+    order.sort_by_key(|i| (recs[*i].time, *i));
+
+    // Records keyed by every field except time, in time order
+    let mut by_key: BTreeMap<TaxBitExportRec, Vec<usize>> = BTreeMap::new();
+    for i in order {
+        let mut key = recs[i].clone();
+        key.time = 0;
+        key.extras.clear();
+        if opts.ignore_external_id {
+            key.external_id.clear();
+        }
+        by_key.entry(key).or_default().push(i);
+    }
+
+    let mut groups: Vec<NearDupGroup> = vec![];
+    for indices in by_key.into_values() {
+        let mut group: Vec<usize> = vec![];
+        for i in indices {
+            if let Some(first) = group.first() {
+                if recs[i].time.saturating_sub(recs[*first].time) > window_ms {
+                    push_group(recs, &mut groups, std::mem::take(&mut group));
+                }
+            }
+            group.push(i);
+        }
+        push_group(recs, &mut groups, group);
+    }
+    groups.sort_by_key(|g| g.indices[0]);
+
+    groups
+}
+
+fn push_group(recs: &[TaxBitExportRec], groups: &mut Vec<NearDupGroup>, indices: Vec<usize>) {
+    if indices.len() > 1 {
+        groups.push(NearDupGroup {
+            span_ms: recs[indices[indices.len() - 1]]
+                .time
+                .saturating_sub(recs[indices[0]].time),
+            indices,
+        });
+    }
+}
+
+/// The largest difference allowed between two values of a field, see
+/// ToleranceOpts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tolerance {
+    /// The largest absolute difference
+    Absolute(Decimal),
+
+    /// The largest difference as a fraction of the larger absolute
+    /// value, zero only allows equal values
+    Relative(Decimal),
+}
+
+impl Default for Tolerance {
+    fn default() -> Self {
+        Tolerance::Relative(Decimal::ZERO)
+    }
+}
+
+impl Tolerance {
+    // True if both are None or the values differ by no more than allowed
+    fn allows(&self, a: Option<Decimal>, b: Option<Decimal>) -> bool {
+        match (a, b) {
+            (None, None) => true,
+            (Some(a), Some(b)) => {
+                let max_diff = match self {
+                    Tolerance::Absolute(t) => *t,
+                    Tolerance::Relative(t) => *t * a.abs().max(b.abs()),
+                };
+                (a - b).abs() <= max_diff
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Options for find_tolerant_duplicates, the default only groups records
+/// with equal quantities, fee amounts, market values and times
+#[derive(Debug, Clone, Default)]
+pub struct ToleranceOpts {
+    pub received_quantity: Tolerance,
+    pub sent_quantity: Tolerance,
+    pub fee_amount: Tolerance,
+    pub market_value: Tolerance,
+
+    /// The largest time after the first record of a group
+    pub window_ms: i64,
+}
+
+/// Records proposed as duplicates of each other, see
+/// find_tolerant_duplicates
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DupGroup {
+    /// Indices of the records in ascending order
+    pub indices: Vec<usize>,
+}
+
+/// Groups of records of the same type, received, sent and fee currencies
+/// and source whose times are within opts.window_ms after, and whose
+/// quantities, fee amounts and market values are within the tolerances
+/// of, the earliest record of the group. For instance the same trade
+/// from two exports which rounded the quantity differently. The external
+/// IDs aren't compared.
+///
+/// A record is added to the first group, in time order, it matches.
+/// Records are only proposed as duplicates, see resolve_keep_first to
+/// remove them. The groups are in order of their first index.
+pub fn find_tolerant_duplicates(recs: &[TaxBitExportRec], opts: &ToleranceOpts) -> Vec<DupGroup> {
+    let mut order: Vec<usize> = (0..recs.len()).collect();
+    order.sort_by_key(|i| (recs[*i].time, *i));
+
+    type Key<'a> = (&'a TaxBitRecType, &'a str, &'a str, &'a str, &'a str);
+    // The groups of each key a later record could still join
+    let mut by_key: BTreeMap<Key, Vec<Vec<usize>>> = BTreeMap::new();
+    // The groups of more than one record past their window
+    let mut closed: Vec<Vec<usize>> = vec![];
+    for i in order {
+        let rec = &recs[i];
+        let groups = by_key
+            .entry((
+                &rec.type_txs,
+                &rec.received_currency,
+                &rec.sent_currency,
+                &rec.fee_currency,
+                &rec.source,
+            ))
+            .or_default();
+        // The records are in time order so a group past its window stays so
+        groups.retain_mut(|group| {
+            let open = rec.time.saturating_sub(recs[group[0]].time) <= opts.window_ms;
+            if !open && group.len() > 1 {
+                closed.push(std::mem::take(group));
+            }
+            open
+        });
+        let matching = groups.iter_mut().find(|group| {
+            let first = &recs[group[0]];
+            opts.received_quantity
+                .allows(first.received_quantity, rec.received_quantity)
+                && opts
+                    .sent_quantity
+                    .allows(first.sent_quantity, rec.sent_quantity)
+                && opts.fee_amount.allows(first.fee_amount, rec.fee_amount)
+                && opts
+                    .market_value
+                    .allows(first.market_value, rec.market_value)
+        });
+        match matching {
+            Some(group) => group.push(i),
+            None => groups.push(vec![i]),
+        }
+    }
+
+    let mut dups: Vec<DupGroup> = by_key
+        .into_values()
+        .flatten()
+        .chain(closed)
+        .filter(|indices| indices.len() > 1)
+        .map(|mut indices| {
+            indices.sort();
+            DupGroup { indices }
+        })
+        .collect();
+    dups.sort_by_key(|g| g.indices[0]);
+
+    dups
+}
+
+/// Remove all but the first record of each group, returns the number
+/// removed. The order of the remaining records is unchanged.
+pub fn resolve_keep_first(groups: &[DupGroup], recs: &mut Vec<TaxBitExportRec>) -> usize {
+    let mut keep = vec![true; recs.len()];
+    for group in groups {
+        for i in group.indices.iter().skip(1) {
+            keep[*i] = false;
+        }
+    }
+
+    let len = recs.len();
+    let mut keep = keep.into_iter();
+    recs.retain(|_| keep.next().expect("SNH"));
+
+    len - recs.len()
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal_macros::dec;
+    use taxbitrec::TaxBitRecType;
+
+    use super::*;
+
+    fn rec(time: i64, quantity: rust_decimal::Decimal, id: &str) -> TaxBitExportRec {
+        let mut rec = TaxBitExportRec::new();
+        rec.time = time;
+        rec.type_txs = TaxBitRecType::Income;
+        rec.received_quantity = Some(quantity);
+        rec.received_currency = "BTC".to_owned();
+        rec.external_id = id.to_owned();
+        rec
+    }
+
+    #[test]
+    fn test_dedup_consecutive() {
+        let mut recs = vec![
+            rec(1, dec!(1), "a"),
+            rec(1, dec!(1), "a"),
+            rec(1, dec!(1), "a"),
+            rec(1, dec!(2), "b"),
+            rec(2, dec!(1), "c"),
+            rec(2, dec!(1), "c"),
+            rec(1, dec!(1), "a"),
+        ];
+
+        assert_eq!(dedup_consecutive(&mut recs), 3);
+        let ids: Vec<&str> = recs.iter().map(|r| r.external_id.as_str()).collect();
+        assert_eq!(ids, vec!["a", "b", "c", "a"]);
+        assert_eq!(dedup_consecutive(&mut recs), 0);
+    }
+
+    #[test]
+    fn test_dedup_consecutive_same_time() {
+        // Sharing a timestamp isn't enough to be a duplicate
+        let mut recs = vec![
+            rec(1, dec!(1), "a"),
+            rec(1, dec!(1.5), "a"),
+            rec(1, dec!(2), "a"),
+        ];
+        assert_eq!(dedup_consecutive(&mut recs), 0);
+        assert_eq!(recs.len(), 3);
+    }
+
+    #[test]
+    fn test_dedup_consecutive_by() {
+        let mut recs = vec![
+            rec(1, dec!(1), "a"),
+            rec(1, dec!(1), "b"),
+            rec(1, dec!(2), "c"),
+        ];
+        assert_eq!(dedup_consecutive(&mut recs.clone()), 0);
+
+        let removed = dedup_consecutive_by(&mut recs, |r| {
+            let mut key = r.clone();
+            key.external_id.clear();
+            key
+        });
+        assert_eq!(removed, 1);
+        let ids: Vec<&str> = recs.iter().map(|r| r.external_id.as_str()).collect();
+        assert_eq!(ids, vec!["a", "c"]);
+    }
+
+    #[test]
+    fn test_find_duplicate_ids() {
+        let mut recs = vec![
+            rec(1, dec!(1), "a"),
+            rec(2, dec!(2), "b"),
+            rec(3, dec!(3), "a"),
+            rec(4, dec!(4), ""),
+            rec(5, dec!(5), ""),
+            rec(6, dec!(6), "b"),
+            rec(7, dec!(7), "c"),
+        ];
+        recs[5].source = "Coinbase".to_owned();
+
+        let dups = find_duplicate_ids(&recs);
+        assert_eq!(dups.len(), 1);
+        assert_eq!(dups[&("".to_owned(), "a".to_owned())], vec![0, 2]);
+        assert!(find_duplicate_ids(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_find_near_duplicates() {
+        // 2021-01-02T10:00:00Z
+        let t = 1609581600000;
+        let day = 24 * 60 * 60 * 1000;
+        let mut recs = vec![
+            rec(t, dec!(1), "a"),
+            rec(t + 2 * day, dec!(1), "a"),
+            rec(t + 300, dec!(1), "a"),
+            rec(t + 100, dec!(1), "b"),
+            rec(t + 200, dec!(1), "a"),
+            rec(t + 2 * day + 300, dec!(1), "a"),
+        ];
+        recs[5].source = "Coinbase".to_owned();
+
+        assert_eq!(
+            find_near_duplicates(&recs, 500),
+            vec![NearDupGroup {
+                indices: vec![0, 4, 2],
+                span_ms: 300,
+            }]
+        );
+        assert!(find_near_duplicates(&recs, 50).is_empty());
+
+        let opts = NearDupOpts {
+            ignore_external_id: true,
+        };
+        assert_eq!(
+            find_near_duplicates_with_opts(&recs, 500, &opts),
+            vec![NearDupGroup {
+                indices: vec![0, 3, 4, 2],
+                span_ms: 300,
+            }]
+        );
+
+        // Only the first is within the window of the group's first record
+        let recs = vec![
+            rec(t, dec!(1), "a"),
+            rec(t + 400, dec!(1), "a"),
+            rec(t + 800, dec!(1), "a"),
+            rec(t + 1000, dec!(1), "a"),
+        ];
+        assert_eq!(
+            find_near_duplicates(&recs, 500)
+                .into_iter()
+                .map(|g| g.indices)
+                .collect::<Vec<_>>(),
+            vec![vec![0, 1], vec![2, 3]]
+        );
+
+        // The times at the extremes don't overflow
+        let recs = vec![
+            rec(i64::MIN, dec!(1), "a"),
+            rec(i64::MAX, dec!(1), "a"),
+            rec(i64::MAX, dec!(1), "a"),
+        ];
+        assert_eq!(
+            find_near_duplicates(&recs, i64::MAX),
+            vec![NearDupGroup {
+                indices: vec![0, 1, 2],
+                span_ms: i64::MAX,
+            }]
+        );
+        assert_eq!(
+            find_near_duplicates(&recs, 500),
+            vec![NearDupGroup {
+                indices: vec![1, 2],
+                span_ms: 0,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_eq_content() {
+        let a = rec(1, dec!(1), "coinbase-1");
+        let b = rec(1, dec!(1.0), "koinly-1");
+        assert!(a.eq_content(&b));
+        assert_ne!(a, b);
+        assert_eq!(a.cmp_content(&b), std::cmp::Ordering::Equal);
+        assert_ne!(a.cmp(&b), std::cmp::Ordering::Equal);
+
+        // Other fields are still compared, optional decimals None first as
+        // in Ord
+        let mut c = b.clone();
+        c.received_quantity = None;
+        assert!(!a.eq_content(&c));
+        assert_eq!(c.cmp_content(&a), std::cmp::Ordering::Less);
+        assert_eq!(c.cmp(&a), std::cmp::Ordering::Less);
+
+        let mut d = b.clone();
+        d.source = "Koinly".to_owned();
+        assert!(!a.eq_content(&d));
+        let opts = ContentOpts {
+            ignore_source: true,
+        };
+        assert!(a.eq_content_with_opts(&d, &opts));
+    }
+
+    #[test]
+    fn test_dedup_by_content() {
+        let mut recs = vec![
+            rec(2, dec!(1), "a"),
+            rec(1, dec!(1), "b"),
+            rec(2, dec!(1), "c"),
+            rec(2, dec!(1.00), "d"),
+        ];
+        assert_eq!(dedup_by_content(&mut recs), 2);
+        let ids: Vec<&str> = recs.iter().map(|r| r.external_id.as_str()).collect();
+        assert_eq!(ids, vec!["a", "b"]);
+
+        let mut recs = vec![rec(1, dec!(1), "a"), rec(1, dec!(1), "b")];
+        recs[1].source = "Koinly".to_owned();
+        assert_eq!(dedup_by_content(&mut recs), 0);
+        let opts = ContentOpts {
+            ignore_source: true,
+        };
+        assert_eq!(dedup_by_content_with_opts(&mut recs, &opts), 1);
+        assert_eq!(recs[0].external_id, "a");
+    }
+
+    #[test]
+    fn test_find_tolerant_duplicates() {
+        let recs = vec![
+            rec(1000, dec!(0.123456789), "a"),
+            rec(1000, dec!(0.5), "b"),
+            rec(1500, dec!(0.123456788), "c"),
+            rec(1600, dec!(0.505), "d"),
+            // Outside the window
+            rec(5000, dec!(0.123456789), "e"),
+        ];
+        let opts = ToleranceOpts {
+            received_quantity: Tolerance::Absolute(dec!(0.000000001)),
+            window_ms: 1000,
+            ..ToleranceOpts::default()
+        };
+        let groups = find_tolerant_duplicates(&recs, &opts);
+        assert_eq!(
+            groups,
+            vec![DupGroup {
+                indices: vec![0, 2]
+            }]
+        );
+
+        // A 1% difference isn't within 0.1%, it is within 1%
+        let opts = ToleranceOpts {
+            received_quantity: Tolerance::Relative(dec!(0.001)),
+            window_ms: 1000,
+            ..ToleranceOpts::default()
+        };
+        assert!(!find_tolerant_duplicates(&recs, &opts)
+            .iter()
+            .any(|g| g.indices.contains(&3)));
+        let opts = ToleranceOpts {
+            received_quantity: Tolerance::Relative(dec!(0.01)),
+            window_ms: 1000,
+            ..ToleranceOpts::default()
+        };
+        let groups = find_tolerant_duplicates(&recs, &opts);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[1].indices, vec![1, 3]);
+
+        // Removing the duplicates
+        let mut deduped = recs.clone();
+        assert_eq!(resolve_keep_first(&groups, &mut deduped), 2);
+        let ids: Vec<&str> = deduped.iter().map(|r| r.external_id.as_str()).collect();
+        assert_eq!(ids, vec!["a", "b", "e"]);
+    }
+
+    #[test]
+    fn test_find_tolerant_duplicates_exact() {
+        let mut other_source = rec(1000, dec!(1), "c");
+        other_source.source = "Koinly".to_owned();
+        let mut no_quantity = rec(1000, dec!(1), "d");
+        no_quantity.received_quantity = None;
+        let recs = vec![
+            rec(1000, dec!(1), "a"),
+            rec(1000, dec!(1.000000001), "b"),
+            other_source,
+            no_quantity,
+            rec(1000, dec!(1.0), "e"),
+            rec(1001, dec!(1), "f"),
+        ];
+
+        // A relative tolerance of zero only groups equal quantities
+        let groups = find_tolerant_duplicates(&recs, &ToleranceOpts::default());
+        assert_eq!(
+            groups,
+            vec![DupGroup {
+                indices: vec![0, 4]
+            }]
+        );
+    }
+
+    #[test]
+    fn test_find_tolerant_duplicates_past_window() {
+        // A daily duplicate for a year, each group closes a day later
+        let day = 86_400_000;
+        let recs: Vec<TaxBitExportRec> = (0..365)
+            .flat_map(|d| {
+                [
+                    rec(d * day, dec!(1), &format!("{d}-a")),
+                    rec(d * day + 10, dec!(1), &format!("{d}-b")),
+                ]
+            })
+            .collect();
+        let opts = ToleranceOpts {
+            window_ms: 1000,
+            ..ToleranceOpts::default()
+        };
+        let groups = find_tolerant_duplicates(&recs, &opts);
+        assert_eq!(groups.len(), 365);
+        assert!(groups
+            .iter()
+            .enumerate()
+            .all(|(d, g)| g.indices == vec![2 * d, 2 * d + 1]));
+
+        // Times too far apart to subtract
+        let recs = vec![rec(i64::MIN, dec!(1), "a"), rec(i64::MAX, dec!(1), "b")];
+        assert_eq!(find_tolerant_duplicates(&recs, &opts), vec![]);
+    }
+}
@@ -0,0 +1,254 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use sha2::{Digest, Sha256};
+
+use crate::TaxBitExportRec;
+
+/// The separator between an external ID and the sequence number
+/// DisambiguationStrategy::LeaveTimeSetSequence appends
+pub const SEQUENCE_SEPARATOR: &str = "#";
+
+/// How disambiguate_timestamps separates records with the same time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DisambiguationStrategy {
+    /// Keep the first record of the colliding records at its time and
+    /// move the others to the next unused milliseconds of the same
+    /// second, or the previous ones if the end of the second is reached
+    #[default]
+    SpreadWithinSecond,
+
+    /// Leave the times and append SEQUENCE_SEPARATOR and the sequence
+    /// number, starting at 1, to the external IDs of the colliding records
+    LeaveTimeSetSequence,
+}
+
+/// What disambiguate_timestamps did
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DisambiguationReport {
+    /// Number of sets of records sharing a time
+    pub collisions: usize,
+
+    /// Number of records whose time or external ID was changed
+    pub changed: usize,
+
+    /// Number of records left colliding as every millisecond of their
+    /// second is in use
+    pub unresolved: usize,
+}
+
+// The order of colliding records, by a hash of their content so it
+// doesn't depend on the order they're in, identical records are
+// interchangeable
+fn content_order(recs: &[TaxBitExportRec], indices: &mut [usize]) {
+    let hash = |rec: &TaxBitExportRec| -> Vec<u8> {
+        Sha256::digest(format!("{rec:?}").as_bytes()).to_vec()
+    };
+    let mut keyed: Vec<(Vec<u8>, usize)> = indices.iter().map(|i| (hash(&recs[*i]), *i)).collect();
+    keyed.sort_by(|(ha, a), (hb, b)| ha.cmp(hb).then(recs[*a].cmp_strict(&recs[*b])));
+    for (index, (_, i)) in indices.iter_mut().zip(keyed) {
+        *index = i;
+    }
+}
+
+// The indices of the records of each time shared by more than one record
+fn collisions(recs: &[TaxBitExportRec]) -> BTreeMap<i64, Vec<usize>> {
+    let mut times: BTreeMap<i64, Vec<usize>> = BTreeMap::new();
+    for (i, rec) in recs.iter().enumerate() {
+        times.entry(rec.time).or_default().push(i);
+    }
+    times.retain(|_, indices| indices.len() > 1);
+
+    times
+}
+
+// True if the external IDs of the records already end with the sequence
+// numbers 1 to their count
+fn is_sequenced(recs: &[TaxBitExportRec], indices: &[usize]) -> bool {
+    let numbers: BTreeSet<usize> = indices
+        .iter()
+        .filter_map(|i| {
+            let (_, n) = recs[*i].external_id.rsplit_once(SEQUENCE_SEPARATOR)?;
+            n.parse().ok()
+        })
+        .collect();
+
+    numbers.len() == indices.len() && numbers.iter().copied().eq(1..=indices.len())
+}
+
+/// Separate the records which share the same millisecond, as exports
+/// truncated to the second have many, so their order is the same on
+/// every run, see DisambiguationStrategy. The colliding records are
+/// ordered by a hash of their content so the result doesn't depend on
+/// their initial order, and running it again changes nothing.
+///
+/// SpreadWithinSecond never moves a record out of its second and never
+/// changes the time of a record which doesn't collide.
+pub fn disambiguate_timestamps(
+    recs: &mut [TaxBitExportRec],
+    strategy: DisambiguationStrategy,
+) -> DisambiguationReport {
+    let mut report = DisambiguationReport::default();
+    let collisions = collisions(recs);
+    report.collisions = collisions.len();
+
+    match strategy {
+        DisambiguationStrategy::SpreadWithinSecond => {
+            let mut used: BTreeSet<i64> = recs.iter().map(|r| r.time).collect();
+            for (time, mut indices) in collisions {
+                content_order(recs, &mut indices);
+                let second = time.div_euclid(1000) * 1000;
+                let seconds_ms = second..second + 1000;
+                for i in indices.into_iter().skip(1) {
+                    let free = (time + 1..seconds_ms.end)
+                        .chain((seconds_ms.start..time).rev())
+                        .find(|t| !used.contains(t));
+                    match free {
+                        Some(t) => {
+                            used.insert(t);
+                            recs[i].time = t;
+                            report.changed += 1;
+                        }
+                        None => report.unresolved += 1,
+                    }
+                }
+            }
+        }
+        DisambiguationStrategy::LeaveTimeSetSequence => {
+            for (_, mut indices) in collisions {
+                if is_sequenced(recs, &indices) {
+                    continue;
+                }
+                content_order(recs, &mut indices);
+                for (n, i) in indices.into_iter().enumerate() {
+                    let rec = &mut recs[i];
+                    rec.external_id = format!("{}{SEQUENCE_SEPARATOR}{}", rec.external_id, n + 1);
+                    report.changed += 1;
+                }
+            }
+        }
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal_macros::dec;
+    use taxbitrec::TaxBitRecType;
+
+    use super::*;
+
+    // 2022-01-01T00:00:05Z
+    const T: i64 = 1640995205000;
+
+    fn income(time: i64, asset: &str, id: &str) -> TaxBitExportRec {
+        let mut rec = TaxBitExportRec::new();
+        rec.time = time;
+        rec.type_txs = TaxBitRecType::Income;
+        rec.received_quantity = Some(dec!(1));
+        rec.received_currency = asset.to_owned();
+        rec.source = "Staking".to_owned();
+        rec.external_id = id.to_owned();
+        rec
+    }
+
+    fn colliding() -> Vec<TaxBitExportRec> {
+        vec![
+            income(T, "ADA", "a"),
+            income(T + 1, "DOT", "d"),
+            income(T, "ETH", "e"),
+            income(T, "SOL", "s"),
+            income(T + 999, "ATOM", "x"),
+            income(T + 999, "ATOM", "y"),
+        ]
+    }
+
+    // The time of each external ID, which doesn't depend on the order
+    fn times(recs: &[TaxBitExportRec]) -> BTreeMap<String, i64> {
+        recs.iter()
+            .map(|r| (r.external_id.clone(), r.time))
+            .collect()
+    }
+
+    #[test]
+    fn test_spread_within_second() {
+        let mut recs = colliding();
+        let report = disambiguate_timestamps(&mut recs, DisambiguationStrategy::SpreadWithinSecond);
+        assert_eq!(
+            report,
+            DisambiguationReport {
+                collisions: 2,
+                changed: 3,
+                unresolved: 0,
+            }
+        );
+
+        // No two records share a time, the non-colliding DOT is
+        // unchanged and nothing leaves the second
+        let spread = times(&recs);
+        assert_eq!(spread.values().collect::<BTreeSet<_>>().len(), recs.len());
+        assert_eq!(spread["d"], T + 1);
+        assert!(spread.values().all(|t| (T..T + 1000).contains(t)));
+        let mut at_t: Vec<i64> = ["a", "e", "s"].iter().map(|id| spread[*id]).collect();
+        at_t.sort();
+        assert_eq!(at_t, vec![T, T + 2, T + 3]);
+        let mut atom = vec![spread["x"], spread["y"]];
+        atom.sort();
+        assert_eq!(atom, vec![T + 998, T + 999]);
+
+        // The same times whatever the initial order
+        let mut reversed: Vec<TaxBitExportRec> = colliding().into_iter().rev().collect();
+        disambiguate_timestamps(&mut reversed, DisambiguationStrategy::SpreadWithinSecond);
+        assert_eq!(times(&reversed), spread);
+
+        // Running it again changes nothing
+        let before = recs.clone();
+        let report = disambiguate_timestamps(&mut recs, DisambiguationStrategy::SpreadWithinSecond);
+        assert_eq!(report, DisambiguationReport::default());
+        assert_eq!(recs, before);
+    }
+
+    #[test]
+    fn test_spread_full_second() {
+        let mut recs: Vec<TaxBitExportRec> =
+            (0..1000).map(|ms| income(T + ms, "ADA", "")).collect();
+        recs.push(income(T + 500, "ETH", ""));
+        let report = disambiguate_timestamps(&mut recs, DisambiguationStrategy::SpreadWithinSecond);
+        assert_eq!(report.unresolved, 1);
+        assert_eq!(report.changed, 0);
+    }
+
+    #[test]
+    fn test_leave_time_set_sequence() {
+        let mut recs = colliding();
+        let report =
+            disambiguate_timestamps(&mut recs, DisambiguationStrategy::LeaveTimeSetSequence);
+        assert_eq!(report.changed, 5);
+        assert_eq!(
+            recs.iter().map(|r| r.time).collect::<Vec<_>>(),
+            colliding().iter().map(|r| r.time).collect::<Vec<_>>()
+        );
+        assert_eq!(recs[1].external_id, "d");
+        let mut numbers: Vec<&str> = [0, 2, 3]
+            .iter()
+            .map(|i| &recs[*i].external_id[2..])
+            .collect();
+        numbers.sort();
+        assert_eq!(numbers, vec!["1", "2", "3"]);
+        assert!(recs[0].external_id.starts_with("a#"));
+
+        // The same sequence numbers whatever the initial order
+        let mut reversed: Vec<TaxBitExportRec> = colliding().into_iter().rev().collect();
+        disambiguate_timestamps(&mut reversed, DisambiguationStrategy::LeaveTimeSetSequence);
+        let sorted = |recs: &[TaxBitExportRec]| -> BTreeSet<String> {
+            recs.iter().map(|r| r.external_id.clone()).collect()
+        };
+        assert_eq!(sorted(&reversed), sorted(&recs));
+
+        let before = recs.clone();
+        let report =
+            disambiguate_timestamps(&mut recs, DisambiguationStrategy::LeaveTimeSetSequence);
+        assert_eq!(report.changed, 0);
+        assert_eq!(recs, before);
+    }
+}
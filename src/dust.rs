@@ -0,0 +1,384 @@
+use std::collections::BTreeMap;
+
+use rust_decimal::Decimal;
+use taxbitrec::TaxBitRecType;
+
+use crate::{TaxBitExportRec, Traced};
+
+/// What filter_dust does with the dust
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DustMode {
+    /// Remove the dust
+    #[default]
+    Drop,
+
+    /// Replace the dust Income records of each source, asset and UTC
+    /// calendar year by one Income record of their total, other dust is
+    /// removed and counted in DustReport::dropped
+    AggregateIncome,
+}
+
+/// Options for filter_dust, a record is dust if it's below either
+/// threshold
+#[derive(Debug, Clone, Default)]
+pub struct DustOpts {
+    /// Records with a market value less than this are dust, records
+    /// without a market value aren't
+    pub max_market_value: Option<Decimal>,
+
+    /// Records whose quantity, see TaxBitExportRec::get_quantity, is
+    /// less than the threshold of their asset are dust
+    pub max_quantity: BTreeMap<String, Decimal>,
+
+    pub mode: DustMode,
+}
+
+/// Totals of the dust of an asset
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DustTotals {
+    /// Number of records
+    pub count: usize,
+
+    /// Sum of their quantities
+    pub quantity: Decimal,
+
+    /// Sum of their market values, records without one count as zero
+    pub market_value: Decimal,
+}
+
+/// The dust filter_dust removed, so what was dropped can be audited
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DustReport {
+    /// Number of dust records removed, including those aggregated
+    pub removed: usize,
+
+    /// Sum of the market values of the dust records
+    pub market_value_removed: Decimal,
+
+    /// Totals of the dust records by asset
+    pub assets: BTreeMap<String, DustTotals>,
+
+    /// Number of Income records added by DustMode::AggregateIncome
+    pub aggregated: usize,
+
+    /// Number of dust records removed and not aggregated, all of them
+    /// with DustMode::Drop
+    pub dropped: usize,
+}
+
+impl TaxBitExportRec {
+    // True if the record is dust by opts, Trades, transfers and records
+    // of an Unknown or Invalid type never are
+    fn is_dust(&self, opts: &DustOpts) -> bool {
+        if matches!(
+            self.type_txs,
+            TaxBitRecType::Trade
+                | TaxBitRecType::TransferIn
+                | TaxBitRecType::TransferOut
+                | TaxBitRecType::Unknown
+                | TaxBitRecType::Invalid
+        ) {
+            return false;
+        }
+
+        let below_value = match (self.market_value, opts.max_market_value) {
+            (Some(mv), Some(max)) => mv < max,
+            _ => false,
+        };
+        let below_quantity = match (self.get_quantity(), opts.max_quantity.get(self.get_asset())) {
+            (Some(q), Some(max)) => q < *max,
+            _ => false,
+        };
+
+        below_value || below_quantity
+    }
+}
+
+/// Remove the records below the thresholds of opts, or with
+/// DustMode::AggregateIncome consolidate the dust Income records and
+/// remove the other dust, and report what was removed. Trades and
+/// transfers are never dust.
+///
+/// An aggregated Income record takes the place of the last record it
+/// replaces, with its time, the sum of the quantities and market values,
+/// the market value is None if none of them has one, and the external ID
+/// "dust-YYYY-ASSET". The order of the other records is unchanged.
+pub fn filter_dust(
+    recs: Vec<TaxBitExportRec>,
+    opts: &DustOpts,
+) -> (Vec<TaxBitExportRec>, DustReport) {
+    let recs = recs.into_iter().map(Traced::untraced).collect();
+    let (kept, report) = filter_dust_traced(recs, opts);
+
+    (kept.into_iter().map(|t| t.rec).collect(), report)
+}
+
+/// Filter the dust as filter_dust does, the inputs of an aggregated
+/// Income record are those of the records it replaces in order
+pub fn filter_dust_traced(
+    recs: Vec<Traced<TaxBitExportRec>>,
+    opts: &DustOpts,
+) -> (Vec<Traced<TaxBitExportRec>>, DustReport) {
+    let mut report = DustReport::default();
+    let aggregating = |rec: &TaxBitExportRec| {
+        opts.mode == DustMode::AggregateIncome && rec.type_txs == TaxBitRecType::Income
+    };
+
+    // The aggregated record of each (source, asset, year) keyed by the
+    // index of the last record it replaces
+    let mut groups: BTreeMap<(String, String, i32), usize> = BTreeMap::new();
+    let mut aggregates: BTreeMap<usize, Traced<TaxBitExportRec>> = BTreeMap::new();
+    let mut dust = vec![false; recs.len()];
+    for (i, traced) in recs.iter().enumerate() {
+        let rec = &traced.rec;
+        if !rec.is_dust(opts) {
+            continue;
+        }
+        dust[i] = true;
+        let asset = rec.get_asset().to_owned();
+        let quantity = rec.get_quantity().unwrap_or_default();
+        let totals = report.assets.entry(asset.clone()).or_default();
+        totals.count += 1;
+        totals.quantity += quantity;
+        totals.market_value += rec.market_value.unwrap_or_default();
+        report.market_value_removed += rec.market_value.unwrap_or_default();
+        report.removed += 1;
+        if !aggregating(rec) {
+            report.dropped += 1;
+            continue;
+        }
+
+        let key = (rec.source.clone(), asset, rec.year());
+        let mut aggregate = match groups.get(&key).and_then(|last| aggregates.remove(last)) {
+            Some(aggregate) => aggregate,
+            None => {
+                let mut aggregate = TaxBitExportRec::new();
+                aggregate.type_txs = TaxBitRecType::Income;
+                aggregate.received_quantity = Some(Decimal::ZERO);
+                aggregate.received_currency = key.1.clone();
+                aggregate.source = key.0.clone();
+                aggregate.external_id = format!("dust-{}-{}", key.2, key.1);
+                Traced::untraced(aggregate)
+            }
+        };
+        aggregate.rec.time = rec.time;
+        aggregate.rec.received_quantity =
+            Some(aggregate.rec.received_quantity.unwrap_or_default() + quantity);
+        aggregate.rec.market_value = match (aggregate.rec.market_value, rec.market_value) {
+            (Some(a), Some(b)) => Some(a + b),
+            (a, b) => a.or(b),
+        };
+        aggregate.inputs.extend(traced.inputs.iter().cloned());
+        aggregates.insert(i, aggregate);
+        groups.insert(key, i);
+    }
+    report.aggregated = aggregates.len();
+
+    let kept = recs
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, rec)| match dust[i] {
+            true => aggregates.remove(&i),
+            false => Some(rec),
+        })
+        .collect();
+
+    (kept, report)
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    // 2022-01-01T00:00:00Z and 2023-01-01T00:00:00Z
+    const Y2022: i64 = 1640995200000;
+    const Y2023: i64 = 1672531200000;
+
+    fn rec(
+        time: i64,
+        type_txs: TaxBitRecType,
+        quantity: Decimal,
+        asset: &str,
+        market_value: Option<Decimal>,
+    ) -> TaxBitExportRec {
+        let mut rec = TaxBitExportRec::new();
+        rec.time = time;
+        rec.type_txs = type_txs.clone();
+        match type_txs {
+            TaxBitRecType::Sale | TaxBitRecType::TransferOut | TaxBitRecType::Expense => {
+                rec.sent_quantity = Some(quantity);
+                rec.sent_currency = asset.to_owned();
+            }
+            _ => {
+                rec.received_quantity = Some(quantity);
+                rec.received_currency = asset.to_owned();
+            }
+        }
+        rec.market_value = market_value;
+        rec.source = "Faucet".to_owned();
+        rec.external_id = format!("{time}");
+        rec
+    }
+
+    fn recs() -> Vec<TaxBitExportRec> {
+        vec![
+            rec(
+                Y2022,
+                TaxBitRecType::Income,
+                dec!(0.0001),
+                "ETH",
+                Some(dec!(0.003)),
+            ),
+            rec(
+                Y2022 + 1,
+                TaxBitRecType::Income,
+                dec!(2),
+                "ETH",
+                Some(dec!(60)),
+            ),
+            rec(Y2022 + 2, TaxBitRecType::Income, dec!(0.0002), "ETH", None),
+            rec(
+                Y2022 + 3,
+                TaxBitRecType::Expense,
+                dec!(0.00001),
+                "BTC",
+                Some(dec!(0.004)),
+            ),
+            rec(
+                Y2022 + 4,
+                TaxBitRecType::TransferIn,
+                dec!(0.0001),
+                "ETH",
+                Some(dec!(0.003)),
+            ),
+            rec(
+                Y2022 + 5,
+                TaxBitRecType::Trade,
+                dec!(0.0001),
+                "ETH",
+                Some(dec!(0.003)),
+            ),
+            rec(
+                Y2023,
+                TaxBitRecType::Income,
+                dec!(0.0003),
+                "ETH",
+                Some(dec!(0.005)),
+            ),
+            rec(
+                Y2023 + 1,
+                TaxBitRecType::Income,
+                dec!(5),
+                "DOGE",
+                Some(dec!(0.005)),
+            ),
+        ]
+    }
+
+    fn opts(mode: DustMode) -> DustOpts {
+        DustOpts {
+            max_market_value: Some(dec!(0.01)),
+            max_quantity: BTreeMap::from([("ETH".to_owned(), dec!(0.001))]),
+            mode,
+        }
+    }
+
+    #[test]
+    fn test_filter_dust_drop() {
+        let recs = recs();
+        let (kept, report) = filter_dust(recs.clone(), &opts(DustMode::Drop));
+
+        // The transfer and trade are kept though tiny, the DOGE has no
+        // quantity threshold but is dust by value
+        assert_eq!(
+            kept,
+            vec![recs[1].clone(), recs[4].clone(), recs[5].clone()]
+        );
+        assert_eq!(report.removed, 5);
+        assert_eq!(report.market_value_removed, dec!(0.017));
+        assert_eq!(report.aggregated, 0);
+        assert_eq!(report.dropped, 5);
+        assert_eq!(
+            report.assets["ETH"],
+            DustTotals {
+                count: 3,
+                quantity: dec!(0.0006),
+                market_value: dec!(0.008),
+            }
+        );
+        assert_eq!(report.assets["BTC"].count, 1);
+        assert_eq!(report.assets["DOGE"].quantity, dec!(5));
+
+        let (kept, report) = filter_dust(recs.clone(), &DustOpts::default());
+        assert_eq!(kept, recs);
+        assert_eq!(report, DustReport::default());
+    }
+
+    #[test]
+    fn test_filter_dust_aggregate() {
+        let recs = recs();
+        let (kept, report) = filter_dust(recs.clone(), &opts(DustMode::AggregateIncome));
+        assert_eq!(report.removed, 5);
+        assert_eq!(report.aggregated, 3);
+        // The BTC Expense isn't Income so isn't aggregated
+        assert_eq!(report.dropped, 1);
+        assert!(!kept.iter().any(|r| r.type_txs == TaxBitRecType::Expense));
+
+        let mut eth_2022 = TaxBitExportRec::new();
+        eth_2022.time = Y2022 + 2;
+        eth_2022.type_txs = TaxBitRecType::Income;
+        eth_2022.received_quantity = Some(dec!(0.0003));
+        eth_2022.received_currency = "ETH".to_owned();
+        eth_2022.market_value = Some(dec!(0.003));
+        eth_2022.source = "Faucet".to_owned();
+        eth_2022.external_id = "dust-2022-ETH".to_owned();
+        let ids: Vec<&str> = kept.iter().map(|r| r.external_id.as_str()).collect();
+        assert_eq!(
+            ids,
+            vec![
+                recs[1].external_id.as_str(),
+                "dust-2022-ETH",
+                recs[4].external_id.as_str(),
+                recs[5].external_id.as_str(),
+                "dust-2023-ETH",
+                "dust-2023-DOGE",
+            ]
+        );
+        assert_eq!(kept[1], eth_2022);
+        assert_eq!(kept[4].received_quantity, Some(dec!(0.0003)));
+        assert_eq!(kept[4].time, Y2023);
+
+        // The sum of the aggregated quantities is unchanged
+        let total = |recs: &[TaxBitExportRec]| -> Decimal {
+            recs.iter()
+                .filter(|r| r.type_txs == TaxBitRecType::Income)
+                .filter_map(|r| r.received_quantity)
+                .sum()
+        };
+        assert_eq!(total(&kept), total(&recs));
+    }
+
+    #[test]
+    fn test_filter_dust_traced() {
+        let recs = recs();
+        let opts = opts(DustMode::AggregateIncome);
+        let (kept, report) = filter_dust_traced(crate::trace(recs.clone()), &opts);
+        assert_eq!(
+            (crate::untrace(kept.clone()), report),
+            filter_dust(recs, &opts)
+        );
+
+        let rows = |t: &Traced<TaxBitExportRec>| -> Vec<usize> {
+            t.inputs.iter().map(|i| i.row).collect()
+        };
+        assert_eq!(kept[0].rec.external_id, "1640995200001");
+        assert_eq!(rows(&kept[0]), vec![1]);
+        assert_eq!(kept[1].rec.external_id, "dust-2022-ETH");
+        assert_eq!(rows(&kept[1]), vec![0, 2]);
+        assert_eq!(kept[1].inputs[1].external_id, "1640995200002");
+        assert_eq!(rows(&kept[4]), vec![6]);
+        assert_eq!(rows(&kept[5]), vec![7]);
+    }
+}
@@ -0,0 +1,335 @@
+use std::{collections::BTreeMap, error::Error, io::Read};
+#[cfg(feature = "std-fs")]
+use std::{fs::File, io::BufReader, path::Path};
+
+use rust_decimal::Decimal;
+use taxbitrec::TaxBitRecType;
+
+use crate::{convert::parse_decimal_opt, income::pattern_matches, IncomeTotals, TaxBitExportRec};
+
+/// What an Expense record was spent on, see ExpenseRules
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ExpenseCategory {
+    GasFee,
+    Purchase,
+    Service,
+    Other,
+}
+
+impl ExpenseCategory {
+    /// The category named name ignoring case, spaces and underscores, so
+    /// "GasFee", "gas fee" and "GAS_FEE" are all GasFee
+    pub fn from_name(name: &str) -> Option<ExpenseCategory> {
+        let name: String = name
+            .chars()
+            .filter(|c| *c != ' ' && *c != '_')
+            .collect::<String>()
+            .to_lowercase();
+        match name.as_str() {
+            "gasfee" => Some(ExpenseCategory::GasFee),
+            "purchase" => Some(ExpenseCategory::Purchase),
+            "service" => Some(ExpenseCategory::Service),
+            "other" => Some(ExpenseCategory::Other),
+            _ => None,
+        }
+    }
+}
+
+/// A rule of ExpenseRules, it matches a record if all of its conditions
+/// do, an absent condition matches anything.
+///
+/// source and external_id are patterns as IncomeRule's are, asset must
+/// equal the sent currency ignoring case and the sent quantity must be
+/// at least min_quantity and at most max_quantity, so a tiny amount of
+/// ETH can be taken as a GasFee.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpenseRule {
+    pub source: Option<String>,
+    pub asset: Option<String>,
+    pub external_id: Option<String>,
+    pub min_quantity: Option<Decimal>,
+    pub max_quantity: Option<Decimal>,
+    pub category: ExpenseCategory,
+}
+
+impl ExpenseRule {
+    fn matches(&self, rec: &TaxBitExportRec) -> bool {
+        let matches = |pattern: &Option<String>, value: &str| match pattern {
+            Some(pattern) => pattern_matches(pattern, value),
+            None => true,
+        };
+        let quantity = rec.sent_quantity.unwrap_or_default();
+        matches(&self.source, &rec.source)
+            && matches(&self.external_id, &rec.external_id)
+            && match &self.asset {
+                Some(asset) => asset.eq_ignore_ascii_case(&rec.sent_currency),
+                None => true,
+            }
+            && !matches!(self.min_quantity, Some(min) if quantity < min)
+            && !matches!(self.max_quantity, Some(max) if quantity > max)
+    }
+}
+
+/// Rules categorizing Expense records, the first matching rule wins
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExpenseRules {
+    pub rules: Vec<ExpenseRule>,
+}
+
+impl ExpenseRules {
+    /// Load the rules from a CSV with a Category column, see
+    /// ExpenseCategory::from_name, and any of the columns Source, Asset,
+    /// External ID, Min Quantity and Max Quantity. An empty cell or
+    /// missing column is an absent condition. The rules are in the order
+    /// of the rows.
+    pub fn from_reader<R: Read>(rdr: R) -> Result<ExpenseRules, Box<dyn Error>> {
+        let mut reader = csv::Reader::from_reader(rdr);
+        let header = reader.headers()?.clone();
+        let column = |name: &str| header.iter().position(|h| h.trim() == name);
+        let category_column = column("Category").ok_or("No Category column")?;
+        let columns = [
+            column("Source"),
+            column("Asset"),
+            column("External ID"),
+            column("Min Quantity"),
+            column("Max Quantity"),
+        ];
+
+        let mut rules = ExpenseRules::default();
+        for record in reader.records() {
+            let record = record?;
+            let line = record.position().map_or(0, |p| p.line());
+            let field = |i: Option<usize>| {
+                i.and_then(|i| record.get(i))
+                    .map(|f| f.trim())
+                    .filter(|f| !f.is_empty())
+            };
+            let quantity = |i: Option<usize>, name: &str| {
+                parse_decimal_opt(name, field(i).unwrap_or(""))
+                    .map_err(|e| format!("line {line}: {e}"))
+            };
+
+            let category = field(Some(category_column)).unwrap_or("");
+            let category = ExpenseCategory::from_name(category)
+                .ok_or_else(|| format!("line {line}: Unknown Category '{category}'"))?;
+            rules.rules.push(ExpenseRule {
+                source: field(columns[0]).map(|f| f.to_owned()),
+                asset: field(columns[1]).map(|f| f.to_owned()),
+                external_id: field(columns[2]).map(|f| f.to_owned()),
+                min_quantity: quantity(columns[3], "Min Quantity")?,
+                max_quantity: quantity(columns[4], "Max Quantity")?,
+                category,
+            });
+        }
+
+        Ok(rules)
+    }
+
+    #[cfg(feature = "std-fs")]
+    pub fn from_path(path: &Path) -> Result<ExpenseRules, Box<dyn Error>> {
+        ExpenseRules::from_reader(BufReader::new(File::open(path)?))
+    }
+}
+
+impl TaxBitExportRec {
+    /// The category of an Expense record given by the first of rules it
+    /// matches, None if it isn't an Expense or matches no rule
+    pub fn categorize_expense(&self, rules: &ExpenseRules) -> Option<ExpenseCategory> {
+        if self.type_txs != TaxBitRecType::Expense {
+            return None;
+        }
+        rules
+            .rules
+            .iter()
+            .find(|r| r.matches(self))
+            .map(|r| r.category)
+    }
+}
+
+/// The index and category of each Expense record a rule matches, see
+/// TaxBitExportRec::categorize_expense
+pub fn categorize_expenses(
+    recs: &[TaxBitExportRec],
+    rules: &ExpenseRules,
+) -> Vec<(usize, ExpenseCategory)> {
+    recs.iter()
+        .enumerate()
+        .filter_map(|(i, rec)| rec.categorize_expense(rules).map(|c| (i, c)))
+        .collect()
+}
+
+/// The result of expense_summary
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExpenseSummary {
+    /// Totals of the categorized Expense records keyed by UTC calendar
+    /// year and category and then asset
+    pub totals: BTreeMap<(u32, ExpenseCategory), BTreeMap<String, IncomeTotals>>,
+
+    /// Indices of the Expense records no rule matched
+    pub uncategorized: Vec<usize>,
+
+    /// Indices of the categorized Expense records dated before year 0,
+    /// they can't be totalled by year so aren't in totals
+    pub before_year_0: Vec<usize>,
+}
+
+/// Total the Expense records by UTC calendar year, the category rules
+/// give them and asset, listing those no rule matches
+pub fn expense_summary(recs: &[TaxBitExportRec], rules: &ExpenseRules) -> ExpenseSummary {
+    let mut summary = ExpenseSummary::default();
+    for (index, rec) in recs.iter().enumerate() {
+        if rec.type_txs != TaxBitRecType::Expense {
+            continue;
+        }
+        let Some(category) = rec.categorize_expense(rules) else {
+            summary.uncategorized.push(index);
+            continue;
+        };
+        let Ok(year) = u32::try_from(rec.year()) else {
+            summary.before_year_0.push(index);
+            continue;
+        };
+        summary
+            .totals
+            .entry((year, category))
+            .or_default()
+            .entry(rec.get_asset().to_owned())
+            .or_default()
+            .add(rec.get_quantity().unwrap_or_default(), rec.market_value);
+    }
+
+    summary
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    // 2022-01-01T00:00:00Z and 2023-01-01T00:00:00Z
+    const Y2022: i64 = 1640995200000;
+    const Y2023: i64 = 1672531200000;
+
+    fn expense(
+        time: i64,
+        source: &str,
+        id: &str,
+        quantity: Decimal,
+        asset: &str,
+    ) -> TaxBitExportRec {
+        let mut rec = TaxBitExportRec::new();
+        rec.time = time;
+        rec.type_txs = TaxBitRecType::Expense;
+        rec.sent_quantity = Some(quantity);
+        rec.sent_currency = asset.to_owned();
+        rec.market_value = Some(quantity * dec!(1000));
+        rec.source = source.to_owned();
+        rec.external_id = id.to_owned();
+        rec
+    }
+
+    fn recs() -> Vec<TaxBitExportRec> {
+        let mut buy = expense(Y2022, "Metamask", "0x1", dec!(0.001), "ETH");
+        buy.type_txs = TaxBitRecType::Buy;
+        vec![
+            expense(Y2022, "Metamask", "0x2", dec!(0.002), "ETH"),
+            expense(Y2022 + 1, "Metamask", "0x3", dec!(0.5), "ETH"),
+            expense(Y2022 + 2, "Coinbase Card", "card-1", dec!(0.01), "BTC"),
+            expense(Y2023, "Metamask", "0x4", dec!(0.004), "eth"),
+            expense(Y2023, "Kraken", "sub-2023", dec!(10), "USDC"),
+            buy,
+        ]
+    }
+
+    const RULES_CSV: &str = "Category,Source,Asset,External ID,Min Quantity,Max Quantity\n\
+        Gas Fee,Metamask,ETH,,,0.01\n\
+        Purchase,Coinbase Card,,,,\n";
+
+    #[test]
+    fn test_categorize_expenses() {
+        let rules = ExpenseRules::from_reader(RULES_CSV.as_bytes()).unwrap();
+        assert_eq!(rules.rules.len(), 2);
+        assert_eq!(
+            rules.rules[0],
+            ExpenseRule {
+                source: Some("Metamask".to_owned()),
+                asset: Some("ETH".to_owned()),
+                external_id: None,
+                min_quantity: None,
+                max_quantity: Some(dec!(0.01)),
+                category: ExpenseCategory::GasFee,
+            }
+        );
+
+        let recs = recs();
+        assert_eq!(
+            categorize_expenses(&recs, &rules),
+            vec![
+                (0, ExpenseCategory::GasFee),
+                (2, ExpenseCategory::Purchase),
+                (3, ExpenseCategory::GasFee),
+            ]
+        );
+
+        let summary = expense_summary(&recs, &rules);
+        assert_eq!(summary.uncategorized, vec![1, 4]);
+        assert_eq!(
+            summary.totals[&(2022, ExpenseCategory::GasFee)]["ETH"],
+            IncomeTotals {
+                quantity: dec!(0.002),
+                market_value: dec!(2),
+                count: 1,
+                missing_market_value: 0,
+            }
+        );
+        assert_eq!(
+            summary.totals[&(2023, ExpenseCategory::GasFee)]["eth"].count,
+            1
+        );
+        assert_eq!(summary.totals.len(), 3);
+        assert_eq!(summary.before_year_0, Vec::<usize>::new());
+
+        // -0001-12-31
+        let mut ancient = recs.clone();
+        ancient[2].time = -62167219200001;
+        let summary = expense_summary(&ancient, &rules);
+        assert_eq!(summary.before_year_0, vec![2]);
+        assert!(!summary
+            .totals
+            .contains_key(&(2022, ExpenseCategory::Purchase)));
+
+        // A rule built in code categorizes the subscription
+        let mut rules = rules;
+        rules.rules.push(ExpenseRule {
+            source: None,
+            asset: None,
+            external_id: Some("sub-*".to_owned()),
+            min_quantity: Some(dec!(1)),
+            max_quantity: None,
+            category: ExpenseCategory::Service,
+        });
+        assert_eq!(
+            recs[4].categorize_expense(&rules),
+            Some(ExpenseCategory::Service)
+        );
+        assert_eq!(expense_summary(&recs, &rules).uncategorized, vec![1]);
+    }
+
+    #[test]
+    fn test_expense_rules_errors() {
+        assert_eq!(
+            ExpenseCategory::from_name("GAS_FEE"),
+            Some(ExpenseCategory::GasFee)
+        );
+        assert_eq!(ExpenseCategory::from_name("gift"), None);
+
+        let err = ExpenseRules::from_reader("Source\nKraken\n".as_bytes()).unwrap_err();
+        assert_eq!(err.to_string(), "No Category column");
+        let err = ExpenseRules::from_reader("Category\nGift\n".as_bytes()).unwrap_err();
+        assert_eq!(err.to_string(), "line 2: Unknown Category 'Gift'");
+        let err =
+            ExpenseRules::from_reader("Category,Max Quantity\nOther,x\n".as_bytes()).unwrap_err();
+        assert_eq!(err.to_string(), "line 2: Max Quantity 'x' is not a number");
+    }
+}
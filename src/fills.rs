@@ -0,0 +1,202 @@
+use rust_decimal::Decimal;
+use taxbitrec::TaxBitRecType;
+
+use crate::{TaxBitExportRec, Traced};
+
+/// Options for merge_partial_fills_with_opts
+#[derive(Debug, Clone)]
+pub struct FillOpts {
+    /// Records of these types are never merged, by default TransferIn
+    /// and TransferOut as each is a separate movement of funds
+    pub excluded_types: Vec<TaxBitRecType>,
+}
+
+impl Default for FillOpts {
+    fn default() -> Self {
+        FillOpts {
+            excluded_types: vec![TaxBitRecType::TransferIn, TaxBitRecType::TransferOut],
+        }
+    }
+}
+
+/// Merge the partial fills of an order into one record, see
+/// merge_partial_fills_with_opts
+pub fn merge_partial_fills(recs: Vec<TaxBitExportRec>, window_ms: i64) -> Vec<TaxBitExportRec> {
+    merge_partial_fills_with_opts(recs, window_ms, &FillOpts::default())
+}
+
+/// Merge consecutive records of the same type, source, received, sent
+/// and fee currencies, internal transfer flag, lot ID and extras whose
+/// times are no more than window_ms after the first record of the group.
+///
+/// The received and sent quantities, fee amounts and market values are
+/// summed, an absent value only counts as zero if another fill has one.
+/// The merged record has the time of the first record and its
+/// external_id is the non-empty external_ids of the group joined with
+/// "+". Records of a type in opts.excluded_types are passed through.
+pub fn merge_partial_fills_with_opts(
+    recs: Vec<TaxBitExportRec>,
+    window_ms: i64,
+    opts: &FillOpts,
+) -> Vec<TaxBitExportRec> {
+    let recs = recs.into_iter().map(Traced::untraced).collect();
+    merge_partial_fills_traced(recs, window_ms, opts)
+        .into_iter()
+        .map(|t| t.rec)
+        .collect()
+}
+
+/// Merge the partial fills as merge_partial_fills_with_opts does, the
+/// inputs of a merged record are those of its fills in order
+pub fn merge_partial_fills_traced(
+    recs: Vec<Traced<TaxBitExportRec>>,
+    window_ms: i64,
+    opts: &FillOpts,
+) -> Vec<Traced<TaxBitExportRec>> {
+    let mut merged: Vec<Traced<TaxBitExportRec>> = Vec::with_capacity(recs.len());
+    // The time of the first fill of the last merged record, None if the
+    // last record can't be merged into
+    let mut group_time: Option<i64> = None;
+    for rec in recs {
+        if opts.excluded_types.contains(&rec.rec.type_txs) {
+            merged.push(rec);
+            group_time = None;
+            continue;
+        }
+
+        match (merged.last_mut(), group_time) {
+            (Some(last), Some(first))
+                if is_fill_of(&last.rec, &rec.rec)
+                    && rec.rec.time >= first
+                    && rec.rec.time - first <= window_ms =>
+            {
+                add_fill(&mut last.rec, rec.rec);
+                last.inputs.extend(rec.inputs);
+            }
+            _ => {
+                group_time = Some(rec.rec.time);
+                merged.push(rec);
+            }
+        }
+    }
+
+    merged
+}
+
+// True if rec could be another fill of the same order as last
+fn is_fill_of(last: &TaxBitExportRec, rec: &TaxBitExportRec) -> bool {
+    last.type_txs == rec.type_txs
+        && last.source == rec.source
+        && last.received_currency == rec.received_currency
+        && last.sent_currency == rec.sent_currency
+        && last.fee_currency == rec.fee_currency
+        && last.internal_transfer == rec.internal_transfer
+        && last.lot_id == rec.lot_id
+        && last.extras == rec.extras
+}
+
+fn add_fill(last: &mut TaxBitExportRec, rec: TaxBitExportRec) {
+    let sum = |a: Option<Decimal>, b: Option<Decimal>| match (a, b) {
+        (Some(a), Some(b)) => Some(a + b),
+        (a, b) => a.or(b),
+    };
+    last.received_quantity = sum(last.received_quantity, rec.received_quantity);
+    last.sent_quantity = sum(last.sent_quantity, rec.sent_quantity);
+    last.fee_amount = sum(last.fee_amount, rec.fee_amount);
+    last.market_value = sum(last.market_value, rec.market_value);
+    if !rec.external_id.is_empty() {
+        if !last.external_id.is_empty() {
+            last.external_id.push('+');
+        }
+        last.external_id.push_str(&rec.external_id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    // 2022-01-01T00:00:00Z
+    const T: i64 = 1640995200000;
+
+    fn fill(time: i64, btc: Decimal, usd: Decimal, id: &str) -> TaxBitExportRec {
+        let mut rec = TaxBitExportRec::new();
+        rec.time = time;
+        rec.type_txs = TaxBitRecType::Buy;
+        rec.received_quantity = Some(btc);
+        rec.received_currency = "BTC".to_owned();
+        rec.sent_quantity = Some(usd);
+        rec.sent_currency = "USD".to_owned();
+        rec.fee_amount = Some(dec!(0.01));
+        rec.fee_currency = "USD".to_owned();
+        rec.market_value = Some(usd);
+        rec.source = "Kraken".to_owned();
+        rec.external_id = id.to_owned();
+        rec
+    }
+
+    #[test]
+    fn test_merge_partial_fills() {
+        let recs = vec![
+            fill(T, dec!(0.1), dec!(4000.01), "a"),
+            fill(T, dec!(0.2), dec!(8000.02), "b"),
+            fill(T + 1000, dec!(0.00000001), dec!(0.0004), "c"),
+            // 5 seconds outside the window
+            fill(T + 7000, dec!(0.3), dec!(12000), "d"),
+        ];
+        let merged = merge_partial_fills(recs, 2000);
+        assert_eq!(merged.len(), 2);
+
+        let mut expected = fill(T, dec!(0.30000001), dec!(12000.0304), "a+b+c");
+        expected.fee_amount = Some(dec!(0.03));
+        assert_eq!(merged[0], expected);
+        assert_eq!(merged[1].external_id, "d");
+        assert_eq!(merged[1].received_quantity, Some(dec!(0.3)));
+    }
+
+    #[test]
+    fn test_merge_partial_fills_keys() {
+        let mut sale = fill(T, dec!(0.1), dec!(4000), "sale");
+        sale.type_txs = TaxBitRecType::Sale;
+        let mut other_source = fill(T, dec!(0.1), dec!(4000), "other");
+        other_source.source = "Coinbase".to_owned();
+        let mut no_fee = fill(T, dec!(0.1), dec!(4000), "no-fee");
+        no_fee.fee_amount = None;
+        no_fee.fee_currency = String::new();
+        let recs = vec![
+            fill(T, dec!(0.1), dec!(4000), "a"),
+            sale,
+            fill(T, dec!(0.1), dec!(4000), "b"),
+            other_source,
+            no_fee,
+        ];
+        assert_eq!(merge_partial_fills(recs.clone(), 1000), recs);
+    }
+
+    #[test]
+    fn test_merge_partial_fills_transfers() {
+        let transfer = |time: i64, id: &str| {
+            let mut rec = TaxBitExportRec::new();
+            rec.time = time;
+            rec.type_txs = TaxBitRecType::TransferIn;
+            rec.received_quantity = Some(dec!(1));
+            rec.received_currency = "ETH".to_owned();
+            rec.source = "Kraken".to_owned();
+            rec.external_id = id.to_owned();
+            rec
+        };
+        let recs = vec![transfer(T, "a"), transfer(T, "b")];
+        assert_eq!(merge_partial_fills(recs.clone(), 1000), recs);
+
+        let opts = FillOpts {
+            excluded_types: vec![],
+        };
+        let merged = merge_partial_fills_with_opts(recs, 1000, &opts);
+        assert_eq!(merged.len(), 1);
+        assert_eq!(merged[0].received_quantity, Some(dec!(2)));
+        assert_eq!(merged[0].sent_quantity, None);
+        assert_eq!(merged[0].external_id, "a+b");
+    }
+}
@@ -0,0 +1,216 @@
+use std::collections::BTreeSet;
+
+use rust_decimal::Decimal;
+
+use crate::TaxBitExportRec;
+
+/// Options for TaxBitExportRec::fuzzy_eq, the default compares as
+/// strictly as PartialEq
+#[derive(Debug, Clone, Default)]
+pub struct FuzzyOpts {
+    pub ignore_external_id: bool,
+    pub ignore_source: bool,
+
+    /// The largest difference in time in milliseconds
+    pub time_skew_ms: i64,
+
+    /// The largest difference of the sent, received and fee quantities
+    /// as a fraction of the larger of the two
+    pub quantity_tolerance: Decimal,
+
+    /// The largest difference of the market values as a fraction of the
+    /// larger of the two
+    pub market_value_tolerance: Decimal,
+}
+
+// True if both are None or the difference of both values is at most
+// tolerance times the larger absolute value
+fn within_tolerance(a: Option<Decimal>, b: Option<Decimal>, tolerance: Decimal) -> bool {
+    match (a, b) {
+        (None, None) => true,
+        (Some(a), Some(b)) => (a - b).abs() <= tolerance * a.abs().max(b.abs()),
+        _ => false,
+    }
+}
+
+impl TaxBitExportRec {
+    /// Equality allowing for the differences between exports of the
+    /// same transaction by different tools, see FuzzyOpts. The fields
+    /// without an option in FuzzyOpts must be equal.
+    pub fn fuzzy_eq(&self, other: &Self, opts: &FuzzyOpts) -> bool {
+        i64::try_from(self.time.abs_diff(other.time)).is_ok_and(|d| d <= opts.time_skew_ms)
+            && self.type_txs == other.type_txs
+            && self.sent_currency == other.sent_currency
+            && self.received_currency == other.received_currency
+            && self.fee_currency == other.fee_currency
+            && within_tolerance(
+                self.sent_quantity,
+                other.sent_quantity,
+                opts.quantity_tolerance,
+            )
+            && within_tolerance(
+                self.received_quantity,
+                other.received_quantity,
+                opts.quantity_tolerance,
+            )
+            && within_tolerance(self.fee_amount, other.fee_amount, opts.quantity_tolerance)
+            && within_tolerance(
+                self.market_value,
+                other.market_value,
+                opts.market_value_tolerance,
+            )
+            && self.internal_transfer == other.internal_transfer
+            && self.lot_id == other.lot_id
+            && (opts.ignore_source || self.source == other.source)
+            && (opts.ignore_external_id || self.external_id == other.external_id)
+    }
+}
+
+/// The result of fuzzy_match_sets, the indices are of the records in a
+/// and b and each list is in ascending order of the a or b index
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MatchReport {
+    /// (a index, b index) of each matched pair
+    pub pairs: Vec<(usize, usize)>,
+    pub unmatched_a: Vec<usize>,
+    pub unmatched_b: Vec<usize>,
+}
+
+/// Match each record of a with at most one fuzzy_eq record of b. The
+/// pairs closest in time are matched first, ties go to the lower
+/// indices.
+pub fn fuzzy_match_sets(
+    a: &[TaxBitExportRec],
+    b: &[TaxBitExportRec],
+    opts: &FuzzyOpts,
+) -> MatchReport {
+    // (time delta, a index, b index) of every possible pair
+    let mut candidates: Vec<(u64, usize, usize)> = vec![];
+    for (ia, rec_a) in a.iter().enumerate() {
+        for (ib, rec_b) in b.iter().enumerate() {
+            if rec_a.fuzzy_eq(rec_b, opts) {
+                candidates.push((rec_a.time.abs_diff(rec_b.time), ia, ib));
+            }
+        }
+    }
+    candidates.sort();
+
+    let mut matched_a: BTreeSet<usize> = BTreeSet::new();
+    let mut matched_b: BTreeSet<usize> = BTreeSet::new();
+    let mut pairs: Vec<(usize, usize)> = vec![];
+    for (_, ia, ib) in candidates {
+        if !matched_a.contains(&ia) && !matched_b.contains(&ib) {
+            matched_a.insert(ia);
+            matched_b.insert(ib);
+            pairs.push((ia, ib));
+        }
+    }
+    pairs.sort();
+
+    MatchReport {
+        pairs,
+        unmatched_a: (0..a.len()).filter(|i| !matched_a.contains(i)).collect(),
+        unmatched_b: (0..b.len()).filter(|i| !matched_b.contains(i)).collect(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal_macros::dec;
+    use taxbitrec::TaxBitRecType;
+
+    use super::*;
+
+    // 2021-01-02T10:00:00Z
+    const T: i64 = 1609581600000;
+    const MINUTE: i64 = 60 * 1000;
+
+    fn buy(time: i64, quantity: Decimal, market_value: Decimal, source: &str) -> TaxBitExportRec {
+        let mut rec = TaxBitExportRec::new();
+        rec.time = time;
+        rec.type_txs = TaxBitRecType::Buy;
+        rec.sent_quantity = Some(market_value);
+        rec.sent_currency = "USD".to_owned();
+        rec.received_quantity = Some(quantity);
+        rec.received_currency = "BTC".to_owned();
+        rec.market_value = Some(market_value);
+        rec.source = source.to_owned();
+        rec.external_id = format!("{source}-{time}");
+        rec
+    }
+
+    fn opts() -> FuzzyOpts {
+        FuzzyOpts {
+            ignore_external_id: true,
+            ignore_source: true,
+            time_skew_ms: MINUTE,
+            quantity_tolerance: dec!(0.005),
+            market_value_tolerance: dec!(0.005),
+        }
+    }
+
+    #[test]
+    fn test_fuzzy_eq() {
+        let a = buy(T, dec!(0.03), dec!(990), "Coinbase");
+        let b = buy(T + MINUTE, dec!(0.03), dec!(994.9), "Koinly");
+        assert!(a.fuzzy_eq(&b, &opts()));
+        assert!(b.fuzzy_eq(&a, &opts()));
+        assert!(!a.fuzzy_eq(&b, &FuzzyOpts::default()));
+        assert!(a.fuzzy_eq(&a.clone(), &FuzzyOpts::default()));
+
+        // One ms too far apart
+        let late = buy(T + MINUTE + 1, dec!(0.03), dec!(990), "Koinly");
+        assert!(!a.fuzzy_eq(&late, &opts()));
+
+        // Times far enough apart to overflow a subtraction
+        let mut min = a.clone();
+        min.time = i64::MIN;
+        let mut max = a.clone();
+        max.time = i64::MAX;
+        assert!(!min.fuzzy_eq(&max, &opts()));
+        assert!(fuzzy_match_sets(&[min], &[max], &opts()).pairs.is_empty());
+
+        // Source and external ID compared unless ignored
+        let strict_ids = FuzzyOpts {
+            ignore_external_id: false,
+            ..opts()
+        };
+        assert!(!a.fuzzy_eq(&b, &strict_ids));
+
+        let mut no_value = b.clone();
+        no_value.market_value = None;
+        assert!(!a.fuzzy_eq(&no_value, &opts()));
+    }
+
+    #[test]
+    fn test_fuzzy_match_sets() {
+        let a = vec![
+            buy(T, dec!(0.03), dec!(990), "Coinbase"),
+            buy(T + 60 * MINUTE, dec!(0.01), dec!(330), "Coinbase"),
+            buy(T + 120 * MINUTE, dec!(0.02), dec!(660), "Coinbase"),
+        ];
+        let b = vec![
+            // Value differs by 1%, more than the tolerance
+            buy(T + 120 * MINUTE, dec!(0.02), dec!(666.6), "Koinly"),
+            buy(T + 60 * MINUTE - MINUTE, dec!(0.01), dec!(331), "Koinly"),
+            buy(T + 30 * 1000, dec!(0.03), dec!(990), "Koinly"),
+        ];
+
+        let report = fuzzy_match_sets(&a, &b, &opts());
+        assert_eq!(report.pairs, vec![(0, 2), (1, 1)]);
+        assert_eq!(report.unmatched_a, vec![2]);
+        assert_eq!(report.unmatched_b, vec![0]);
+    }
+
+    #[test]
+    fn test_fuzzy_match_sets_one_to_one() {
+        let a = vec![buy(T, dec!(0.03), dec!(990), "Coinbase")];
+        let b = vec![
+            buy(T + 30 * 1000, dec!(0.03), dec!(990), "Koinly"),
+            buy(T + 10 * 1000, dec!(0.03), dec!(990), "Koinly"),
+        ];
+        let report = fuzzy_match_sets(&a, &b, &opts());
+        assert_eq!(report.pairs, vec![(0, 1)]);
+        assert_eq!(report.unmatched_b, vec![0]);
+    }
+}
@@ -0,0 +1,351 @@
+use std::{collections::BTreeMap, error::Error, io::Write};
+
+use chrono::DateTime;
+use rust_decimal::Decimal;
+use taxbitrec::TaxBitRecType;
+
+use crate::{format_decimal, DecimalFormat, IncomeTotals, TaxBitExportRec};
+
+/// The extra column, see TaxBitExportRec::extras, naming the recipient
+/// of a GiftSent or the giver of a GiftReceived, the source is used if
+/// a record doesn't have it
+pub const GIFT_COUNTERPARTY_COLUMN: &str = "Counterparty";
+
+/// A GiftSent or GiftReceived record in a GiftReport
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Gift {
+    /// Index of the record in the records reported
+    pub index: usize,
+
+    pub time: i64,
+    pub asset: String,
+    pub quantity: Decimal,
+
+    /// The fair market value, None if the record has none
+    pub market_value: Option<Decimal>,
+
+    /// The recipient of a GiftSent or the giver of a GiftReceived, see
+    /// GIFT_COUNTERPARTY_COLUMN
+    pub counterparty: String,
+
+    pub external_id: String,
+}
+
+/// The gifts of one type in a GiftReport
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GiftSide {
+    /// The gifts in the order of the records
+    pub gifts: Vec<Gift>,
+
+    /// Totals keyed by UTC calendar year and then asset
+    pub totals: BTreeMap<u32, BTreeMap<String, IncomeTotals>>,
+}
+
+impl GiftSide {
+    fn add(&mut self, year: u32, gift: Gift) {
+        self.totals
+            .entry(year)
+            .or_default()
+            .entry(gift.asset.clone())
+            .or_default()
+            .add(gift.quantity, gift.market_value);
+        self.gifts.push(gift);
+    }
+
+    /// The total market value of the gifts of year, an underestimate if
+    /// any is missing its market value
+    pub fn year_market_value(&self, year: u32) -> Decimal {
+        self.totals.get(&year).map_or(Decimal::ZERO, |assets| {
+            assets.values().map(|t| t.market_value).sum()
+        })
+    }
+}
+
+/// The GiftSent and GiftReceived records, see gift_report
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GiftReport {
+    pub sent: GiftSide,
+    pub received: GiftSide,
+
+    /// Indices of the gifts with no market value in ascending order,
+    /// which substantiating a donation requires
+    pub missing_market_value: Vec<usize>,
+
+    /// Indices of the gifts dated before year 0 in ascending order, they
+    /// can't be totalled by year so aren't in sent or received
+    pub before_year_0: Vec<usize>,
+}
+
+/// Gather the GiftSent and GiftReceived records and total them by UTC
+/// calendar year and asset, records of other types are ignored. Gifts
+/// before year 0 are only listed in GiftReport::before_year_0.
+pub fn gift_report(recs: &[TaxBitExportRec]) -> GiftReport {
+    let mut report = GiftReport::default();
+    for (index, rec) in recs.iter().enumerate() {
+        let side = match rec.type_txs {
+            TaxBitRecType::GiftSent => &mut report.sent,
+            TaxBitRecType::GiftReceived => &mut report.received,
+            _ => continue,
+        };
+        let Ok(year) = u32::try_from(rec.year()) else {
+            report.before_year_0.push(index);
+            continue;
+        };
+        if rec.market_value.is_none() {
+            report.missing_market_value.push(index);
+        }
+        let counterparty = match rec.extras.get(GIFT_COUNTERPARTY_COLUMN) {
+            Some(c) if !c.is_empty() => c.clone(),
+            _ => rec.source.clone(),
+        };
+        side.add(
+            year,
+            Gift {
+                index,
+                time: rec.time,
+                asset: rec.get_asset().to_owned(),
+                quantity: rec.get_quantity().unwrap_or_default(),
+                market_value: rec.market_value,
+                counterparty,
+                external_id: rec.external_id.clone(),
+            },
+        );
+    }
+
+    report
+}
+
+impl GiftReport {
+    /// The gifts as CSV with a header, one row per gift with the sent
+    /// gifts first. The Market Value of a gift without one is empty.
+    pub fn to_csv_string(&self) -> String {
+        self.to_csv_string_with_format(&DecimalFormat::default())
+    }
+
+    /// The gifts as to_csv_string with the quantities and market values
+    /// formatted by format
+    pub fn to_csv_string_with_format(&self, format: &DecimalFormat) -> String {
+        let mut wtr = csv::Writer::from_writer(vec![]);
+        wtr.write_record([
+            "Type",
+            "Date",
+            "Asset",
+            "Quantity",
+            "Market Value",
+            "Counterparty",
+            "External ID",
+        ])
+        .expect("SNH");
+        for (type_name, side) in [("Gift Sent", &self.sent), ("Gift Received", &self.received)] {
+            for gift in &side.gifts {
+                let date = DateTime::from_timestamp_millis(gift.time)
+                    .map_or(String::new(), |t| t.format("%Y-%m-%d").to_string());
+                wtr.write_record([
+                    type_name,
+                    &date,
+                    &gift.asset,
+                    &format_decimal(gift.quantity, format),
+                    &gift
+                        .market_value
+                        .map_or(String::new(), |mv| format_decimal(mv, format)),
+                    &gift.counterparty,
+                    &gift.external_id,
+                ])
+                .expect("SNH");
+            }
+        }
+
+        String::from_utf8(wtr.into_inner().expect("SNH")).expect("SNH")
+    }
+
+    /// The totals as CSV with a header, one row per type, year and asset
+    pub fn totals_to_csv_string(&self) -> String {
+        let format = DecimalFormat::default();
+        let mut wtr = csv::Writer::from_writer(vec![]);
+        wtr.write_record([
+            "Type",
+            "Year",
+            "Asset",
+            "Quantity",
+            "Market Value",
+            "Gifts",
+            "Missing Market Value",
+        ])
+        .expect("SNH");
+        for (type_name, side) in [("Gift Sent", &self.sent), ("Gift Received", &self.received)] {
+            for (year, assets) in &side.totals {
+                for (asset, totals) in assets {
+                    wtr.write_record([
+                        type_name,
+                        &year.to_string(),
+                        asset,
+                        &format_decimal(totals.quantity, &format),
+                        &format_decimal(totals.market_value, &format),
+                        &totals.count.to_string(),
+                        &totals.missing_market_value.to_string(),
+                    ])
+                    .expect("SNH");
+                }
+            }
+        }
+
+        String::from_utf8(wtr.into_inner().expect("SNH")).expect("SNH")
+    }
+
+    /// Write the gifts to wtr as to_csv_string formats them
+    pub fn write_csv<W: Write>(&self, mut wtr: W) -> Result<(), Box<dyn Error>> {
+        wtr.write_all(self.to_csv_string().as_bytes())?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    // 2022-12-31T23:59:59.999Z and 2023-01-01T00:00:00.000Z
+    const END_2022: i64 = 1672531199999;
+    const START_2023: i64 = 1672531200000;
+
+    fn gift(
+        time: i64,
+        type_txs: TaxBitRecType,
+        quantity: Decimal,
+        asset: &str,
+        market_value: Option<Decimal>,
+    ) -> TaxBitExportRec {
+        let mut rec = TaxBitExportRec::new();
+        rec.time = time;
+        rec.type_txs = type_txs.clone();
+        if type_txs == TaxBitRecType::GiftSent {
+            rec.sent_quantity = Some(quantity);
+            rec.sent_currency = asset.to_owned();
+        } else {
+            rec.received_quantity = Some(quantity);
+            rec.received_currency = asset.to_owned();
+        }
+        rec.market_value = market_value;
+        rec.source = "Coinbase".to_owned();
+        rec.external_id = format!("{time}");
+        rec
+    }
+
+    #[test]
+    fn test_gift_report() {
+        let mut charity = gift(
+            END_2022,
+            TaxBitRecType::GiftSent,
+            dec!(0.5),
+            "ETH",
+            Some(dec!(600)),
+        );
+        charity.extras.insert(
+            GIFT_COUNTERPARTY_COLUMN.to_owned(),
+            "Red Cross, Inc".to_owned(),
+        );
+        let recs = vec![
+            gift(
+                END_2022 - 1000,
+                TaxBitRecType::GiftSent,
+                dec!(0.25),
+                "ETH",
+                Some(dec!(300.5)),
+            ),
+            charity,
+            gift(START_2023, TaxBitRecType::GiftSent, dec!(0.1), "ETH", None),
+            gift(
+                START_2023,
+                TaxBitRecType::GiftReceived,
+                dec!(0.01),
+                "BTC",
+                Some(dec!(170)),
+            ),
+            gift(
+                START_2023,
+                TaxBitRecType::Income,
+                dec!(1),
+                "BTC",
+                Some(dec!(17000)),
+            ),
+        ];
+
+        let report = gift_report(&recs);
+        assert_eq!(report.sent.gifts.len(), 3);
+        assert_eq!(report.received.gifts.len(), 1);
+        assert_eq!(report.missing_market_value, vec![2]);
+        assert_eq!(report.sent.gifts[0].counterparty, "Coinbase");
+        assert_eq!(report.sent.gifts[1].counterparty, "Red Cross, Inc");
+        assert_eq!(
+            report.sent.totals[&2022]["ETH"],
+            IncomeTotals {
+                quantity: dec!(0.75),
+                market_value: dec!(900.5),
+                count: 2,
+                missing_market_value: 0,
+            }
+        );
+        assert_eq!(
+            report.sent.totals[&2023]["ETH"],
+            IncomeTotals {
+                quantity: dec!(0.1),
+                market_value: dec!(0),
+                count: 1,
+                missing_market_value: 1,
+            }
+        );
+        assert_eq!(report.sent.year_market_value(2022), dec!(900.5));
+        assert_eq!(report.sent.year_market_value(2024), dec!(0));
+        assert_eq!(report.received.year_market_value(2023), dec!(170));
+        assert!(!report.received.totals.contains_key(&2022));
+
+        assert_eq!(
+            report.to_csv_string(),
+            "Type,Date,Asset,Quantity,Market Value,Counterparty,External ID\n\
+            Gift Sent,2022-12-31,ETH,0.25,300.5,Coinbase,1672531198999\n\
+            Gift Sent,2022-12-31,ETH,0.5,600,\"Red Cross, Inc\",1672531199999\n\
+            Gift Sent,2023-01-01,ETH,0.1,,Coinbase,1672531200000\n\
+            Gift Received,2023-01-01,BTC,0.01,170,Coinbase,1672531200000\n"
+        );
+        assert_eq!(
+            report.totals_to_csv_string(),
+            "Type,Year,Asset,Quantity,Market Value,Gifts,Missing Market Value\n\
+            Gift Sent,2022,ETH,0.75,900.5,2,0\n\
+            Gift Sent,2023,ETH,0.1,0,1,1\n\
+            Gift Received,2023,BTC,0.01,170,1,0\n"
+        );
+    }
+
+    #[test]
+    fn test_gift_report_odd_values() {
+        let recs = vec![
+            gift(
+                START_2023,
+                TaxBitRecType::GiftSent,
+                dec!(1),
+                "TOKEN, \"A\"",
+                Some(dec!(5)),
+            ),
+            // -0001-12-31
+            gift(
+                -62167219200001,
+                TaxBitRecType::GiftSent,
+                dec!(1),
+                "ETH",
+                None,
+            ),
+        ];
+
+        let report = gift_report(&recs);
+        assert_eq!(report.before_year_0, vec![1]);
+        assert_eq!(report.missing_market_value, Vec::<usize>::new());
+        assert_eq!(report.sent.gifts.len(), 1);
+        assert_eq!(
+            report.totals_to_csv_string(),
+            "Type,Year,Asset,Quantity,Market Value,Gifts,Missing Market Value\n\
+            Gift Sent,2023,\"TOKEN, \"\"A\"\"\",1,5,1,0\n"
+        );
+    }
+}
@@ -0,0 +1,286 @@
+use std::{
+    error::Error,
+    fmt::Display,
+    io::{self, Read, Write},
+};
+#[cfg(feature = "std-fs")]
+use std::{fs::File, path::Path};
+
+use flate2::{read::MultiGzDecoder, write::GzEncoder, Compression};
+
+#[cfg(feature = "std-fs")]
+use crate::{writer::write_recs, TaxBitExportRec, WriterConfig};
+use crate::{TaxBitExportRecReader, TaxBitExportRecWriter};
+
+/// A compression level balancing speed and size, the default of the gzip
+/// command
+pub const GZIP_DEFAULT_LEVEL: u32 = 6;
+
+/// An error in a gzip compressed stream, as opposed to the CSV it
+/// contains, returned by the readers of compressed files
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GzipError {
+    pub message: String,
+}
+
+impl Display for GzipError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Gzip error: {}", self.message)
+    }
+}
+
+impl Error for GzipError {}
+
+/// Decompresses a gzip stream, errors are returned as io::Errors
+/// wrapping a GzipError, see TaxBitExportRecReader::from_gzip_reader
+pub struct GzipReader<R: Read> {
+    decoder: MultiGzDecoder<R>,
+}
+
+impl<R: Read> GzipReader<R> {
+    pub fn new(rdr: R) -> GzipReader<R> {
+        GzipReader {
+            decoder: MultiGzDecoder::new(rdr),
+        }
+    }
+}
+
+impl<R: Read> Read for GzipReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.decoder.read(buf).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                GzipError {
+                    message: e.to_string(),
+                },
+            )
+        })
+    }
+}
+
+impl<R: Read> TaxBitExportRecReader<GzipReader<R>> {
+    /// Create a reader of a gzip compressed stream, errors in the
+    /// compressed stream are GzipErrors
+    pub fn from_gzip_reader(
+        rdr: R,
+    ) -> Result<TaxBitExportRecReader<GzipReader<R>>, Box<dyn Error>> {
+        TaxBitExportRecReader::new(GzipReader::new(rdr))
+    }
+}
+
+/// Compresses to a gzip stream, see TaxBitExportRecWriter::gzip.
+///
+/// The stream is only complete once finish is called. If it's dropped
+/// instead the stream is finished but errors are ignored, if it's never
+/// finished, e.g. the process is killed, the stream has no trailer and
+/// reading it fails with a GzipError.
+pub struct GzipWriter<W: Write> {
+    encoder: GzEncoder<W>,
+}
+
+impl<W: Write> GzipWriter<W> {
+    /// Compress at level, 0 for none to 9 for the best, larger levels are
+    /// 9, see GZIP_DEFAULT_LEVEL
+    pub fn new(wtr: W, level: u32) -> GzipWriter<W> {
+        GzipWriter {
+            encoder: GzEncoder::new(wtr, Compression::new(level.min(9))),
+        }
+    }
+
+    /// Write the end of the gzip stream and return the underlying writer
+    pub fn finish(self) -> io::Result<W> {
+        self.encoder.finish()
+    }
+}
+
+impl<W: Write> Write for GzipWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.encoder.write(buf)
+    }
+
+    /// Flush the data written so far, the stream isn't finished
+    fn flush(&mut self) -> io::Result<()> {
+        self.encoder.flush()
+    }
+}
+
+impl<W: Write> TaxBitExportRecWriter<GzipWriter<W>> {
+    /// Create a writer compressing to a gzip stream at level, see
+    /// GzipWriter::new. Use finish to complete the stream.
+    pub fn gzip(wtr: W, level: u32) -> TaxBitExportRecWriter<GzipWriter<W>> {
+        TaxBitExportRecWriter::new(GzipWriter::new(wtr, level))
+    }
+
+    /// Flush, finish the gzip stream and return the underlying writer
+    pub fn finish(self) -> Result<W, Box<dyn Error>> {
+        Ok(self.into_inner()?.finish()?)
+    }
+}
+
+/// Write the records to a gzip compressed file at level, see
+/// GZIP_DEFAULT_LEVEL, as write_tb_export_rec_file does
+#[cfg(feature = "std-fs")]
+pub fn write_tb_export_rec_file_gz(
+    path: &Path,
+    recs: &[TaxBitExportRec],
+    level: u32,
+) -> Result<(), Box<dyn Error>> {
+    let wtr = GzipWriter::new(File::create(path)?, level);
+    write_recs(wtr, recs, &WriterConfig::default())?.finish()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    #[cfg(feature = "std-fs")]
+    use std::fs;
+
+    use super::*;
+    #[cfg(feature = "std-fs")]
+    use crate::read_tb_export_rec_file;
+    use crate::{test_support::sample_recs_all_types, TaxBitExportRec};
+
+    #[cfg(feature = "std-fs")]
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[cfg(feature = "std-fs")]
+    fn fixture() -> Vec<u8> {
+        let mut writer = crate::TaxBitExportRecWriter::new(vec![]);
+        for rec in sample_recs_all_types() {
+            writer.write_rec(&rec).unwrap();
+        }
+        writer.into_inner().unwrap()
+    }
+
+    #[cfg(feature = "std-fs")]
+    #[test]
+    fn test_read_gzip_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("recs.csv");
+        let gz = dir.path().join("recs.csv.gz");
+        // Detected by the magic bytes, not the name
+        let gz_no_ext = dir.path().join("recs-gz.csv");
+        fs::write(&plain, fixture()).unwrap();
+        fs::write(&gz, gzip(&fixture())).unwrap();
+        fs::write(&gz_no_ext, gzip(&fixture())).unwrap();
+
+        let recs = read_tb_export_rec_file(&plain).unwrap();
+        assert_eq!(recs.len(), 9);
+        assert_eq!(read_tb_export_rec_file(&gz).unwrap(), recs);
+        assert_eq!(read_tb_export_rec_file(&gz_no_ext).unwrap(), recs);
+
+        let streamed = TaxBitExportRecReader::from_gzip_reader(fs::File::open(&gz).unwrap())
+            .unwrap()
+            .collect::<Result<Vec<TaxBitExportRec>, Box<dyn Error>>>()
+            .unwrap();
+        assert_eq!(streamed, recs);
+    }
+
+    #[cfg(feature = "std-fs")]
+    #[test]
+    fn test_read_corrupt_gzip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("recs.csv.gz");
+
+        // Corrupt the compressed data after the 10 byte gzip header
+        let mut gz = gzip(&fixture());
+        for b in &mut gz[10..30] {
+            *b = !*b;
+        }
+        fs::write(&path, &gz).unwrap();
+        let e = read_tb_export_rec_file(&path).unwrap_err();
+        assert!(e.is::<GzipError>(), "{e}");
+
+        // A .gz file which isn't compressed
+        fs::write(&path, fixture()).unwrap();
+        let e = read_tb_export_rec_file(&path).unwrap_err();
+        assert!(e.is::<GzipError>(), "{e}");
+
+        // CSV errors aren't GzipErrors
+        fs::write(&path, gzip(b"Date,Transaction Type\n")).unwrap();
+        let e = read_tb_export_rec_file(&path).unwrap_err();
+        assert!(!e.is::<GzipError>(), "{e}");
+    }
+
+    #[cfg(feature = "std-fs")]
+    #[test]
+    fn test_write_gzip_file_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let recs = sample_recs_all_types();
+        let plain = dir.path().join("recs.csv");
+        crate::write_tb_export_rec_file(&plain, &recs).unwrap();
+
+        for level in [0, 1, GZIP_DEFAULT_LEVEL, 9, 100] {
+            let path = dir.path().join(format!("recs-{level}.csv.gz"));
+            write_tb_export_rec_file_gz(&path, &recs, level).unwrap();
+            assert_eq!(&fs::read(&path).unwrap()[..2], &[0x1f, 0x8b]);
+            assert_eq!(read_tb_export_rec_file(&path).unwrap(), recs);
+        }
+
+        let best = fs::metadata(dir.path().join("recs-9.csv.gz"))
+            .unwrap()
+            .len();
+        let none = fs::metadata(dir.path().join("recs-0.csv.gz"))
+            .unwrap()
+            .len();
+        assert!(best < fs::metadata(&plain).unwrap().len());
+        assert!(best < none);
+    }
+
+    #[test]
+    fn test_gzip_writer_finish() {
+        let mut writer = TaxBitExportRecWriter::gzip(vec![], GZIP_DEFAULT_LEVEL);
+        for rec in sample_recs_all_types() {
+            writer.write_rec(&rec).unwrap();
+        }
+        let gz = writer.finish().unwrap();
+        let recs = TaxBitExportRecReader::from_gzip_reader(gz.as_slice())
+            .unwrap()
+            .collect::<Result<Vec<TaxBitExportRec>, Box<dyn Error>>>()
+            .unwrap();
+        assert_eq!(recs, sample_recs_all_types());
+
+        // Without its 8 byte trailer the stream is an error, not a
+        // shorter list of records
+        let truncated = &gz[..gz.len() - 8];
+        let e = TaxBitExportRecReader::from_gzip_reader(truncated)
+            .unwrap()
+            .collect::<Result<Vec<TaxBitExportRec>, Box<dyn Error>>>()
+            .unwrap_err();
+        assert!(e.is::<GzipError>(), "{e}");
+    }
+
+    #[cfg(feature = "std-fs")]
+    #[test]
+    fn test_gzip_writer_unfinished() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("unfinished.csv.gz");
+
+        // Flushed but never finished, as if the process was killed
+        let mut writer = TaxBitExportRecWriter::gzip(File::create(&path).unwrap(), 6);
+        for rec in sample_recs_all_types() {
+            writer.write_rec(&rec).unwrap();
+        }
+        writer.flush().unwrap();
+        std::mem::forget(writer);
+
+        let e = read_tb_export_rec_file(&path).unwrap_err();
+        assert!(e.is::<GzipError>(), "{e}");
+
+        // Dropping finishes the stream
+        let mut writer = TaxBitExportRecWriter::gzip(File::create(&path).unwrap(), 6);
+        for rec in sample_recs_all_types() {
+            writer.write_rec(&rec).unwrap();
+        }
+        drop(writer);
+        assert_eq!(
+            read_tb_export_rec_file(&path).unwrap(),
+            sample_recs_all_types()
+        );
+    }
+}
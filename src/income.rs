@@ -0,0 +1,424 @@
+use std::{collections::BTreeMap, error::Error, io::Write};
+
+use rust_decimal::Decimal;
+use taxbitrec::TaxBitRecType;
+
+use crate::{format_decimal, DecimalFormat, TaxBitExportRec};
+
+/// Totals of the records of an asset, used by the income, gift and
+/// expense reports
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IncomeTotals {
+    /// Sum of the quantities, received_quantity for income
+    pub quantity: Decimal,
+
+    /// Sum of market_value, an underestimate if missing_market_value isn't zero
+    pub market_value: Decimal,
+
+    /// Number of records
+    pub count: usize,
+
+    /// Number of records with no market_value
+    pub missing_market_value: usize,
+}
+
+impl IncomeTotals {
+    // Count a record of quantity and market_value
+    pub(crate) fn add(&mut self, quantity: Decimal, market_value: Option<Decimal>) {
+        self.quantity += quantity;
+        self.count += 1;
+        match market_value {
+            Some(mv) => self.market_value += mv,
+            None => self.missing_market_value += 1,
+        }
+    }
+}
+
+/// Income totals keyed by (year, month) and then asset, see income_by_month
+pub type IncomeByMonth = BTreeMap<(u32, u32), BTreeMap<String, IncomeTotals>>;
+
+/// Total the Income and GiftReceived records by UTC calendar month and
+/// received asset, those before year 0 are skipped
+pub fn income_by_month(recs: &[TaxBitExportRec]) -> IncomeByMonth {
+    let mut report = IncomeByMonth::new();
+    for rec in recs {
+        add_income_by_month(&mut report, rec);
+    }
+
+    report
+}
+
+// Add rec to report if it's Income or GiftReceived and not before year 0
+pub(crate) fn add_income_by_month(report: &mut IncomeByMonth, rec: &TaxBitExportRec) {
+    let Ok(year) = u32::try_from(rec.year()) else {
+        return;
+    };
+    if matches!(
+        rec.type_txs,
+        TaxBitRecType::Income | TaxBitRecType::GiftReceived
+    ) {
+        report
+            .entry((year, rec.month()))
+            .or_default()
+            .entry(rec.received_currency.clone())
+            .or_default()
+            .add(rec.received_quantity.unwrap_or_default(), rec.market_value);
+    }
+}
+
+/// What an Income record was received for, see IncomeRules
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum IncomeKind {
+    Staking,
+    Airdrop,
+    Mining,
+    Interest,
+}
+
+/// A rule of IncomeRules, it matches a record if both its source and
+/// external_id match their patterns, an absent pattern matches anything.
+///
+/// A pattern containing `*`, any number of characters, or `?`, one
+/// character, must match the whole value, otherwise it matches if it's
+/// a substring of the value. Matching is case sensitive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IncomeRule {
+    pub source: Option<String>,
+    pub external_id: Option<String>,
+    pub kind: IncomeKind,
+}
+
+impl IncomeRule {
+    fn matches(&self, rec: &TaxBitExportRec) -> bool {
+        let matches = |pattern: &Option<String>, value: &str| match pattern {
+            Some(pattern) => pattern_matches(pattern, value),
+            None => true,
+        };
+        matches(&self.source, &rec.source) && matches(&self.external_id, &rec.external_id)
+    }
+}
+
+/// Rules classifying Income records, the first matching rule wins
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IncomeRules {
+    pub rules: Vec<IncomeRule>,
+}
+
+// Match value against a glob pattern, or a substring if pattern has no
+// wildcards
+pub(crate) fn pattern_matches(pattern: &str, value: &str) -> bool {
+    if !pattern.contains(['*', '?']) {
+        return value.contains(pattern);
+    }
+
+    let pattern: Vec<char> = pattern.chars().collect();
+    let value: Vec<char> = value.chars().collect();
+    let (mut p, mut v) = (0, 0);
+    // The position after the last * and the value position it's matched up to
+    let mut star: Option<(usize, usize)> = None;
+    while v < value.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == value[v]) {
+            p += 1;
+            v += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            p += 1;
+            star = Some((p, v));
+        } else if let Some((star_p, star_v)) = star {
+            p = star_p;
+            v = star_v + 1;
+            star = Some((star_p, v));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+impl TaxBitExportRec {
+    /// The kind of an Income record given by the first of rules it
+    /// matches, None if it isn't Income or matches no rule
+    pub fn classify_income(&self, rules: &IncomeRules) -> Option<IncomeKind> {
+        if self.type_txs != TaxBitRecType::Income {
+            return None;
+        }
+        rules.rules.iter().find(|r| r.matches(self)).map(|r| r.kind)
+    }
+}
+
+/// The result of income_breakdown
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IncomeBreakdown {
+    /// Totals of the classified Income records by (asset, kind)
+    pub classified: BTreeMap<(String, IncomeKind), IncomeTotals>,
+
+    /// Totals of the Income records no rule matched by asset
+    pub unclassified: BTreeMap<String, IncomeTotals>,
+
+    /// Indices of the Income records no rule matched
+    pub unclassified_indices: Vec<usize>,
+}
+
+/// Total the Income records by received asset and the IncomeKind
+/// rules classify them as, see TaxBitExportRec::classify_income.
+/// Records no rule matches are totaled separately.
+pub fn income_breakdown(recs: &[TaxBitExportRec], rules: &IncomeRules) -> IncomeBreakdown {
+    let mut breakdown = IncomeBreakdown::default();
+    for (index, rec) in recs.iter().enumerate() {
+        if rec.type_txs != TaxBitRecType::Income {
+            continue;
+        }
+        let asset = rec.received_currency.clone();
+        let totals = match rec.classify_income(rules) {
+            Some(kind) => breakdown.classified.entry((asset, kind)).or_default(),
+            None => {
+                breakdown.unclassified_indices.push(index);
+                breakdown.unclassified.entry(asset).or_default()
+            }
+        };
+        totals.add(rec.received_quantity.unwrap_or_default(), rec.market_value);
+    }
+
+    breakdown
+}
+
+/// Formatting of an IncomeByMonth report
+pub trait IncomeReport {
+    /// The report as CSV with a header, one row per month and asset
+    fn to_csv_string(&self) -> String {
+        self.to_csv_string_with_format(&DecimalFormat::default())
+    }
+
+    /// The report as to_csv_string with the quantities and market
+    /// values formatted by format
+    fn to_csv_string_with_format(&self, format: &DecimalFormat) -> String;
+
+    /// Write the report to wtr as to_csv_string formats it
+    fn write_csv<W: Write>(&self, mut wtr: W) -> Result<(), Box<dyn Error>> {
+        wtr.write_all(self.to_csv_string().as_bytes())?;
+
+        Ok(())
+    }
+}
+
+impl IncomeReport for IncomeByMonth {
+    fn to_csv_string_with_format(&self, format: &DecimalFormat) -> String {
+        let mut wtr = csv::Writer::from_writer(vec![]);
+        wtr.write_record([
+            "Year",
+            "Month",
+            "Asset",
+            "Quantity",
+            "Market Value",
+            "Records",
+            "Missing Market Value",
+        ])
+        .expect("SNH");
+        for ((year, month), assets) in self {
+            for (asset, totals) in assets {
+                wtr.write_record([
+                    &year.to_string(),
+                    &format!("{month:02}"),
+                    asset,
+                    &format_decimal(totals.quantity, format),
+                    &format_decimal(totals.market_value, format),
+                    &totals.count.to_string(),
+                    &totals.missing_market_value.to_string(),
+                ])
+                .expect("SNH");
+            }
+        }
+
+        String::from_utf8(wtr.into_inner().expect("SNH")).expect("SNH")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    fn rec(
+        time: i64,
+        type_txs: TaxBitRecType,
+        quantity: Decimal,
+        asset: &str,
+        market_value: Option<Decimal>,
+    ) -> TaxBitExportRec {
+        let mut rec = TaxBitExportRec::new();
+        rec.time = time;
+        rec.type_txs = type_txs;
+        rec.received_quantity = Some(quantity);
+        rec.received_currency = asset.to_owned();
+        rec.market_value = market_value;
+        rec
+    }
+
+    #[test]
+    fn test_income_by_month() {
+        // 2021-01-31T23:59:59.999Z and 2021-02-01T00:00:00.000Z
+        let jan_end = 1612137599999;
+        let feb_start = 1612137600000;
+        let recs = vec![
+            rec(
+                jan_end - 1000,
+                TaxBitRecType::Income,
+                dec!(0.1),
+                "ADA",
+                Some(dec!(0.03)),
+            ),
+            rec(
+                jan_end,
+                TaxBitRecType::Income,
+                dec!(0.2),
+                "ADA",
+                Some(dec!(0.07)),
+            ),
+            rec(
+                jan_end,
+                TaxBitRecType::GiftReceived,
+                dec!(0.01),
+                "BTC",
+                Some(dec!(330)),
+            ),
+            rec(feb_start, TaxBitRecType::Income, dec!(0.3), "ADA", None),
+            rec(
+                feb_start,
+                TaxBitRecType::Income,
+                dec!(0.4),
+                "ADA",
+                Some(dec!(0.2)),
+            ),
+            rec(feb_start, TaxBitRecType::Buy, dec!(5), "ADA", Some(dec!(2))),
+        ];
+
+        let report = income_by_month(&recs);
+        assert_eq!(report.len(), 2);
+        assert_eq!(
+            report[&(2021, 1)]["ADA"],
+            IncomeTotals {
+                quantity: dec!(0.3),
+                market_value: dec!(0.10),
+                count: 2,
+                missing_market_value: 0,
+            }
+        );
+        assert_eq!(report[&(2021, 1)]["BTC"].quantity, dec!(0.01));
+        assert_eq!(
+            report[&(2021, 2)]["ADA"],
+            IncomeTotals {
+                quantity: dec!(0.7),
+                market_value: dec!(0.2),
+                count: 2,
+                missing_market_value: 1,
+            }
+        );
+
+        assert_eq!(
+            report.to_csv_string(),
+            "Year,Month,Asset,Quantity,Market Value,Records,Missing Market Value\n\
+            2021,01,ADA,0.3,0.10,2,0\n\
+            2021,01,BTC,0.01,330,1,0\n\
+            2021,02,ADA,0.7,0.2,2,1\n"
+        );
+    }
+
+    #[test]
+    fn test_income_by_month_odd_values() {
+        let recs = vec![
+            rec(0, TaxBitRecType::Income, dec!(1), "TOKEN, A", None),
+            // -0001-12-31
+            rec(-62167219200001, TaxBitRecType::Income, dec!(1), "ADA", None),
+        ];
+
+        let report = income_by_month(&recs);
+        assert_eq!(report.len(), 1);
+        assert_eq!(
+            report.to_csv_string(),
+            "Year,Month,Asset,Quantity,Market Value,Records,Missing Market Value\n\
+            1970,01,\"TOKEN, A\",1,0,1,1\n"
+        );
+    }
+
+    #[test]
+    fn test_pattern_matches() {
+        assert!(pattern_matches("stak", "eth-staking-123"));
+        assert!(!pattern_matches("Stak", "eth-staking-123"));
+        assert!(pattern_matches("*-staking-*", "eth-staking-123"));
+        assert!(!pattern_matches("staking-*", "eth-staking-123"));
+        assert!(pattern_matches("eth-???????-1*", "eth-staking-123"));
+        assert!(pattern_matches("*a*b*", "xaxxbb"));
+        assert!(!pattern_matches("*a*b", "xaxxbbc"));
+        assert!(pattern_matches("*", ""));
+    }
+
+    fn income(source: &str, id: &str, asset: &str, quantity: Decimal) -> TaxBitExportRec {
+        let mut rec = rec(
+            1612137599999,
+            TaxBitRecType::Income,
+            quantity,
+            asset,
+            Some(quantity * dec!(2)),
+        );
+        rec.source = source.to_owned();
+        rec.external_id = id.to_owned();
+        rec
+    }
+
+    #[test]
+    fn test_income_breakdown() {
+        let rule = |source: Option<&str>, id: Option<&str>, kind| IncomeRule {
+            source: source.map(|s| s.to_owned()),
+            external_id: id.map(|s| s.to_owned()),
+            kind,
+        };
+        let rules = IncomeRules {
+            rules: vec![
+                rule(Some("Coinbase"), Some("staking"), IncomeKind::Staking),
+                rule(Some("Coinbase"), Some("airdrop-*"), IncomeKind::Airdrop),
+                rule(Some("F2Pool"), None, IncomeKind::Mining),
+                rule(None, Some("*-stake"), IncomeKind::Staking),
+            ],
+        };
+
+        let mut buy = income("Coinbase", "staking-1", "ETH", dec!(1));
+        buy.type_txs = TaxBitRecType::Buy;
+        let recs = vec![
+            income("Coinbase", "eth-staking-1", "ETH", dec!(0.01)),
+            income("Coinbase", "eth-staking-2", "ETH", dec!(0.02)),
+            income("Coinbase", "airdrop-uni", "UNI", dec!(400)),
+            income("F2Pool", "block-1", "BTC", dec!(0.0001)),
+            income("Kraken", "dot-stake", "DOT", dec!(1.5)),
+            income("Coinbase", "learn-earn", "XLM", dec!(3)),
+            buy,
+        ];
+        assert_eq!(recs[2].classify_income(&rules), Some(IncomeKind::Airdrop));
+        assert_eq!(recs[5].classify_income(&rules), None);
+        assert_eq!(recs[6].classify_income(&rules), None);
+
+        let breakdown = income_breakdown(&recs, &rules);
+        let key = |a: &str, k| (a.to_owned(), k);
+        assert_eq!(
+            breakdown.classified[&key("ETH", IncomeKind::Staking)],
+            IncomeTotals {
+                quantity: dec!(0.03),
+                market_value: dec!(0.06),
+                count: 2,
+                missing_market_value: 0,
+            }
+        );
+        assert_eq!(
+            breakdown.classified.keys().cloned().collect::<Vec<_>>(),
+            vec![
+                key("BTC", IncomeKind::Mining),
+                key("DOT", IncomeKind::Staking),
+                key("ETH", IncomeKind::Staking),
+                key("UNI", IncomeKind::Airdrop),
+            ]
+        );
+        assert_eq!(breakdown.unclassified_indices, vec![5]);
+        assert_eq!(breakdown.unclassified["XLM"].quantity, dec!(3));
+        assert_eq!(breakdown.unclassified.len(), 1);
+    }
+}
@@ -0,0 +1,176 @@
+//! JSON Schema of TaxBitExportRec, enabled with the `schemars` feature
+
+use schemars::{
+    gen::SchemaGenerator,
+    schema::{
+        InstanceType, Metadata, RootSchema, Schema, SchemaObject, StringValidation,
+        SubschemaValidation,
+    },
+    schema_for,
+};
+use serde_json::Value;
+use taxbitrec::TaxBitRecType;
+
+use crate::TaxBitExportRec;
+
+/// The JSON Schema of a serialized TaxBitExportRec, the property
+/// names are the CSV column names.
+pub fn export_rec_json_schema() -> RootSchema {
+    schema_for!(TaxBitExportRec)
+}
+
+fn described(mut schema: SchemaObject, description: &str) -> Schema {
+    schema.metadata = Some(Box::new(Metadata {
+        description: Some(description.to_owned()),
+        ..Default::default()
+    }));
+    schema.into()
+}
+
+pub(crate) fn date_schema(_: &mut SchemaGenerator) -> Schema {
+    let schema = SchemaObject {
+        instance_type: Some(InstanceType::String.into()),
+        format: Some("date-time".to_owned()),
+        ..Default::default()
+    };
+    described(schema, "RFC3339 UTC date, \"2022-03-01T14:35:06.000Z\"")
+}
+
+pub(crate) fn type_txs_schema(_: &mut SchemaGenerator) -> Schema {
+    let variants = [
+        TaxBitRecType::Buy,
+        TaxBitRecType::Sale,
+        TaxBitRecType::Trade,
+        TaxBitRecType::TransferIn,
+        TaxBitRecType::TransferOut,
+        TaxBitRecType::Income,
+        TaxBitRecType::Expense,
+        TaxBitRecType::GiftReceived,
+        TaxBitRecType::GiftSent,
+    ];
+    let schema = SchemaObject {
+        instance_type: Some(InstanceType::String.into()),
+        enum_values: Some(
+            variants
+                .iter()
+                .map(|v| serde_json::to_value(v).unwrap_or(Value::Null))
+                .collect(),
+        ),
+        ..Default::default()
+    };
+    described(schema, "TaxBit transaction type")
+}
+
+// Decimals are described as strings so consumers don't lose precision
+// by parsing them as floats. Numbers are also accepted because with the
+// serde-arbitrary-precision feature of rust_decimal, which this crate
+// uses, a Decimal is serialized as an arbitrary precision JSON number.
+pub(crate) fn decimal_schema(_: &mut SchemaGenerator) -> Schema {
+    let string = SchemaObject {
+        instance_type: Some(InstanceType::String.into()),
+        string: Some(Box::new(StringValidation {
+            pattern: Some(r"^[-+]?[0-9]*\.?[0-9]+([eE][-+]?[0-9]+)?$".to_owned()),
+            ..Default::default()
+        })),
+        ..Default::default()
+    };
+    let number = SchemaObject {
+        instance_type: Some(InstanceType::Number.into()),
+        ..Default::default()
+    };
+    let null = SchemaObject {
+        instance_type: Some(InstanceType::Null.into()),
+        ..Default::default()
+    };
+    let schema = SchemaObject {
+        subschemas: Some(Box::new(SubschemaValidation {
+            any_of: Some(vec![string.into(), number.into(), null.into()]),
+            ..Default::default()
+        })),
+        ..Default::default()
+    };
+    described(schema, "Decimal as a string, or null when absent")
+}
+
+pub(crate) fn bool_schema(_: &mut SchemaGenerator) -> Schema {
+    let schema = SchemaObject {
+        instance_type: Some(InstanceType::String.into()),
+        string: Some(Box::new(StringValidation {
+            pattern: Some("^([Tt][Rr][Uu][Ee]|[Ff][Aa][Ll][Ss][Ee])$".to_owned()),
+            ..Default::default()
+        })),
+        ..Default::default()
+    };
+    described(schema, "TRUE or FALSE in upper or lower case")
+}
+
+#[cfg(test)]
+mod test {
+    use jsonschema::JSONSchema;
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    fn compiled_schema() -> JSONSchema {
+        let schema = serde_json::to_value(export_rec_json_schema()).unwrap();
+        JSONSchema::compile(&schema).unwrap()
+    }
+
+    fn rec() -> TaxBitExportRec {
+        let mut rec = TaxBitExportRec::new();
+        rec.time = 1583134325000;
+        rec.type_txs = TaxBitRecType::TransferIn;
+        rec.received_quantity = Some(dec!(0.0000003));
+        rec.received_currency = "BTC".to_owned();
+        rec.market_value = Some(dec!(0.0025979719720382955));
+        rec.source = "BinanceUS".to_owned();
+        rec.internal_transfer = true;
+        rec.external_id = "2459217f-1a6f-4693-974c-d8d65f21abab".to_owned();
+        rec
+    }
+
+    #[test]
+    fn test_schema_properties() {
+        let schema = serde_json::to_value(export_rec_json_schema()).unwrap();
+        let properties = schema["properties"].as_object().unwrap();
+        let mut names: Vec<&str> = properties.keys().map(|k| k.as_str()).collect();
+        names.sort_unstable();
+        let mut expected = crate::TB_EXPORT_REC_HEADER.to_vec();
+        expected.push(crate::TB_EXPORT_REC_LOT_ID_COLUMN);
+        expected.sort_unstable();
+        assert_eq!(names, expected);
+
+        assert_eq!(properties["Date"]["format"], "date-time");
+        let types = properties["Transaction Type"]["enum"].as_array().unwrap();
+        assert_eq!(types.len(), 9);
+        assert!(types.contains(&serde_json::to_value(TaxBitRecType::TransferIn).unwrap()));
+    }
+
+    #[test]
+    fn test_serialized_rec_validates() {
+        let schema = compiled_schema();
+        let value = serde_json::to_value(rec()).unwrap();
+        assert!(schema.is_valid(&value), "{value}");
+
+        let mut value = serde_json::to_value(rec()).unwrap();
+        value["Received Quantity"] = Value::String("0.0000003".to_owned());
+        assert!(schema.is_valid(&value), "{value}");
+    }
+
+    #[test]
+    fn test_invalid_values_rejected() {
+        let schema = compiled_schema();
+
+        let mut value = serde_json::to_value(rec()).unwrap();
+        value["Received Quantity"] = Value::String("1,5".to_owned());
+        assert!(!schema.is_valid(&value));
+
+        let mut value = serde_json::to_value(rec()).unwrap();
+        value["Transaction Type"] = Value::String("Steal".to_owned());
+        assert!(!schema.is_valid(&value));
+
+        let mut value = serde_json::to_value(rec()).unwrap();
+        value["Internal Transfer"] = Value::String("yes".to_owned());
+        assert!(!schema.is_valid(&value));
+    }
+}
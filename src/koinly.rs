@@ -0,0 +1,207 @@
+//! Convert a Koinly universal CSV export to TaxBit export records
+//!
+//! The columns used are Date, Sent Amount, Sent Currency, Received Amount,
+//! Received Currency, Fee Amount, Fee Currency, Net Worth Amount, Label,
+//! Description and TxHash, any others are ignored.
+//!
+//! The Label, case insensitive, determines the TaxBitRecType:
+//!
+//! | Label      | TaxBitRecType                                             |
+//! |------------|-----------------------------------------------------------|
+//! | trade      | Buy if USD was sent, Sale if USD was received, else Trade |
+//! | deposit    | TransferIn                                                |
+//! | withdrawal | TransferOut                                               |
+//! | reward     | Income                                                    |
+//! | airdrop    | Income                                                    |
+//! | gift       | GiftReceived or GiftSent depending on the side present    |
+//! | cost       | Expense                                                   |
+//!
+//! Koinly leaves Label empty for plain trades, deposits and withdrawals,
+//! in that case it's inferred from the sides present. Rows with any other
+//! Label are rejected.
+use std::{error::Error, io::Read};
+
+use chrono::{NaiveDateTime, TimeZone, Utc};
+use taxbitrec::TaxBitRecType;
+
+use crate::{
+    convert::{exchange_type, parse_decimal_opt, Columns},
+    dt_str_to_utc_time_ms_flexible, RejectedRow, TaxBitExportRec,
+};
+
+/// Source used when the Description column is empty
+pub const KOINLY_SOURCE: &str = "Koinly";
+
+const COLUMNS: [&str; 11] = [
+    "Date",
+    "Sent Amount",
+    "Sent Currency",
+    "Received Amount",
+    "Received Currency",
+    "Fee Amount",
+    "Fee Currency",
+    "Net Worth Amount",
+    "Label",
+    "Description",
+    "TxHash",
+];
+
+// Koinly writes "2018-01-01 14:25 UTC", seconds and the suffix are optional
+fn parse_koinly_date(s: &str) -> Result<i64, String> {
+    let s = s.trim_end_matches("UTC").trim();
+    if let Ok(ndt) = NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M") {
+        return Ok(Utc.from_utc_datetime(&ndt).timestamp_millis());
+    }
+
+    dt_str_to_utc_time_ms_flexible(s)
+}
+
+fn type_from_label(label: &str, rec: &TaxBitExportRec) -> Result<TaxBitRecType, String> {
+    let has_received = !rec.received_currency.is_empty();
+    let has_sent = !rec.sent_currency.is_empty();
+
+    let label = label.to_lowercase();
+    let label = match label.as_str() {
+        "" if has_received && has_sent => "trade",
+        "" if has_received => "deposit",
+        "" if has_sent => "withdrawal",
+        "" => return Err("Empty Label and no sent or received amount".to_owned()),
+        label => label,
+    };
+
+    Ok(match label {
+        "trade" => exchange_type(rec),
+        "deposit" => TaxBitRecType::TransferIn,
+        "withdrawal" => TaxBitRecType::TransferOut,
+        "reward" | "airdrop" => TaxBitRecType::Income,
+        "gift" if has_received && !has_sent => TaxBitRecType::GiftReceived,
+        "gift" if has_sent && !has_received => TaxBitRecType::GiftSent,
+        "gift" => return Err("gift must have only a sent or a received amount".to_owned()),
+        "cost" => TaxBitRecType::Expense,
+        label => return Err(format!("Unsupported Label '{label}'")),
+    })
+}
+
+fn koinly_record_to_rec(
+    columns: &Columns,
+    record: &csv::StringRecord,
+) -> Result<TaxBitExportRec, String> {
+    let mut rec = TaxBitExportRec::new();
+    rec.time = parse_koinly_date(columns.get(record, "Date"))?;
+    rec.sent_quantity = parse_decimal_opt("Sent Amount", columns.get(record, "Sent Amount"))?;
+    rec.sent_currency = columns.get(record, "Sent Currency").to_owned();
+    rec.received_quantity =
+        parse_decimal_opt("Received Amount", columns.get(record, "Received Amount"))?;
+    rec.received_currency = columns.get(record, "Received Currency").to_owned();
+    rec.fee_amount = parse_decimal_opt("Fee Amount", columns.get(record, "Fee Amount"))?;
+    rec.fee_currency = columns.get(record, "Fee Currency").to_owned();
+    rec.market_value =
+        parse_decimal_opt("Net Worth Amount", columns.get(record, "Net Worth Amount"))?;
+    rec.external_id = columns.get(record, "TxHash").to_owned();
+    rec.source = match columns.get(record, "Description") {
+        "" => KOINLY_SOURCE.to_owned(),
+        wallet => wallet.to_owned(),
+    };
+    rec.type_txs = type_from_label(columns.get(record, "Label"), &rec)?;
+
+    Ok(rec)
+}
+
+/// Read a Koinly universal CSV returning the converted records and the
+/// rows which couldn't be converted. An error is returned only if the
+/// input isn't a readable Koinly universal CSV.
+pub fn read_koinly_csv<R: Read>(
+    rdr: R,
+) -> Result<(Vec<TaxBitExportRec>, Vec<RejectedRow>), Box<dyn Error>> {
+    let mut reader = csv::Reader::from_reader(rdr);
+    let columns = Columns::new(reader.headers()?, &COLUMNS, "Koinly universal")?;
+
+    let mut recs: Vec<TaxBitExportRec> = vec![];
+    let mut rejects: Vec<RejectedRow> = vec![];
+    for record in reader.records() {
+        let record = record?;
+        match koinly_record_to_rec(&columns, &record) {
+            Ok(rec) => recs.push(rec),
+            Err(reason) => rejects.push(RejectedRow::new(&record, reason)),
+        }
+    }
+
+    Ok((recs, rejects))
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    const KOINLY_CSV: &str = r#"Date,Sent Amount,Sent Currency,Received Amount,Received Currency,Fee Amount,Fee Currency,Net Worth Amount,Net Worth Currency,Label,Description,TxHash
+2021-01-02 10:00 UTC,1000,USD,0.03,BTC,1.5,USD,1000,USD,trade,Coinbase,tx-buy
+2021-01-03 11:00 UTC,0.01,BTC,350,USD,,,350,USD,trade,,tx-sale
+2021-01-04 12:00:30 UTC,0.01,BTC,0.3,ETH,,,360,USD,trade,,tx-trade
+2021-01-05 13:00 UTC,,,0.02,BTC,,,700,USD,deposit,Ledger,tx-deposit
+2021-01-06 14:00 UTC,0.02,BTC,,,0.0001,BTC,710,USD,withdrawal,,tx-withdrawal
+2021-01-07 15:00 UTC,,,1.25,ADA,,,0.5,USD,Reward,,tx-reward
+2021-01-08 16:00 UTC,,,100,UNI,,,300,USD,airdrop,,tx-airdrop
+2021-01-09 17:00 UTC,,,0.1,ETH,,,120,USD,gift,,tx-gift-received
+2021-01-10 18:00 UTC,0.1,ETH,,,,,125,USD,gift,,tx-gift-sent
+2021-01-11 19:00 UTC,0.001,ETH,,,,,1.2,USD,cost,,tx-cost
+2021-01-12 20:00 UTC,,,5,DOT,,,80,USD,,,tx-unlabeled-deposit
+2021-01-13 21:00 UTC,,,1,BTC,,,30000,USD,margin fee,,tx-margin
+2021-01-14 22:00 UTC,abc,BTC,,,,,1,USD,withdrawal,,tx-bad-amount
+not a date,,,1,BTC,,,1,USD,deposit,,tx-bad-date
+"#;
+
+    #[test]
+    fn test_read_koinly_csv() {
+        let (recs, rejects) = read_koinly_csv(KOINLY_CSV.as_bytes()).unwrap();
+        let types: Vec<(&str, TaxBitRecType)> = recs
+            .iter()
+            .map(|r| (r.external_id.as_str(), r.type_txs.clone()))
+            .collect();
+        assert_eq!(
+            types,
+            vec![
+                ("tx-buy", TaxBitRecType::Buy),
+                ("tx-sale", TaxBitRecType::Sale),
+                ("tx-trade", TaxBitRecType::Trade),
+                ("tx-deposit", TaxBitRecType::TransferIn),
+                ("tx-withdrawal", TaxBitRecType::TransferOut),
+                ("tx-reward", TaxBitRecType::Income),
+                ("tx-airdrop", TaxBitRecType::Income),
+                ("tx-gift-received", TaxBitRecType::GiftReceived),
+                ("tx-gift-sent", TaxBitRecType::GiftSent),
+                ("tx-cost", TaxBitRecType::Expense),
+                ("tx-unlabeled-deposit", TaxBitRecType::TransferIn),
+            ]
+        );
+
+        let buy = &recs[0];
+        assert_eq!(buy.time, 1609581600000);
+        assert_eq!(buy.sent_quantity, Some(dec!(1000)));
+        assert_eq!(buy.sent_currency, "USD");
+        assert_eq!(buy.received_quantity, Some(dec!(0.03)));
+        assert_eq!(buy.received_currency, "BTC");
+        assert_eq!(buy.fee_amount, Some(dec!(1.5)));
+        assert_eq!(buy.fee_currency, "USD");
+        assert_eq!(buy.market_value, Some(dec!(1000)));
+        assert_eq!(buy.source, "Coinbase");
+        assert_eq!(recs[1].source, KOINLY_SOURCE);
+        assert_eq!(recs[2].time, 1609761630000);
+
+        assert_eq!(rejects.len(), 3);
+        assert_eq!(rejects[0].line, 13);
+        assert_eq!(rejects[0].reason, "Unsupported Label 'margin fee'");
+        assert!(rejects[0].row.ends_with(",margin fee,,tx-margin"));
+        assert_eq!(rejects[1].reason, "Sent Amount 'abc' is not a number");
+        assert!(rejects[2]
+            .reason
+            .starts_with("Unable to parse 'not a date'"));
+    }
+
+    #[test]
+    fn test_read_koinly_csv_not_koinly() {
+        let err = read_koinly_csv("Date,Label\n".as_bytes()).unwrap_err();
+        assert!(err.to_string().contains("missing columns: Sent Amount"));
+    }
+}
@@ -0,0 +1,314 @@
+//! Convert a Kraken ledgers CSV export to TaxBit export records
+//!
+//! The columns used are txid, refid, time, type, asset, amount and fee,
+//! any others are ignored. Kraken asset codes are translated using an
+//! asset table, see kraken_asset_codes, codes not in the table are used
+//! as is.
+//!
+//! | type       | TaxBitRecType                                          |
+//! |------------|--------------------------------------------------------|
+//! | trade      | Buy, Sale or Trade from the two legs sharing the refid |
+//! | deposit    | TransferIn                                             |
+//! | withdrawal | TransferOut                                            |
+//! | staking    | Income                                                 |
+//!
+//! A trade leg with a negative amount is the sent side and one with a
+//! positive amount the received side. The fee is taken from whichever
+//! leg has a non-zero fee, in that leg's asset. Other types are rejected
+//! as are trade legs which can't be paired, including those whose refid
+//! has no second leg by the end of the file.
+use std::{collections::BTreeMap, error::Error, io::Read};
+
+use rust_decimal::Decimal;
+use taxbitrec::TaxBitRecType;
+
+use crate::{
+    convert::{exchange_type, parse_decimal_opt, Columns},
+    dt_str_to_utc_time_ms_flexible, RejectedRow, TaxBitExportRec,
+};
+
+/// Source of the converted records
+pub const KRAKEN_SOURCE: &str = "Kraken";
+
+const COLUMNS: [&str; 7] = ["txid", "refid", "time", "type", "asset", "amount", "fee"];
+
+/// The built-in table translating Kraken asset codes to common symbols,
+/// modify the returned table to add or override translations.
+pub fn kraken_asset_codes() -> BTreeMap<String, String> {
+    [
+        ("XXBT", "BTC"),
+        ("XBT", "BTC"),
+        ("XETH", "ETH"),
+        ("XETC", "ETC"),
+        ("XLTC", "LTC"),
+        ("XXRP", "XRP"),
+        ("XXLM", "XLM"),
+        ("XXMR", "XMR"),
+        ("XXDG", "DOGE"),
+        ("XDG", "DOGE"),
+        ("XZEC", "ZEC"),
+        ("XREP", "REP"),
+        ("XMLN", "MLN"),
+        ("ZUSD", "USD"),
+        ("ZEUR", "EUR"),
+        ("ZGBP", "GBP"),
+        ("ZCAD", "CAD"),
+        ("ZJPY", "JPY"),
+    ]
+    .iter()
+    .map(|(code, symbol)| (code.to_string(), symbol.to_string()))
+    .collect()
+}
+
+// One line of the ledger
+struct Leg {
+    record: csv::StringRecord,
+    refid: String,
+    time: i64,
+    asset: String,
+    amount: Decimal,
+    fee: Decimal,
+}
+
+impl Leg {
+    fn new(
+        columns: &Columns,
+        record: &csv::StringRecord,
+        asset_codes: &BTreeMap<String, String>,
+    ) -> Result<Leg, String> {
+        let asset = columns.get(record, "asset");
+        Ok(Leg {
+            record: record.clone(),
+            refid: columns.get(record, "refid").to_owned(),
+            time: dt_str_to_utc_time_ms_flexible(columns.get(record, "time"))?,
+            asset: asset_codes
+                .get(asset)
+                .cloned()
+                .unwrap_or_else(|| asset.to_owned()),
+            amount: parse_decimal_opt("amount", columns.get(record, "amount"))?
+                .ok_or("amount is empty")?,
+            fee: parse_decimal_opt("fee", columns.get(record, "fee"))?.unwrap_or_default(),
+        })
+    }
+
+    fn new_rec(&self, type_txs: TaxBitRecType) -> TaxBitExportRec {
+        let mut rec = TaxBitExportRec::new();
+        rec.time = self.time;
+        rec.type_txs = type_txs;
+        rec.source = KRAKEN_SOURCE.to_owned();
+        rec.external_id = self.refid.clone();
+        if !self.fee.is_zero() {
+            rec.fee_amount = Some(self.fee);
+            rec.fee_currency = self.asset.clone();
+        }
+
+        rec
+    }
+}
+
+fn trade_legs_to_rec(first: &Leg, second: &Leg) -> Result<TaxBitExportRec, String> {
+    let (sent, received) = match (
+        first.amount.is_sign_negative(),
+        second.amount.is_sign_negative(),
+    ) {
+        (true, false) => (first, second),
+        (false, true) => (second, first),
+        _ => {
+            return Err(format!(
+                "Trade refid {} needs one negative and one positive amount",
+                first.refid
+            ))
+        }
+    };
+    if !sent.fee.is_zero() && !received.fee.is_zero() {
+        return Err(format!(
+            "Trade refid {} has a fee on both legs",
+            first.refid
+        ));
+    }
+
+    let fee_leg = if sent.fee.is_zero() { received } else { sent };
+    let mut rec = fee_leg.new_rec(TaxBitRecType::Trade);
+    rec.time = first.time.min(second.time);
+    rec.sent_quantity = Some(-sent.amount);
+    rec.sent_currency = sent.asset.clone();
+    rec.received_quantity = Some(received.amount);
+    rec.received_currency = received.asset.clone();
+    rec.type_txs = exchange_type(&rec);
+    rec.market_value = match rec.type_txs {
+        TaxBitRecType::Buy => rec.sent_quantity,
+        TaxBitRecType::Sale => rec.received_quantity,
+        _ => None,
+    };
+
+    Ok(rec)
+}
+
+fn single_leg_to_rec(columns: &Columns, leg: &Leg) -> Result<TaxBitExportRec, String> {
+    let rec = match columns.get(&leg.record, "type") {
+        "deposit" => {
+            let mut rec = leg.new_rec(TaxBitRecType::TransferIn);
+            rec.received_quantity = Some(leg.amount);
+            rec.received_currency = leg.asset.clone();
+            rec
+        }
+        "withdrawal" => {
+            let mut rec = leg.new_rec(TaxBitRecType::TransferOut);
+            rec.sent_quantity = Some(leg.amount.abs());
+            rec.sent_currency = leg.asset.clone();
+            rec
+        }
+        "staking" => {
+            let mut rec = leg.new_rec(TaxBitRecType::Income);
+            rec.received_quantity = Some(leg.amount);
+            rec.received_currency = leg.asset.clone();
+            rec
+        }
+        txs_type => return Err(format!("Unsupported type '{txs_type}'")),
+    };
+
+    Ok(rec)
+}
+
+/// Read a Kraken ledgers CSV returning the converted records and the
+/// rows which couldn't be converted, asset_codes translates the Kraken
+/// asset codes. Trades are returned when their second leg is read and
+/// unpaired trade legs are rejected at the end. An error is returned
+/// only if the input isn't a readable Kraken ledgers CSV.
+pub fn read_kraken_ledgers<R: Read>(
+    rdr: R,
+    asset_codes: &BTreeMap<String, String>,
+) -> Result<(Vec<TaxBitExportRec>, Vec<RejectedRow>), Box<dyn Error>> {
+    let mut reader = csv::Reader::from_reader(rdr);
+    let columns = Columns::new(reader.headers()?, &COLUMNS, "Kraken ledgers")?;
+
+    let mut recs: Vec<TaxBitExportRec> = vec![];
+    let mut rejects: Vec<RejectedRow> = vec![];
+    let mut unpaired: BTreeMap<String, Leg> = BTreeMap::new();
+    for record in reader.records() {
+        let record = record?;
+        let leg = match Leg::new(&columns, &record, asset_codes) {
+            Ok(leg) => leg,
+            Err(reason) => {
+                rejects.push(RejectedRow::new(&record, reason));
+                continue;
+            }
+        };
+
+        if columns.get(&record, "type") != "trade" {
+            match single_leg_to_rec(&columns, &leg) {
+                Ok(rec) => recs.push(rec),
+                Err(reason) => rejects.push(RejectedRow::new(&record, reason)),
+            }
+        } else if let Some(first) = unpaired.remove(&leg.refid) {
+            match trade_legs_to_rec(&first, &leg) {
+                Ok(rec) => recs.push(rec),
+                Err(reason) => {
+                    rejects.push(RejectedRow::new(&first.record, reason.clone()));
+                    rejects.push(RejectedRow::new(&leg.record, reason));
+                }
+            }
+        } else {
+            unpaired.insert(leg.refid.clone(), leg);
+        }
+    }
+
+    let mut orphans: Vec<Leg> = unpaired.into_values().collect();
+    orphans.sort_by_key(|leg| leg.record.position().map_or(0, |p| p.line()));
+    for leg in orphans {
+        let reason = format!("Trade refid {} has no second leg", leg.refid);
+        rejects.push(RejectedRow::new(&leg.record, reason));
+    }
+
+    Ok((recs, rejects))
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    const LEDGERS_CSV: &str = r#""txid","refid","time","type","subtype","aclass","asset","amount","fee","balance"
+"L1","R-TRADE","2021-01-02 10:00:00","trade","","currency","ZUSD",-990.0000,0.0000,10.0000
+"L2","R-TRADE","2021-01-02 10:00:00","trade","","currency","XXBT",0.0300000000,0.0000000000,0.0300000000
+"L3","R-STAKE","2021-01-03 11:00:00","staking","","currency","DOT.S",0.1250000000,0.0000000000,5.1250000000
+"L4","R-WD","2021-01-04 12:00:00","withdrawal","","currency","XXBT",-0.0100000000,0.0005000000,0.0195000000
+"L5","R-ORPHAN","2021-01-05 13:00:00","trade","","currency","XETH",-0.5000000000,0.0000000000,1.0000000000
+"L6","R-TRADE2","2021-01-06 14:00:00","trade","","currency","XETH",-0.5000000000,0.0000000000,0.5000000000
+"L7","R-TRADE2","2021-01-06 14:00:00","trade","","currency","XXBT",0.0150000000,0.0000300000,0.0345000000
+"L8","R-MARGIN","2021-01-07 15:00:00","margin","","currency","XXBT",-0.0010000000,0.0000000000,0.0335000000
+"L9","R-DEP","2021-01-08 16:00:00","deposit","","currency","ZUSD",100.0000,0.0000,110.0000
+"#;
+
+    #[test]
+    fn test_read_kraken_ledgers() {
+        let mut asset_codes = kraken_asset_codes();
+        asset_codes.insert("DOT.S".to_owned(), "DOT".to_owned());
+        let (recs, rejects) = read_kraken_ledgers(LEDGERS_CSV.as_bytes(), &asset_codes).unwrap();
+        let types: Vec<TaxBitRecType> = recs.iter().map(|r| r.type_txs.clone()).collect();
+        assert_eq!(
+            types,
+            vec![
+                TaxBitRecType::Buy,
+                TaxBitRecType::Income,
+                TaxBitRecType::TransferOut,
+                TaxBitRecType::Trade,
+                TaxBitRecType::TransferIn,
+            ]
+        );
+        assert!(recs.iter().all(|r| r.source == KRAKEN_SOURCE));
+
+        // Paired trade, USD sent so a Buy
+        let buy = &recs[0];
+        assert_eq!(buy.time, 1609581600000);
+        assert_eq!(buy.sent_quantity, Some(dec!(990)));
+        assert_eq!(buy.sent_currency, "USD");
+        assert_eq!(buy.received_quantity, Some(dec!(0.03)));
+        assert_eq!(buy.received_currency, "BTC");
+        assert_eq!(buy.market_value, Some(dec!(990)));
+        assert_eq!(buy.fee_amount, None);
+        assert_eq!(buy.external_id, "R-TRADE");
+
+        // Staking reward with an overridden asset code
+        assert_eq!(recs[1].received_quantity, Some(dec!(0.125)));
+        assert_eq!(recs[1].received_currency, "DOT");
+
+        // Withdrawal with fee
+        let withdrawal = &recs[2];
+        assert_eq!(withdrawal.sent_quantity, Some(dec!(0.01)));
+        assert_eq!(withdrawal.sent_currency, "BTC");
+        assert_eq!(withdrawal.fee_amount, Some(dec!(0.0005)));
+        assert_eq!(withdrawal.fee_currency, "BTC");
+
+        // Fee from the received leg
+        let trade = &recs[3];
+        assert_eq!(trade.sent_currency, "ETH");
+        assert_eq!(trade.received_currency, "BTC");
+        assert_eq!(trade.fee_amount, Some(dec!(0.00003)));
+        assert_eq!(trade.fee_currency, "BTC");
+        assert_eq!(trade.market_value, None);
+
+        assert_eq!(recs[4].received_quantity, Some(dec!(100)));
+
+        assert_eq!(rejects.len(), 2);
+        assert_eq!(rejects[0].reason, "Unsupported type 'margin'");
+        assert_eq!(rejects[1].line, 6);
+        assert_eq!(rejects[1].reason, "Trade refid R-ORPHAN has no second leg");
+    }
+
+    #[test]
+    fn test_read_kraken_ledgers_bad_pair() {
+        let csv = r#"txid,refid,time,type,subtype,aclass,asset,amount,fee,balance
+L1,R1,2021-01-02 10:00:00,trade,,currency,XXBT,0.1,0.001,0.1
+L2,R1,2021-01-02 10:00:00,trade,,currency,XETH,1.0,0.0,1.0
+"#;
+        let (recs, rejects) = read_kraken_ledgers(csv.as_bytes(), &kraken_asset_codes()).unwrap();
+        assert!(recs.is_empty());
+        assert_eq!(rejects.len(), 2);
+        assert_eq!(
+            rejects[0].reason,
+            "Trade refid R1 needs one negative and one positive amount"
+        );
+    }
+}
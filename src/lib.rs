@@ -1,33 +1,301 @@
-use std::fmt::Display;
+use std::{collections::BTreeMap, fmt::Display};
 
+use chrono::{DateTime, Datelike, NaiveDate, NaiveDateTime, TimeZone, Timelike, Utc};
 use dec_utils::dec_to_string_or_empty;
 use rust_decimal::prelude::*;
 use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
-use serde_utc_time_ms::{de_string_to_utc_time_ms, se_time_ms_to_utc_z_string};
+use serde_utc_time_ms::se_time_ms_to_utc_z_string;
 use taxbitrec::TaxBitRecType;
 use time_ms_conversions::time_ms_to_utc_string;
 
+mod api_json;
+#[cfg(feature = "arbitrary")]
+pub mod arbitrary_rec;
+mod assets;
+#[cfg(feature = "async")]
+mod async_io;
+mod balances;
+pub mod binanceus;
+mod bucket;
+pub mod coinbase;
+pub mod cointracker;
+mod collection;
+mod convert;
+mod cost_basis;
+mod decimal_format;
+mod dedup;
+mod disambiguate;
+mod dust;
+mod expenses;
+mod fills;
+mod fuzzy;
+mod gifts;
+#[cfg(feature = "gzip")]
+mod gzip;
+mod income;
+#[cfg(feature = "schemars")]
+mod json_schema;
+pub mod koinly;
+pub mod kraken;
+mod lint;
+#[cfg(feature = "std-fs")]
+mod load;
+mod manifest;
+mod mapping;
+#[cfg(feature = "rayon")]
+mod parallel;
+#[cfg(feature = "parquet")]
+mod parquet_io;
+mod pipeline;
+mod pivot;
+mod price;
+mod progress;
+mod provenance;
+mod reader;
+mod rebates;
+mod rec_v2;
+mod running;
+mod sanity;
+mod sort;
+#[cfg(feature = "std-fs")]
+mod split;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod stats;
+mod summarizer;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_support;
+mod time_offset;
+mod time_range;
+mod trades;
+mod transfers;
+pub mod turbotax;
+mod validate;
+mod writer;
+#[cfg(feature = "xlsx")]
+mod xlsx;
+
+pub use assets::{AssetRegistry, UnknownAsset};
+#[cfg(feature = "async")]
+pub use async_io::{AsyncTaxBitExportRecReader, AsyncTaxBitExportRecWriter};
+pub use balances::{
+    holdings_at, holdings_at_with_opts, holdings_at_year_end, reconcile_balances,
+    reconcile_balances_with_opts, BalanceOpts, BalancePoint, BalanceReport, Holdings,
+};
+pub use bucket::{bucket_by, bucket_by_with_opts, BucketOpts, BucketPeriod, TimeBucket};
+pub use collection::{TaxBitExportRecCollection, TopologicalSortError};
+pub use convert::{convert_all, RejectedRow, ToTaxBitExportRec};
+pub use cost_basis::{fifo_preview, CostBasisEvent, FifoAsset, FifoReport};
+pub use decimal_format::{format_decimal, DecimalFormat};
+pub use dedup::{
+    dedup_by_content, dedup_by_content_with_opts, dedup_consecutive, dedup_consecutive_by,
+    find_duplicate_ids, find_near_duplicates, find_near_duplicates_with_opts,
+    find_tolerant_duplicates, resolve_keep_first, ContentOpts, DupGroup, NearDupGroup, NearDupOpts,
+    Tolerance, ToleranceOpts,
+};
+pub use disambiguate::{
+    disambiguate_timestamps, DisambiguationReport, DisambiguationStrategy, SEQUENCE_SEPARATOR,
+};
+pub use dust::{filter_dust, filter_dust_traced, DustMode, DustOpts, DustReport, DustTotals};
+pub use expenses::{
+    categorize_expenses, expense_summary, ExpenseCategory, ExpenseRule, ExpenseRules,
+    ExpenseSummary,
+};
+pub use fills::{
+    merge_partial_fills, merge_partial_fills_traced, merge_partial_fills_with_opts, FillOpts,
+};
+pub use fuzzy::{fuzzy_match_sets, FuzzyOpts, MatchReport};
+pub use gifts::{gift_report, Gift, GiftReport, GiftSide, GIFT_COUNTERPARTY_COLUMN};
+#[cfg(all(feature = "gzip", feature = "std-fs"))]
+pub use gzip::write_tb_export_rec_file_gz;
+#[cfg(feature = "gzip")]
+pub use gzip::{GzipError, GzipReader, GzipWriter, GZIP_DEFAULT_LEVEL};
+pub use income::{
+    income_breakdown, income_by_month, IncomeBreakdown, IncomeByMonth, IncomeKind, IncomeReport,
+    IncomeRule, IncomeRules, IncomeTotals,
+};
+#[cfg(feature = "schemars")]
+pub use json_schema::export_rec_json_schema;
+pub use lint::{apply_fixes, lint, lint_with_opts, LintFinding, LintFix, LintOpts, Severity};
+#[cfg(feature = "std-fs")]
+pub use load::{load_many, load_many_with_opts, LoadOpts, LoadResult, LoadStats, SourceCollision};
+pub use manifest::{manifest_path, Manifest, ManifestMismatch};
+#[cfg(feature = "std-fs")]
+pub use manifest::{verify_manifest, write_with_manifest};
+pub use mapping::{read_with_profile, FieldSource, MappingProfile};
+#[cfg(feature = "rayon")]
+pub use parallel::read_tb_export_rec_file_parallel;
+#[cfg(feature = "parquet")]
+pub use parquet_io::{read_tb_export_rec_parquet, write_tb_export_rec_parquet};
+pub use pipeline::{Pipeline, PipelineError, PipelineReport, PipelineStep, StepReport};
+pub use pivot::{
+    pivot_by_asset_and_type, pivot_by_asset_and_type_with_value, PivotTable, PivotValue,
+};
+pub use price::{
+    fees_usd, fill_missing_market_values, total_fees_usd, CsvPriceProvider, FeesUsd, FillReport,
+    MarketValueOpts, PriceError, PriceProvider, IMPLIED_UNIT_PRICE_SCALE,
+};
+pub use progress::{LocalProgress, Progress, ProgressEvent};
+#[cfg(feature = "std-fs")]
+pub use provenance::write_provenance_csv;
+pub use provenance::{provenance_csv_string, trace, untrace, InputRef, Traced};
+pub use reader::{
+    canonical_column_name, normalize_column_name, read_tb_export_recs_from_reader,
+    read_tb_export_recs_from_reader_with_config, verify_header, DecimalLocale, RawLine,
+    ReaderConfig, RecWithRaw, RowError, SkippedRow, TaxBitExportLayout, TaxBitExportRecReader,
+};
+#[cfg(feature = "std-fs")]
+pub use reader::{
+    read_tb_export_rec_file, read_tb_export_rec_file_lenient,
+    read_tb_export_rec_file_lenient_with_opts, read_tb_export_rec_file_with_config,
+    read_tb_export_rec_file_with_progress, LenientOpts, LenientReadResult,
+};
+pub use rebates::convert_rebates;
+pub use rec_v2::{
+    read_tb_export_recs, write_tb_export_rec_v2_to_writer, TaxBitExportRecV2, TaxBitExportRecs,
+};
+#[cfg(feature = "std-fs")]
+pub use rec_v2::{read_tb_export_recs_file, write_tb_export_rec_v2_file};
+pub use running::{AssetRunningTotals, RunningTotals, RunningTotalsExt};
+pub use sanity::{sanity_check, SanityFinding, SanityOpts};
+#[cfg(feature = "std-fs")]
+pub use sort::{
+    external_sort_file, external_sort_file_with_progress, merge_sorted_files,
+    merge_sorted_files_with_progress,
+};
+pub use sort::{
+    interleave_sorted, interleave_sorted_checked, sort_by_keys, ExternalSortOpts, InterleaveSorted,
+    MergeStats, SortKey, SortStats,
+};
+#[cfg(feature = "std-fs")]
+pub use split::{split_by_source, split_by_year, SplitFile};
+#[cfg(feature = "sqlite")]
+pub use sqlite::{export_to_sqlite, export_to_sqlite_with_opts, import_from_sqlite, SqliteOpts};
+#[cfg(feature = "std-fs")]
+pub use stats::summarize_file;
+pub use stats::{count_by_type, stats, summarize, CountChange, FileSummary, RecStats};
+pub use summarizer::{CombinedReport, Summarizer};
+pub use time_offset::{apply_time_offset, preview_time_offset, TimeOffsetReport};
+#[cfg(feature = "std-fs")]
+pub use time_range::{read_file_for_year, read_file_for_year_with_opts};
+pub use time_range::{records_for_year, records_for_year_with_opts, TimeRange, YearOpts};
+pub use trades::{
+    merge_sale_buy_pairs, merge_sale_buy_pairs_traced, merge_sale_buy_pairs_with_opts, split_trade,
+    split_trade_traced, trades_to_pair_csv, MergeInfo, SaleBuyPairOpts, TradePair,
+    TRADE_PRICE_SCALE,
+};
+pub use transfers::{
+    auto_mark_internal_transfers, mark_internal_transfers, mark_internal_transfers_by_id,
+    mark_internal_transfers_with_opts, pair_transfers, MarkOpts, PairOpts, TransferId,
+    TransferPair, TransferPairing,
+};
+pub use validate::{validate_csv_bytes, InvalidRow, ValidationError, ValidationReport};
+#[cfg(feature = "std-fs")]
+pub use writer::{write_tb_export_rec_file, write_tb_export_rec_file_with_config};
+pub use writer::{
+    write_tb_export_recs_to_writer, write_tb_export_recs_to_writer_with_config, OpenMode,
+    TaxBitExportRecWriter, TimestampPrecision, WriteRejected, WriteViolation, WriterConfig,
+};
+#[cfg(feature = "xlsx")]
+pub use xlsx::{write_tb_export_rec_xlsx, XlsxOpts};
+
+/// Column names of the current TaxBit export layout
+pub const TB_EXPORT_REC_HEADER: [&str; 12] = [
+    "Date",
+    "Transaction Type",
+    "Received Quantity",
+    "Received Currency",
+    "Sent Quantity",
+    "Sent Currency",
+    "Fee Currency",
+    "Fee Amount",
+    "Market Value",
+    "Source",
+    "Internal Transfer",
+    "External ID",
+];
+
+/// Column names of TaxBit's newer export template, the current columns
+/// followed by "Transaction Hash" and "Blockchain", see TaxBitExportRecV2
+pub const TB_EXPORT_REC_V2_HEADER: [&str; 14] = [
+    "Date",
+    "Transaction Type",
+    "Received Quantity",
+    "Received Currency",
+    "Sent Quantity",
+    "Sent Currency",
+    "Fee Currency",
+    "Fee Amount",
+    "Market Value",
+    "Source",
+    "Internal Transfer",
+    "External ID",
+    "Transaction Hash",
+    "Blockchain",
+];
+
+/// Name of the optional column holding TaxBitExportRec::lot_id, it
+/// follows the TB_EXPORT_REC_HEADER columns when present
+pub const TB_EXPORT_REC_LOT_ID_COLUMN: &str = "Lot ID";
+
+/// Column names of TaxBit exports created before the
+/// "Internal Transfer" column was added
+pub const TB_EXPORT_REC_LEGACY_HEADER: [&str; 11] = [
+    "Date",
+    "Transaction Type",
+    "Received Quantity",
+    "Received Currency",
+    "Sent Quantity",
+    "Sent Currency",
+    "Fee Currency",
+    "Fee Amount",
+    "Market Value",
+    "Source",
+    "External ID",
+];
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
+#[cfg_attr(feature = "strict-parse", serde(deny_unknown_fields))]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 // CSV Header
 // Date,Transaction Type,Received Quantity,Received Currency,
 // Sent Quantity,Sent Currency,Fee Currency,Fee Amount,
 // Market Value,Source,Internal Transfer,External ID
 pub struct TaxBitExportRec {
     #[serde(rename = "Date")]
-    #[serde(deserialize_with = "de_string_to_utc_time_ms")]
+    #[serde(deserialize_with = "de_string_to_utc_time_ms_flexible")]
     #[serde(serialize_with = "se_time_ms_to_utc_z_string")]
+    #[cfg_attr(
+        feature = "schemars",
+        schemars(schema_with = "json_schema::date_schema")
+    )]
     pub time: i64,
 
     #[serde(rename = "Transaction Type")]
+    #[serde(deserialize_with = "de_taxbit_rec_type_lenient")]
+    #[cfg_attr(
+        feature = "schemars",
+        schemars(schema_with = "json_schema::type_txs_schema")
+    )]
     pub type_txs: TaxBitRecType,
 
     #[serde(rename = "Received Quantity")]
+    #[serde(default, deserialize_with = "de_string_to_decimal_opt_flexible")]
+    #[cfg_attr(
+        feature = "schemars",
+        schemars(schema_with = "json_schema::decimal_schema")
+    )]
     pub received_quantity: Option<Decimal>,
 
     #[serde(rename = "Received Currency")]
     pub received_currency: String,
 
     #[serde(rename = "Sent Quantity")]
+    #[serde(default, deserialize_with = "de_string_to_decimal_opt_flexible")]
+    #[cfg_attr(
+        feature = "schemars",
+        schemars(schema_with = "json_schema::decimal_schema")
+    )]
     pub sent_quantity: Option<Decimal>,
 
     #[serde(rename = "Sent Currency")]
@@ -37,21 +305,199 @@ pub struct TaxBitExportRec {
     pub fee_currency: String,
 
     #[serde(rename = "Fee Amount")]
+    #[serde(default, deserialize_with = "de_string_to_decimal_opt_flexible")]
+    #[cfg_attr(
+        feature = "schemars",
+        schemars(schema_with = "json_schema::decimal_schema")
+    )]
     pub fee_amount: Option<Decimal>,
 
     #[serde(rename = "Market Value")]
+    #[serde(default, deserialize_with = "de_string_to_decimal_opt_flexible")]
+    #[cfg_attr(
+        feature = "schemars",
+        schemars(schema_with = "json_schema::decimal_schema")
+    )]
     pub market_value: Option<Decimal>,
 
     #[serde(rename = "Source")]
     pub source: String,
 
+    // Legacy exports don't have this column, default to false
     #[serde(rename = "Internal Transfer")]
+    #[serde(default)]
     #[serde(deserialize_with = "de_string_true_false_to_bool")]
     #[serde(serialize_with = "se_bool_to_uppercase_string_true_false")]
+    #[cfg_attr(
+        feature = "schemars",
+        schemars(schema_with = "json_schema::bool_schema")
+    )]
     pub internal_transfer: bool,
 
     #[serde(rename = "External ID")]
     pub external_id: String,
+
+    /// Identifies the lot an acquisition created or a disposal consumed,
+    /// assigned by a cost basis engine, see FifoReport::with_lot_ids. Not
+    /// part of the TaxBit export layout, see TB_EXPORT_REC_LOT_ID_COLUMN.
+    #[serde(rename = "Lot ID")]
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub lot_id: Option<String>,
+
+    /// Columns beyond the TaxBit columns, keyed by column name.
+    /// Captured by TaxBitExportRecReader and written back by
+    /// write_tb_export_rec_file. Ignored by Eq and Ord, see eq_strict
+    /// and cmp_strict.
+    #[serde(skip)]
+    pub extras: BTreeMap<String, String>,
+}
+
+/// Parse a date string to UTC time in milliseconds trying, in order:
+///   - RFC3339 with a Z or an offset, "2023-01-05T14:22:01Z" or "2023-01-05T14:22:01-07:00"
+///   - "YYYY-MM-DD HH:MM:SS[.fff]", "2023-01-05 14:22:01"
+///   - "MM/DD/YYYY HH:MM[:SS]", "01/05/2023 14:22"
+///
+/// The later two have no time zone and are always treated as UTC.
+pub fn dt_str_to_utc_time_ms_flexible(dt_str: &str) -> Result<i64, String> {
+    if let Ok(dt) = DateTime::parse_from_rfc3339(dt_str) {
+        return Ok(dt.timestamp_millis());
+    }
+
+    for fmt in [
+        "%Y-%m-%d %H:%M:%S%.f",
+        "%m/%d/%Y %H:%M:%S",
+        "%m/%d/%Y %H:%M",
+    ] {
+        if let Ok(ndt) = NaiveDateTime::parse_from_str(dt_str, fmt) {
+            return Ok(Utc.from_utc_datetime(&ndt).timestamp_millis());
+        }
+    }
+
+    Err(format!(
+        "Unable to parse '{dt_str}' as a date, attempted formats: RFC3339 with Z or offset, \
+        YYYY-MM-DD HH:MM:SS[.fff] as UTC and MM/DD/YYYY HH:MM[:SS] as UTC"
+    ))
+}
+
+/// Deserializes a date string to UTC time in milliseconds,
+/// see dt_str_to_utc_time_ms_flexible for the accepted formats
+pub fn de_string_to_utc_time_ms_flexible<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<i64, D::Error> {
+    dt_str_to_utc_time_ms_flexible(&String::deserialize(deserializer)?).map_err(de::Error::custom)
+}
+
+/// Parse a decimal in positional, "0.00000012", or scientific notation,
+/// "1.2E-7" or "3e+2", with an upper or lower case e and an optional
+/// sign on the exponent. Scientific notation is converted exactly, so
+/// "1.20e-7" has a scale of 9, and it's an error if the value needs more
+/// than the 28 decimal places or 96 bits of a Decimal rather than being
+/// rounded.
+pub fn decimal_str_to_decimal_flexible(s: &str) -> Result<Decimal, String> {
+    let s = s.trim();
+    let not_a_number = || format!("'{s}' isn't a number");
+    let Some((mantissa, exponent)) = s.split_once(['e', 'E']) else {
+        return Decimal::from_str(s).map_err(|_| not_a_number());
+    };
+    let mantissa = Decimal::from_str_exact(mantissa).map_err(|_| not_a_number())?;
+    let exponent: i64 = exponent.parse().map_err(|_| not_a_number())?;
+    if mantissa.is_zero() {
+        return Ok(Decimal::ZERO);
+    }
+
+    let out_of_range = || format!("'{s}' is out of the range of a Decimal");
+    let scale = i64::from(mantissa.scale()) - exponent;
+    if scale >= 0 {
+        let scale = u32::try_from(scale).map_err(|_| out_of_range())?;
+        Decimal::try_from_i128_with_scale(mantissa.mantissa(), scale).map_err(|_| out_of_range())
+    } else {
+        // 10^29 is more than Decimal::MAX
+        if scale < -28 {
+            return Err(out_of_range());
+        }
+        let mut d = Decimal::from_i128_with_scale(mantissa.mantissa(), 0);
+        for _ in scale..0 {
+            d = d.checked_mul(Decimal::TEN).ok_or_else(out_of_range)?;
+        }
+        Ok(d)
+    }
+}
+
+// The visitor of de_string_to_decimal_opt_flexible
+struct DecimalOptFlexibleVisitor;
+
+impl<'de> de::Visitor<'de> for DecimalOptFlexibleVisitor {
+    type Value = Option<Decimal>;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a decimal number or string")
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Option<Decimal>, E> {
+        Ok(None)
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Option<Decimal>, E> {
+        Ok(None)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, d: D) -> Result<Option<Decimal>, D::Error> {
+        d.deserialize_any(self)
+    }
+
+    fn visit_str<E: de::Error>(self, s: &str) -> Result<Option<Decimal>, E> {
+        if s.trim().is_empty() {
+            return Ok(None);
+        }
+        decimal_str_to_decimal_flexible(s)
+            .map(Some)
+            .map_err(de::Error::custom)
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Option<Decimal>, E> {
+        Ok(Some(Decimal::from(v)))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Option<Decimal>, E> {
+        Ok(Some(Decimal::from(v)))
+    }
+
+    fn visit_i128<E: de::Error>(self, v: i128) -> Result<Option<Decimal>, E> {
+        Decimal::from_i128(v)
+            .map(Some)
+            .ok_or_else(|| de::Error::custom(format!("'{v}' is out of the range of a Decimal")))
+    }
+
+    fn visit_u128<E: de::Error>(self, v: u128) -> Result<Option<Decimal>, E> {
+        Decimal::from_u128(v)
+            .map(Some)
+            .ok_or_else(|| de::Error::custom(format!("'{v}' is out of the range of a Decimal")))
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<Option<Decimal>, E> {
+        <Decimal as Deserialize>::deserialize(de::IntoDeserializer::<E>::into_deserializer(v))
+            .map(Some)
+    }
+
+    // A JSON number, as serde_json's arbitrary_precision is enabled
+    fn visit_map<A: de::MapAccess<'de>>(self, map: A) -> Result<Option<Decimal>, A::Error> {
+        <Decimal as Deserialize>::deserialize(de::value::MapAccessDeserializer::new(map)).map(Some)
+    }
+}
+
+/// Deserializes an optional decimal from a string, see
+/// decimal_str_to_decimal_flexible for the accepted formats, or a number
+/// such as a Decimal serialized to JSON. An empty or blank string is
+/// None.
+///
+/// Formats which infer the type of a value, as the csv deserializer
+/// does, pass a number with a fraction as an f64 which may lose
+/// precision, TaxBitExportRecReader parses the decimal columns itself so
+/// it's exact.
+pub fn de_string_to_decimal_opt_flexible<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Decimal>, D::Error> {
+    deserializer.deserialize_option(DecimalOptFlexibleVisitor)
 }
 
 /// Deserilizes to boolean from upper or lower case TRUE FALSE
@@ -71,6 +517,39 @@ pub fn de_string_true_false_to_bool<'de, D: Deserializer<'de>>(
     )
 }
 
+/// Deserializes a TaxBitRecType ignoring case, spaces, hyphens and
+/// underscores so "buy", "TRANSFER IN" and "Transfer-In" are accepted
+pub fn de_taxbit_rec_type_lenient<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<TaxBitRecType, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    let normalized: String = s
+        .chars()
+        .filter(|c| !matches!(c, ' ' | '-' | '_'))
+        .collect::<String>()
+        .to_lowercase();
+    Ok(match normalized.as_str() {
+        "buy" => TaxBitRecType::Buy,
+        "sale" => TaxBitRecType::Sale,
+        "trade" => TaxBitRecType::Trade,
+        "transferin" => TaxBitRecType::TransferIn,
+        "transferout" => TaxBitRecType::TransferOut,
+        "income" => TaxBitRecType::Income,
+        "expense" => TaxBitRecType::Expense,
+        "giftreceived" => TaxBitRecType::GiftReceived,
+        "giftsent" => TaxBitRecType::GiftSent,
+        "unknown" => TaxBitRecType::Unknown,
+        "invalid" => TaxBitRecType::Invalid,
+        _ => {
+            return Err(de::Error::custom(format!(
+                "Unknown Transaction Type '{s}', expecting one of Buy, Sale, Trade, \
+                Transfer In, Transfer Out, Income, Expense, Gift Received, Gift Sent, \
+                Unknown or Invalid in any case with optional spaces, hyphens or underscores"
+            )))
+        }
+    })
+}
+
 /// Serilizes boolean as upper case string TRUE or FALSE
 pub fn se_bool_to_uppercase_string_true_false<S>(b: &bool, s: S) -> Result<S::Ok, S::Error>
 where
@@ -80,8 +559,64 @@ where
     s.serialize_str(b_str)
 }
 
+// A quantity and its currency for the alternate Display, "-" if both
+// are empty
+fn quantity_and_currency(quantity: Option<Decimal>, currency: &str) -> String {
+    match (quantity, currency) {
+        (None, "") => "-".to_owned(),
+        (None, currency) => format!("- {currency}"),
+        (Some(quantity), "") => quantity.to_string(),
+        (Some(quantity), currency) => format!("{quantity} {currency}"),
+    }
+}
+
+/// The normal format is the fields on one line separated by commas, the
+/// alternate format, `{:#}`, is one labeled field per line with empty
+/// fields shown as "-".
 impl Display for TaxBitExportRec {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if f.alternate() {
+            let or_dash = |s: &str| if s.is_empty() { "-" } else { s }.to_owned();
+            let lines = [
+                (
+                    "Date",
+                    format!(
+                        "{} ({})",
+                        self.time,
+                        self.time_utc().format("%Y-%m-%dT%H:%M:%S%.3fZ")
+                    ),
+                ),
+                ("Type", format!("{:?}", self.type_txs)),
+                (
+                    "Received",
+                    quantity_and_currency(self.received_quantity, &self.received_currency),
+                ),
+                (
+                    "Sent",
+                    quantity_and_currency(self.sent_quantity, &self.sent_currency),
+                ),
+                (
+                    "Fee",
+                    quantity_and_currency(self.fee_amount, &self.fee_currency),
+                ),
+                (
+                    "Market Value",
+                    self.market_value
+                        .map_or("-".to_owned(), |mv| mv.to_string()),
+                ),
+                ("Source", or_dash(&self.source)),
+                ("Internal Transfer", self.internal_transfer.to_string()),
+                ("External ID", or_dash(&self.external_id)),
+            ];
+            for (i, (label, value)) in lines.iter().enumerate() {
+                if i > 0 {
+                    writeln!(f)?;
+                }
+                write!(f, "{:<19}{value}", format!("{label}:"))?;
+            }
+            return Ok(());
+        }
+
         write!(
             f,
             "{},{:?},{},{},{},{},{},{},{},{},{},{}",
@@ -116,11 +651,121 @@ impl TaxBitExportRec {
             source: "".to_owned(),
             internal_transfer: false,
             external_id: "".to_owned(),
+            lot_id: None,
+            extras: BTreeMap::new(),
         }
     }
 
-    pub fn get_asset(&self) -> &str {
-        match self.type_txs {
+    /// A copy of this record with lot_id set to id
+    pub fn with_lot_id(&self, id: &str) -> Self {
+        TaxBitExportRec {
+            lot_id: Some(id.to_owned()),
+            ..self.clone()
+        }
+    }
+
+    /// The lot this record created or consumed, if one was assigned
+    pub fn lot_id(&self) -> Option<&str> {
+        self.lot_id.as_deref()
+    }
+
+    /// The time as a UTC DateTime, clamped to the range supported by
+    /// chrono
+    pub(crate) fn time_utc(&self) -> DateTime<Utc> {
+        match Utc.timestamp_millis_opt(self.time).single() {
+            Some(dt) => dt,
+            None if self.time < 0 => DateTime::<Utc>::MIN_UTC,
+            None => DateTime::<Utc>::MAX_UTC,
+        }
+    }
+
+    /// The UTC calendar date of time
+    pub(crate) fn date_utc(&self) -> NaiveDate {
+        self.time_utc().date_naive()
+    }
+
+    /// The UTC calendar year of time
+    pub fn year(&self) -> i32 {
+        self.date_utc().year()
+    }
+
+    /// The UTC calendar month of time, 1 to 12
+    pub fn month(&self) -> u32 {
+        self.date_utc().month()
+    }
+
+    /// The UTC day of the month of time, 1 to 31
+    pub fn day(&self) -> u32 {
+        self.date_utc().day()
+    }
+
+    /// The UTC date of time as "YYYY-MM-DD"
+    pub fn date_utc_string(&self) -> String {
+        self.date_utc().format("%Y-%m-%d").to_string()
+    }
+
+    /// The time as time_ms_to_utc_string formats it
+    pub fn time_utc_string(&self) -> String {
+        time_ms_to_utc_string(self.time)
+    }
+
+    /// The UTC components of time as (year, month, day, hour, minute, second),
+    /// milliseconds are truncated.
+    pub fn time_utc_components(&self) -> (i32, u8, u8, u8, u8, u8) {
+        let dt = self.time_utc();
+        (
+            dt.year(),
+            dt.month() as u8,
+            dt.day() as u8,
+            dt.hour() as u8,
+            dt.minute() as u8,
+            dt.second() as u8,
+        )
+    }
+
+    /// Equality including extras
+    pub fn eq_strict(&self, other: &Self) -> bool {
+        self == other && self.extras == other.extras
+    }
+
+    /// Ordering including extras, which are compared last
+    pub fn cmp_strict(&self, other: &Self) -> std::cmp::Ordering {
+        self.cmp(other).then_with(|| self.extras.cmp(&other.extras))
+    }
+
+    // The ordering of Ord, field by field with None before Some, shared
+    // by the content comparisons so they order records consistently
+    pub(crate) fn cmp_fields(
+        &self,
+        other: &Self,
+        ignore_source: bool,
+        ignore_external_id: bool,
+    ) -> std::cmp::Ordering {
+        self.time
+            .cmp(&other.time)
+            .then_with(|| self.type_txs.cmp(&other.type_txs))
+            .then_with(|| self.received_currency.cmp(&other.received_currency))
+            .then_with(|| self.sent_currency.cmp(&other.sent_currency))
+            .then_with(|| self.fee_currency.cmp(&other.fee_currency))
+            .then_with(|| self.received_quantity.cmp(&other.received_quantity))
+            .then_with(|| self.sent_quantity.cmp(&other.sent_quantity))
+            .then_with(|| self.fee_amount.cmp(&other.fee_amount))
+            .then_with(|| self.market_value.cmp(&other.market_value))
+            .then_with(|| match ignore_source {
+                true => std::cmp::Ordering::Equal,
+                false => self.source.cmp(&other.source),
+            })
+            .then_with(|| self.internal_transfer.cmp(&other.internal_transfer))
+            .then_with(|| match ignore_external_id {
+                true => std::cmp::Ordering::Equal,
+                false => self.external_id.cmp(&other.external_id),
+            })
+            .then_with(|| self.lot_id.cmp(&other.lot_id))
+    }
+
+    /// The asset, see get_asset, None if the type is Unknown
+    pub fn try_get_asset(&self) -> Option<&str> {
+        let asset = match self.type_txs {
             TaxBitRecType::Expense
             | TaxBitRecType::TransferOut
             | TaxBitRecType::GiftSent
@@ -141,9 +786,118 @@ impl TaxBitExportRec {
                     "no-currency-field"
                 }
             }
-            TaxBitRecType::Unknown => panic!("SNH"),
+            TaxBitRecType::Unknown => return None,
+        };
+
+        Some(asset)
+    }
+
+    /// The asset of the record, empty if the type is Unknown, see
+    /// try_get_asset
+    pub fn get_asset(&self) -> &str {
+        self.try_get_asset().unwrap_or("")
+    }
+
+    /// Set received_quantity, sent_quantity and fee_amount to None if
+    /// they're zero and their currency is empty, as some exports write
+    /// "0" in the unused quantity columns. Zero quantities with a
+    /// currency are unchanged.
+    pub fn normalize_zero_quantities(&mut self) {
+        for (quantity, currency) in [
+            (&mut self.received_quantity, &self.received_currency),
+            (&mut self.sent_quantity, &self.sent_currency),
+            (&mut self.fee_amount, &self.fee_currency),
+        ] {
+            if currency.is_empty() && quantity.is_some_and(|q| q.is_zero()) {
+                *quantity = None;
+            }
         }
     }
+
+    /// The quantity of the asset returned by get_asset, None if that
+    /// side has no quantity or the type is Unknown
+    pub fn get_quantity(&self) -> Option<Decimal> {
+        match self.type_txs {
+            TaxBitRecType::Expense
+            | TaxBitRecType::TransferOut
+            | TaxBitRecType::GiftSent
+            | TaxBitRecType::Sale => self.sent_quantity,
+            TaxBitRecType::Buy
+            | TaxBitRecType::TransferIn
+            | TaxBitRecType::Income
+            | TaxBitRecType::GiftReceived
+            | TaxBitRecType::Trade => self.received_quantity,
+            TaxBitRecType::Invalid => {
+                if !self.received_currency.is_empty() {
+                    self.received_quantity
+                } else if !self.sent_currency.is_empty() {
+                    self.sent_quantity
+                } else {
+                    self.fee_amount
+                }
+            }
+            TaxBitRecType::Unknown => None,
+        }
+    }
+
+    /// The currency exchanged for the asset returned by get_asset, the
+    /// sent currency of a Buy or Trade and the received currency of a
+    /// Sale. None for the other types, which have one side, or if it's
+    /// empty.
+    pub fn get_other_currency(&self) -> Option<&str> {
+        let currency = match self.type_txs {
+            TaxBitRecType::Buy | TaxBitRecType::Trade => &self.sent_currency,
+            TaxBitRecType::Sale => &self.received_currency,
+            _ => return None,
+        };
+
+        Some(currency.as_str()).filter(|c| !c.is_empty())
+    }
+
+    /// True if the record has a non-zero fee amount
+    pub fn has_fee(&self) -> bool {
+        self.fee_amount.is_some_and(|f| !f.is_zero())
+    }
+
+    /// The currency the fee was paid in, None if there's no fee, see
+    /// has_fee, or its currency is empty
+    pub fn get_fee_asset(&self) -> Option<&str> {
+        Some(self.fee_currency.as_str()).filter(|c| self.has_fee() && !c.is_empty())
+    }
+
+    /// How the fee's currency relates to the record's assets
+    pub fn fee_relation(&self) -> FeeRelation {
+        let Some(asset) = self.try_get_asset() else {
+            return FeeRelation::UnknownType;
+        };
+        match self.get_fee_asset() {
+            None => FeeRelation::NoFee,
+            Some(fee) if fee == asset => FeeRelation::SameAsAsset,
+            Some(fee) if Some(fee) == self.get_other_currency() => FeeRelation::SameAsCounterAsset,
+            Some(_) => FeeRelation::ThirdCurrency,
+        }
+    }
+}
+
+/// The currency of a record's fee relative to its assets, see
+/// TaxBitExportRec::fee_relation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeeRelation {
+    /// The record has no fee, see TaxBitExportRec::has_fee
+    NoFee,
+
+    /// The fee is in the asset, see TaxBitExportRec::get_asset
+    SameAsAsset,
+
+    /// The fee is in the currency exchanged for the asset, see
+    /// TaxBitExportRec::get_other_currency
+    SameAsCounterAsset,
+
+    /// The fee is in a currency the record doesn't otherwise use
+    ThirdCurrency,
+
+    /// The type is Unknown so the record's asset isn't known
+    UnknownType,
 }
 
 impl Default for TaxBitExportRec {
@@ -168,73 +922,30 @@ impl PartialEq for TaxBitExportRec {
             && self.source == other.source
             && self.internal_transfer == other.internal_transfer
             && self.external_id == other.external_id
+            && self.lot_id == other.lot_id
     }
 }
 
 impl PartialOrd for TaxBitExportRec {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        match self.time.partial_cmp(&other.time) {
-            Some(core::cmp::Ordering::Equal) => {}
-            ord => return ord,
-        }
-        match self.type_txs.partial_cmp(&other.type_txs) {
-            Some(core::cmp::Ordering::Equal) => {}
-            ord => return ord,
-        }
-        match self.received_currency.partial_cmp(&other.received_currency) {
-            Some(core::cmp::Ordering::Equal) => {}
-            ord => return ord,
-        }
-        match self.sent_currency.partial_cmp(&other.sent_currency) {
-            Some(core::cmp::Ordering::Equal) => {}
-            ord => return ord,
-        }
-        match self.fee_currency.partial_cmp(&other.fee_currency) {
-            Some(core::cmp::Ordering::Equal) => {}
-            ord => return ord,
-        }
-        match self.received_quantity.partial_cmp(&other.received_quantity) {
-            Some(core::cmp::Ordering::Equal) => {}
-            ord => return ord,
-        }
-        match self.sent_quantity.partial_cmp(&other.sent_quantity) {
-            Some(core::cmp::Ordering::Equal) => {}
-            ord => return ord,
-        }
-        match self.fee_amount.partial_cmp(&other.fee_amount) {
-            Some(core::cmp::Ordering::Equal) => {}
-            ord => return ord,
-        }
-        match self.market_value.partial_cmp(&other.market_value) {
-            Some(core::cmp::Ordering::Equal) => {}
-            ord => return ord,
-        }
-        match self.source.partial_cmp(&other.source) {
-            Some(core::cmp::Ordering::Equal) => {}
-            ord => return ord,
-        }
-        match self.internal_transfer.partial_cmp(&other.internal_transfer) {
-            Some(core::cmp::Ordering::Equal) => {}
-            ord => return ord,
-        }
-        self.external_id.partial_cmp(&other.external_id)
+        Some(self.cmp(other))
     }
 }
 
 impl Ord for TaxBitExportRec {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        match self.partial_cmp(other) {
-            Some(ord) => ord,
-            None => panic!("SNH"),
-        }
+        self.cmp_fields(other, false, false)
     }
 }
 
 #[cfg(test)]
 mod test {
+    use std::collections::BTreeMap;
+
+    use rust_decimal::prelude::*;
     use rust_decimal_macros::dec;
 
-    use crate::{TaxBitExportRec, TaxBitRecType};
+    use crate::{dt_str_to_utc_time_ms_flexible, FeeRelation, TaxBitExportRec, TaxBitRecType};
 
     #[test]
     fn test_new() {
@@ -268,6 +979,9 @@ mod test {
 
         // The order is important so we go though all the paths,
         // so we modifiy the last test first
+        tbr.lot_id = Some("a".to_owned());
+        assert!(tbr != tbr_other);
+
         tbr.external_id = "a".to_owned();
         assert!(tbr != tbr_other);
 
@@ -314,6 +1028,181 @@ mod test {
         assert!(tbr != tbr_other);
     }
 
+    #[test]
+    fn test_dt_str_to_utc_time_ms_flexible() {
+        // 2023-01-05T14:22:01Z
+        let expected = 1672928521000;
+
+        assert_eq!(
+            dt_str_to_utc_time_ms_flexible("2023-01-05T14:22:01Z"),
+            Ok(expected)
+        );
+        assert_eq!(
+            dt_str_to_utc_time_ms_flexible("2023-01-05T14:22:01.123Z"),
+            Ok(expected + 123)
+        );
+        assert_eq!(
+            dt_str_to_utc_time_ms_flexible("2023-01-05T07:22:01-07:00"),
+            Ok(expected)
+        );
+        assert_eq!(
+            dt_str_to_utc_time_ms_flexible("2023-01-05 14:22:01"),
+            Ok(expected)
+        );
+        assert_eq!(
+            dt_str_to_utc_time_ms_flexible("2023-01-05 14:22:01.5"),
+            Ok(expected + 500)
+        );
+        assert_eq!(
+            dt_str_to_utc_time_ms_flexible("01/05/2023 14:22:01"),
+            Ok(expected)
+        );
+        assert_eq!(
+            dt_str_to_utc_time_ms_flexible("01/05/2023 14:22"),
+            Ok(expected - 1000)
+        );
+
+        let err = dt_str_to_utc_time_ms_flexible("Jan 5, 2023").unwrap_err();
+        assert!(err.contains("Jan 5, 2023"));
+        assert!(err.contains("RFC3339"));
+        assert!(err.contains("MM/DD/YYYY"));
+    }
+
+    #[test]
+    fn test_deserialize_flexible_date() {
+        let csv = r#"
+Date,Transaction Type,Received Quantity,Received Currency,Sent Quantity,Sent Currency,Fee Currency,Fee Amount,Market Value,Source,Internal Transfer,External ID
+2023-01-05 14:22:01,Income,1,BTC,,,,,1,BinanceUS,FALSE,1
+01/05/2023 14:22,Income,1,BTC,,,,,1,BinanceUS,FALSE,2
+2023-01-05T14:22:01+00:00,Income,1,BTC,,,,,1,BinanceUS,FALSE,3
+yesterday,Income,1,BTC,,,,,1,BinanceUS,FALSE,4
+"#;
+
+        let mut reader = csv::Reader::from_reader(csv.as_bytes());
+        let results: Vec<Result<TaxBitExportRec, csv::Error>> = reader.deserialize().collect();
+        assert_eq!(results.len(), 4);
+        assert_eq!(results[0].as_ref().unwrap().time, 1672928521000);
+        assert_eq!(results[1].as_ref().unwrap().time, 1672928520000);
+        assert_eq!(results[2].as_ref().unwrap().time, 1672928521000);
+        assert!(results[3].is_err());
+    }
+
+    #[test]
+    fn test_decimal_str_to_decimal_flexible() {
+        let parse = super::decimal_str_to_decimal_flexible;
+        assert_eq!(parse("1.2E-7"), Ok(dec!(0.00000012)));
+        assert_eq!(parse("3e-9"), Ok(dec!(0.000000003)));
+        assert_eq!(parse("-3e2"), Ok(dec!(-300)));
+        assert_eq!(parse("1.5e+1"), Ok(dec!(15)));
+        assert_eq!(parse(" 0.25 "), Ok(dec!(0.25)));
+        assert_eq!(parse("0e-100"), Ok(dec!(0)));
+
+        // The scale is exact
+        assert_eq!(parse("1.20e-7").unwrap().scale(), 9);
+        assert_eq!(parse("1.23456789e-20").unwrap().scale(), 28);
+        assert_eq!(parse("7.9228162514264337593543950335e28"), Ok(Decimal::MAX));
+
+        // Too many decimal places or too large, not rounded
+        for s in ["1.23456789e-21", "1e-29", "1e29", "8e28", "1e-9999999999"] {
+            let err = parse(s).unwrap_err();
+            assert!(err.contains("out of the range"), "{s}: {err}");
+        }
+        for s in ["", "e5", "1e", "1.2e-7.5", "1e5e3", "one"] {
+            let err = parse(s).unwrap_err();
+            assert!(err.contains("isn't a number"), "{s}: {err}");
+        }
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let mut tbr = TaxBitExportRec::new();
+        tbr.time = 1583134325000;
+        tbr.type_txs = TaxBitRecType::Trade;
+        tbr.received_quantity = Some(dec!(0.123456789012345678));
+        tbr.received_currency = "ETH".to_owned();
+        tbr.sent_quantity = Some(dec!(1.50));
+        tbr.sent_currency = "BTC".to_owned();
+        tbr.fee_amount = Some(dec!(-3));
+        tbr.fee_currency = "ETH".to_owned();
+        tbr.external_id = "id-1".to_owned();
+
+        let json = serde_json::to_string(&tbr).unwrap();
+        let read: TaxBitExportRec = serde_json::from_str(&json).unwrap();
+        assert!(read.eq_strict(&tbr), "{json}");
+        assert_eq!(read.sent_quantity.unwrap().scale(), 2);
+
+        // Numbers and strings, including scientific notation, are accepted
+        let json = json
+            .replace("\"1.50\"", "1.50")
+            .replace("\"-3\"", "-3")
+            .replace("\"Market Value\":null", "\"Market Value\":\"2.5e3\"");
+        let read: TaxBitExportRec = serde_json::from_str(&json).unwrap();
+        assert_eq!(read.sent_quantity, Some(dec!(1.50)));
+        assert_eq!(read.fee_amount, Some(dec!(-3)));
+        assert_eq!(read.market_value, Some(dec!(2500)));
+        let err = serde_json::from_str::<TaxBitExportRec>(&json.replace("2.5e3", "x")).unwrap_err();
+        assert!(err.to_string().contains("'x' isn't a number"), "{err}");
+    }
+
+    #[test]
+    fn test_time_utc_components() {
+        let mut tbr = TaxBitExportRec::new();
+
+        tbr.time = 1672531200000; // 2023-01-01T00:00:00Z
+        assert_eq!(tbr.time_utc_components(), (2023, 1, 1, 0, 0, 0));
+
+        tbr.time = 1672617599999; // 2023-01-01T23:59:59.999Z
+        assert_eq!(tbr.time_utc_components(), (2023, 1, 1, 23, 59, 59));
+
+        tbr.time = -1; // 1969-12-31T23:59:59.999Z
+        assert_eq!(tbr.time_utc_components(), (1969, 12, 31, 23, 59, 59));
+    }
+
+    #[test]
+    fn test_calendar_accessors() {
+        let mut tbr = TaxBitExportRec::new();
+        let date = |tbr: &TaxBitExportRec| (tbr.year(), tbr.month(), tbr.day());
+
+        tbr.time = 1709164800000; // 2024-02-29T00:00:00Z
+        assert_eq!(date(&tbr), (2024, 2, 29));
+        assert_eq!(tbr.date_utc_string(), "2024-02-29");
+
+        tbr.time = 1709251199999; // 2024-02-29T23:59:59.999Z
+        assert_eq!(tbr.date_utc_string(), "2024-02-29");
+        tbr.time += 1;
+        assert_eq!(tbr.date_utc_string(), "2024-03-01");
+
+        tbr.time = 1704067199999; // 2023-12-31T23:59:59.999Z
+        assert_eq!(date(&tbr), (2023, 12, 31));
+        tbr.time += 1;
+        assert_eq!(date(&tbr), (2024, 1, 1));
+
+        tbr.time = -1; // 1969-12-31T23:59:59.999Z
+        assert_eq!(date(&tbr), (1969, 12, 31));
+        assert_eq!(tbr.date_utc_string(), "1969-12-31");
+
+        tbr.time = -68169600000; // 1967-11-04T00:00:00Z
+        assert_eq!(tbr.date_utc_string(), "1967-11-04");
+
+        assert_eq!(
+            tbr.time_utc_string(),
+            super::time_ms_to_utc_string(tbr.time)
+        );
+    }
+
+    #[test]
+    fn test_eq_cmp_strict() {
+        let tbr = TaxBitExportRec::default();
+        let mut tbr_other = TaxBitExportRec::default();
+        tbr_other.extras.insert("Notes".to_owned(), "a".to_owned());
+
+        assert!(tbr == tbr_other);
+        assert_eq!(tbr.cmp(&tbr_other), core::cmp::Ordering::Equal);
+        assert!(!tbr.eq_strict(&tbr_other));
+        assert_eq!(tbr.cmp_strict(&tbr_other), core::cmp::Ordering::Less);
+        assert!(tbr_other.eq_strict(&tbr_other.clone()));
+    }
+
     #[test]
     fn test_partial_ord() {
         let mut tbr = TaxBitExportRec::default();
@@ -323,6 +1212,9 @@ mod test {
 
         // The order is important so we go though all the paths,
         // so we modifiy the last test first
+        tbr_other.lot_id = Some("a".to_owned());
+        assert!(tbr < tbr_other);
+
         tbr.external_id = "a".to_owned();
         tbr_other.external_id = "b".to_owned();
         assert!(tbr < tbr_other);
@@ -379,24 +1271,93 @@ mod test {
     }
 
     #[test]
-    #[should_panic]
-    fn test_ord_panic() {
+    fn test_ord_none_before_some() {
         let mut tbr = TaxBitExportRec::default();
         let mut tbr_other = TaxBitExportRec::default();
 
-        // Panic when a field is None and the same field in other is Some
+        // A field which is None is before the same field which is Some
         tbr.received_quantity = None;
         tbr_other.received_quantity = Some(dec!(1));
-        assert_eq!(tbr.cmp(&tbr_other), core::cmp::Ordering::Equal);
+        assert_eq!(tbr.cmp(&tbr_other), core::cmp::Ordering::Less);
+        assert_eq!(tbr_other.cmp(&tbr), core::cmp::Ordering::Greater);
     }
 
     #[test]
-    #[should_panic]
-    fn test_get_asset_panic() {
-        let tbr = TaxBitExportRec::new();
+    fn test_get_asset_unknown() {
+        let mut tbr = TaxBitExportRec::new();
+        tbr.received_currency = "ABC".to_owned();
 
         assert_eq!(tbr.type_txs, TaxBitRecType::Unknown);
-        tbr.get_asset();
+        assert_eq!(tbr.get_asset(), "");
+        assert_eq!(tbr.get_quantity(), None);
+    }
+
+    #[test]
+    fn test_time_out_of_range() {
+        use chrono::Datelike;
+
+        let mut tbr = TaxBitExportRec::new();
+        tbr.time = i64::MAX;
+        assert_eq!(tbr.year(), chrono::DateTime::<chrono::Utc>::MAX_UTC.year());
+        tbr.time = i64::MIN;
+        assert_eq!(tbr.year(), chrono::DateTime::<chrono::Utc>::MIN_UTC.year());
+    }
+
+    #[test]
+    fn test_fee_relation() {
+        let rec = |type_txs: TaxBitRecType, fee: Option<(&str, Decimal)>| {
+            let mut tbr = TaxBitExportRec::new();
+            tbr.type_txs = type_txs;
+            tbr.received_quantity = Some(dec!(1));
+            tbr.received_currency = "ETH".to_owned();
+            tbr.sent_quantity = Some(dec!(0.05));
+            tbr.sent_currency = "BTC".to_owned();
+            if let Some((currency, amount)) = fee {
+                tbr.fee_currency = currency.to_owned();
+                tbr.fee_amount = Some(amount);
+            }
+            tbr
+        };
+
+        // A Trade with the fee in the sent currency
+        let trade = rec(TaxBitRecType::Trade, Some(("BTC", dec!(0.0001))));
+        assert!(trade.has_fee());
+        assert_eq!(trade.get_fee_asset(), Some("BTC"));
+        assert_eq!(trade.get_other_currency(), Some("BTC"));
+        assert_eq!(trade.fee_relation(), FeeRelation::SameAsCounterAsset);
+        let trade = rec(TaxBitRecType::Trade, Some(("ETH", dec!(0.001))));
+        assert_eq!(trade.fee_relation(), FeeRelation::SameAsAsset);
+
+        // A Buy with the fee in USD
+        let mut buy = rec(TaxBitRecType::Buy, Some(("USD", dec!(1.99))));
+        assert_eq!(buy.fee_relation(), FeeRelation::ThirdCurrency);
+        buy.sent_currency = "USD".to_owned();
+        assert_eq!(buy.fee_relation(), FeeRelation::SameAsCounterAsset);
+
+        // No fee, or a zero fee
+        let income = rec(TaxBitRecType::Income, None);
+        assert!(!income.has_fee());
+        assert_eq!(income.get_fee_asset(), None);
+        assert_eq!(income.get_other_currency(), None);
+        assert_eq!(income.fee_relation(), FeeRelation::NoFee);
+        let sale = rec(TaxBitRecType::Sale, Some(("ETH", dec!(0))));
+        assert_eq!(sale.get_other_currency(), Some("ETH"));
+        assert_eq!(sale.fee_relation(), FeeRelation::NoFee);
+
+        // Unknown type
+        let unknown = rec(TaxBitRecType::Unknown, Some(("BTC", dec!(1))));
+        assert_eq!(unknown.get_fee_asset(), Some("BTC"));
+        assert_eq!(unknown.fee_relation(), FeeRelation::UnknownType);
+    }
+
+    #[test]
+    fn test_try_get_asset() {
+        let mut tbr = TaxBitExportRec::new();
+        tbr.received_currency = "ABC".to_owned();
+        assert_eq!(tbr.try_get_asset(), None);
+
+        tbr.type_txs = TaxBitRecType::Income;
+        assert_eq!(tbr.try_get_asset(), Some("ABC"));
     }
 
     #[test]
@@ -440,6 +1401,58 @@ mod test {
         assert_eq!(tbr.get_asset(), "ABC");
     }
 
+    #[test]
+    fn test_normalize_zero_quantities() {
+        let mut rec = TaxBitExportRec::new();
+        rec.type_txs = TaxBitRecType::Sale;
+        rec.received_quantity = Some(dec!(0));
+        rec.sent_quantity = Some(dec!(0));
+        rec.sent_currency = "BTC".to_owned();
+        rec.fee_amount = Some(dec!(0.00));
+        rec.normalize_zero_quantities();
+        assert_eq!(rec.received_quantity, None);
+        assert_eq!(rec.sent_quantity, Some(dec!(0)));
+        assert_eq!(rec.fee_amount, None);
+
+        let mut blank = TaxBitExportRec::new();
+        blank.received_currency = "BTC".to_owned();
+        blank.normalize_zero_quantities();
+        assert_eq!(blank.received_quantity, None);
+    }
+
+    #[test]
+    fn test_get_quantity() {
+        let mut tbr = TaxBitExportRec::new();
+        tbr.sent_quantity = Some(dec!(1));
+        tbr.sent_currency = "USD".to_owned();
+        tbr.received_quantity = Some(dec!(2));
+        tbr.received_currency = "ABC".to_owned();
+
+        for type_txs in [
+            TaxBitRecType::Expense,
+            TaxBitRecType::TransferOut,
+            TaxBitRecType::GiftSent,
+            TaxBitRecType::Sale,
+        ] {
+            tbr.type_txs = type_txs;
+            assert_eq!(tbr.get_quantity(), Some(dec!(1)));
+        }
+        for type_txs in [
+            TaxBitRecType::Buy,
+            TaxBitRecType::TransferIn,
+            TaxBitRecType::Income,
+            TaxBitRecType::GiftReceived,
+            TaxBitRecType::Trade,
+            TaxBitRecType::Invalid,
+        ] {
+            tbr.type_txs = type_txs;
+            assert_eq!(tbr.get_quantity(), Some(dec!(2)));
+        }
+
+        tbr.received_currency = "".to_owned();
+        assert_eq!(tbr.get_quantity(), Some(dec!(1)));
+    }
+
     #[test]
     fn test_deserialize_from_csv() {
         let csv = r#"
@@ -473,6 +1486,8 @@ Date,Transaction Type,Received Quantity,Received Currency,Sent Quantity,Sent Cur
                 source: "BinanceUS".to_owned(),
                 internal_transfer: false,
                 external_id: "2459217f-1a6f-4693-974c-d8d65f21abab".to_owned(),
+                lot_id: None,
+                extras: BTreeMap::new(),
             },
             TaxBitExportRec {
                 time: 1583134354000,
@@ -487,6 +1502,8 @@ Date,Transaction Type,Received Quantity,Received Currency,Sent Quantity,Sent Cur
                 source: "BinanceUS".to_owned(),
                 internal_transfer: false,
                 external_id: "bf5cd6e1-64ec-4cb1-bbb2-502ac667561d".to_owned(),
+                lot_id: None,
+                extras: BTreeMap::new(),
             },
             TaxBitExportRec {
                 time: 1583190837000,
@@ -501,9 +1518,295 @@ Date,Transaction Type,Received Quantity,Received Currency,Sent Quantity,Sent Cur
                 source: "BinanceUS".to_owned(),
                 internal_transfer: false,
                 external_id: "95be8346-8a8e-41b9-a7e3-d1baa4d1144f".to_owned(),
+                lot_id: None,
+                extras: BTreeMap::new(),
             },
         ];
         println!("{:#?}", tber_a);
         assert_eq!(tber_a, tber_a_expected);
     }
+
+    #[test]
+    fn test_deserialize_from_csv_extra_column() {
+        let csv = r#"
+Date,Transaction Type,Received Quantity,Received Currency,Sent Quantity,Sent Currency,Fee Currency,Fee Amount,Market Value,Source,Internal Transfer,External ID,Blockchain
+2020-03-02T07:32:05.000Z,Income,3e-7,BTC,,,,,0.0025979719720382955,BinanceUS,FALSE,2459217f-1a6f-4693-974c-d8d65f21abab,Bitcoin
+"#;
+
+        let rdr = csv.as_bytes();
+        let mut reader = csv::Reader::from_reader(rdr);
+        let result: Result<Vec<TaxBitExportRec>, csv::Error> = reader.deserialize().collect();
+
+        #[cfg(feature = "strict-parse")]
+        assert!(result.is_err());
+
+        #[cfg(not(feature = "strict-parse"))]
+        {
+            let tber_a = result.unwrap();
+            assert_eq!(tber_a.len(), 1);
+            assert_eq!(tber_a[0].received_currency, "BTC");
+            assert_eq!(
+                tber_a[0].external_id,
+                "2459217f-1a6f-4693-974c-d8d65f21abab"
+            );
+        }
+    }
+
+    #[test]
+    fn test_csv_roundtrip() {
+        let rec = |time: i64,
+                   type_txs: TaxBitRecType,
+                   received: (&str, &str),
+                   sent: (&str, &str),
+                   market_value: &str,
+                   internal_transfer: bool| TaxBitExportRec {
+            time,
+            type_txs,
+            received_quantity: Decimal::from_str(received.0).ok(),
+            received_currency: received.1.to_owned(),
+            sent_quantity: Decimal::from_str(sent.0).ok(),
+            sent_currency: sent.1.to_owned(),
+            fee_currency: "BNB".to_owned(),
+            fee_amount: Some(dec!(0.00075)),
+            market_value: Decimal::from_str(market_value).ok(),
+            source: "BinanceUS".to_owned(),
+            internal_transfer,
+            external_id: format!("id-{time}"),
+            lot_id: None,
+            extras: BTreeMap::new(),
+        };
+
+        let tber_a: Vec<TaxBitExportRec> = vec![
+            rec(
+                1583134325000,
+                TaxBitRecType::Buy,
+                ("0.5", "BTC"),
+                ("4321.12", "USD"),
+                "4321.12",
+                false,
+            ),
+            rec(
+                1583134325123,
+                TaxBitRecType::Sale,
+                ("4400", "USD"),
+                ("0.5", "BTC"),
+                "4400",
+                false,
+            ),
+            rec(
+                1583134326000,
+                TaxBitRecType::Trade,
+                ("12.345678", "ETH"),
+                ("0.25", "BTC"),
+                "2205.5",
+                false,
+            ),
+            rec(
+                1583134327000,
+                TaxBitRecType::TransferIn,
+                ("1.1", "ETH"),
+                ("", ""),
+                "196.9",
+                true,
+            ),
+            rec(
+                1583134328000,
+                TaxBitRecType::TransferOut,
+                ("", ""),
+                ("1.1", "ETH"),
+                "196.9",
+                true,
+            ),
+            rec(
+                1583134329000,
+                TaxBitRecType::Income,
+                ("0.0000003", "BTC"),
+                ("", ""),
+                "0.0025979719720382955",
+                false,
+            ),
+            rec(
+                1583134330000,
+                TaxBitRecType::Expense,
+                ("", ""),
+                ("0.01", "ETH"),
+                "1.79",
+                false,
+            ),
+            rec(
+                1583134331000,
+                TaxBitRecType::GiftReceived,
+                ("100", "ADA"),
+                ("", ""),
+                "5.12",
+                false,
+            ),
+            rec(
+                1583134332000,
+                TaxBitRecType::GiftSent,
+                ("", ""),
+                ("50", "ADA"),
+                "2.56",
+                false,
+            ),
+        ];
+
+        let mut writer = csv::Writer::from_writer(vec![]);
+        for tber in &tber_a {
+            writer.serialize(tber).unwrap();
+        }
+        let csv = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        println!("{csv}");
+
+        let mut reader = csv::Reader::from_reader(csv.as_bytes());
+        let tber_a_read: Vec<TaxBitExportRec> =
+            reader.deserialize().map(|entry| entry.unwrap()).collect();
+
+        assert_eq!(tber_a_read, tber_a);
+    }
+
+    #[test]
+    fn test_lot_id() {
+        let rec = TaxBitExportRec::new();
+        assert_eq!(rec.lot_id(), None);
+
+        let lot = rec.with_lot_id("lot-1");
+        assert_eq!(lot.lot_id(), Some("lot-1"));
+        assert_eq!(rec.lot_id(), None);
+        assert_ne!(lot, rec);
+    }
+
+    #[test]
+    fn test_de_taxbit_rec_type_lenient() {
+        #[derive(serde::Deserialize)]
+        struct TypeTxs {
+            #[serde(deserialize_with = "super::de_taxbit_rec_type_lenient")]
+            t: TaxBitRecType,
+        }
+        fn de(s: &str) -> Result<TaxBitRecType, serde_json::Error> {
+            serde_json::from_value::<TypeTxs>(serde_json::json!({ "t": s })).map(|v| v.t)
+        }
+
+        let matrix = [
+            (TaxBitRecType::Buy, vec!["Buy", "buy", "BUY", " b-u_y "]),
+            (TaxBitRecType::Sale, vec!["Sale", "sale", "SALE"]),
+            (TaxBitRecType::Trade, vec!["Trade", "trade", "TRADE"]),
+            (
+                TaxBitRecType::TransferIn,
+                vec![
+                    "Transfer In",
+                    "transfer in",
+                    "TRANSFER IN",
+                    "Transfer-In",
+                    "transfer_in",
+                    "TransferIn",
+                ],
+            ),
+            (
+                TaxBitRecType::TransferOut,
+                vec![
+                    "Transfer Out",
+                    "transfer out",
+                    "TRANSFER OUT",
+                    "Transfer-Out",
+                    "TRANSFER_OUT",
+                    "transferout",
+                ],
+            ),
+            (TaxBitRecType::Income, vec!["Income", "income", "INCOME"]),
+            (
+                TaxBitRecType::Expense,
+                vec!["Expense", "expense", "EXPENSE"],
+            ),
+            (
+                TaxBitRecType::GiftReceived,
+                vec![
+                    "Gift Received",
+                    "gift received",
+                    "GIFT-RECEIVED",
+                    "gift_received",
+                ],
+            ),
+            (
+                TaxBitRecType::GiftSent,
+                vec!["Gift Sent", "gift sent", "GIFT-SENT", "GiftSent"],
+            ),
+            (TaxBitRecType::Unknown, vec!["Unknown", "unknown"]),
+            (TaxBitRecType::Invalid, vec!["Invalid", "INVALID"]),
+        ];
+        for (expected, spellings) in matrix {
+            for s in spellings {
+                assert_eq!(de(s).unwrap(), expected, "{s}");
+            }
+        }
+
+        let err = de("Steal").unwrap_err().to_string();
+        assert!(err.starts_with("Unknown Transaction Type 'Steal', expecting one of Buy,"));
+    }
+
+    #[test]
+    fn test_lenient_type_round_trip() {
+        let csv = "Date,Transaction Type,Received Quantity,Received Currency,Sent Quantity,Sent Currency,Fee Currency,Fee Amount,Market Value,Source,Internal Transfer,External ID
+2021-01-02T10:00:00.000Z,transfer-in,1,BTC,,,,,,Coinbase,FALSE,id-1
+";
+        let mut rdr = csv::Reader::from_reader(csv.as_bytes());
+        let rec: TaxBitExportRec = rdr.deserialize().next().unwrap().unwrap();
+        assert_eq!(rec.type_txs, TaxBitRecType::TransferIn);
+
+        let mut wtr = csv::Writer::from_writer(vec![]);
+        wtr.serialize(&rec).unwrap();
+        let out = String::from_utf8(wtr.into_inner().unwrap()).unwrap();
+        assert!(out.lines().nth(1).unwrap().contains(",Transfer In,"));
+    }
+
+    #[test]
+    fn test_display() {
+        let mut rec = TaxBitExportRec::new();
+        rec.time = 1583134325000;
+        rec.type_txs = TaxBitRecType::Trade;
+        rec.received_quantity = Some(dec!(0.02314));
+        rec.received_currency = "BTC".to_owned();
+        rec.sent_quantity = Some(dec!(0.35));
+        rec.sent_currency = "ETH".to_owned();
+        rec.fee_amount = Some(dec!(0.0001));
+        rec.fee_currency = "ETH".to_owned();
+        rec.market_value = Some(dec!(200.50));
+        rec.source = "BinanceUS".to_owned();
+        rec.internal_transfer = true;
+        rec.external_id = "2459217f".to_owned();
+
+        let utc = super::time_ms_to_utc_string(rec.time);
+        assert_eq!(
+            format!("{rec}"),
+            format!("{utc},Trade,0.35,ETH,0.02314,BTC,ETH,0.0001,200.50,BinanceUS,true,2459217f")
+        );
+        assert_eq!(
+            format!("{rec:#}"),
+            "Date:              1583134325000 (2020-03-02T07:32:05.000Z)\n\
+            Type:              Trade\n\
+            Received:          0.02314 BTC\n\
+            Sent:              0.35 ETH\n\
+            Fee:               0.0001 ETH\n\
+            Market Value:      200.50\n\
+            Source:            BinanceUS\n\
+            Internal Transfer: true\n\
+            External ID:       2459217f"
+        );
+
+        let rec = TaxBitExportRec::new();
+        let utc = super::time_ms_to_utc_string(0);
+        assert_eq!(format!("{rec}"), format!("{utc},Unknown,,,,,,,,,false,"));
+        assert_eq!(
+            format!("{rec:#}"),
+            "Date:              0 (1970-01-01T00:00:00.000Z)\n\
+            Type:              Unknown\n\
+            Received:          -\n\
+            Sent:              -\n\
+            Fee:               -\n\
+            Market Value:      -\n\
+            Source:            -\n\
+            Internal Transfer: false\n\
+            External ID:       -"
+        );
+    }
 }
@@ -0,0 +1,399 @@
+use serde::{Deserialize, Serialize};
+use taxbitrec::TaxBitRecType;
+
+use crate::{find_duplicate_ids, TaxBitExportRec};
+
+const DAY_MS: i64 = 86_400_000;
+
+/// How serious a LintFinding or SanityFinding is, ordered from Info to
+/// Error
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+/// A change to a record suggested by a LintFinding, see apply_fixes
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LintFix {
+    SetReceivedCurrency(String),
+    SetSentCurrency(String),
+    SetFeeCurrency(String),
+    SetSource(String),
+    SetExternalId(String),
+
+    /// Set fee_amount to None and fee_currency to empty
+    ClearFee,
+}
+
+impl LintFix {
+    pub fn apply(&self, rec: &mut TaxBitExportRec) {
+        match self {
+            LintFix::SetReceivedCurrency(c) => rec.received_currency = c.clone(),
+            LintFix::SetSentCurrency(c) => rec.sent_currency = c.clone(),
+            LintFix::SetFeeCurrency(c) => rec.fee_currency = c.clone(),
+            LintFix::SetSource(s) => rec.source = s.clone(),
+            LintFix::SetExternalId(id) => rec.external_id = id.clone(),
+            LintFix::ClearFee => {
+                rec.fee_amount = None;
+                rec.fee_currency = String::new();
+            }
+        }
+    }
+}
+
+/// Something lint found which is likely a mistake, with a fix if one is
+/// obvious
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LintFinding {
+    pub severity: Severity,
+
+    /// The rule which found it, such as "currency-case", see lint
+    pub rule: &'static str,
+
+    /// Index of the record, the first of a run for a finding about
+    /// several records
+    pub index: usize,
+
+    pub message: String,
+    pub fix: Option<LintFix>,
+}
+
+/// Options for lint_with_opts
+#[derive(Debug, Clone)]
+pub struct LintOpts {
+    /// The fewest consecutive records at midnight UTC exactly reported
+    /// by the midnight-run rule, 20 by default
+    pub min_midnight_run: usize,
+}
+
+impl Default for LintOpts {
+    fn default() -> Self {
+        LintOpts {
+            min_midnight_run: 20,
+        }
+    }
+}
+
+/// Lint the records, see lint_with_opts
+pub fn lint(recs: &[TaxBitExportRec]) -> Vec<LintFinding> {
+    lint_with_opts(recs, &LintOpts::default())
+}
+
+/// Look for likely mistakes in the records, suggesting a fix where one
+/// is obvious, see apply_fixes. Returns the findings ordered by record
+/// index. The rules are:
+///
+/// - "currency-case", Warning: a currency which isn't upper case or has
+///   surrounding whitespace, fixed by the upper case trimmed code
+/// - "whitespace", Warning: a source or external ID with surrounding
+///   whitespace, fixed by trimming it
+/// - "orphan-fee", Warning: a Fee Currency without a Fee Amount, fixed
+///   by clearing the fee, or Error: a Fee Amount without a Fee Currency
+/// - "zero-fee", Info: a Fee Amount of zero, fixed by clearing the fee
+/// - "market-value-without-quantity", Warning: a Market Value when the
+///   quantity of the asset, see TaxBitExportRec::get_quantity, is missing
+/// - "duplicate-external-id", Warning: a record sharing its source and
+///   external ID with an earlier one, see find_duplicate_ids
+/// - "midnight-run", Info: at least opts.min_midnight_run consecutive
+///   records at midnight UTC exactly, possibly a source with only dates
+pub fn lint_with_opts(recs: &[TaxBitExportRec], opts: &LintOpts) -> Vec<LintFinding> {
+    let mut findings = vec![];
+    for (index, rec) in recs.iter().enumerate() {
+        lint_rec(&mut findings, index, rec);
+    }
+
+    for ((source, id), indices) in find_duplicate_ids(recs) {
+        for index in &indices[1..] {
+            findings.push(LintFinding {
+                severity: Severity::Warning,
+                rule: "duplicate-external-id",
+                index: *index,
+                message: format!(
+                    "External ID {id} for Source {source} is also record {}",
+                    indices[0]
+                ),
+                fix: None,
+            });
+        }
+    }
+
+    let mut run_start = 0;
+    for index in 0..=recs.len() {
+        if index < recs.len() && recs[index].time.rem_euclid(DAY_MS) == 0 {
+            continue;
+        }
+        let run = index - run_start;
+        if run > 0 && run >= opts.min_midnight_run {
+            findings.push(LintFinding {
+                severity: Severity::Info,
+                rule: "midnight-run",
+                index: run_start,
+                message: format!(
+                    "{run} consecutive records are at midnight UTC exactly, the source may have only dates"
+                ),
+                fix: None,
+            });
+        }
+        run_start = index + 1;
+    }
+
+    findings.sort_by_key(|f| f.index);
+    findings
+}
+
+// The name of a column, its value and the fix setting it
+type Column<'a> = (&'static str, &'a String, fn(String) -> LintFix);
+
+// Add the findings of the rules about a single record
+fn lint_rec(findings: &mut Vec<LintFinding>, index: usize, rec: &TaxBitExportRec) {
+    let mut add = |severity, rule, message: String, fix| {
+        findings.push(LintFinding {
+            severity,
+            rule,
+            index,
+            message,
+            fix,
+        })
+    };
+
+    // A fee which is cleared isn't worth correcting the currency of
+    let fee_kept = rec.fee_amount.is_some_and(|f| !f.is_zero());
+    let mut currencies: Vec<Column> = vec![
+        (
+            "Received Currency",
+            &rec.received_currency,
+            LintFix::SetReceivedCurrency,
+        ),
+        (
+            "Sent Currency",
+            &rec.sent_currency,
+            LintFix::SetSentCurrency,
+        ),
+    ];
+    if fee_kept {
+        currencies.push(("Fee Currency", &rec.fee_currency, LintFix::SetFeeCurrency));
+    }
+    for (column, currency, set) in currencies {
+        let fixed = currency.trim().to_uppercase();
+        if !currency.is_empty() && *currency != fixed {
+            add(
+                Severity::Warning,
+                "currency-case",
+                format!("{column} '{currency}' should be '{fixed}'"),
+                Some(set(fixed)),
+            );
+        }
+    }
+
+    let fields: [Column; 2] = [
+        ("Source", &rec.source, LintFix::SetSource),
+        ("External ID", &rec.external_id, LintFix::SetExternalId),
+    ];
+    for (column, value, set) in fields {
+        let fixed = value.trim();
+        if value != fixed {
+            add(
+                Severity::Warning,
+                "whitespace",
+                format!("{column} '{value}' has surrounding whitespace"),
+                Some(set(fixed.to_owned())),
+            );
+        }
+    }
+
+    match (rec.fee_amount, rec.fee_currency.is_empty()) {
+        (None, false) => add(
+            Severity::Warning,
+            "orphan-fee",
+            format!("Fee Currency '{}' without a Fee Amount", rec.fee_currency),
+            Some(LintFix::ClearFee),
+        ),
+        (Some(amount), _) if amount.is_zero() => add(
+            Severity::Info,
+            "zero-fee",
+            "Fee Amount is zero".to_owned(),
+            Some(LintFix::ClearFee),
+        ),
+        (Some(amount), true) => add(
+            Severity::Error,
+            "orphan-fee",
+            format!("Fee Amount {amount} without a Fee Currency"),
+            None,
+        ),
+        _ => {}
+    }
+
+    if rec.market_value.is_some()
+        && rec.get_quantity().is_none()
+        && rec.type_txs != TaxBitRecType::Unknown
+    {
+        add(
+            Severity::Warning,
+            "market-value-without-quantity",
+            "Market Value present but the quantity is missing".to_owned(),
+            None,
+        );
+    }
+}
+
+/// Apply the fixes of the findings at least as severe as min_severity
+/// to the records, returning the number applied. Findings of records
+/// not in recs are ignored.
+pub fn apply_fixes(
+    recs: &mut [TaxBitExportRec],
+    findings: &[LintFinding],
+    min_severity: Severity,
+) -> usize {
+    let mut applied = 0;
+    for finding in findings {
+        if finding.severity < min_severity {
+            continue;
+        }
+        if let (Some(fix), Some(rec)) = (&finding.fix, recs.get_mut(finding.index)) {
+            fix.apply(rec);
+            applied += 1;
+        }
+    }
+
+    applied
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    // 2022-01-01T00:00:00Z
+    const T: i64 = 1640995200000;
+
+    fn buy(id: &str) -> TaxBitExportRec {
+        let mut rec = TaxBitExportRec::new();
+        rec.time = T + 1000;
+        rec.type_txs = TaxBitRecType::Buy;
+        rec.received_quantity = Some(dec!(0.1));
+        rec.received_currency = "BTC".to_owned();
+        rec.sent_quantity = Some(dec!(4000));
+        rec.sent_currency = "USD".to_owned();
+        rec.fee_amount = Some(dec!(1));
+        rec.fee_currency = "USD".to_owned();
+        rec.market_value = Some(dec!(4000));
+        rec.source = "Kraken".to_owned();
+        rec.external_id = id.to_owned();
+        rec
+    }
+
+    fn fixture() -> Vec<TaxBitExportRec> {
+        let mut lower = buy("0");
+        lower.received_currency = "btc".to_owned();
+        lower.fee_currency = " usd".to_owned();
+        let mut no_amount = buy("1");
+        no_amount.fee_amount = None;
+        let mut zero_fee = buy("2");
+        zero_fee.fee_amount = Some(dec!(0));
+        zero_fee.fee_currency = "usd".to_owned();
+        let mut spaces = buy(" 3");
+        spaces.source = "Kraken ".to_owned();
+        vec![lower, no_amount, zero_fee, spaces, buy("4")]
+    }
+
+    fn rules(findings: &[LintFinding]) -> Vec<(usize, &str)> {
+        findings.iter().map(|f| (f.index, f.rule)).collect()
+    }
+
+    #[test]
+    fn test_lint_fixes() {
+        let mut recs = fixture();
+        let findings = lint(&recs);
+        assert_eq!(
+            rules(&findings),
+            vec![
+                (0, "currency-case"),
+                (0, "currency-case"),
+                (1, "orphan-fee"),
+                (2, "zero-fee"),
+                (3, "whitespace"),
+                (3, "whitespace"),
+            ]
+        );
+        assert_eq!(
+            findings[1],
+            LintFinding {
+                severity: Severity::Warning,
+                rule: "currency-case",
+                index: 0,
+                message: "Fee Currency ' usd' should be 'USD'".to_owned(),
+                fix: Some(LintFix::SetFeeCurrency("USD".to_owned())),
+            }
+        );
+        assert_eq!(findings[3].severity, Severity::Info);
+
+        // The zero fee is only Info
+        assert_eq!(apply_fixes(&mut recs, &findings, Severity::Warning), 5);
+        assert_eq!(rules(&lint(&recs)), vec![(2, "zero-fee")]);
+        assert_eq!(recs[0], buy("0"));
+        assert_eq!(recs[3].external_id, "3");
+
+        let findings = lint(&recs);
+        assert_eq!(apply_fixes(&mut recs, &findings, Severity::Info), 1);
+        assert_eq!(lint(&recs), vec![]);
+        assert_eq!(recs[2].fee_amount, None);
+        assert_eq!(recs[2].fee_currency, "");
+    }
+
+    #[test]
+    fn test_lint_without_fixes() {
+        let mut no_currency = buy("0");
+        no_currency.fee_currency = String::new();
+        let mut no_quantity = buy("1");
+        no_quantity.received_quantity = None;
+        let recs = vec![no_currency, no_quantity, buy("1")];
+
+        let findings = lint(&recs);
+        assert_eq!(
+            rules(&findings),
+            vec![
+                (0, "orphan-fee"),
+                (1, "market-value-without-quantity"),
+                (2, "duplicate-external-id"),
+            ]
+        );
+        assert_eq!(findings[0].severity, Severity::Error);
+        assert_eq!(
+            findings[2].message,
+            "External ID 1 for Source Kraken is also record 1"
+        );
+        assert!(findings.iter().all(|f| f.fix.is_none()));
+        let mut fixed = recs.clone();
+        assert_eq!(apply_fixes(&mut fixed, &findings, Severity::Info), 0);
+        assert_eq!(fixed, recs);
+    }
+
+    #[test]
+    fn test_lint_midnight_run() {
+        let at = |time: i64, id: &str| {
+            let mut rec = buy(id);
+            rec.time = time;
+            rec
+        };
+        let recs = vec![
+            at(T, "0"),
+            at(T + 1, "1"),
+            at(T, "2"),
+            at(T + DAY_MS, "3"),
+            at(T - DAY_MS, "4"),
+        ];
+        let opts = LintOpts {
+            min_midnight_run: 3,
+        };
+        let findings = lint_with_opts(&recs, &opts);
+        assert_eq!(rules(&findings), vec![(2, "midnight-run")]);
+        assert_eq!(
+            findings[0].message,
+            "3 consecutive records are at midnight UTC exactly, the source may have only dates"
+        );
+        assert_eq!(lint(&recs), vec![]);
+    }
+}
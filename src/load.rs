@@ -0,0 +1,245 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    error::Error,
+    path::PathBuf,
+};
+
+use crate::{reader::file_reader, ReaderConfig, SkippedRow, TaxBitExportRec};
+
+/// Options for load_many_with_opts
+#[derive(Debug, Clone, Default)]
+pub struct LoadOpts {
+    /// Return an error at the first file which can't be read or row which
+    /// can't be parsed rather than recording it and loading the others
+    pub abort_on_error: bool,
+}
+
+/// What load_many read from one file
+#[derive(Debug)]
+pub struct LoadStats {
+    pub path: PathBuf,
+
+    /// The number of records read
+    pub records: usize,
+
+    /// The earliest and latest times of the records, None if there are
+    /// none
+    pub min_time: Option<i64>,
+    pub max_time: Option<i64>,
+
+    /// The distinct sources of the records
+    pub sources: BTreeSet<String>,
+
+    /// The rows which couldn't be parsed, numbered from the first data
+    /// row of the file
+    pub skipped: Vec<SkippedRow>,
+
+    /// The error which stopped reading the file, such as it not existing
+    /// or having an unknown header, the records before it are kept
+    pub error: Option<String>,
+}
+
+/// A source found in more than one file, most likely the same exchange
+/// exported twice or two exports given the same source name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceCollision {
+    pub source: String,
+
+    /// The files with records of source in the order they were loaded
+    pub paths: Vec<PathBuf>,
+}
+
+/// The result of load_many
+#[derive(Debug, Default)]
+pub struct LoadResult {
+    /// The records of all the files sorted
+    pub recs: Vec<TaxBitExportRec>,
+
+    /// The stats of each file in the order of the paths
+    pub files: Vec<LoadStats>,
+
+    /// The sources found in more than one file ordered by source
+    pub collisions: Vec<SourceCollision>,
+}
+
+/// Load TaxBit export files into one sorted Vec, see load_many_with_opts
+pub fn load_many(paths: &[PathBuf]) -> Result<LoadResult, Box<dyn Error>> {
+    load_many_with_opts(paths, &LoadOpts::default())
+}
+
+/// Load TaxBit export files, each read one record at a time as
+/// read_tb_export_rec_file reads it, into one sorted Vec with the stats
+/// of each file and the sources found in more than one file.
+///
+/// A file which can't be read or rows which can't be parsed are recorded
+/// in its LoadStats and the other files are still loaded, unless
+/// opts.abort_on_error is set.
+pub fn load_many_with_opts(
+    paths: &[PathBuf],
+    opts: &LoadOpts,
+) -> Result<LoadResult, Box<dyn Error>> {
+    let mut result = LoadResult::default();
+    for path in paths {
+        let mut stats = LoadStats {
+            path: path.clone(),
+            records: 0,
+            min_time: None,
+            max_time: None,
+            sources: BTreeSet::new(),
+            skipped: vec![],
+            error: None,
+        };
+        if let Err(e) = load_file(&mut result.recs, &mut stats, opts) {
+            if opts.abort_on_error {
+                return Err(format!("{}: {e}", path.display()).into());
+            }
+            stats.error = Some(e.to_string());
+        }
+        result.files.push(stats);
+    }
+    result.recs.sort();
+
+    let mut paths_of: BTreeMap<&str, Vec<PathBuf>> = BTreeMap::new();
+    for stats in &result.files {
+        for source in &stats.sources {
+            paths_of.entry(source).or_default().push(stats.path.clone());
+        }
+    }
+    result.collisions = paths_of
+        .into_iter()
+        .filter(|(_, paths)| paths.len() > 1)
+        .map(|(source, paths)| SourceCollision {
+            source: source.to_owned(),
+            paths,
+        })
+        .collect();
+
+    Ok(result)
+}
+
+// Append the records of the file at stats.path to recs updating stats
+fn load_file(
+    recs: &mut Vec<TaxBitExportRec>,
+    stats: &mut LoadStats,
+    opts: &LoadOpts,
+) -> Result<(), Box<dyn Error>> {
+    let mut reader = file_reader(&stats.path, &ReaderConfig::default(), false)?
+        .skip_errors(!opts.abort_on_error);
+    let result = reader.by_ref().try_for_each(|rec| {
+        let rec = rec?;
+        stats.records += 1;
+        stats.min_time = Some(stats.min_time.map_or(rec.time, |t| t.min(rec.time)));
+        stats.max_time = Some(stats.max_time.map_or(rec.time, |t| t.max(rec.time)));
+        if !stats.sources.contains(&rec.source) {
+            stats.sources.insert(rec.source.clone());
+        }
+        recs.push(rec);
+        Ok(())
+    });
+    stats.skipped = reader.take_skipped_rows();
+
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, path::Path};
+
+    use rust_decimal_macros::dec;
+    use taxbitrec::TaxBitRecType;
+
+    use super::*;
+    use crate::write_tb_export_rec_file;
+
+    // 2022-01-01T00:00:00Z
+    const T: i64 = 1640995200000;
+
+    fn rec(time: i64, source: &str) -> TaxBitExportRec {
+        let mut rec = TaxBitExportRec::new();
+        rec.time = time;
+        rec.type_txs = TaxBitRecType::Income;
+        rec.received_quantity = Some(dec!(1));
+        rec.received_currency = "BTC".to_owned();
+        rec.source = source.to_owned();
+        rec.external_id = format!("{source}-{time}");
+        rec
+    }
+
+    fn fixtures(dir: &Path) -> Vec<PathBuf> {
+        let paths: Vec<PathBuf> = ["kraken.csv", "coinbase.csv", "mixed.csv"]
+            .iter()
+            .map(|name| dir.join(name))
+            .collect();
+        write_tb_export_rec_file(&paths[0], &[rec(T + 2, "Kraken"), rec(T, "Kraken")]).unwrap();
+        write_tb_export_rec_file(&paths[1], &[rec(T + 1, "Coinbase")]).unwrap();
+        write_tb_export_rec_file(&paths[2], &[rec(T + 5, "Gemini"), rec(T + 3, "Kraken")]).unwrap();
+
+        // A row of the third file with a bad date
+        let mut csv = fs::read_to_string(&paths[2]).unwrap();
+        let bad_row = csv.lines().nth(1).unwrap().replacen("2022", "20x2", 1);
+        csv.push_str(&bad_row);
+        csv.push('\n');
+        fs::write(&paths[2], csv).unwrap();
+
+        paths
+    }
+
+    #[test]
+    fn test_load_many() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths = fixtures(dir.path());
+
+        let result = load_many(&paths).unwrap();
+        let times: Vec<i64> = result.recs.iter().map(|r| r.time).collect();
+        assert_eq!(times, vec![T, T + 1, T + 2, T + 3, T + 5]);
+
+        assert_eq!(result.files.len(), 3);
+        let kraken = &result.files[0];
+        assert_eq!(kraken.path, paths[0]);
+        assert_eq!(kraken.records, 2);
+        assert_eq!((kraken.min_time, kraken.max_time), (Some(T), Some(T + 2)));
+        assert_eq!(kraken.sources, BTreeSet::from(["Kraken".to_owned()]));
+        let mixed = &result.files[2];
+        assert_eq!(mixed.records, 2);
+        assert_eq!(
+            mixed.sources,
+            BTreeSet::from(["Gemini".to_owned(), "Kraken".to_owned()])
+        );
+        assert_eq!(mixed.skipped.len(), 1);
+        assert_eq!(mixed.skipped[0].row, 3);
+        assert!(result.files.iter().all(|f| f.error.is_none()));
+
+        assert_eq!(
+            result.collisions,
+            vec![SourceCollision {
+                source: "Kraken".to_owned(),
+                paths: vec![paths[0].clone(), paths[2].clone()],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_load_many_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut paths = fixtures(dir.path());
+        paths.insert(1, dir.path().join("missing.csv"));
+
+        // The missing file is reported and the others are loaded
+        let result = load_many(&paths).unwrap();
+        assert_eq!(result.recs.len(), 5);
+        assert!(result.files[1].error.is_some());
+        assert_eq!(result.files[1].records, 0);
+
+        let opts = LoadOpts {
+            abort_on_error: true,
+        };
+        let err = load_many_with_opts(&paths, &opts).unwrap_err();
+        assert!(err.to_string().contains("missing.csv"), "{err}");
+
+        // The bad row aborts the load
+        paths.remove(1);
+        let err = load_many_with_opts(&paths, &opts).unwrap_err();
+        assert!(err.to_string().contains("mixed.csv"), "{err}");
+        assert!(load_many_with_opts(&paths[..2], &opts).is_ok());
+    }
+}
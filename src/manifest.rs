@@ -0,0 +1,280 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    error::Error,
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+#[cfg(feature = "std-fs")]
+use std::{
+    fs::{self, File},
+    io::BufWriter,
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use taxbitrec::TaxBitRecType;
+
+#[cfg(feature = "std-fs")]
+use crate::{read_tb_export_rec_file, write_tb_export_rec_file};
+use crate::{stats, writer::rec_to_csv_fields, TaxBitExportRec, WriterConfig};
+
+/// A summary of a file of records used to verify it later, see
+/// write_with_manifest and verify_manifest
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub record_count: usize,
+    pub counts_by_type: BTreeMap<TaxBitRecType, usize>,
+    pub min_time: Option<i64>,
+    pub max_time: Option<i64>,
+
+    /// The distinct assets, see TaxBitExportRec::get_asset
+    pub assets: BTreeSet<String>,
+
+    /// Lower case hex SHA-256 of the records, see Manifest::new
+    pub sha256: String,
+}
+
+impl Manifest {
+    /// The manifest of the records. The hash is of each record's fields,
+    /// formatted as write_tb_export_rec_file does with the numbers
+    /// normalized, and lot_id so it doesn't depend on line endings,
+    /// quoting or trailing zeros, "1.250" and "1.25" hash the same.
+    /// Extras, like Eq, aren't included.
+    pub fn new(recs: &[TaxBitExportRec]) -> Result<Manifest, Box<dyn Error>> {
+        let mut hasher = Sha256::new();
+        for rec in recs {
+            let mut rec = rec.clone();
+            for d in [
+                &mut rec.received_quantity,
+                &mut rec.sent_quantity,
+                &mut rec.fee_amount,
+                &mut rec.market_value,
+            ] {
+                *d = d.map(|d| d.normalize());
+            }
+            let mut fields = rec_to_csv_fields(&rec, &WriterConfig::default())?;
+            fields.extend(rec.lot_id.clone());
+            for field in fields {
+                hasher.update(field.as_bytes());
+                hasher.update([0x1f]);
+            }
+            hasher.update([0x1e]);
+        }
+
+        let stats = stats(recs);
+        Ok(Manifest {
+            record_count: stats.total,
+            counts_by_type: stats.by_type,
+            min_time: stats.earliest,
+            max_time: stats.latest,
+            assets: stats.assets,
+            sha256: format!("{:x}", hasher.finalize()),
+        })
+    }
+
+    // Descriptions of the fields which differ from other
+    #[cfg(feature = "std-fs")]
+    fn differences(&self, other: &Manifest) -> Vec<String> {
+        let mut differences = vec![];
+        let mut check = |name: &str, a: String, b: String| {
+            if a != b {
+                differences.push(format!("{name}: expected {a} found {b}"));
+            }
+        };
+        check(
+            "record_count",
+            self.record_count.to_string(),
+            other.record_count.to_string(),
+        );
+        check(
+            "counts_by_type",
+            format!("{:?}", self.counts_by_type),
+            format!("{:?}", other.counts_by_type),
+        );
+        check(
+            "min_time",
+            format!("{:?}", self.min_time),
+            format!("{:?}", other.min_time),
+        );
+        check(
+            "max_time",
+            format!("{:?}", self.max_time),
+            format!("{:?}", other.max_time),
+        );
+        check(
+            "assets",
+            format!("{:?}", self.assets),
+            format!("{:?}", other.assets),
+        );
+        check("sha256", self.sha256.clone(), other.sha256.clone());
+
+        differences
+    }
+}
+
+/// The reason verify_manifest failed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ManifestMismatch {
+    /// The CSV or manifest couldn't be read
+    Unreadable(String),
+
+    /// The manifest doesn't match the CSV, a description of each field
+    /// which differs
+    Differs(Vec<String>),
+}
+
+impl Display for ManifestMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ManifestMismatch::Unreadable(e) => write!(f, "Unable to verify manifest: {e}"),
+            ManifestMismatch::Differs(differences) => {
+                write!(f, "Manifest mismatch: {}", differences.join(", "))
+            }
+        }
+    }
+}
+
+impl Error for ManifestMismatch {}
+
+/// The path of the manifest of the file at path, the file name with
+/// ".manifest.json" appended, "out.csv" has "out.csv.manifest.json"
+pub fn manifest_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".manifest.json");
+    path.with_file_name(name)
+}
+
+/// Write the records as write_tb_export_rec_file does and their Manifest
+/// as JSON to manifest_path(path), returning the manifest's path
+#[cfg(feature = "std-fs")]
+pub fn write_with_manifest(
+    path: &Path,
+    recs: &[TaxBitExportRec],
+) -> Result<PathBuf, Box<dyn Error>> {
+    let manifest = Manifest::new(recs)?;
+    write_tb_export_rec_file(path, recs)?;
+
+    let manifest_path = manifest_path(path);
+    serde_json::to_writer_pretty(BufWriter::new(File::create(&manifest_path)?), &manifest)?;
+
+    Ok(manifest_path)
+}
+
+/// Read the records in csv_path and check they match the manifest in
+/// manifest_path
+#[cfg(feature = "std-fs")]
+pub fn verify_manifest(csv_path: &Path, manifest_path: &Path) -> Result<(), ManifestMismatch> {
+    let unreadable = |e: Box<dyn Error>| ManifestMismatch::Unreadable(e.to_string());
+    let expected: Manifest =
+        serde_json::from_str(&fs::read_to_string(manifest_path).map_err(|e| unreadable(e.into()))?)
+            .map_err(|e| unreadable(e.into()))?;
+    let found = Manifest::new(&read_tb_export_rec_file(csv_path).map_err(unreadable)?)
+        .map_err(unreadable)?;
+
+    let differences = expected.differences(&found);
+    if differences.is_empty() {
+        Ok(())
+    } else {
+        Err(ManifestMismatch::Differs(differences))
+    }
+}
+
+#[cfg(all(test, feature = "std-fs"))]
+mod test {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    fn recs() -> Vec<TaxBitExportRec> {
+        let mut buy = TaxBitExportRec::new();
+        buy.time = 1609581600000;
+        buy.type_txs = TaxBitRecType::Buy;
+        buy.sent_quantity = Some(dec!(990));
+        buy.sent_currency = "USD".to_owned();
+        buy.received_quantity = Some(dec!(0.03));
+        buy.received_currency = "BTC".to_owned();
+        buy.external_id = "id-1".to_owned();
+
+        let mut income = TaxBitExportRec::new();
+        income.time = 1609668000000;
+        income.type_txs = TaxBitRecType::Income;
+        income.received_quantity = Some(dec!(1.25));
+        income.received_currency = "ADA".to_owned();
+        income.external_id = "id-2".to_owned();
+
+        vec![buy, income]
+    }
+
+    #[test]
+    fn test_manifest_verifies() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.csv");
+        let manifest = write_with_manifest(&path, &recs()).unwrap();
+        assert_eq!(manifest, dir.path().join("out.csv.manifest.json"));
+
+        let json: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&manifest).unwrap()).unwrap();
+        assert_eq!(json["record_count"], 2);
+        assert_eq!(json["counts_by_type"]["Buy"], 1);
+        assert_eq!(json["min_time"], 1609581600000i64);
+        assert_eq!(json["max_time"], 1609668000000i64);
+        assert_eq!(json["assets"], serde_json::json!(["ADA", "BTC"]));
+        assert_eq!(json["sha256"].as_str().unwrap().len(), 64);
+
+        assert_eq!(verify_manifest(&path, &manifest), Ok(()));
+    }
+
+    #[test]
+    fn test_manifest_line_endings() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.csv");
+        let manifest = write_with_manifest(&path, &recs()).unwrap();
+
+        let lf = fs::read_to_string(&path).unwrap();
+        assert!(!lf.contains('\r'));
+        fs::write(&path, lf.replace('\n', "\r\n")).unwrap();
+        assert_eq!(verify_manifest(&path, &manifest), Ok(()));
+    }
+
+    #[test]
+    fn test_manifest_tampered() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.csv");
+        let manifest = write_with_manifest(&path, &recs()).unwrap();
+
+        let csv = fs::read_to_string(&path).unwrap();
+        fs::write(&path, csv.replace(",1.25,", ",1.26,")).unwrap();
+        match verify_manifest(&path, &manifest) {
+            Err(ManifestMismatch::Differs(differences)) => {
+                assert_eq!(differences.len(), 1);
+                assert!(differences[0].starts_with("sha256: expected "));
+            }
+            r => panic!("Expected a mismatch, got {r:?}"),
+        }
+
+        let missing = dir.path().join("missing.csv");
+        assert!(matches!(
+            verify_manifest(&missing, &manifest),
+            Err(ManifestMismatch::Unreadable(_))
+        ));
+    }
+
+    #[test]
+    fn test_manifest_trailing_zeros() {
+        let mut zeros = recs();
+        zeros[1].received_quantity = Some(dec!(1.250));
+        zeros[0].sent_quantity = Some(dec!(990.00));
+        assert_eq!(
+            Manifest::new(&zeros).unwrap().sha256,
+            Manifest::new(&recs()).unwrap().sha256
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.csv");
+        let manifest = write_with_manifest(&path, &zeros).unwrap();
+        let csv = fs::read_to_string(&path).unwrap();
+        assert!(csv.contains(",1.250,"));
+        fs::write(&path, csv.replace(",1.250,", ",1.25,")).unwrap();
+        assert_eq!(verify_manifest(&path, &manifest), Ok(()));
+    }
+}
@@ -0,0 +1,442 @@
+//! Convert the CSV export of any platform to TaxBit export records with
+//! a MappingProfile, a JSON definition of where each field comes from,
+//! rather than a converter written for the platform.
+//!
+//! A profile for a platform exporting "Timestamp,Kind,Pair,Qty,Total"
+//! with the amount spent as a negative Total looks like:
+//!
+//! ```text
+//! {
+//!   "name": "Zorbex",
+//!   "time": { "Column": "Timestamp" },
+//!   "date_format": "%d.%m.%Y %H:%M:%S",
+//!   "type_txs": { "Column": "Kind" },
+//!   "types": { "buy": "Buy", "swap": "Trade" },
+//!   "received_quantity": { "Column": "Qty" },
+//!   "received_currency": { "SplitSymbolBase": "Pair" },
+//!   "sent_quantity": { "Negate": "Total" },
+//!   "sent_currency": { "SplitSymbolQuote": "Pair" },
+//!   "source": { "Constant": "Zorbex" }
+//! }
+//! ```
+use std::{collections::BTreeMap, error::Error, io::Read};
+#[cfg(feature = "std-fs")]
+use std::{fs::File, io::BufReader, path::Path};
+
+use chrono::{NaiveDate, NaiveDateTime};
+use rust_decimal::Decimal;
+use serde::{
+    de::{self, value, IntoDeserializer},
+    Deserialize, Deserializer,
+};
+use taxbitrec::TaxBitRecType;
+
+use crate::{
+    convert::parse_decimal_opt, de_taxbit_rec_type_lenient, dt_str_to_utc_time_ms_flexible,
+    normalize_column_name, reader::comma_decimal_to_period, DecimalLocale, RejectedRow,
+    TaxBitExportRec,
+};
+
+/// Where a MappingProfile takes the value of a field from
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub enum FieldSource {
+    /// The trimmed value of the column
+    Column(String),
+
+    /// The same value for every row
+    Constant(String),
+
+    /// The negated number in the column, for exports with the amounts
+    /// going out as negative numbers, only for the quantities, fee
+    /// amount and market value
+    Negate(String),
+
+    /// The base, "BTC", of the symbol in the column, "BTC/USD", "BTC-USD"
+    /// or "BTC_USD", only for the currencies and source
+    SplitSymbolBase(String),
+
+    /// The quote, "USD", of the symbol in the column, see SplitSymbolBase
+    SplitSymbolQuote(String),
+}
+
+impl FieldSource {
+    // The column the value is taken from, None for a Constant
+    fn column(&self) -> Option<&str> {
+        match self {
+            FieldSource::Constant(_) => None,
+            FieldSource::Column(c)
+            | FieldSource::Negate(c)
+            | FieldSource::SplitSymbolBase(c)
+            | FieldSource::SplitSymbolQuote(c) => Some(c),
+        }
+    }
+}
+
+// Deserializes the types table parsing the types as
+// de_taxbit_rec_type_lenient does
+fn de_types<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<BTreeMap<String, TaxBitRecType>, D::Error> {
+    BTreeMap::<String, String>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(name, type_txs)| {
+            let de = IntoDeserializer::<value::Error>::into_deserializer(type_txs.as_str());
+            de_taxbit_rec_type_lenient(de)
+                .map(|t| (name, t))
+                .map_err(de::Error::custom)
+        })
+        .collect()
+}
+
+/// How to convert the rows of a platform's CSV export, see the module
+/// documentation. A field without a FieldSource is empty, or None.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct MappingProfile {
+    /// The name of the platform, used in errors
+    pub name: String,
+
+    pub time: FieldSource,
+
+    /// The chrono format of time, with or without a time of day, which is
+    /// UTC. If None any format dt_str_to_utc_time_ms_flexible accepts.
+    #[serde(default)]
+    pub date_format: Option<String>,
+
+    /// The decimal separator of the quantities, fee amount and market value
+    #[serde(default)]
+    pub decimal_locale: DecimalLocale,
+
+    /// The platform's type of the row, looked up in types
+    pub type_txs: FieldSource,
+
+    /// The TaxBitRecType of each of the platform's types, matched
+    /// ignoring case. A row whose type isn't here is rejected.
+    #[serde(deserialize_with = "de_types")]
+    pub types: BTreeMap<String, TaxBitRecType>,
+
+    #[serde(default)]
+    pub received_quantity: Option<FieldSource>,
+    #[serde(default)]
+    pub received_currency: Option<FieldSource>,
+    #[serde(default)]
+    pub sent_quantity: Option<FieldSource>,
+    #[serde(default)]
+    pub sent_currency: Option<FieldSource>,
+    #[serde(default)]
+    pub fee_amount: Option<FieldSource>,
+    #[serde(default)]
+    pub fee_currency: Option<FieldSource>,
+    #[serde(default)]
+    pub market_value: Option<FieldSource>,
+    #[serde(default)]
+    pub source: Option<FieldSource>,
+    #[serde(default)]
+    pub external_id: Option<FieldSource>,
+}
+
+impl MappingProfile {
+    /// Load a profile from its JSON definition
+    pub fn from_reader<R: Read>(rdr: R) -> Result<MappingProfile, Box<dyn Error>> {
+        Ok(serde_json::from_reader(rdr)?)
+    }
+
+    #[cfg(feature = "std-fs")]
+    pub fn from_path(path: &Path) -> Result<MappingProfile, Box<dyn Error>> {
+        MappingProfile::from_reader(BufReader::new(File::open(path)?))
+    }
+
+    // The field names and sources of the fields holding text
+    fn text_fields(&self) -> [(&'static str, Option<&FieldSource>); 6] {
+        [
+            ("type_txs", Some(&self.type_txs)),
+            ("received_currency", self.received_currency.as_ref()),
+            ("sent_currency", self.sent_currency.as_ref()),
+            ("fee_currency", self.fee_currency.as_ref()),
+            ("source", self.source.as_ref()),
+            ("external_id", self.external_id.as_ref()),
+        ]
+    }
+
+    // The field names and sources of the fields holding numbers
+    fn decimal_fields(&self) -> [(&'static str, Option<&FieldSource>); 4] {
+        [
+            ("received_quantity", self.received_quantity.as_ref()),
+            ("sent_quantity", self.sent_quantity.as_ref()),
+            ("fee_amount", self.fee_amount.as_ref()),
+            ("market_value", self.market_value.as_ref()),
+        ]
+    }
+
+    // An error if a field has a FieldSource it can't use
+    fn check(&self) -> Result<(), String> {
+        let unusable = |field: &str, how: &str| format!("{how} can't be used for {field}");
+        if !matches!(self.time, FieldSource::Column(_) | FieldSource::Constant(_)) {
+            return Err(unusable("time", "Only Column or Constant"));
+        }
+        for (field, source) in self.text_fields() {
+            if let Some(FieldSource::Negate(_)) = source {
+                return Err(unusable(field, "Negate"));
+            }
+        }
+        for (field, source) in self.decimal_fields() {
+            if let Some(FieldSource::SplitSymbolBase(_) | FieldSource::SplitSymbolQuote(_)) = source
+            {
+                return Err(unusable(field, "SplitSymbolBase or SplitSymbolQuote"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// A MappingProfile with the index in the header of each column it uses
+struct Mapper<'p> {
+    profile: &'p MappingProfile,
+    indices: BTreeMap<&'p str, usize>,
+}
+
+impl<'p> Mapper<'p> {
+    fn new(profile: &'p MappingProfile, header: &csv::StringRecord) -> Result<Mapper<'p>, String> {
+        profile.check()?;
+
+        let mut indices = BTreeMap::new();
+        let mut missing: Vec<&str> = vec![];
+        let sources = [Some(&profile.time)]
+            .into_iter()
+            .chain(profile.text_fields().map(|(_, s)| s))
+            .chain(profile.decimal_fields().map(|(_, s)| s));
+        for column in sources.flatten().filter_map(|s| s.column()) {
+            let normalized = normalize_column_name(column);
+            match header
+                .iter()
+                .position(|h| normalize_column_name(h) == normalized)
+            {
+                Some(i) => {
+                    indices.insert(column, i);
+                }
+                None if !missing.contains(&column) => missing.push(column),
+                None => (),
+            }
+        }
+        if !missing.is_empty() {
+            return Err(format!(
+                "Not a {} CSV, missing columns: {}",
+                profile.name,
+                missing.join(", ")
+            ));
+        }
+
+        Ok(Mapper { profile, indices })
+    }
+
+    fn cell<'r>(&self, record: &'r csv::StringRecord, column: &str) -> &'r str {
+        record.get(self.indices[column]).unwrap_or("").trim()
+    }
+
+    fn text(
+        &self,
+        record: &csv::StringRecord,
+        source: Option<&FieldSource>,
+    ) -> Result<String, String> {
+        let split = |column: &str| -> Result<(String, String), String> {
+            let symbol = self.cell(record, column);
+            match symbol.split_once(['/', '-', '_']) {
+                Some((base, quote)) => Ok((base.trim().to_owned(), quote.trim().to_owned())),
+                None => Err(format!(
+                    "{column} '{symbol}' isn't a symbol such as BTC/USD"
+                )),
+            }
+        };
+        Ok(match source {
+            None => String::new(),
+            Some(FieldSource::Column(column)) => self.cell(record, column).to_owned(),
+            Some(FieldSource::Constant(value)) => value.clone(),
+            Some(FieldSource::SplitSymbolBase(column)) => split(column)?.0,
+            Some(FieldSource::SplitSymbolQuote(column)) => split(column)?.1,
+            Some(FieldSource::Negate(_)) => unreachable!("rejected by check"),
+        })
+    }
+
+    fn decimal(
+        &self,
+        record: &csv::StringRecord,
+        source: Option<&FieldSource>,
+    ) -> Result<Option<Decimal>, String> {
+        let parse = |name: &str, s: &str| -> Result<Option<Decimal>, String> {
+            match self.profile.decimal_locale {
+                DecimalLocale::Period => parse_decimal_opt(name, s),
+                DecimalLocale::CommaDecimal => match comma_decimal_to_period(s.trim()) {
+                    Some(s) => parse_decimal_opt(name, &s),
+                    None => Err(format!("{name} '{s}' isn't a comma decimal number")),
+                },
+            }
+        };
+        match source {
+            None => Ok(None),
+            Some(FieldSource::Column(column)) => parse(column, self.cell(record, column)),
+            Some(FieldSource::Constant(value)) => parse("Constant", value),
+            Some(FieldSource::Negate(column)) => {
+                Ok(parse(column, self.cell(record, column))?.map(|d| -d))
+            }
+            Some(FieldSource::SplitSymbolBase(_) | FieldSource::SplitSymbolQuote(_)) => {
+                unreachable!("rejected by check")
+            }
+        }
+    }
+
+    fn time(&self, record: &csv::StringRecord) -> Result<i64, String> {
+        let s = self.text(record, Some(&self.profile.time))?;
+        let Some(format) = &self.profile.date_format else {
+            return dt_str_to_utc_time_ms_flexible(&s);
+        };
+        let ndt = NaiveDateTime::parse_from_str(&s, format).or_else(|e| {
+            NaiveDate::parse_from_str(&s, format)
+                .map(|d| d.and_hms_opt(0, 0, 0).expect("SNH"))
+                .map_err(|_| format!("Unable to parse '{s}' as a date with '{format}': {e}"))
+        })?;
+
+        Ok(ndt.and_utc().timestamp_millis())
+    }
+
+    fn to_rec(&self, record: &csv::StringRecord) -> Result<TaxBitExportRec, String> {
+        let profile = self.profile;
+        let type_name = self.text(record, Some(&profile.type_txs))?;
+        let type_txs = profile
+            .types
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(&type_name))
+            .map(|(_, t)| t.clone())
+            .ok_or_else(|| format!("No mapping for type '{type_name}'"))?;
+
+        let mut rec = TaxBitExportRec::new();
+        rec.time = self.time(record)?;
+        rec.type_txs = type_txs;
+        rec.received_quantity = self.decimal(record, profile.received_quantity.as_ref())?;
+        rec.received_currency = self.text(record, profile.received_currency.as_ref())?;
+        rec.sent_quantity = self.decimal(record, profile.sent_quantity.as_ref())?;
+        rec.sent_currency = self.text(record, profile.sent_currency.as_ref())?;
+        rec.fee_amount = self.decimal(record, profile.fee_amount.as_ref())?;
+        rec.fee_currency = self.text(record, profile.fee_currency.as_ref())?;
+        rec.market_value = self.decimal(record, profile.market_value.as_ref())?;
+        rec.source = self.text(record, profile.source.as_ref())?;
+        rec.external_id = self.text(record, profile.external_id.as_ref())?;
+
+        Ok(rec)
+    }
+}
+
+/// Read a CSV as profile describes it returning the converted records
+/// and the rows which couldn't be converted, including those whose type
+/// has no mapping. An error is returned only if the input isn't a
+/// readable CSV with the columns profile uses or profile uses a
+/// FieldSource for a field it doesn't apply to.
+pub fn read_with_profile<R: Read>(
+    rdr: R,
+    profile: &MappingProfile,
+) -> Result<(Vec<TaxBitExportRec>, Vec<RejectedRow>), Box<dyn Error>> {
+    let mut reader = csv::Reader::from_reader(rdr);
+    let mapper = Mapper::new(profile, reader.headers()?)?;
+
+    let mut recs: Vec<TaxBitExportRec> = vec![];
+    let mut rejects: Vec<RejectedRow> = vec![];
+    for record in reader.records() {
+        let record = record?;
+        match mapper.to_rec(&record) {
+            Ok(rec) => recs.push(rec),
+            Err(reason) => rejects.push(RejectedRow::new(&record, reason)),
+        }
+    }
+
+    Ok((recs, rejects))
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    const PROFILE: &str = r#"{
+        "name": "Zorbex",
+        "time": { "Column": "Timestamp" },
+        "date_format": "%d.%m.%Y %H:%M:%S",
+        "decimal_locale": "CommaDecimal",
+        "type_txs": { "Column": "Kind" },
+        "types": { "buy": "Buy", "swap": "trade", "Staking Reward": "Income" },
+        "received_quantity": { "Column": "Qty" },
+        "received_currency": { "SplitSymbolBase": "Pair" },
+        "sent_quantity": { "Negate": "Total" },
+        "sent_currency": { "SplitSymbolQuote": "Pair" },
+        "fee_amount": { "Column": "Fee" },
+        "fee_currency": { "Column": "Fee Asset" },
+        "source": { "Constant": "Zorbex" },
+        "external_id": { "Column": "Ref" },
+        "comment": "unknown keys are ignored"
+    }"#;
+
+    const CSV: &str = "Timestamp,Kind,Pair,Qty,Total,Fee,Fee Asset,Ref\n\
+        01.03.2022 14:05:00,BUY,BTC/EUR,\"0,5\",\"-20.000,00\",\"1,5\",EUR,z-1\n\
+        02.03.2022 09:30:15,Swap,ETH-BTC,10,\"-0,75\",,,z-2\n\
+        03.03.2022 00:00:00,AIRDROP,XYZ/EUR,100,0,,,z-3\n\
+        04.03.2022 00:00:00,BUY,BTCEUR,1,-1,,,z-4\n";
+
+    #[test]
+    fn test_read_with_profile() {
+        let profile = MappingProfile::from_reader(PROFILE.as_bytes()).unwrap();
+        assert_eq!(profile.types["swap"], TaxBitRecType::Trade);
+        assert_eq!(profile.decimal_locale, DecimalLocale::CommaDecimal);
+
+        let (recs, rejects) = read_with_profile(CSV.as_bytes(), &profile).unwrap();
+        assert_eq!(recs.len(), 2);
+
+        let mut buy = TaxBitExportRec::new();
+        buy.time = 1646143500000;
+        buy.type_txs = TaxBitRecType::Buy;
+        buy.received_quantity = Some(dec!(0.5));
+        buy.received_currency = "BTC".to_owned();
+        buy.sent_quantity = Some(dec!(20000.00));
+        buy.sent_currency = "EUR".to_owned();
+        buy.fee_amount = Some(dec!(1.5));
+        buy.fee_currency = "EUR".to_owned();
+        buy.source = "Zorbex".to_owned();
+        buy.external_id = "z-1".to_owned();
+        assert!(recs[0].eq_strict(&buy), "{:?}", recs[0]);
+
+        assert_eq!(recs[1].time, 1646213415000);
+        assert_eq!(recs[1].type_txs, TaxBitRecType::Trade);
+        assert_eq!(recs[1].received_currency, "ETH");
+        assert_eq!(recs[1].sent_quantity, Some(dec!(0.75)));
+        assert_eq!(recs[1].sent_currency, "BTC");
+        assert_eq!(recs[1].fee_amount, None);
+        assert_eq!(recs[1].source, "Zorbex");
+
+        assert_eq!(rejects.len(), 2);
+        assert_eq!(rejects[0].line, 4);
+        assert_eq!(rejects[0].reason, "No mapping for type 'AIRDROP'");
+        assert_eq!(
+            rejects[1].reason,
+            "Pair 'BTCEUR' isn't a symbol such as BTC/USD"
+        );
+    }
+
+    #[test]
+    fn test_read_with_profile_errors() {
+        let mut profile = MappingProfile::from_reader(PROFILE.as_bytes()).unwrap();
+        let err = read_with_profile("Timestamp,Kind\n".as_bytes(), &profile).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Not a Zorbex CSV, missing columns: Pair, Fee Asset, Ref, Qty, Total, Fee"
+        );
+
+        profile.source = Some(FieldSource::Negate("Ref".to_owned()));
+        let err = read_with_profile(CSV.as_bytes(), &profile).unwrap_err();
+        assert_eq!(err.to_string(), "Negate can't be used for source");
+
+        let err =
+            MappingProfile::from_reader(PROFILE.replace("\"Income\"", "\"Staking\"").as_bytes())
+                .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Unknown Transaction Type 'Staking'"));
+    }
+}
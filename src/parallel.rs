@@ -0,0 +1,221 @@
+use std::{error::Error, fs, path::Path};
+
+use rayon::prelude::*;
+
+use crate::{read_tb_export_rec_file, RowError, TaxBitExportRec, TaxBitExportRecReader};
+
+// The offsets of the end of each record in data, a newline outside of
+// quotes, and of data itself if the last record has no newline. Empty
+// lines, which the csv reader skips, are part of the next record so
+// they aren't counted as rows.
+fn record_ends(data: &[u8]) -> Vec<usize> {
+    let mut ends: Vec<usize> = vec![];
+    let mut quoted = false;
+    let mut empty = true;
+    for (i, b) in data.iter().enumerate() {
+        match b {
+            b'\n' if !quoted => {
+                if !empty {
+                    ends.push(i + 1);
+                }
+                empty = true;
+            }
+            b'\r' => (),
+            b => {
+                if *b == b'"' {
+                    quoted = !quoted;
+                }
+                empty = false;
+            }
+        }
+    }
+    if !empty {
+        ends.push(data.len());
+    }
+
+    ends
+}
+
+// Parse the records of chunk, a run of whole records, using the file's
+// header. Row numbers of RowErrors are offset by first_row, the number
+// of data rows before the chunk.
+fn read_chunk(
+    header: &[u8],
+    chunk: &[u8],
+    first_row: usize,
+) -> Result<Vec<TaxBitExportRec>, Box<dyn Error + Send + Sync>> {
+    let mut data = header.to_vec();
+    data.extend_from_slice(chunk);
+
+    let to_send = |e: Box<dyn Error>| -> Box<dyn Error + Send + Sync> {
+        match e.downcast::<RowError>() {
+            Ok(mut row_error) => {
+                row_error.row += first_row;
+                row_error
+            }
+            Err(e) => e.to_string().into(),
+        }
+    };
+    let reader = TaxBitExportRecReader::new(data.as_slice()).map_err(to_send)?;
+    reader
+        .collect::<Result<Vec<TaxBitExportRec>, Box<dyn Error>>>()
+        .map_err(to_send)
+}
+
+/// Read a TaxBit export file as read_tb_export_rec_file does parsing
+/// chunks of chunk_rows records in parallel on the rayon thread pool.
+///
+/// The records are in file order and the row of a RowError is its row in
+/// the file. As the whole file is read into memory first gzip compressed
+/// files are read sequentially by read_tb_export_rec_file.
+pub fn read_tb_export_rec_file_parallel(
+    path: &Path,
+    chunk_rows: usize,
+) -> Result<Vec<TaxBitExportRec>, Box<dyn Error>> {
+    let data = fs::read(path)?;
+    if data.starts_with(&[0x1f, 0x8b]) || path.extension().is_some_and(|e| e == "gz") {
+        return read_tb_export_rec_file(path);
+    }
+
+    let ends = record_ends(&data);
+    let Some(header_end) = ends.first().copied() else {
+        // Empty, let the reader report the missing header
+        return read_tb_export_rec_file(path);
+    };
+    let header = &data[..header_end];
+
+    // (first row, start, end) of each chunk
+    let chunks: Vec<(usize, usize, usize)> = ends[1..]
+        .chunks(chunk_rows.max(1))
+        .enumerate()
+        .scan(header_end, |start, (i, chunk_ends)| {
+            let chunk = (i * chunk_rows.max(1), *start, *chunk_ends.last().unwrap());
+            *start = chunk.2;
+            Some(chunk)
+        })
+        .collect();
+    if chunks.is_empty() {
+        return TaxBitExportRecReader::new(header)?.collect();
+    }
+
+    let results: Vec<Result<Vec<TaxBitExportRec>, Box<dyn Error + Send + Sync>>> = chunks
+        .par_iter()
+        .map(|(first_row, start, end)| read_chunk(header, &data[*start..*end], *first_row))
+        .collect();
+
+    let mut recs: Vec<TaxBitExportRec> = Vec::with_capacity(ends.len() - 1);
+    for result in results {
+        recs.extend(result.map_err(|e| e as Box<dyn Error>)?);
+    }
+
+    Ok(recs)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{test_support::sample_recs_all_types, write_tb_export_rec_file};
+
+    #[test]
+    fn test_record_ends() {
+        assert_eq!(record_ends(b""), Vec::<usize>::new());
+        assert_eq!(record_ends(b"a,b\n1,2\n"), vec![4, 8]);
+        assert_eq!(record_ends(b"a,b\n1,2"), vec![4, 7]);
+        assert_eq!(
+            record_ends(b"a,b\n\"x\ny\",2\n3,\"\"\"\"\n"),
+            vec![4, 12, 19]
+        );
+        assert_eq!(record_ends(b"a,b\n\n1,2\r\n\r\n3,4\n\n"), vec![4, 10, 16]);
+        assert_eq!(record_ends(b"\na,b\n\"x\n\ny\",2"), vec![5, 13]);
+    }
+
+    #[test]
+    fn test_read_parallel_matches_sequential() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("large.csv");
+
+        let samples = sample_recs_all_types();
+        let mut recs: Vec<TaxBitExportRec> = (0..100_000)
+            .map(|i| {
+                let mut rec = samples[i % samples.len()].clone();
+                rec.time += i as i64;
+                rec.external_id = format!("id-{i}");
+                rec
+            })
+            .collect();
+        // A Source with a quoted newline mustn't split a record
+        recs[12_345].source = "Coinbase\nPro".to_owned();
+        write_tb_export_rec_file(&path, &recs).unwrap();
+
+        let sequential = read_tb_export_rec_file(&path).unwrap();
+        assert_eq!(sequential, recs);
+        for chunk_rows in [1_000, 7_777, 100_000, 1_000_000] {
+            let parallel = read_tb_export_rec_file_parallel(&path, chunk_rows).unwrap();
+            assert_eq!(parallel, sequential);
+        }
+    }
+
+    #[test]
+    fn test_read_parallel_row_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bad.csv");
+        write_tb_export_rec_file(&path, &vec![sample_recs_all_types(); 10].concat()).unwrap();
+        let csv = fs::read_to_string(&path).unwrap();
+        let mut lines: Vec<&str> = csv.lines().collect();
+        let bad = format!("yesterday{}", &lines[57][lines[57].find(',').unwrap()..]);
+        lines[57] = &bad;
+        fs::write(&path, lines.join("\n")).unwrap();
+
+        let sequential = read_tb_export_rec_file(&path)
+            .unwrap_err()
+            .downcast::<RowError>()
+            .unwrap();
+        let parallel = read_tb_export_rec_file_parallel(&path, 8)
+            .unwrap_err()
+            .downcast::<RowError>()
+            .unwrap();
+        assert_eq!(parallel.row, 57);
+        assert_eq!(parallel.row, sequential.row);
+        assert_eq!(parallel.to_string(), sequential.to_string());
+    }
+
+    #[test]
+    fn test_read_parallel_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("empty.csv");
+        write_tb_export_rec_file(&path, &[]).unwrap();
+        assert!(read_tb_export_rec_file_parallel(&path, 10)
+            .unwrap()
+            .is_empty());
+
+        fs::write(&path, "").unwrap();
+        assert!(read_tb_export_rec_file_parallel(&path, 10).is_err());
+    }
+
+    #[test]
+    fn test_read_parallel_blank_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("blank.csv");
+        write_tb_export_rec_file(&path, &vec![sample_recs_all_types(); 10].concat()).unwrap();
+        let csv = fs::read_to_string(&path).unwrap();
+        let mut lines: Vec<String> = csv.lines().map(|l| l.to_owned()).collect();
+        lines[57] = format!("yesterday{}", &lines[57][lines[57].find(',').unwrap()..]);
+        for i in [50, 20, 3] {
+            lines.insert(i, String::new());
+        }
+        fs::write(&path, lines.join("\n") + "\n\n").unwrap();
+
+        let sequential = read_tb_export_rec_file(&path)
+            .unwrap_err()
+            .downcast::<RowError>()
+            .unwrap();
+        for chunk_rows in [1, 8, 1_000] {
+            let parallel = read_tb_export_rec_file_parallel(&path, chunk_rows)
+                .unwrap_err()
+                .downcast::<RowError>()
+                .unwrap();
+            assert_eq!(parallel.row, 57);
+            assert_eq!(parallel.row, sequential.row);
+        }
+    }
+}
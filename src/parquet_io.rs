@@ -0,0 +1,297 @@
+use std::{error::Error, fs::File, path::Path, sync::Arc};
+
+use arrow_array::{
+    Array, ArrayRef, BooleanArray, Decimal128Array, RecordBatch, StringArray,
+    TimestampMillisecondArray,
+};
+use arrow_schema::{DataType, Field, Schema};
+use parquet::{
+    arrow::{arrow_reader::ParquetRecordBatchReaderBuilder, ArrowWriter},
+    file::properties::WriterProperties,
+    schema::types::ColumnPath,
+};
+use rust_decimal::Decimal;
+
+use crate::{TaxBitExportRec, TB_EXPORT_REC_HEADER};
+
+// The columns which are dictionary encoded, they have few distinct values
+const DICTIONARY_COLUMNS: [&str; 5] = [
+    "Transaction Type",
+    "Received Currency",
+    "Sent Currency",
+    "Fee Currency",
+    "Source",
+];
+
+// The precision of the Decimal128 columns
+const DECIMAL_PRECISION: u8 = 38;
+
+// A Decimal128 column of values with the largest scale of any of them
+fn decimal_array(values: &[Option<Decimal>], name: &str) -> Result<ArrayRef, Box<dyn Error>> {
+    let scale = values
+        .iter()
+        .flatten()
+        .map(|d| d.scale())
+        .max()
+        .unwrap_or(0);
+    let mut mantissas: Vec<Option<i128>> = Vec::with_capacity(values.len());
+    for d in values {
+        mantissas.push(match d {
+            Some(d) => {
+                let m = 10i128
+                    .checked_pow(scale - d.scale())
+                    .and_then(|p| d.mantissa().checked_mul(p))
+                    .filter(|m| m.unsigned_abs() < 10u128.pow(DECIMAL_PRECISION as u32));
+                Some(m.ok_or_else(|| {
+                    format!("{name} {d} doesn't fit Decimal128({DECIMAL_PRECISION}, {scale})")
+                })?)
+            }
+            None => None,
+        });
+    }
+
+    Ok(Arc::new(
+        Decimal128Array::from(mantissas)
+            .with_precision_and_scale(DECIMAL_PRECISION, scale as i8)?,
+    ))
+}
+
+// The Decimal of mantissa and scale, dropping the trailing zeros a large
+// value may have been padded with to the scale of its column if needed
+fn to_decimal(mut mantissa: i128, mut scale: u32) -> Result<Decimal, Box<dyn Error>> {
+    while Decimal::try_from_i128_with_scale(mantissa, scale).is_err()
+        && scale > 0
+        && mantissa % 10 == 0
+    {
+        mantissa /= 10;
+        scale -= 1;
+    }
+
+    Ok(Decimal::try_from_i128_with_scale(mantissa, scale)?)
+}
+
+/// Write the records to a Parquet file of the TaxBit columns named as
+/// in the CSV header, the lot ID and extras aren't written. The Date is
+/// a UTC millisecond timestamp, the quantities, fee amount and market
+/// value are Decimal128 with the largest scale of the column's values,
+/// Internal Transfer is a boolean and the other columns are strings. The
+/// transaction type, currencies and source are dictionary encoded.
+///
+/// A Decimal128 column has one scale so the scale of each value isn't
+/// kept, 0.5 in a column with 0.25 is read back as 0.50. The values are
+/// equal but their strings differ, compare Decimal::normalize if that
+/// matters.
+pub fn write_tb_export_rec_parquet(
+    path: &Path,
+    recs: &[TaxBitExportRec],
+) -> Result<(), Box<dyn Error>> {
+    let string_array = |f: fn(&TaxBitExportRec) -> &str| -> ArrayRef {
+        Arc::new(StringArray::from_iter_values(recs.iter().map(f)))
+    };
+    let mut types: Vec<String> = Vec::with_capacity(recs.len());
+    for rec in recs {
+        let type_txs = serde_json::to_value(&rec.type_txs)?;
+        types.push(type_txs.as_str().unwrap_or_default().to_owned());
+    }
+    let decimals = |f: fn(&TaxBitExportRec) -> Option<Decimal>| -> Vec<Option<Decimal>> {
+        recs.iter().map(f).collect()
+    };
+
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(
+            TimestampMillisecondArray::from_iter_values(recs.iter().map(|r| r.time))
+                .with_timezone("UTC"),
+        ),
+        Arc::new(StringArray::from(types)),
+        decimal_array(&decimals(|r| r.received_quantity), "Received Quantity")?,
+        string_array(|r| &r.received_currency),
+        decimal_array(&decimals(|r| r.sent_quantity), "Sent Quantity")?,
+        string_array(|r| &r.sent_currency),
+        string_array(|r| &r.fee_currency),
+        decimal_array(&decimals(|r| r.fee_amount), "Fee Amount")?,
+        decimal_array(&decimals(|r| r.market_value), "Market Value")?,
+        string_array(|r| &r.source),
+        Arc::new(BooleanArray::from_iter(
+            recs.iter().map(|r| Some(r.internal_transfer)),
+        )),
+        string_array(|r| &r.external_id),
+    ];
+    let fields: Vec<Field> = TB_EXPORT_REC_HEADER
+        .iter()
+        .zip(&columns)
+        .map(|(name, column)| {
+            let nullable = matches!(column.data_type(), DataType::Decimal128(..));
+            Field::new(*name, column.data_type().clone(), nullable)
+        })
+        .collect();
+    let schema = Arc::new(Schema::new(fields));
+    let batch = RecordBatch::try_new(schema.clone(), columns)?;
+
+    let mut props = WriterProperties::builder().set_dictionary_enabled(false);
+    for name in DICTIONARY_COLUMNS {
+        props = props.set_column_dictionary_enabled(ColumnPath::from(name), true);
+    }
+    let mut writer = ArrowWriter::try_new(File::create(path)?, schema, Some(props.build()))?;
+    writer.write(&batch)?;
+    writer.close()?;
+
+    Ok(())
+}
+
+/// Read the records of a Parquet file written by
+/// write_tb_export_rec_parquet. The decimals are read with the scale of
+/// their column, so they're equal to those written but may have more
+/// trailing zeros.
+pub fn read_tb_export_rec_parquet(path: &Path) -> Result<Vec<TaxBitExportRec>, Box<dyn Error>> {
+    let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(path)?)?.build()?;
+
+    let mut recs: Vec<TaxBitExportRec> = vec![];
+    for batch in reader {
+        let batch = batch?;
+        let column = |name: &str| {
+            batch
+                .column_by_name(name)
+                .ok_or_else(|| format!("No {name} column"))
+        };
+        fn downcast<'a, T: 'static>(
+            column: &'a ArrayRef,
+            name: &str,
+        ) -> Result<&'a T, Box<dyn Error>> {
+            column
+                .as_any()
+                .downcast_ref::<T>()
+                .ok_or_else(|| format!("{name} has type {}", column.data_type()).into())
+        }
+        let strings = |name: &str| -> Result<&StringArray, Box<dyn Error>> {
+            downcast::<StringArray>(column(name)?, name)
+        };
+        let decimals = |name: &str| -> Result<&Decimal128Array, Box<dyn Error>> {
+            downcast::<Decimal128Array>(column(name)?, name)
+        };
+
+        let times = downcast::<TimestampMillisecondArray>(column("Date")?, "Date")?;
+        let types = strings("Transaction Type")?;
+        let received = decimals("Received Quantity")?;
+        let received_currency = strings("Received Currency")?;
+        let sent = decimals("Sent Quantity")?;
+        let sent_currency = strings("Sent Currency")?;
+        let fee_currency = strings("Fee Currency")?;
+        let fee = decimals("Fee Amount")?;
+        let market_value = decimals("Market Value")?;
+        let source = strings("Source")?;
+        let internal_transfer =
+            downcast::<BooleanArray>(column("Internal Transfer")?, "Internal Transfer")?;
+        let external_id = strings("External ID")?;
+
+        let decimal =
+            |array: &Decimal128Array, i: usize| -> Result<Option<Decimal>, Box<dyn Error>> {
+                match array.is_null(i) {
+                    true => Ok(None),
+                    false => Ok(Some(to_decimal(array.value(i), array.scale() as u32)?)),
+                }
+            };
+        for i in 0..batch.num_rows() {
+            let mut rec = TaxBitExportRec::new();
+            rec.time = times.value(i);
+            rec.type_txs =
+                serde_json::from_value(serde_json::Value::String(types.value(i).to_owned()))?;
+            rec.received_quantity = decimal(received, i)?;
+            rec.received_currency = received_currency.value(i).to_owned();
+            rec.sent_quantity = decimal(sent, i)?;
+            rec.sent_currency = sent_currency.value(i).to_owned();
+            rec.fee_currency = fee_currency.value(i).to_owned();
+            rec.fee_amount = decimal(fee, i)?;
+            rec.market_value = decimal(market_value, i)?;
+            rec.source = source.value(i).to_owned();
+            rec.internal_transfer = internal_transfer.value(i);
+            rec.external_id = external_id.value(i).to_owned();
+            recs.push(rec);
+        }
+    }
+
+    Ok(recs)
+}
+
+#[cfg(test)]
+mod test {
+    use arrow_schema::TimeUnit;
+    use parquet::{basic::Encoding, file::reader::FileReader};
+    use rust_decimal_macros::dec;
+    use taxbitrec::TaxBitRecType;
+
+    use super::*;
+    use crate::test_support::sample_buy_and_income;
+
+    fn recs() -> Vec<TaxBitExportRec> {
+        let mut transfer = TaxBitExportRec::new();
+        transfer.time = 1640995200001;
+        transfer.type_txs = TaxBitRecType::TransferOut;
+        transfer.sent_quantity = Some(dec!(-79228162514264337593543950335));
+        transfer.sent_currency = "SHIB".to_owned();
+        transfer.source = "BinanceUS".to_owned();
+
+        [sample_buy_and_income(), vec![transfer]].concat()
+    }
+
+    #[test]
+    fn test_parquet_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("recs.parquet");
+        let recs = recs();
+        write_tb_export_rec_parquet(&path, &recs).unwrap();
+        let read = read_tb_export_rec_parquet(&path).unwrap();
+        assert_eq!(read, recs);
+
+        // Padded to the column scale of 8, that of 0.12345678
+        let income = read[1].received_quantity.unwrap();
+        assert_eq!(income.to_string(), "0.00000030");
+        assert_eq!(income.normalize(), recs[1].received_quantity.unwrap());
+
+        write_tb_export_rec_parquet(&path, &[]).unwrap();
+        assert_eq!(read_tb_export_rec_parquet(&path).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_parquet_schema() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("recs.parquet");
+        write_tb_export_rec_parquet(&path, &recs()).unwrap();
+
+        let builder = ParquetRecordBatchReaderBuilder::try_new(File::open(&path).unwrap()).unwrap();
+        let schema = builder.schema();
+        let names: Vec<&str> = schema.fields().iter().map(|f| f.name().as_str()).collect();
+        assert_eq!(names, TB_EXPORT_REC_HEADER);
+        assert_eq!(
+            schema.field(0).data_type(),
+            &DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into()))
+        );
+        assert_eq!(schema.field(2).data_type(), &DataType::Decimal128(38, 8));
+        assert_eq!(schema.field(10).data_type(), &DataType::Boolean);
+
+        let reader =
+            parquet::file::reader::SerializedFileReader::new(File::open(&path).unwrap()).unwrap();
+        let row_group = reader.metadata().row_group(0);
+        let dictionary = |name: &str| {
+            let column = row_group
+                .columns()
+                .iter()
+                .find(|c| c.column_path().string() == name)
+                .unwrap();
+            column.encodings().contains(&Encoding::RLE_DICTIONARY)
+        };
+        assert!(dictionary("Source"));
+        assert!(dictionary("Transaction Type"));
+        assert!(!dictionary("External ID"));
+    }
+
+    #[test]
+    fn test_parquet_decimal_overflow() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("recs.parquet");
+        let mut recs = recs();
+        recs[0].market_value = Some(dec!(79228162514264337593543950335));
+        recs[1].market_value = Some(dec!(0.0000000000001));
+        let err = write_tb_export_rec_parquet(&path, &recs).unwrap_err();
+        assert!(err.to_string().contains("Market Value"), "{err}");
+    }
+}
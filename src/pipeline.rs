@@ -0,0 +1,511 @@
+#[cfg(feature = "std-fs")]
+use std::path::Path;
+use std::{error::Error, fmt::Display};
+
+use rust_decimal::{Decimal, RoundingStrategy};
+
+use crate::{
+    find_duplicate_ids, mark_internal_transfers, pair_transfers, PairOpts, TaxBitExportRec,
+    WriteViolation,
+};
+#[cfg(feature = "std-fs")]
+use crate::{read_tb_export_rec_file, write_tb_export_rec_file};
+
+/// What a PipelineStep changed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StepReport {
+    /// The name of the step, see PipelineStep::name
+    pub step: String,
+
+    /// Number of records the step modified, moved or removed
+    pub changed: usize,
+
+    /// What was changed, for example "2 duplicates removed"
+    pub detail: String,
+}
+
+/// A transformation of the records run by a Pipeline, implement it to
+/// add a step to Pipeline::step
+pub trait PipelineStep {
+    /// The name used in the StepReport and a PipelineError
+    fn name(&self) -> &str;
+
+    /// Transform recs in place, an error stops the pipeline
+    fn apply(&self, recs: &mut Vec<TaxBitExportRec>) -> Result<StepReport, Box<dyn Error>>;
+}
+
+/// The reports of the steps a Pipeline ran
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PipelineReport {
+    /// Number of records passed to the pipeline
+    pub input: usize,
+
+    /// Number of records after the last step run
+    pub output: usize,
+
+    /// The report of each step in the order they ran
+    pub steps: Vec<StepReport>,
+}
+
+/// A step of a Pipeline failed, the reports of the steps before it are
+/// in report
+#[derive(Debug)]
+pub struct PipelineError {
+    /// The name of the step that failed
+    pub step: String,
+
+    pub error: Box<dyn Error>,
+
+    pub report: PipelineReport,
+}
+
+impl Display for PipelineError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Pipeline step {} failed: {}", self.step, self.error)
+    }
+}
+
+impl Error for PipelineError {}
+
+/// A sequence of steps transforming records in place, built by chaining
+/// the step methods and run with run or run_file. The steps run in the
+/// order they're added.
+#[derive(Default)]
+pub struct Pipeline {
+    steps: Vec<Box<dyn PipelineStep>>,
+}
+
+impl Pipeline {
+    pub fn new() -> Pipeline {
+        Pipeline::default()
+    }
+
+    /// Add a step
+    pub fn step(mut self, step: impl PipelineStep + 'static) -> Pipeline {
+        self.steps.push(Box::new(step));
+        self
+    }
+
+    /// Trim the whitespace around the currencies, source and external ID
+    pub fn trim_strings(self) -> Pipeline {
+        self.step(TrimStrings)
+    }
+
+    /// Round the quantities, fee amount and market value to at most scale
+    /// decimal places, midpoints away from zero, and remove trailing zeros
+    pub fn normalize_decimals(self, scale: u32) -> Pipeline {
+        self.step(NormalizeDecimals { scale })
+    }
+
+    /// Sort the records, see Ord for TaxBitExportRec, the sort is stable
+    pub fn sort(self) -> Pipeline {
+        self.step(Sort)
+    }
+
+    /// Remove the records with the source and non-empty external ID of an
+    /// earlier record, see find_duplicate_ids
+    pub fn dedupe_by_external_id(self) -> Pipeline {
+        self.step(DedupeByExternalId)
+    }
+
+    /// Pair the transfers and mark the pairs as internal, see
+    /// auto_mark_internal_transfers
+    pub fn auto_mark_internal_transfers(self, opts: PairOpts) -> Pipeline {
+        self.step(AutoMarkInternalTransfers { opts })
+    }
+
+    /// Fail if any record isn't valid, see TaxBitExportRec::validate
+    pub fn validate_strict(self) -> Pipeline {
+        self.step(ValidateStrict)
+    }
+
+    /// The names of the steps in the order they run
+    pub fn step_names(&self) -> Vec<&str> {
+        self.steps.iter().map(|s| s.name()).collect()
+    }
+
+    /// Run the steps on recs returning the transformed records and the
+    /// report of each step. The first step to fail stops the pipeline.
+    pub fn run(
+        &self,
+        mut recs: Vec<TaxBitExportRec>,
+    ) -> Result<(Vec<TaxBitExportRec>, PipelineReport), PipelineError> {
+        let mut report = PipelineReport {
+            input: recs.len(),
+            ..PipelineReport::default()
+        };
+        for step in &self.steps {
+            match step.apply(&mut recs) {
+                Ok(step_report) => report.steps.push(step_report),
+                Err(error) => {
+                    report.output = recs.len();
+                    return Err(PipelineError {
+                        step: step.name().to_owned(),
+                        error,
+                        report,
+                    });
+                }
+            }
+        }
+        report.output = recs.len();
+
+        Ok((recs, report))
+    }
+
+    /// Read in_path, run the steps and write the records to out_path,
+    /// which isn't written if a step fails. A failed step is returned as
+    /// a PipelineError.
+    #[cfg(feature = "std-fs")]
+    pub fn run_file(
+        &self,
+        in_path: &Path,
+        out_path: &Path,
+    ) -> Result<PipelineReport, Box<dyn Error>> {
+        let (recs, report) = self.run(read_tb_export_rec_file(in_path)?)?;
+        write_tb_export_rec_file(out_path, &recs)?;
+
+        Ok(report)
+    }
+}
+
+// A StepReport of the changed records
+fn step_report(step: &dyn PipelineStep, changed: usize, detail: String) -> StepReport {
+    StepReport {
+        step: step.name().to_owned(),
+        changed,
+        detail,
+    }
+}
+
+struct TrimStrings;
+
+impl PipelineStep for TrimStrings {
+    fn name(&self) -> &str {
+        "trim_strings"
+    }
+
+    fn apply(&self, recs: &mut Vec<TaxBitExportRec>) -> Result<StepReport, Box<dyn Error>> {
+        let mut changed = 0;
+        for rec in recs.iter_mut() {
+            let mut trimmed = false;
+            for s in [
+                &mut rec.received_currency,
+                &mut rec.sent_currency,
+                &mut rec.fee_currency,
+                &mut rec.source,
+                &mut rec.external_id,
+            ] {
+                if s.trim().len() != s.len() {
+                    *s = s.trim().to_owned();
+                    trimmed = true;
+                }
+            }
+            changed += trimmed as usize;
+        }
+
+        Ok(step_report(
+            self,
+            changed,
+            format!("{changed} records trimmed"),
+        ))
+    }
+}
+
+struct NormalizeDecimals {
+    scale: u32,
+}
+
+impl PipelineStep for NormalizeDecimals {
+    fn name(&self) -> &str {
+        "normalize_decimals"
+    }
+
+    fn apply(&self, recs: &mut Vec<TaxBitExportRec>) -> Result<StepReport, Box<dyn Error>> {
+        // Decimal's == ignores the scale so 1.50 == 1.5, compare the
+        // representations
+        let normalize = |d: &mut Option<Decimal>| -> bool {
+            let Some(old) = *d else {
+                return false;
+            };
+            let new = old
+                .round_dp_with_strategy(self.scale, RoundingStrategy::MidpointAwayFromZero)
+                .normalize();
+            *d = Some(new);
+            new.serialize() != old.serialize()
+        };
+
+        let mut changed = 0;
+        for rec in recs.iter_mut() {
+            let mut normalized = false;
+            for d in [
+                &mut rec.received_quantity,
+                &mut rec.sent_quantity,
+                &mut rec.fee_amount,
+                &mut rec.market_value,
+            ] {
+                normalized |= normalize(d);
+            }
+            changed += normalized as usize;
+        }
+
+        Ok(step_report(
+            self,
+            changed,
+            format!(
+                "{changed} records normalized to at most {} places",
+                self.scale
+            ),
+        ))
+    }
+}
+
+struct Sort;
+
+impl PipelineStep for Sort {
+    fn name(&self) -> &str {
+        "sort"
+    }
+
+    fn apply(&self, recs: &mut Vec<TaxBitExportRec>) -> Result<StepReport, Box<dyn Error>> {
+        let mut order: Vec<usize> = (0..recs.len()).collect();
+        order.sort_by(|a, b| recs[*a].cmp(&recs[*b]));
+        let changed = order.iter().enumerate().filter(|(i, o)| i != *o).count();
+        if changed != 0 {
+            let mut old: Vec<Option<TaxBitExportRec>> = recs.drain(..).map(Some).collect();
+            recs.extend(order.into_iter().map(|o| old[o].take().expect("SNH")));
+        }
+
+        Ok(step_report(
+            self,
+            changed,
+            format!("{changed} records moved"),
+        ))
+    }
+}
+
+struct DedupeByExternalId;
+
+impl PipelineStep for DedupeByExternalId {
+    fn name(&self) -> &str {
+        "dedupe_by_external_id"
+    }
+
+    fn apply(&self, recs: &mut Vec<TaxBitExportRec>) -> Result<StepReport, Box<dyn Error>> {
+        let mut keep = vec![true; recs.len()];
+        for indices in find_duplicate_ids(recs).values() {
+            for i in &indices[1..] {
+                keep[*i] = false;
+            }
+        }
+        let len = recs.len();
+        let mut keep = keep.into_iter();
+        recs.retain(|_| keep.next().expect("SNH"));
+        let changed = len - recs.len();
+
+        Ok(step_report(
+            self,
+            changed,
+            format!("{changed} duplicates removed"),
+        ))
+    }
+}
+
+struct AutoMarkInternalTransfers {
+    opts: PairOpts,
+}
+
+impl PipelineStep for AutoMarkInternalTransfers {
+    fn name(&self) -> &str {
+        "auto_mark_internal_transfers"
+    }
+
+    fn apply(&self, recs: &mut Vec<TaxBitExportRec>) -> Result<StepReport, Box<dyn Error>> {
+        let pairing = pair_transfers(recs, self.opts.clone());
+        let changed = mark_internal_transfers(recs, &pairing);
+
+        Ok(step_report(
+            self,
+            changed,
+            format!(
+                "{} pairs, {changed} records marked, {} outs and {} ins unmatched",
+                pairing.pairs.len(),
+                pairing.unmatched_outs.len(),
+                pairing.unmatched_ins.len()
+            ),
+        ))
+    }
+}
+
+struct ValidateStrict;
+
+impl PipelineStep for ValidateStrict {
+    fn name(&self) -> &str {
+        "validate_strict"
+    }
+
+    fn apply(&self, recs: &mut Vec<TaxBitExportRec>) -> Result<StepReport, Box<dyn Error>> {
+        let invalid: Vec<String> = recs
+            .iter()
+            .enumerate()
+            .filter_map(|(index, rec)| {
+                rec.validate()
+                    .err()
+                    .map(|errors| WriteViolation::Invalid { index, errors }.to_string())
+            })
+            .collect();
+        if !invalid.is_empty() {
+            return Err(invalid.join("; ").into());
+        }
+
+        Ok(step_report(
+            self,
+            0,
+            format!("{} records valid", recs.len()),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal_macros::dec;
+    use taxbitrec::TaxBitRecType;
+
+    use super::*;
+
+    // 2022-01-01T00:00:00Z
+    const Y2022: i64 = 1640995200000;
+
+    fn buy(time: i64, quantity: Decimal, asset: &str, id: &str) -> TaxBitExportRec {
+        let mut rec = TaxBitExportRec::new();
+        rec.time = time;
+        rec.type_txs = TaxBitRecType::Buy;
+        rec.received_quantity = Some(quantity);
+        rec.received_currency = asset.to_owned();
+        rec.sent_quantity = Some(dec!(100));
+        rec.sent_currency = "USD".to_owned();
+        rec.source = "Coinbase".to_owned();
+        rec.external_id = id.to_owned();
+        rec
+    }
+
+    fn recs() -> Vec<TaxBitExportRec> {
+        let mut padded = buy(Y2022 + 2, dec!(1.123456789), "ETH", " b ");
+        padded.received_currency = "ETH ".to_owned();
+        vec![
+            buy(Y2022 + 1, dec!(0.50), "BTC", "a"),
+            padded,
+            buy(Y2022, dec!(2), "ETH", "b"),
+            buy(Y2022 + 3, dec!(3), "ADA", ""),
+            buy(Y2022 + 4, dec!(3), "ADA", ""),
+        ]
+    }
+
+    #[test]
+    fn test_pipeline_run() {
+        let pipeline = Pipeline::new()
+            .trim_strings()
+            .normalize_decimals(4)
+            .dedupe_by_external_id();
+        assert_eq!(
+            pipeline.step_names(),
+            vec![
+                "trim_strings",
+                "normalize_decimals",
+                "dedupe_by_external_id"
+            ]
+        );
+
+        let (out, report) = pipeline.run(recs()).unwrap();
+        let mut expected = recs();
+        expected[0].received_quantity = Some(dec!(0.5));
+        expected[1] = buy(Y2022 + 2, dec!(1.1235), "ETH", "b");
+        expected.remove(2);
+        assert_eq!(out.len(), 4);
+        for (o, e) in out.iter().zip(&expected) {
+            assert!(o.eq_strict(e), "{o:?} != {e:?}");
+        }
+
+        assert_eq!(report.input, 5);
+        assert_eq!(report.output, 4);
+        assert_eq!(
+            report.steps,
+            vec![
+                StepReport {
+                    step: "trim_strings".to_owned(),
+                    changed: 1,
+                    detail: "1 records trimmed".to_owned(),
+                },
+                StepReport {
+                    step: "normalize_decimals".to_owned(),
+                    changed: 2,
+                    detail: "2 records normalized to at most 4 places".to_owned(),
+                },
+                StepReport {
+                    step: "dedupe_by_external_id".to_owned(),
+                    changed: 1,
+                    detail: "1 duplicates removed".to_owned(),
+                },
+            ]
+        );
+    }
+
+    struct Fail;
+
+    impl PipelineStep for Fail {
+        fn name(&self) -> &str {
+            "fail"
+        }
+
+        fn apply(&self, _recs: &mut Vec<TaxBitExportRec>) -> Result<StepReport, Box<dyn Error>> {
+            Err("out of coffee".into())
+        }
+    }
+
+    #[test]
+    fn test_pipeline_short_circuits() {
+        let err = Pipeline::new()
+            .sort()
+            .step(Fail)
+            .trim_strings()
+            .run(recs())
+            .unwrap_err();
+        assert_eq!(err.step, "fail");
+        assert_eq!(err.to_string(), "Pipeline step fail failed: out of coffee");
+        assert_eq!(err.report.steps.len(), 1);
+        assert_eq!(err.report.steps[0].step, "sort");
+        assert_eq!(err.report.steps[0].changed, 3);
+
+        let mut recs = recs();
+        recs[3].received_currency.clear();
+        let err = Pipeline::new().validate_strict().run(recs).unwrap_err();
+        assert_eq!(
+            err.error.to_string(),
+            "record 3: Received Quantity and Received Currency are required"
+        );
+        assert!(err.report.steps.is_empty());
+    }
+
+    #[cfg(feature = "std-fs")]
+    #[test]
+    fn test_pipeline_run_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let in_path = dir.path().join("in.csv");
+        let out_path = dir.path().join("out.csv");
+        write_tb_export_rec_file(&in_path, &recs()).unwrap();
+
+        let pipeline = Pipeline::new().trim_strings().sort().validate_strict();
+        let report = pipeline.run_file(&in_path, &out_path).unwrap();
+        assert_eq!(report.steps.len(), 3);
+        let out = read_tb_export_rec_file(&out_path).unwrap();
+        assert_eq!(out.len(), 5);
+        assert_eq!(out[0].external_id, "b");
+        assert!(out.windows(2).all(|w| w[0] <= w[1]));
+
+        let err = Pipeline::new()
+            .step(Fail)
+            .run_file(&in_path, &dir.path().join("none.csv"))
+            .unwrap_err();
+        assert!(err.downcast_ref::<PipelineError>().is_some());
+        assert!(!dir.path().join("none.csv").exists());
+    }
+}
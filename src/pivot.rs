@@ -0,0 +1,242 @@
+use std::{collections::BTreeMap, error::Error, io::Write};
+
+use rust_decimal::Decimal;
+use taxbitrec::TaxBitRecType;
+
+use crate::{format_decimal, DecimalFormat, TaxBitExportRec};
+
+// The columns of the pivot table and their header names
+const PIVOT_COLUMNS: [(TaxBitRecType, &str); 9] = [
+    (TaxBitRecType::Buy, "Buy"),
+    (TaxBitRecType::Sale, "Sale"),
+    (TaxBitRecType::Trade, "Trade"),
+    (TaxBitRecType::TransferIn, "Transfer In"),
+    (TaxBitRecType::TransferOut, "Transfer Out"),
+    (TaxBitRecType::Income, "Income"),
+    (TaxBitRecType::Expense, "Expense"),
+    (TaxBitRecType::GiftReceived, "Gift Received"),
+    (TaxBitRecType::GiftSent, "Gift Sent"),
+];
+
+/// What a PivotTable sums
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PivotValue {
+    /// The quantity, see TaxBitExportRec::get_quantity
+    #[default]
+    Quantity,
+
+    /// The market value, records without one aren't counted
+    MarketValue,
+}
+
+/// Sums per asset and transaction type, see pivot_by_asset_and_type
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PivotTable {
+    pub value: PivotValue,
+
+    /// Sums keyed by asset and then type, a type with no records for
+    /// the asset has no entry
+    pub cells: BTreeMap<String, BTreeMap<TaxBitRecType, Decimal>>,
+}
+
+impl PivotTable {
+    /// The table as CSV with a header of "Asset", the type names and
+    /// "Total", one row per asset in alphabetical order and a final
+    /// "Total" row. Cells with no records are empty so they're
+    /// distinguishable from sums of zero.
+    pub fn to_csv_string(&self) -> String {
+        self.to_csv_string_with_format(&DecimalFormat::default())
+    }
+
+    /// Write the table to wtr as to_csv_string formats it
+    pub fn write_csv<W: Write>(&self, mut wtr: W) -> Result<(), Box<dyn Error>> {
+        wtr.write_all(self.to_csv_string().as_bytes())?;
+
+        Ok(())
+    }
+
+    /// The table as to_csv_string with the sums formatted by format
+    pub fn to_csv_string_with_format(&self, format: &DecimalFormat) -> String {
+        let cell_to_string = |cell: Option<Decimal>| match cell {
+            Some(sum) => format_decimal(sum, format),
+            None => "".to_owned(),
+        };
+        let mut header = vec!["Asset"];
+        header.extend(PIVOT_COLUMNS.iter().map(|(_, name)| *name));
+        header.push("Total");
+        let mut csv = header.join(",");
+        csv.push('\n');
+
+        let mut column_totals: BTreeMap<TaxBitRecType, Decimal> = BTreeMap::new();
+        for (asset, row) in &self.cells {
+            let mut fields = vec![asset.clone()];
+            for (type_txs, _) in &PIVOT_COLUMNS {
+                let cell = row.get(type_txs).copied();
+                if let Some(sum) = cell {
+                    *column_totals.entry(type_txs.clone()).or_default() += sum;
+                }
+                fields.push(cell_to_string(cell));
+            }
+            fields.push(format_decimal(row.values().sum(), format));
+            csv.push_str(&fields.join(","));
+            csv.push('\n');
+        }
+
+        let mut fields = vec!["Total".to_owned()];
+        for (type_txs, _) in &PIVOT_COLUMNS {
+            fields.push(cell_to_string(column_totals.get(type_txs).copied()));
+        }
+        fields.push(format_decimal(column_totals.values().sum(), format));
+        csv.push_str(&fields.join(","));
+        csv.push('\n');
+
+        csv
+    }
+}
+
+/// Sum the quantity of each asset per transaction type, see
+/// TaxBitExportRec::get_asset and get_quantity. Unknown and Invalid
+/// records aren't counted.
+pub fn pivot_by_asset_and_type(recs: &[TaxBitExportRec]) -> PivotTable {
+    pivot_by_asset_and_type_with_value(recs, PivotValue::Quantity)
+}
+
+/// Sum value, the quantity or market value, of each asset per
+/// transaction type as pivot_by_asset_and_type does
+pub fn pivot_by_asset_and_type_with_value(
+    recs: &[TaxBitExportRec],
+    value: PivotValue,
+) -> PivotTable {
+    let mut table = PivotTable {
+        value,
+        ..PivotTable::default()
+    };
+    for rec in recs {
+        table.add(rec);
+    }
+
+    table
+}
+
+impl PivotTable {
+    pub(crate) fn add(&mut self, rec: &TaxBitExportRec) {
+        if matches!(
+            rec.type_txs,
+            TaxBitRecType::Unknown | TaxBitRecType::Invalid
+        ) {
+            return;
+        }
+        let amount = match self.value {
+            PivotValue::Quantity => rec.get_quantity(),
+            PivotValue::MarketValue => rec.market_value,
+        };
+        if let Some(amount) = amount {
+            *self
+                .cells
+                .entry(rec.get_asset().to_owned())
+                .or_default()
+                .entry(rec.type_txs.clone())
+                .or_default() += amount;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    fn rec(
+        type_txs: TaxBitRecType,
+        quantity: Decimal,
+        asset: &str,
+        market_value: Option<Decimal>,
+    ) -> TaxBitExportRec {
+        let mut rec = TaxBitExportRec::new();
+        rec.type_txs = type_txs.clone();
+        rec.market_value = market_value;
+        match type_txs {
+            TaxBitRecType::Sale | TaxBitRecType::TransferOut | TaxBitRecType::Expense => {
+                rec.sent_quantity = Some(quantity);
+                rec.sent_currency = asset.to_owned();
+            }
+            _ => {
+                rec.received_quantity = Some(quantity);
+                rec.received_currency = asset.to_owned();
+            }
+        }
+        rec
+    }
+
+    fn fixture() -> Vec<TaxBitExportRec> {
+        vec![
+            rec(TaxBitRecType::Buy, dec!(1), "BTC", Some(dec!(30000))),
+            rec(TaxBitRecType::Buy, dec!(0.5), "BTC", Some(dec!(16000))),
+            rec(TaxBitRecType::Sale, dec!(0.25), "BTC", Some(dec!(9000))),
+            rec(TaxBitRecType::Income, dec!(10), "ADA", Some(dec!(3))),
+            rec(TaxBitRecType::Income, dec!(5), "ADA", None),
+            rec(TaxBitRecType::TransferIn, dec!(2), "ETH", None),
+            rec(TaxBitRecType::TransferOut, dec!(2), "ETH", None),
+            rec(TaxBitRecType::Invalid, dec!(7), "ETH", None),
+        ]
+    }
+
+    #[test]
+    fn test_pivot_by_asset_and_type() {
+        let table = pivot_by_asset_and_type(&fixture());
+        assert_eq!(table.cells["BTC"][&TaxBitRecType::Buy], dec!(1.5));
+        assert_eq!(
+            table.to_csv_string(),
+            "Asset,Buy,Sale,Trade,Transfer In,Transfer Out,Income,Expense,Gift Received,Gift Sent,Total\n\
+            ADA,,,,,,15,,,,15\n\
+            BTC,1.5,0.25,,,,,,,,1.75\n\
+            ETH,,,,2,2,,,,,4\n\
+            Total,1.5,0.25,,2,2,15,,,,20.75\n"
+        );
+    }
+
+    #[test]
+    fn test_pivot_by_asset_and_type_market_value() {
+        let table = pivot_by_asset_and_type_with_value(&fixture(), PivotValue::MarketValue);
+        assert_eq!(
+            table.to_csv_string(),
+            "Asset,Buy,Sale,Trade,Transfer In,Transfer Out,Income,Expense,Gift Received,Gift Sent,Total\n\
+            ADA,,,,,,3,,,,3\n\
+            BTC,46000,9000,,,,,,,,55000\n\
+            Total,46000,9000,,,,3,,,,55003\n"
+        );
+    }
+
+    #[test]
+    fn test_pivot_net_zero_is_not_empty() {
+        let recs = vec![rec(TaxBitRecType::Income, dec!(0), "ADA", None)];
+        assert_eq!(
+            pivot_by_asset_and_type(&recs)
+                .to_csv_string()
+                .lines()
+                .nth(1),
+            Some("ADA,,,,,,0,,,,0")
+        );
+    }
+
+    #[test]
+    fn test_pivot_to_csv_string_with_format() {
+        let table = pivot_by_asset_and_type_with_value(&fixture(), PivotValue::MarketValue);
+        assert_eq!(
+            table
+                .to_csv_string_with_format(&DecimalFormat::fixed(2))
+                .lines()
+                .nth(1),
+            Some("ADA,,,,,,3.00,,,,3.00")
+        );
+    }
+
+    #[test]
+    fn test_pivot_write_csv() {
+        let table = pivot_by_asset_and_type(&fixture());
+        let mut out: Vec<u8> = vec![];
+        table.write_csv(&mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), table.to_csv_string());
+    }
+}
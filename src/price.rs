@@ -0,0 +1,540 @@
+use std::{collections::BTreeMap, error::Error, fmt::Display, io::Read};
+#[cfg(feature = "std-fs")]
+use std::{fs::File, io::BufReader, path::Path};
+
+use chrono::NaiveDate;
+use rust_decimal::{Decimal, RoundingStrategy};
+
+use crate::{convert::parse_decimal_opt, dt_str_to_utc_time_ms_flexible, TaxBitExportRec};
+
+/// An error looking up a price
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PriceError {
+    pub message: String,
+}
+
+impl Display for PriceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for PriceError {}
+
+/// A source of USD prices
+pub trait PriceProvider {
+    /// The USD price of one unit of asset at time_ms, None if there's
+    /// no price
+    fn price_usd(&self, asset: &str, time_ms: i64) -> Result<Option<Decimal>, PriceError>;
+}
+
+/// Prices loaded from a CSV with the columns Asset, Date and Price.
+///
+/// Date is either "YYYY-MM-DD", which is midnight UTC, or any format
+/// accepted by dt_str_to_utc_time_ms_flexible. The price of an asset at
+/// a time is the price with the latest Date at or before that time.
+#[derive(Debug, Clone, Default)]
+pub struct CsvPriceProvider {
+    prices: BTreeMap<String, BTreeMap<i64, Decimal>>,
+}
+
+impl CsvPriceProvider {
+    pub fn from_reader<R: Read>(rdr: R) -> Result<CsvPriceProvider, Box<dyn Error>> {
+        let mut reader = csv::Reader::from_reader(rdr);
+        let mut prices: BTreeMap<String, BTreeMap<i64, Decimal>> = BTreeMap::new();
+        for record in reader.records() {
+            let record = record?;
+            let line = record.position().map_or(0, |p| p.line());
+            let field = |i: usize| record.get(i).unwrap_or("").trim();
+
+            let time = match NaiveDate::parse_from_str(field(1), "%Y-%m-%d") {
+                Ok(date) => date
+                    .and_hms_opt(0, 0, 0)
+                    .expect("SNH")
+                    .and_utc()
+                    .timestamp_millis(),
+                Err(_) => dt_str_to_utc_time_ms_flexible(field(1))
+                    .map_err(|e| format!("line {line}: {e}"))?,
+            };
+            let price = parse_decimal_opt("Price", field(2))
+                .map_err(|e| format!("line {line}: {e}"))?
+                .ok_or_else(|| format!("line {line}: Price is empty"))?;
+            prices
+                .entry(field(0).to_owned())
+                .or_default()
+                .insert(time, price);
+        }
+
+        Ok(CsvPriceProvider { prices })
+    }
+
+    #[cfg(feature = "std-fs")]
+    pub fn from_path(path: &Path) -> Result<CsvPriceProvider, Box<dyn Error>> {
+        CsvPriceProvider::from_reader(BufReader::new(File::open(path)?))
+    }
+}
+
+impl PriceProvider for CsvPriceProvider {
+    fn price_usd(&self, asset: &str, time_ms: i64) -> Result<Option<Decimal>, PriceError> {
+        Ok(self
+            .prices
+            .get(asset)
+            .and_then(|prices| prices.range(..=time_ms).next_back())
+            .map(|(_, price)| *price))
+    }
+}
+
+/// The result of fill_missing_market_values
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FillReport {
+    /// Records whose market_value was set
+    pub filled: usize,
+
+    /// Records with no market_value and no price or quantity
+    pub skipped: usize,
+
+    /// Records which already had a market_value
+    pub already_present: usize,
+
+    /// Records where the provider returned an error or the market value
+    /// overflowed, with the record index
+    pub errors: Vec<(usize, PriceError)>,
+}
+
+/// Set market_value, when it's None, to quantity times the provider's
+/// price at the record's time. The received quantity and currency are
+/// used if present otherwise the sent quantity and currency. USD is
+/// always priced at 1 without asking the provider. Existing market values
+/// are never changed.
+pub fn fill_missing_market_values(
+    recs: &mut [TaxBitExportRec],
+    provider: &impl PriceProvider,
+) -> FillReport {
+    let mut report = FillReport::default();
+    for (i, rec) in recs.iter_mut().enumerate() {
+        if rec.market_value.is_some() {
+            report.already_present += 1;
+            continue;
+        }
+
+        let (quantity, asset) = match (rec.received_quantity, rec.sent_quantity) {
+            (Some(q), _) if !rec.received_currency.is_empty() => (q, &rec.received_currency),
+            (_, Some(q)) if !rec.sent_currency.is_empty() => (q, &rec.sent_currency),
+            _ => {
+                report.skipped += 1;
+                continue;
+            }
+        };
+
+        let price = if asset == "USD" {
+            Ok(Some(Decimal::ONE))
+        } else {
+            provider.price_usd(asset, rec.time)
+        };
+        match price {
+            Ok(Some(price)) => match quantity.checked_mul(price) {
+                Some(market_value) => {
+                    rec.market_value = Some(market_value);
+                    report.filled += 1;
+                }
+                None => report.errors.push((
+                    i,
+                    PriceError {
+                        message: format!(
+                            "{quantity} times {price} is out of the range of a Decimal"
+                        ),
+                    },
+                )),
+            },
+            Ok(None) => report.skipped += 1,
+            Err(e) => report.errors.push((i, e)),
+        }
+    }
+
+    report
+}
+
+impl TaxBitExportRec {
+    /// The USD value of the fee, None if there's no fee. A fee in USD is
+    /// its fee_amount, otherwise it's valued at the provider's price at
+    /// the record's time and it's an error if there is no price.
+    pub fn fee_value_usd(
+        &self,
+        provider: &impl PriceProvider,
+    ) -> Result<Option<Decimal>, PriceError> {
+        let fee = match self.fee_amount {
+            Some(fee) => fee,
+            None => return Ok(None),
+        };
+        if self.fee_currency == "USD" {
+            return Ok(Some(fee));
+        }
+
+        match provider.price_usd(&self.fee_currency, self.time)? {
+            Some(price) => Ok(Some(fee * price)),
+            None => Err(PriceError {
+                message: format!(
+                    "No USD price for fee currency {} at {}",
+                    self.fee_currency,
+                    self.time_utc().format("%Y-%m-%dT%H:%M:%S%.3fZ")
+                ),
+            }),
+        }
+    }
+}
+
+/// The scale implied_unit_price rounds to, so a price with a repeating
+/// expansion doesn't carry 28 decimal places
+pub const IMPLIED_UNIT_PRICE_SCALE: u32 = 18;
+
+/// Options for TaxBitExportRec::set_market_value_from_price_with_opts
+#[derive(Debug, Clone, Default)]
+pub struct MarketValueOpts {
+    /// Round the market value to this many decimal places, midpoints
+    /// away from zero, None keeps the exact product
+    pub scale: Option<u32>,
+
+    /// Replace an existing market value rather than refusing to
+    pub force: bool,
+}
+
+impl TaxBitExportRec {
+    /// The market value divided by the quantity of the record, see
+    /// get_quantity, rounded to IMPLIED_UNIT_PRICE_SCALE decimal places.
+    /// None if either is missing, the quantity is zero or the division
+    /// overflows.
+    pub fn implied_unit_price(&self) -> Option<Decimal> {
+        let market_value = self.market_value?;
+        let quantity = self.get_quantity().filter(|q| !q.is_zero())?;
+        market_value.checked_div(quantity).map(|price| {
+            price
+                .round_dp_with_strategy(
+                    IMPLIED_UNIT_PRICE_SCALE,
+                    RoundingStrategy::MidpointAwayFromZero,
+                )
+                .normalize()
+        })
+    }
+
+    /// Set market_value to the quantity of the record times unit_price,
+    /// see set_market_value_from_price_with_opts
+    pub fn set_market_value_from_price(&mut self, unit_price: Decimal) -> Result<(), PriceError> {
+        self.set_market_value_from_price_with_opts(unit_price, &MarketValueOpts::default())
+    }
+
+    /// Set market_value to the quantity of the record, see get_quantity,
+    /// times unit_price rounded as opts.scale gives. It's an error, and
+    /// the record is unchanged, if there's no quantity, the product
+    /// overflows or there's already a market value and opts.force is
+    /// false.
+    pub fn set_market_value_from_price_with_opts(
+        &mut self,
+        unit_price: Decimal,
+        opts: &MarketValueOpts,
+    ) -> Result<(), PriceError> {
+        let error = |message: String| PriceError { message };
+        if let (Some(market_value), false) = (self.market_value, opts.force) {
+            return Err(error(format!(
+                "Market value {market_value} is already set, use force to replace it"
+            )));
+        }
+        let quantity = self
+            .get_quantity()
+            .ok_or_else(|| error("No quantity to value".to_owned()))?;
+        let mut market_value = quantity.checked_mul(unit_price).ok_or_else(|| {
+            error(format!(
+                "{quantity} times {unit_price} is out of the range of a Decimal"
+            ))
+        })?;
+        if let Some(scale) = opts.scale {
+            market_value =
+                market_value.round_dp_with_strategy(scale, RoundingStrategy::MidpointAwayFromZero);
+        }
+        self.market_value = Some(market_value);
+
+        Ok(())
+    }
+}
+
+/// USD value of the fees of a set of records, see fees_usd
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FeesUsd {
+    /// Sum of the fees which could be valued
+    pub total: Decimal,
+
+    /// USD value of the fees which could be valued keyed by fee_currency
+    pub by_asset: BTreeMap<String, Decimal>,
+
+    /// Records whose fee couldn't be valued, with the record index
+    pub unpriced: Vec<(usize, PriceError)>,
+}
+
+/// The USD value of the fees of recs, see TaxBitExportRec::fee_value_usd,
+/// in total and by fee currency. Fees which can't be valued aren't
+/// counted and are reported in unpriced.
+pub fn fees_usd(recs: &[TaxBitExportRec], provider: &impl PriceProvider) -> FeesUsd {
+    let mut fees = FeesUsd::default();
+    for (i, rec) in recs.iter().enumerate() {
+        match rec.fee_value_usd(provider) {
+            Ok(Some(value)) => {
+                fees.total += value;
+                *fees.by_asset.entry(rec.fee_currency.clone()).or_default() += value;
+            }
+            Ok(None) => (),
+            Err(e) => fees.unpriced.push((i, e)),
+        }
+    }
+
+    fees
+}
+
+/// The total USD value of the fees of recs, an error if any fee can't be
+/// valued. Use fees_usd for the per asset values and all unpriced fees.
+pub fn total_fees_usd(
+    recs: &[TaxBitExportRec],
+    provider: &impl PriceProvider,
+) -> Result<Decimal, PriceError> {
+    let mut total = Decimal::ZERO;
+    for rec in recs {
+        total += rec.fee_value_usd(provider)?.unwrap_or_default();
+    }
+
+    Ok(total)
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal_macros::dec;
+    use taxbitrec::TaxBitRecType;
+
+    use super::*;
+
+    // 2021-01-02T00:00:00Z
+    const DAY2: i64 = 1609545600000;
+    const DAY: i64 = 24 * 60 * 60 * 1000;
+
+    struct MemoryPriceProvider(BTreeMap<&'static str, Decimal>);
+
+    impl PriceProvider for MemoryPriceProvider {
+        fn price_usd(&self, asset: &str, _time_ms: i64) -> Result<Option<Decimal>, PriceError> {
+            if asset == "ERR" {
+                return Err(PriceError {
+                    message: "unavailable".to_owned(),
+                });
+            }
+            Ok(self.0.get(asset).copied())
+        }
+    }
+
+    fn income(asset: &str, quantity: Decimal, market_value: Option<Decimal>) -> TaxBitExportRec {
+        let mut rec = TaxBitExportRec::new();
+        rec.time = DAY2;
+        rec.type_txs = TaxBitRecType::Income;
+        rec.received_quantity = Some(quantity);
+        rec.received_currency = asset.to_owned();
+        rec.market_value = market_value;
+        rec
+    }
+
+    #[test]
+    fn test_fill_missing_market_values() {
+        let provider =
+            MemoryPriceProvider(BTreeMap::from([("ADA", dec!(0.3)), ("BTC", dec!(30000))]));
+        let mut expense = TaxBitExportRec::new();
+        expense.type_txs = TaxBitRecType::Expense;
+        expense.sent_quantity = Some(dec!(0.001));
+        expense.sent_currency = "BTC".to_owned();
+        let mut recs = vec![
+            income("ADA", dec!(2), None),
+            income("ADA", dec!(2), Some(dec!(1))),
+            income("DOT", dec!(2), None),
+            expense,
+            income("ERR", dec!(1), None),
+        ];
+
+        let report = fill_missing_market_values(&mut recs, &provider);
+        assert_eq!(report.filled, 2);
+        assert_eq!(report.skipped, 1);
+        assert_eq!(report.already_present, 1);
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.errors[0].0, 4);
+
+        assert_eq!(recs[0].market_value, Some(dec!(0.6)));
+        assert_eq!(recs[1].market_value, Some(dec!(1)));
+        assert_eq!(recs[2].market_value, None);
+        assert_eq!(recs[3].market_value, Some(dec!(30)));
+        assert_eq!(recs[4].market_value, None);
+    }
+
+    #[test]
+    fn test_csv_price_provider() {
+        let csv = "Asset,Date,Price\n\
+            ADA,2021-01-01,0.2\n\
+            ADA,2021-01-03,0.4\n\
+            ADA,2021-01-02T12:00:00Z,0.3\n\
+            BTC,2021-01-02,30000\n";
+        let provider = CsvPriceProvider::from_reader(csv.as_bytes()).unwrap();
+
+        assert_eq!(
+            provider.price_usd("ADA", DAY2 - DAY).unwrap(),
+            Some(dec!(0.2))
+        );
+        assert_eq!(provider.price_usd("ADA", DAY2).unwrap(), Some(dec!(0.2)));
+        assert_eq!(
+            provider.price_usd("ADA", DAY2 + DAY / 2).unwrap(),
+            Some(dec!(0.3))
+        );
+        assert_eq!(
+            provider.price_usd("ADA", DAY2 + DAY).unwrap(),
+            Some(dec!(0.4))
+        );
+        assert_eq!(provider.price_usd("ADA", DAY2 - DAY - 1).unwrap(), None);
+        assert_eq!(provider.price_usd("BTC", DAY2).unwrap(), Some(dec!(30000)));
+        assert_eq!(provider.price_usd("DOT", DAY2).unwrap(), None);
+
+        assert!(CsvPriceProvider::from_reader("Asset,Date,Price\nADA,x,1\n".as_bytes()).is_err());
+    }
+
+    fn with_fee(fee: Option<Decimal>, currency: &str) -> TaxBitExportRec {
+        let mut rec = income("ADA", dec!(1), None);
+        rec.fee_amount = fee;
+        rec.fee_currency = currency.to_owned();
+        rec
+    }
+
+    #[test]
+    fn test_fee_value_usd() {
+        let provider = MemoryPriceProvider(BTreeMap::from([("BNB", dec!(40))]));
+        assert_eq!(with_fee(None, "").fee_value_usd(&provider), Ok(None));
+        assert_eq!(
+            with_fee(Some(dec!(1.5)), "USD").fee_value_usd(&provider),
+            Ok(Some(dec!(1.5)))
+        );
+        assert_eq!(
+            with_fee(Some(dec!(0.01)), "BNB").fee_value_usd(&provider),
+            Ok(Some(dec!(0.4)))
+        );
+        assert_eq!(
+            with_fee(Some(dec!(1)), "DOT")
+                .fee_value_usd(&provider)
+                .unwrap_err()
+                .to_string(),
+            "No USD price for fee currency DOT at 2021-01-02T00:00:00.000Z"
+        );
+    }
+
+    #[test]
+    fn test_fees_usd() {
+        let provider = MemoryPriceProvider(BTreeMap::from([("BNB", dec!(40))]));
+        let recs = vec![
+            with_fee(Some(dec!(1.5)), "USD"),
+            with_fee(Some(dec!(0.01)), "BNB"),
+            with_fee(None, ""),
+            with_fee(Some(dec!(1)), "DOT"),
+            with_fee(Some(dec!(0.02)), "BNB"),
+        ];
+
+        let fees = fees_usd(&recs, &provider);
+        assert_eq!(fees.total, dec!(2.7));
+        assert_eq!(
+            fees.by_asset,
+            BTreeMap::from([("BNB".to_owned(), dec!(1.2)), ("USD".to_owned(), dec!(1.5))])
+        );
+        assert_eq!(fees.unpriced.len(), 1);
+        assert_eq!(fees.unpriced[0].0, 3);
+
+        assert!(total_fees_usd(&recs, &provider).is_err());
+        assert_eq!(total_fees_usd(&recs[0..3], &provider), Ok(dec!(1.9)));
+    }
+
+    #[test]
+    fn test_implied_unit_price() {
+        let mut buy = TaxBitExportRec::new();
+        buy.type_txs = TaxBitRecType::Buy;
+        buy.received_quantity = Some(dec!(0.25));
+        buy.received_currency = "BTC".to_owned();
+        buy.sent_quantity = Some(dec!(5000));
+        buy.sent_currency = "USD".to_owned();
+        buy.market_value = Some(dec!(5000.00));
+        assert_eq!(buy.implied_unit_price(), Some(dec!(20000)));
+
+        // A repeating expansion is rounded
+        buy.received_quantity = Some(dec!(3));
+        buy.market_value = Some(dec!(1));
+        assert_eq!(buy.implied_unit_price(), Some(dec!(0.333333333333333333)));
+
+        // Zero or missing quantity and missing market value
+        buy.received_quantity = Some(dec!(0));
+        assert_eq!(buy.implied_unit_price(), None);
+        buy.received_quantity = None;
+        assert_eq!(buy.implied_unit_price(), None);
+        assert_eq!(income("ADA", dec!(2), None).implied_unit_price(), None);
+    }
+
+    #[test]
+    fn test_set_market_value_from_price() {
+        let mut rec = income("ADA", dec!(3.3333), None);
+        rec.set_market_value_from_price(dec!(0.3)).unwrap();
+        assert_eq!(rec.market_value, Some(dec!(0.99999)));
+
+        // An existing value isn't replaced unless forced
+        let err = rec.set_market_value_from_price(dec!(0.5)).unwrap_err();
+        assert_eq!(
+            err.message,
+            "Market value 0.99999 is already set, use force to replace it"
+        );
+        assert_eq!(rec.market_value, Some(dec!(0.99999)));
+        let opts = MarketValueOpts {
+            scale: Some(2),
+            force: true,
+        };
+        rec.set_market_value_from_price_with_opts(dec!(0.5), &opts)
+            .unwrap();
+        assert_eq!(rec.market_value, Some(dec!(1.67)));
+
+        let mut rec = income("ADA", dec!(1), None);
+        rec.received_quantity = None;
+        assert_eq!(
+            rec.set_market_value_from_price(dec!(0.5))
+                .unwrap_err()
+                .message,
+            "No quantity to value"
+        );
+        rec.received_quantity = Some(Decimal::MAX);
+        assert!(rec.set_market_value_from_price(dec!(2)).is_err());
+        assert_eq!(rec.market_value, None);
+    }
+
+    #[test]
+    fn test_fill_missing_market_values_usd() {
+        // The provider has no USD price and errors on every lookup
+        struct ErrPriceProvider;
+        impl PriceProvider for ErrPriceProvider {
+            fn price_usd(&self, asset: &str, _time_ms: i64) -> Result<Option<Decimal>, PriceError> {
+                Err(PriceError {
+                    message: format!("unexpected lookup of {asset}"),
+                })
+            }
+        }
+
+        let mut recs = vec![income("USD", dec!(12.50), None)];
+        let report = fill_missing_market_values(&mut recs, &ErrPriceProvider);
+        assert_eq!(report.filled, 1);
+        assert_eq!(report.errors, vec![]);
+        assert_eq!(recs[0].market_value, Some(dec!(12.50)));
+    }
+
+    #[test]
+    fn test_fill_missing_market_values_overflow() {
+        let provider = MemoryPriceProvider(BTreeMap::from([("BTC", Decimal::MAX)]));
+        let mut recs = vec![income("BTC", dec!(2), None), income("BTC", dec!(0.5), None)];
+
+        let report = fill_missing_market_values(&mut recs, &provider);
+        assert_eq!(report.filled, 1);
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.errors[0].0, 0);
+        assert!(report.errors[0].1.message.contains("out of the range"));
+        assert_eq!(recs[0].market_value, None);
+        assert_eq!(recs[1].market_value, Some(Decimal::MAX * dec!(0.5)));
+    }
+}
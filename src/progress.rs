@@ -0,0 +1,185 @@
+use std::fmt::Debug;
+
+/// How far a long operation has got, see Progress
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProgressEvent {
+    pub records_done: u64,
+
+    /// Bytes of CSV read or written, for a compressed file these are
+    /// the uncompressed bytes
+    pub bytes_done: u64,
+
+    /// The bytes expected in total if known, see Progress::total_bytes
+    pub total_bytes: Option<u64>,
+}
+
+/// A callback reporting the progress of reading, writing, sorting or
+/// merging records, see TaxBitExportRecReader::with_progress,
+/// TaxBitExportRecWriter::with_progress, external_sort_file_with_progress
+/// and merge_sorted_files_with_progress.
+///
+/// The callback is called once every_records records or every_bytes bytes
+/// have been done since the previous call and once more when the
+/// operation is done, so the last event has the final counts.
+///
+/// C is the type of the callback, a Progress created with new needn't be
+/// Send, a reader or writer with one can't be sent to another thread.
+/// Use new_send for a Progress which is Send, the default C.
+pub struct Progress<C: ?Sized = dyn FnMut(ProgressEvent) + Send> {
+    callback: Box<C>,
+    every_records: u64,
+    every_bytes: u64,
+    total_bytes: Option<u64>,
+    reported: Option<ProgressEvent>,
+}
+
+/// A Progress whose callback needn't be Send, see Progress::new
+pub type LocalProgress = Progress<dyn FnMut(ProgressEvent)>;
+
+impl<C: ?Sized> Debug for Progress<C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Progress")
+            .field("every_records", &self.every_records)
+            .field("every_bytes", &self.every_bytes)
+            .field("total_bytes", &self.total_bytes)
+            .finish()
+    }
+}
+
+impl LocalProgress {
+    /// Call callback every 10,000 records
+    pub fn new<F: FnMut(ProgressEvent) + 'static>(callback: F) -> LocalProgress {
+        let callback: Box<dyn FnMut(ProgressEvent)> = Box::new(callback);
+        Progress::with_callback(callback)
+    }
+}
+
+impl Progress {
+    /// Call callback every 10,000 records, as it's Send readers and
+    /// writers with the Progress can still be sent between threads
+    pub fn new_send<F: FnMut(ProgressEvent) + Send + 'static>(callback: F) -> Progress {
+        let callback: Box<dyn FnMut(ProgressEvent) + Send> = Box::new(callback);
+        Progress::with_callback(callback)
+    }
+}
+
+impl<C: ?Sized + FnMut(ProgressEvent)> Progress<C> {
+    fn with_callback(callback: Box<C>) -> Progress<C> {
+        Progress {
+            callback,
+            every_records: 10_000,
+            every_bytes: 0,
+            total_bytes: None,
+            reported: None,
+        }
+    }
+
+    /// Call the callback every n records, 0 to not count records
+    pub fn every_records(mut self, n: u64) -> Progress<C> {
+        self.every_records = n;
+        self
+    }
+
+    /// Call the callback every n bytes, 0, the default, to not count
+    /// bytes
+    pub fn every_bytes(mut self, n: u64) -> Progress<C> {
+        self.every_bytes = n;
+        self
+    }
+
+    /// The bytes expected in total reported in each ProgressEvent
+    pub fn total_bytes(mut self, total: u64) -> Progress<C> {
+        self.total_bytes = Some(total);
+        self
+    }
+
+    #[cfg(feature = "std-fs")]
+    pub(crate) fn has_total_bytes(&self) -> bool {
+        self.total_bytes.is_some()
+    }
+
+    fn report(&mut self, records_done: u64, bytes_done: u64) {
+        let event = ProgressEvent {
+            records_done,
+            bytes_done,
+            total_bytes: self.total_bytes,
+        };
+        self.reported = Some(event);
+        (self.callback)(event);
+    }
+
+    // Call the callback if every_records or every_bytes have been done
+    // since it was last called
+    pub(crate) fn update(&mut self, records_done: u64, bytes_done: u64) {
+        let last = self.reported.unwrap_or_default();
+        let due = |every: u64, done: u64, last: u64| every > 0 && done >= last + every;
+        if due(self.every_records, records_done, last.records_done)
+            || due(self.every_bytes, bytes_done, last.bytes_done)
+        {
+            self.report(records_done, bytes_done);
+        }
+    }
+
+    // Call the callback with the final counts unless it was last called
+    // with them
+    pub(crate) fn finish(&mut self, records_done: u64, bytes_done: u64) {
+        let last = self.reported.map(|e| (e.records_done, e.bytes_done));
+        if last != Some((records_done, bytes_done)) {
+            self.report(records_done, bytes_done);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+
+    // The callback isn't Send
+    fn recorder() -> (Rc<RefCell<Vec<ProgressEvent>>>, LocalProgress) {
+        let events: Rc<RefCell<Vec<ProgressEvent>>> = Rc::default();
+        let sink = events.clone();
+        (events, Progress::new(move |e| sink.borrow_mut().push(e)))
+    }
+
+    #[test]
+    fn test_progress_every() {
+        let (events, progress) = recorder();
+        let mut progress = progress.every_records(10).every_bytes(1000);
+        for i in 1..=25 {
+            progress.update(i, i * 30);
+        }
+        progress.finish(25, 750);
+        progress.finish(25, 750);
+        let done: Vec<(u64, u64)> = events
+            .borrow()
+            .iter()
+            .map(|e| (e.records_done, e.bytes_done))
+            .collect();
+        assert_eq!(done, vec![(10, 300), (20, 600), (25, 750)]);
+
+        // Bytes only, and no final call if the last one had the final counts
+        let (events, progress) = recorder();
+        let mut progress = progress.every_records(0).every_bytes(100).total_bytes(400);
+        for i in 1..=4 {
+            progress.update(i, i * 100);
+        }
+        progress.finish(4, 400);
+        assert_eq!(events.borrow().len(), 4);
+        assert_eq!(events.borrow()[3].total_bytes, Some(400));
+    }
+
+    #[test]
+    fn test_progress_new_send() {
+        fn assert_send<T: Send>(_: &T) {}
+        let events: std::sync::Arc<std::sync::Mutex<u64>> = Default::default();
+        let sink = events.clone();
+        let mut progress = Progress::new_send(move |e| *sink.lock().unwrap() = e.records_done);
+        assert_send(&progress);
+        std::thread::spawn(move || progress.finish(3, 0))
+            .join()
+            .unwrap();
+        assert_eq!(*events.lock().unwrap(), 3);
+    }
+}
@@ -0,0 +1,188 @@
+#[cfg(feature = "std-fs")]
+use std::{error::Error, fs::File, io::Write, path::Path};
+
+use crate::TaxBitExportRec;
+
+/// An input record which contributed to a Traced record
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct InputRef {
+    /// Index of the record in the records passed to trace
+    pub row: usize,
+
+    pub source: String,
+    pub external_id: String,
+}
+
+/// A record with the input records it was produced from, see trace.
+/// The _traced variants of transformations, such as
+/// merge_partial_fills_traced and split_trade_traced, carry the inputs
+/// through and transform the records exactly as the plain functions do.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Traced<T> {
+    pub rec: T,
+
+    /// The inputs in the order they were combined
+    pub inputs: Vec<InputRef>,
+}
+
+impl<T> Traced<T> {
+    /// A record with no inputs, used by the plain transformations to run
+    /// the traced ones
+    pub fn untraced(rec: T) -> Traced<T> {
+        Traced {
+            rec,
+            inputs: vec![],
+        }
+    }
+}
+
+/// Start tracing recs, each record's input is itself
+pub fn trace(recs: Vec<TaxBitExportRec>) -> Vec<Traced<TaxBitExportRec>> {
+    recs.into_iter()
+        .enumerate()
+        .map(|(row, rec)| Traced {
+            inputs: vec![InputRef {
+                row,
+                source: rec.source.clone(),
+                external_id: rec.external_id.clone(),
+            }],
+            rec,
+        })
+        .collect()
+}
+
+/// The records without their inputs
+pub fn untrace(traced: Vec<Traced<TaxBitExportRec>>) -> Vec<TaxBitExportRec> {
+    traced.into_iter().map(|t| t.rec).collect()
+}
+
+/// The mapping of the records to their inputs as CSV with a header, one
+/// row per output record and input. The Output Row is the index of the
+/// record in traced, so it lines up with the records written to the
+/// main file.
+pub fn provenance_csv_string(traced: &[Traced<TaxBitExportRec>]) -> String {
+    let mut wtr = csv::Writer::from_writer(vec![]);
+    wtr.write_record([
+        "Output Row",
+        "Output Source",
+        "Output External ID",
+        "Input Row",
+        "Input Source",
+        "Input External ID",
+    ])
+    .expect("SNH");
+    for (row, t) in traced.iter().enumerate() {
+        for input in &t.inputs {
+            wtr.write_record([
+                &row.to_string(),
+                &t.rec.source,
+                &t.rec.external_id,
+                &input.row.to_string(),
+                &input.source,
+                &input.external_id,
+            ])
+            .expect("SNH");
+        }
+    }
+
+    String::from_utf8(wtr.into_inner().expect("SNH")).expect("SNH")
+}
+
+/// Write the mapping of the records to their inputs to path, see
+/// provenance_csv_string
+#[cfg(feature = "std-fs")]
+pub fn write_provenance_csv(
+    path: &Path,
+    traced: &[Traced<TaxBitExportRec>],
+) -> Result<(), Box<dyn Error>> {
+    File::create(path)?.write_all(provenance_csv_string(traced).as_bytes())?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeSet;
+
+    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
+    use taxbitrec::TaxBitRecType;
+
+    use super::*;
+    use crate::{merge_partial_fills, merge_partial_fills_traced, FillOpts};
+
+    // 2022-01-01T00:00:00Z
+    const T: i64 = 1640995200000;
+
+    fn fill(time: i64, asset: &str, quantity: Decimal, id: &str) -> TaxBitExportRec {
+        let mut rec = TaxBitExportRec::new();
+        rec.time = time;
+        rec.type_txs = TaxBitRecType::Buy;
+        rec.received_quantity = Some(quantity);
+        rec.received_currency = asset.to_owned();
+        rec.sent_quantity = Some(quantity * dec!(100));
+        rec.sent_currency = "USD".to_owned();
+        rec.source = "Kraken".to_owned();
+        rec.external_id = id.to_owned();
+        rec
+    }
+
+    fn recs() -> Vec<TaxBitExportRec> {
+        vec![
+            fill(T, "ETH", dec!(1), "a"),
+            fill(T + 10, "ETH", dec!(2), "b"),
+            fill(T + 20, "SOL", dec!(4), "c"),
+            fill(T + 30, "SOL", dec!(8), ""),
+            fill(T + 60_000, "SOL", dec!(16), "e"),
+        ]
+    }
+
+    #[test]
+    fn test_merge_partial_fills_traced() {
+        let merged = merge_partial_fills_traced(trace(recs()), 1000, &FillOpts::default());
+
+        // The same records as without tracing
+        assert_eq!(untrace(merged.clone()), merge_partial_fills(recs(), 1000));
+
+        // Each output maps to exactly the inputs summed into it
+        let inputs = recs();
+        assert_eq!(merged.len(), 3);
+        for t in &merged {
+            let quantity: Decimal = t
+                .inputs
+                .iter()
+                .map(|i| inputs[i.row].received_quantity.unwrap())
+                .sum();
+            assert_eq!(t.rec.received_quantity, Some(quantity));
+        }
+        let rows = |t: &Traced<TaxBitExportRec>| -> BTreeSet<usize> {
+            t.inputs.iter().map(|i| i.row).collect()
+        };
+        assert_eq!(rows(&merged[0]), BTreeSet::from([0, 1]));
+        assert_eq!(rows(&merged[1]), BTreeSet::from([2, 3]));
+        assert_eq!(rows(&merged[2]), BTreeSet::from([4]));
+
+        assert_eq!(
+            provenance_csv_string(&merged),
+            "Output Row,Output Source,Output External ID,Input Row,Input Source,Input External ID\n\
+            0,Kraken,a+b,0,Kraken,a\n\
+            0,Kraken,a+b,1,Kraken,b\n\
+            1,Kraken,c,2,Kraken,c\n\
+            1,Kraken,c,3,Kraken,\n\
+            2,Kraken,e,4,Kraken,e\n"
+        );
+    }
+
+    #[cfg(feature = "std-fs")]
+    #[test]
+    fn test_write_provenance_csv() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("provenance.csv");
+        let traced = trace(recs());
+        write_provenance_csv(&path, &traced).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            provenance_csv_string(&traced)
+        );
+    }
+}
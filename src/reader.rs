@@ -0,0 +1,1922 @@
+use std::{
+    error::Error,
+    fmt::Display,
+    io::{self, BufRead, BufReader, Read},
+    ops::{Deref, DerefMut},
+};
+#[cfg(feature = "std-fs")]
+use std::{fs::File, path::Path};
+
+use rust_decimal::Decimal;
+#[cfg(feature = "std-fs")]
+use rust_decimal_macros::dec;
+use serde::{
+    de::{value, IntoDeserializer},
+    Deserialize,
+};
+
+use crate::{
+    de_string_to_utc_time_ms_flexible, de_string_true_false_to_bool, de_taxbit_rec_type_lenient,
+    decimal_str_to_decimal_flexible, Progress, ProgressEvent, TaxBitExportRec,
+    TB_EXPORT_REC_HEADER, TB_EXPORT_REC_LEGACY_HEADER, TB_EXPORT_REC_LOT_ID_COLUMN,
+    TB_EXPORT_REC_V2_HEADER,
+};
+
+/// The column layout of a TaxBit export file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaxBitExportLayout {
+    /// All columns in TB_EXPORT_REC_HEADER
+    Current,
+
+    /// All columns in TB_EXPORT_REC_LEGACY_HEADER, i.e. no "Internal Transfer"
+    Legacy,
+
+    /// All columns in TB_EXPORT_REC_V2_HEADER, i.e. the current columns
+    /// plus "Transaction Hash" and "Blockchain"
+    V2,
+}
+
+/// A column name lower cased without spaces and underscores, column
+/// names are matched by it so "Transaction Type", "transaction_type" and
+/// "TRANSACTION TYPE" are the same column
+pub fn normalize_column_name(name: &str) -> String {
+    name.chars()
+        .filter(|c| !matches!(c, ' ' | '_'))
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// The canonical name of a column of the TaxBit layouts, see
+/// TB_EXPORT_REC_V2_HEADER and TB_EXPORT_REC_LOT_ID_COLUMN, that name
+/// matches, None if it isn't one, see normalize_column_name
+pub fn canonical_column_name(name: &str) -> Option<&'static str> {
+    let name = normalize_column_name(name);
+    TB_EXPORT_REC_V2_HEADER
+        .iter()
+        .chain([&TB_EXPORT_REC_LOT_ID_COLUMN])
+        .find(|c| normalize_column_name(c) == name)
+        .copied()
+}
+
+/// Verify the header has the columns of a known layout and return it.
+///
+/// The verification is relaxed, the columns may be in any order and
+/// columns not part of the layout are ignored. Names are matched as
+/// canonical_column_name matches them.
+pub fn verify_header(header: &csv::StringRecord) -> Result<TaxBitExportLayout, Box<dyn Error>> {
+    let canonical: Vec<&str> = header.iter().filter_map(canonical_column_name).collect();
+    let has_all = |columns: &[&str]| columns.iter().all(|c| canonical.contains(c));
+
+    if has_all(&TB_EXPORT_REC_V2_HEADER) {
+        Ok(TaxBitExportLayout::V2)
+    } else if has_all(&TB_EXPORT_REC_HEADER) {
+        Ok(TaxBitExportLayout::Current)
+    } else if has_all(&TB_EXPORT_REC_LEGACY_HEADER) {
+        Ok(TaxBitExportLayout::Legacy)
+    } else {
+        let missing: Vec<&str> = TB_EXPORT_REC_HEADER
+            .iter()
+            .filter(|c| !canonical.contains(c))
+            .copied()
+            .collect();
+        let mut e = format!("Unexpected header, missing columns: {}", missing.join(", "));
+        let unknown: Vec<String> = header
+            .iter()
+            .filter(|h| canonical_column_name(h).is_none())
+            .map(|h| format!("'{h}' ({})", normalize_column_name(h)))
+            .collect();
+        if !unknown.is_empty() {
+            e.push_str(&format!(
+                "; unknown columns, normalized: {}",
+                unknown.join(", ")
+            ));
+        }
+        Err(e.into())
+    }
+}
+
+// The most common of tab, semicolon and comma in the first line of buf,
+// comma if there's a tie
+fn sniff_delimiter(buf: &[u8]) -> u8 {
+    let line = buf.split(|b| *b == b'\n').next().unwrap_or_default();
+    [b'\t', b';', b',']
+        .into_iter()
+        .max_by_key(|d| (line.iter().filter(|b| *b == d).count(), *d == b','))
+        .unwrap()
+}
+
+// The columns parsed as Decimal
+const DECIMAL_COLUMNS: [&str; 4] = [
+    "Received Quantity",
+    "Sent Quantity",
+    "Fee Amount",
+    "Market Value",
+];
+
+/// The decimal separator of the quantity, fee amount and market value
+/// columns
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+pub enum DecimalLocale {
+    /// "1234.5", commas are an error as "1,234" is ambiguous
+    #[default]
+    Period,
+
+    /// "1.234,5", a single comma is the decimal separator and periods
+    /// separate thousands
+    CommaDecimal,
+}
+
+// The comma decimal number s in the form Decimal parses, None if it has
+// more than one comma or a period isn't a thousands separator
+pub(crate) fn comma_decimal_to_period(s: &str) -> Option<String> {
+    let (int, frac) = match s.split_once(',') {
+        Some((int, frac)) => (int, Some(frac)),
+        None => (s, None),
+    };
+    if frac.is_some_and(|f| f.contains([',', '.'])) {
+        return None;
+    }
+    let groups: Vec<&str> = int.split('.').collect();
+    if groups[1..].iter().any(|g| g.len() != 3) {
+        return None;
+    }
+
+    let mut period = groups.concat();
+    if let Some(frac) = frac {
+        period.push('.');
+        period.push_str(frac);
+    }
+    Some(period)
+}
+
+/// Options used when reading TaxBit export records
+#[derive(Debug, Clone, Default)]
+pub struct ReaderConfig {
+    /// The field delimiter, if None it's the most common of tab,
+    /// semicolon and comma in the header line, comma if there are none
+    pub delimiter: Option<u8>,
+
+    /// The decimal separator of the numeric columns, see DecimalLocale
+    pub decimal_locale: DecimalLocale,
+
+    /// Read zero quantities and fee amounts whose currency is empty as
+    /// None, see TaxBitExportRec::normalize_zero_quantities
+    pub normalize_zero_quantities: bool,
+
+    /// Read zero quantities, fee amounts and market values as None
+    /// whatever their currency, reading back a file written with
+    /// WriterConfig::none_decimal_as_zero. Genuine zeros are read as
+    /// None too.
+    pub zero_decimal_as_none: bool,
+}
+
+/// A record which couldn't be parsed, returned by TaxBitExportRecReader
+/// boxed as the error of the row
+#[derive(Debug)]
+pub struct RowError {
+    /// The 1-based number of the data row, the header isn't counted
+    pub row: usize,
+
+    /// The header of the column which couldn't be parsed, None if the
+    /// row itself is malformed, e.g. has the wrong number of fields
+    pub column: Option<String>,
+
+    /// The text of the cell in column, or the row's cells joined with
+    /// commas if column is None
+    pub raw: String,
+
+    pub source: Box<dyn Error + Send + Sync>,
+}
+
+impl RowError {
+    // What the values of column are parsed as
+    fn column_kind(&self) -> Option<&'static str> {
+        match self.column.as_deref()? {
+            "Date" => Some("date"),
+            "Transaction Type" => Some("transaction type"),
+            column if DECIMAL_COLUMNS.contains(&column) => Some("decimal"),
+            "Internal Transfer" => Some("boolean"),
+            _ => None,
+        }
+    }
+}
+
+impl Display for RowError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.column, self.column_kind()) {
+            (Some(column), Some(kind)) => write!(
+                f,
+                "row {}, column '{column}': cannot parse '{}' as {kind}",
+                self.row, self.raw
+            ),
+            (Some(column), None) => write!(
+                f,
+                "row {}, column '{column}': cannot parse '{}': {}",
+                self.row, self.raw, self.source
+            ),
+            (None, _) => write!(f, "row {}: {}", self.row, self.source),
+        }
+    }
+}
+
+impl Error for RowError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
+
+/// A row a reader skipped, see TaxBitExportRecReader::skip_errors
+#[derive(Debug)]
+pub struct SkippedRow {
+    /// The 1-based number of the data row, as RowError::row
+    pub row: usize,
+
+    /// The text of the row without its line terminator if the reader
+    /// keeps raw lines, otherwise its fields joined with the delimiter
+    pub raw_line: String,
+
+    pub error: RowError,
+}
+
+/// The original text of a record, see TaxBitExportRecReader::read_with_raw
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawLine {
+    /// The byte offset of the start of the record in the input
+    pub offset: u64,
+
+    /// The 1-based number of the data row, as RowError::row
+    pub row: usize,
+
+    /// The bytes of the record exactly as read, including its line
+    /// terminator unless it's the last line and has none. A record with
+    /// a quoted field containing a newline spans several lines.
+    pub text: String,
+}
+
+impl RawLine {
+    /// The text without its line terminator
+    pub fn line(&self) -> &str {
+        self.text.trim_end_matches(['\r', '\n'])
+    }
+}
+
+/// A record and its RawLine, it derefs to the record so it can be
+/// processed as one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecWithRaw {
+    pub rec: TaxBitExportRec,
+    pub raw: RawLine,
+}
+
+impl RecWithRaw {
+    pub fn into_parts(self) -> (TaxBitExportRec, RawLine) {
+        (self.rec, self.raw)
+    }
+}
+
+impl Deref for RecWithRaw {
+    type Target = TaxBitExportRec;
+
+    fn deref(&self) -> &TaxBitExportRec {
+        &self.rec
+    }
+}
+
+impl DerefMut for RecWithRaw {
+    fn deref_mut(&mut self) -> &mut TaxBitExportRec {
+        &mut self.rec
+    }
+}
+
+// Passes reads through keeping a copy of the bytes read from offset
+// start when keep is set, the csv crate doesn't expose the raw bytes of
+// a record
+struct RawTee<R: Read> {
+    rdr: R,
+    keep: bool,
+    start: u64,
+    bytes: Vec<u8>,
+}
+
+impl<R: Read> Read for RawTee<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.rdr.read(buf)?;
+        if self.keep {
+            self.bytes.extend_from_slice(&buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+impl<R: Read> RawTee<R> {
+    // The text from offset from to offset to, the bytes before to are
+    // dropped
+    fn take(&mut self, from: u64, to: u64) -> String {
+        let from = (from.max(self.start) - self.start) as usize;
+        let to = (to.max(self.start) - self.start) as usize;
+        let to = to.min(self.bytes.len());
+        let text = String::from_utf8_lossy(&self.bytes[from.min(to)..to]).into_owned();
+        self.bytes.drain(..to);
+        self.start += to as u64;
+        text
+    }
+}
+
+// The offset and raw text of the record from offset from to offset to.
+// The csv crate stops after the \r of a \r\n so the \n is included if
+// it's next, and the next record starts with it and any blank lines
+// which are skipped.
+fn take_raw<R: Read>(
+    reader: &mut csv::Reader<BufReader<RawTee<R>>>,
+    from: u64,
+    to: u64,
+) -> (u64, String) {
+    let text = reader.get_mut().get_mut().take(from, to);
+    let mut text = text.trim_start_matches(['\r', '\n']).to_owned();
+    let from = to.saturating_sub(text.len() as u64);
+    if text.ends_with('\r') {
+        // Nothing is buffered past to if the tee has nothing past it, a
+        // read error is left for the csv reader to return
+        if reader.get_ref().get_ref().bytes.is_empty() {
+            let _ = reader.get_mut().fill_buf();
+        }
+        if reader.get_ref().get_ref().bytes.first() == Some(&b'\n') {
+            text.push('\n');
+        }
+    }
+    (from, text)
+}
+
+// The columns of a header, the known columns are parsed into a
+// TaxBitExportRec and the others are its extras
+struct Columns {
+    known_header: csv::StringRecord,
+    known_indices: Vec<usize>,
+    extra_columns: Vec<(usize, String)>,
+}
+
+impl Columns {
+    // The columns of header and its layout, see verify_header
+    fn new(header: &csv::StringRecord) -> Result<(Columns, TaxBitExportLayout), Box<dyn Error>> {
+        let layout = verify_header(header)?;
+
+        let mut columns = Columns {
+            known_header: csv::StringRecord::new(),
+            known_indices: vec![],
+            extra_columns: vec![],
+        };
+        for (i, column) in header.iter().enumerate() {
+            // Columns are named canonically so "transaction_type" is read
+            // and written back as "Transaction Type"
+            let column = canonical_column_name(column).unwrap_or(column);
+            if TB_EXPORT_REC_HEADER.contains(&column) || column == TB_EXPORT_REC_LOT_ID_COLUMN {
+                columns.known_header.push_field(column);
+                columns.known_indices.push(i);
+            } else {
+                columns.extra_columns.push((i, column.to_owned()));
+            }
+        }
+
+        #[cfg(feature = "strict-parse")]
+        {
+            let names: Vec<String> = columns
+                .extra_columns
+                .iter()
+                .map(|(_, n)| n.as_str())
+                .filter(|n| {
+                    layout != TaxBitExportLayout::V2 || !TB_EXPORT_REC_V2_HEADER.contains(n)
+                })
+                .map(|n| format!("{n} ({})", normalize_column_name(n)))
+                .collect();
+            if !names.is_empty() {
+                return Err(format!("Unknown columns: {}", names.join(", ")).into());
+            }
+        }
+
+        Ok((columns, layout))
+    }
+
+    // Parse record, the rowth data row
+    fn parse(
+        &self,
+        record: &csv::StringRecord,
+        row: usize,
+        config: &ReaderConfig,
+    ) -> Result<TaxBitExportRec, Box<dyn Error>> {
+        let mut known: csv::StringRecord = self
+            .known_indices
+            .iter()
+            .map(|i| record.get(*i).unwrap_or(""))
+            .collect();
+
+        // The decimals are parsed here and their cells emptied, as the csv
+        // deserializer infers numbers and passes them to
+        // de_string_to_decimal_opt_flexible as f64
+        let mut decimals: Vec<(&str, Decimal)> = vec![];
+        let mut decimal_error: Option<(usize, String)> = None;
+        let mut cells = csv::StringRecord::new();
+        for (i, (column, cell)) in self.known_header.iter().zip(known.iter()).enumerate() {
+            if !DECIMAL_COLUMNS.contains(&column) {
+                cells.push_field(cell);
+                continue;
+            }
+            let cell = match config.decimal_locale {
+                DecimalLocale::Period => cell.to_owned(),
+                DecimalLocale::CommaDecimal => match comma_decimal_to_period(cell) {
+                    Some(cell) => cell,
+                    None => {
+                        let e = format!("'{cell}' isn't a comma decimal number");
+                        return Err(self.cell_error(record, row, i, e.into()));
+                    }
+                },
+            };
+            if !cell.trim().is_empty() {
+                match decimal_str_to_decimal_flexible(&cell) {
+                    Ok(d) => decimals.push((column, d)),
+                    Err(e) => {
+                        decimal_error.get_or_insert((i, e));
+                    }
+                }
+            }
+            cells.push_field("");
+        }
+        // The error of the first unparsable column is reported, if it's
+        // before the decimal it's found by deserializing
+        if let Some((i, e)) = decimal_error {
+            let before: csv::StringRecord = cells.iter().take(i).collect();
+            if unparsable_column(&self.known_header, &before).is_none() {
+                return Err(self.cell_error(record, row, i, e.into()));
+            }
+        }
+        known = cells;
+        let mut rec: TaxBitExportRec = match known.deserialize(Some(&self.known_header)) {
+            Ok(rec) => rec,
+            Err(e) => return Err(self.row_error(record, row, e, Some(&known))),
+        };
+        for (column, d) in decimals {
+            let field = match column {
+                "Received Quantity" => &mut rec.received_quantity,
+                "Sent Quantity" => &mut rec.sent_quantity,
+                "Fee Amount" => &mut rec.fee_amount,
+                _ => &mut rec.market_value,
+            };
+            *field = Some(d);
+        }
+
+        for (i, name) in &self.extra_columns {
+            let value = record.get(*i).unwrap_or("");
+            rec.extras.insert(name.clone(), value.to_owned());
+        }
+        if config.normalize_zero_quantities {
+            rec.normalize_zero_quantities();
+        }
+        if config.zero_decimal_as_none {
+            for field in [
+                &mut rec.received_quantity,
+                &mut rec.sent_quantity,
+                &mut rec.fee_amount,
+                &mut rec.market_value,
+            ] {
+                if field.is_some_and(|d| d.is_zero()) {
+                    *field = None;
+                }
+            }
+        }
+
+        Ok(rec)
+    }
+
+    // The RowError of a csv::Error from reading or deserializing known,
+    // the known columns of record, or the unwrapped I/O error, see
+    // csv_error
+    fn row_error(
+        &self,
+        record: &csv::StringRecord,
+        row: usize,
+        e: csv::Error,
+        known: Option<&csv::StringRecord>,
+    ) -> Box<dyn Error> {
+        let field = match e.kind() {
+            csv::ErrorKind::Io(_) => return csv_error(e),
+            csv::ErrorKind::Deserialize { err, .. } => err.field().or_else(|| {
+                known
+                    .and_then(|k| unparsable_column(&self.known_header, k))
+                    .map(|i| i as u64)
+            }),
+            _ => None,
+        };
+        match (field, known) {
+            (Some(i), Some(_)) => self.cell_error(record, row, i as usize, Box::new(e)),
+            _ => Box::new(RowError {
+                row,
+                column: None,
+                raw: record.iter().collect::<Vec<&str>>().join(","),
+                source: Box::new(e),
+            }),
+        }
+    }
+
+    // The RowError of the cell in the ith known column of record, the raw
+    // text is from the record as known may be localized
+    fn cell_error(
+        &self,
+        record: &csv::StringRecord,
+        row: usize,
+        i: usize,
+        source: Box<dyn Error + Send + Sync>,
+    ) -> Box<dyn Error> {
+        Box::new(RowError {
+            row,
+            column: self.known_header.get(i).map(|c| c.to_owned()),
+            raw: self
+                .known_indices
+                .get(i)
+                .and_then(|ri| record.get(*ri))
+                .unwrap_or("")
+                .to_owned(),
+            source,
+        })
+    }
+}
+
+/// Streaming reader of TaxBit export records.
+///
+/// The optional TB_EXPORT_REC_LOT_ID_COLUMN is read into
+/// TaxBitExportRec::lot_id. Other columns which aren't part of the
+/// TaxBit export layout are captured
+/// in TaxBitExportRec::extras, unless the strict-parse feature is
+/// enabled in which case they are an error. The V2 layout's
+/// "Transaction Hash" and "Blockchain" are always captured in extras,
+/// see read_tb_export_recs to read them into TaxBitExportRecV2.
+///
+/// Records which can't be parsed are returned as a RowError and reading
+/// continues with the next row, see read_lenient, or are skipped, see
+/// skip_errors.
+pub struct TaxBitExportRecReader<R: Read, C: ?Sized = dyn FnMut(ProgressEvent) + Send> {
+    reader: csv::Reader<BufReader<RawTee<R>>>,
+    delimiter: u8,
+    layout: TaxBitExportLayout,
+    columns: Columns,
+    record: csv::StringRecord,
+    row: usize,
+    config: ReaderConfig,
+    progress: Option<Progress<C>>,
+    // The header and last record's raw text if keeping raw lines
+    raw_header: String,
+    raw: Option<RawLine>,
+    skip_errors: bool,
+    skipped: Vec<SkippedRow>,
+}
+
+impl<R: Read> TaxBitExportRecReader<R> {
+    /// Create a reader, the header is read and verified immediately
+    pub fn new(rdr: R) -> Result<TaxBitExportRecReader<R>, Box<dyn Error>> {
+        TaxBitExportRecReader::new_with_config(rdr, ReaderConfig::default())
+    }
+
+    /// Create a reader using config, see new
+    pub fn new_with_config(
+        rdr: R,
+        config: ReaderConfig,
+    ) -> Result<TaxBitExportRecReader<R>, Box<dyn Error>> {
+        TaxBitExportRecReader::new_reader(rdr, config, false)
+    }
+
+    /// Create a reader using config which keeps the original text of the
+    /// header and each record, see read_with_raw and raw_header
+    pub fn new_keeping_raw(
+        rdr: R,
+        config: ReaderConfig,
+    ) -> Result<TaxBitExportRecReader<R>, Box<dyn Error>> {
+        TaxBitExportRecReader::new_reader(rdr, config, true)
+    }
+
+    fn new_reader(
+        rdr: R,
+        config: ReaderConfig,
+        keep_raw: bool,
+    ) -> Result<TaxBitExportRecReader<R>, Box<dyn Error>> {
+        let mut rdr = BufReader::new(RawTee {
+            rdr,
+            keep: keep_raw,
+            start: 0,
+            bytes: vec![],
+        });
+        let delimiter = match config.delimiter {
+            Some(delimiter) => delimiter,
+            None => sniff_delimiter(rdr.fill_buf().map_err(|e| csv_error(e.into()))?),
+        };
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(delimiter)
+            .from_reader(rdr);
+        let header = reader.headers().map_err(csv_error)?.clone();
+        let (columns, layout) = Columns::new(&header)?;
+
+        let end = reader.position().byte();
+        let (_, raw_header) = take_raw(&mut reader, 0, end);
+
+        Ok(TaxBitExportRecReader {
+            reader,
+            delimiter,
+            layout,
+            columns,
+            record: csv::StringRecord::new(),
+            row: 0,
+            config,
+            progress: None,
+            raw_header,
+            raw: None,
+            skip_errors: false,
+            skipped: vec![],
+        })
+    }
+}
+
+impl<R: Read, C: ?Sized + FnMut(ProgressEvent)> TaxBitExportRecReader<R, C> {
+    /// Report the records and bytes read to progress, the last event is
+    /// sent when the end of the input is read
+    pub fn with_progress<D: ?Sized + FnMut(ProgressEvent)>(
+        self,
+        progress: Progress<D>,
+    ) -> TaxBitExportRecReader<R, D> {
+        self.with_progress_opt(Some(progress))
+    }
+
+    // See with_progress, None reports no progress
+    pub(crate) fn with_progress_opt<D: ?Sized + FnMut(ProgressEvent)>(
+        self,
+        progress: Option<Progress<D>>,
+    ) -> TaxBitExportRecReader<R, D> {
+        TaxBitExportRecReader {
+            reader: self.reader,
+            delimiter: self.delimiter,
+            layout: self.layout,
+            columns: self.columns,
+            record: self.record,
+            row: self.row,
+            config: self.config,
+            progress,
+            raw_header: self.raw_header,
+            raw: self.raw,
+            skip_errors: self.skip_errors,
+            skipped: self.skipped,
+        }
+    }
+
+    /// When enabled the iterator skips the rows which can't be parsed
+    /// rather than returning their RowErrors, they're kept as
+    /// SkippedRows, see skipped_rows. Other errors are still returned.
+    pub fn skip_errors(mut self, enabled: bool) -> TaxBitExportRecReader<R, C> {
+        self.skip_errors = enabled;
+        self
+    }
+
+    /// The rows skipped so far, see skip_errors
+    pub fn skipped_rows(&self) -> &[SkippedRow] {
+        &self.skipped
+    }
+
+    /// Remove and return the rows skipped so far
+    pub fn take_skipped_rows(&mut self) -> Vec<SkippedRow> {
+        std::mem::take(&mut self.skipped)
+    }
+
+    /// The bytes of CSV read so far, the header included
+    #[cfg(feature = "std-fs")]
+    pub(crate) fn bytes_read(&self) -> u64 {
+        self.reader.position().byte()
+    }
+
+    /// The text of the header including its line terminator, empty
+    /// unless the reader was created with new_keeping_raw
+    pub fn raw_header(&self) -> &str {
+        &self.raw_header
+    }
+
+    /// Read the next record with its original text, None at the end of
+    /// the input. Errors are returned as the iterator returns them.
+    ///
+    /// # Panics
+    ///
+    /// If the reader wasn't created with new_keeping_raw
+    pub fn read_with_raw(&mut self) -> Option<Result<RecWithRaw, Box<dyn Error>>> {
+        assert!(
+            self.reader.get_ref().get_ref().keep,
+            "read_with_raw requires a reader created with new_keeping_raw"
+        );
+        Some(self.read_rec()?.map(|rec| RecWithRaw {
+            rec,
+            raw: self.raw.take().unwrap(),
+        }))
+    }
+
+    /// The field delimiter, see ReaderConfig::delimiter
+    pub fn delimiter(&self) -> u8 {
+        self.delimiter
+    }
+
+    /// The layout found in the header
+    pub fn layout(&self) -> TaxBitExportLayout {
+        self.layout
+    }
+
+    /// Names of the columns which will be captured in TaxBitExportRec::extras
+    pub fn extra_columns(&self) -> Vec<String> {
+        self.columns
+            .extra_columns
+            .iter()
+            .map(|(_, n)| n.clone())
+            .collect()
+    }
+
+    /// True if the header has the TB_EXPORT_REC_LOT_ID_COLUMN
+    #[cfg(feature = "std-fs")]
+    pub(crate) fn has_lot_id_column(&self) -> bool {
+        self.columns
+            .known_header
+            .iter()
+            .any(|c| c == TB_EXPORT_REC_LOT_ID_COLUMN)
+    }
+
+    /// The line number of the start of the last record read, 0 if none
+    #[cfg(feature = "std-fs")]
+    pub(crate) fn line(&self) -> u64 {
+        self.record.position().map_or(0, |p| p.line())
+    }
+
+    /// Read all the remaining records, returning the RowErrors rather
+    /// than stopping at the first. Other errors, such as I/O errors,
+    /// still stop reading.
+    pub fn read_lenient(self) -> Result<(Vec<TaxBitExportRec>, Vec<RowError>), Box<dyn Error>> {
+        let mut recs: Vec<TaxBitExportRec> = vec![];
+        let mut row_errors: Vec<RowError> = vec![];
+        for entry in self {
+            match entry {
+                Ok(rec) => recs.push(rec),
+                Err(e) => row_errors.push(*e.downcast::<RowError>()?),
+            }
+        }
+
+        Ok((recs, row_errors))
+    }
+
+    fn read_rec(&mut self) -> Option<Result<TaxBitExportRec, Box<dyn Error>>> {
+        let read = self.reader.read_record(&mut self.record);
+        if !matches!(read, Ok(false)) {
+            self.row += 1;
+        }
+        if let Some(progress) = &mut self.progress {
+            let bytes = self.reader.position().byte();
+            match read {
+                Ok(false) => progress.finish(self.row as u64, bytes),
+                _ => progress.update(self.row as u64, bytes),
+            }
+        }
+        if self.reader.get_ref().get_ref().keep {
+            let end = self.reader.position().byte();
+            let start = self.record.position().map_or(end, |p| p.byte());
+            let (offset, text) = take_raw(&mut self.reader, start, end);
+            self.raw = Some(RawLine {
+                offset,
+                row: self.row,
+                text,
+            });
+        }
+        match read {
+            Ok(true) => {}
+            Ok(false) => return None,
+            Err(e) => return Some(Err(self.columns.row_error(&self.record, self.row, e, None))),
+        }
+
+        Some(self.columns.parse(&self.record, self.row, &self.config))
+    }
+}
+
+/// Parse a record with the canonical columns, TB_EXPORT_REC_HEADER, in
+/// order as the reader parses a row. An error is a RowError whose row is
+/// 0, see TaxBitExportRec::from_string_record_with_header to parse a
+/// record of another layout.
+impl TryFrom<&csv::StringRecord> for TaxBitExportRec {
+    type Error = Box<dyn Error>;
+
+    fn try_from(record: &csv::StringRecord) -> Result<TaxBitExportRec, Box<dyn Error>> {
+        let header = csv::StringRecord::from(TB_EXPORT_REC_HEADER.to_vec());
+        TaxBitExportRec::from_string_record_with_header(&header, record)
+    }
+}
+
+impl TaxBitExportRec {
+    /// Parse a record mapping its fields to columns by the names in
+    /// header as the reader parses a row with that header, columns which
+    /// aren't part of the layout are captured in extras. An error is a
+    /// RowError whose row is 0.
+    pub fn from_string_record_with_header(
+        header: &csv::StringRecord,
+        record: &csv::StringRecord,
+    ) -> Result<TaxBitExportRec, Box<dyn Error>> {
+        let (columns, _) = Columns::new(header)?;
+        if record.len() != header.len() {
+            return Err(Box::new(RowError {
+                row: 0,
+                column: None,
+                raw: record.iter().collect::<Vec<&str>>().join(","),
+                source: format!("expected {} fields, found {}", header.len(), record.len()).into(),
+            }));
+        }
+        columns.parse(record, 0, &ReaderConfig::default())
+    }
+}
+
+impl<R: Read, C: ?Sized + FnMut(ProgressEvent)> Iterator for TaxBitExportRecReader<R, C> {
+    type Item = Result<TaxBitExportRec, Box<dyn Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let entry = self.read_rec()?;
+            if !self.skip_errors {
+                return Some(entry);
+            }
+            match entry {
+                Ok(rec) => return Some(Ok(rec)),
+                Err(e) => match e.downcast::<RowError>() {
+                    Ok(error) => {
+                        let raw_line = match &self.raw {
+                            Some(raw) => raw.line().to_owned(),
+                            None => self
+                                .record
+                                .iter()
+                                .collect::<Vec<&str>>()
+                                .join(&char::from(self.delimiter).to_string()),
+                        };
+                        self.skipped.push(SkippedRow {
+                            row: self.row,
+                            raw_line,
+                            error: *error,
+                        });
+                    }
+                    Err(e) => return Some(Err(e)),
+                },
+            }
+        }
+    }
+}
+
+// The index of the first column whose cell doesn't parse, parsing each
+// cell as TaxBitExportRec's Deserialize does. The csv crate doesn't know
+// the field of errors from the deserialize_with functions.
+fn unparsable_column(header: &csv::StringRecord, record: &csv::StringRecord) -> Option<usize> {
+    header.iter().zip(record.iter()).position(|(column, cell)| {
+        let de = || IntoDeserializer::<value::Error>::into_deserializer(cell);
+        match column {
+            "Date" => de_string_to_utc_time_ms_flexible(de()).is_err(),
+            "Transaction Type" => de_taxbit_rec_type_lenient(de()).is_err(),
+            column if DECIMAL_COLUMNS.contains(&column) => {
+                !cell.trim().is_empty() && decimal_str_to_decimal_flexible(cell).is_err()
+            }
+            "Internal Transfer" => de_string_true_false_to_bool(de()).is_err(),
+            _ => false,
+        }
+    })
+}
+
+// The error of a failed read, an io::Error wrapping an error from the
+// underlying reader, such as a GzipError, is returned as that error.
+fn csv_error(e: csv::Error) -> Box<dyn Error> {
+    match e.kind() {
+        csv::ErrorKind::Io(io_error) if io_error.get_ref().is_some() => match e.into_kind() {
+            csv::ErrorKind::Io(io_error) => io_error.into_inner().unwrap(),
+            _ => unreachable!(),
+        },
+        _ => e.into(),
+    }
+}
+
+/// Read TaxBit export records, both the current and legacy layouts are
+/// supported. With the gzip feature input starting with the gzip magic
+/// bytes is decompressed.
+pub fn read_tb_export_recs_from_reader<R: Read>(
+    rdr: R,
+) -> Result<Vec<TaxBitExportRec>, Box<dyn Error>> {
+    read_tb_export_recs_from_reader_with_config(rdr, &ReaderConfig::default())
+}
+
+/// Read TaxBit export records as read_tb_export_recs_from_reader does
+/// using config
+pub fn read_tb_export_recs_from_reader_with_config<R: Read>(
+    rdr: R,
+    config: &ReaderConfig,
+) -> Result<Vec<TaxBitExportRec>, Box<dyn Error>> {
+    reader_of(BufReader::new(rdr), false, config, false)?.collect()
+}
+
+/// Read a TaxBit export file as read_tb_export_recs_from_reader does,
+/// files named *.gz are always decompressed
+#[cfg(feature = "std-fs")]
+pub fn read_tb_export_rec_file(path: &Path) -> Result<Vec<TaxBitExportRec>, Box<dyn Error>> {
+    read_tb_export_rec_file_with_config(path, &ReaderConfig::default())
+}
+
+/// Read a TaxBit export file as read_tb_export_rec_file does using config
+#[cfg(feature = "std-fs")]
+pub fn read_tb_export_rec_file_with_config(
+    path: &Path,
+    config: &ReaderConfig,
+) -> Result<Vec<TaxBitExportRec>, Box<dyn Error>> {
+    rec_file_iter(path, config, None::<Progress>)?.collect()
+}
+
+#[cfg(feature = "std-fs")]
+pub(crate) type RecIter<'a> =
+    Box<dyn Iterator<Item = Result<TaxBitExportRec, Box<dyn Error>>> + 'a>;
+
+// The records of the file read one at a time as
+// read_tb_export_rec_file_with_config reads them, for files too large to
+// collect
+#[cfg(feature = "std-fs")]
+pub(crate) fn rec_file_iter<C: ?Sized + FnMut(ProgressEvent) + 'static>(
+    path: &Path,
+    config: &ReaderConfig,
+    progress: Option<Progress<C>>,
+) -> Result<RecIter<'static>, Box<dyn Error>> {
+    let reader = file_reader(path, config, false)?;
+    Ok(Box::new(reader.with_progress_opt(progress)))
+}
+
+// The reader of the file, decompressing it if it's gzipped
+#[cfg(feature = "std-fs")]
+pub(crate) fn file_reader(
+    path: &Path,
+    config: &ReaderConfig,
+    keep_raw: bool,
+) -> Result<TaxBitExportRecReader<Box<dyn Read>>, Box<dyn Error>> {
+    reader_of(
+        BufReader::new(File::open(path)?),
+        path.extension().is_some_and(|e| e == "gz"),
+        config,
+        keep_raw,
+    )
+}
+
+/// Read a TaxBit export file as read_tb_export_rec_file does reporting
+/// progress, the total bytes is the size of the file unless progress has
+/// one. Compressed files report the uncompressed bytes read so the total
+/// should be set to the uncompressed size if it's known.
+#[cfg(feature = "std-fs")]
+pub fn read_tb_export_rec_file_with_progress<C: ?Sized + FnMut(ProgressEvent) + 'static>(
+    path: &Path,
+    progress: Progress<C>,
+) -> Result<Vec<TaxBitExportRec>, Box<dyn Error>> {
+    let progress = match progress.has_total_bytes() {
+        true => progress,
+        false => progress.total_bytes(std::fs::metadata(path)?.len()),
+    };
+    rec_file_iter(path, &ReaderConfig::default(), Some(progress))?.collect()
+}
+
+/// Options for read_tb_export_rec_file_lenient_with_opts
+#[cfg(feature = "std-fs")]
+#[derive(Debug, Clone)]
+pub struct LenientOpts {
+    /// The largest fraction of the rows which may fail to parse before
+    /// the file is rejected as not a TaxBit export, between 0 and 1, 0.5
+    /// by default
+    pub max_failed_fraction: Decimal,
+}
+
+#[cfg(feature = "std-fs")]
+impl Default for LenientOpts {
+    fn default() -> Self {
+        LenientOpts {
+            max_failed_fraction: dec!(0.5),
+        }
+    }
+}
+
+/// The records read by read_tb_export_rec_file_lenient and the rows
+/// which couldn't be parsed
+#[cfg(feature = "std-fs")]
+#[derive(Debug, Default)]
+pub struct LenientReadResult {
+    pub recs: Vec<TaxBitExportRec>,
+
+    /// The rows which couldn't be parsed, their raw_line is the text of
+    /// the row
+    pub failures: Vec<SkippedRow>,
+
+    /// The number of data rows read
+    pub rows: usize,
+}
+
+#[cfg(feature = "std-fs")]
+impl LenientReadResult {
+    /// The number of rows which couldn't be parsed
+    pub fn failed(&self) -> usize {
+        self.failures.len()
+    }
+}
+
+/// Read a TaxBit export file as read_tb_export_rec_file does skipping
+/// the rows which can't be parsed, see
+/// read_tb_export_rec_file_lenient_with_opts
+#[cfg(feature = "std-fs")]
+pub fn read_tb_export_rec_file_lenient(path: &Path) -> Result<LenientReadResult, Box<dyn Error>> {
+    read_tb_export_rec_file_lenient_with_opts(path, &LenientOpts::default())
+}
+
+/// Read a TaxBit export file skipping the rows which can't be parsed.
+/// Returns an error if more than opts.max_failed_fraction of the rows
+/// fail, as the file is most likely not a TaxBit export, if the file
+/// can't be read or if opts.max_failed_fraction isn't between 0 and 1.
+#[cfg(feature = "std-fs")]
+pub fn read_tb_export_rec_file_lenient_with_opts(
+    path: &Path,
+    opts: &LenientOpts,
+) -> Result<LenientReadResult, Box<dyn Error>> {
+    if !(Decimal::ZERO..=Decimal::ONE).contains(&opts.max_failed_fraction) {
+        return Err(format!(
+            "LenientOpts::max_failed_fraction must be between 0 and 1, not {}",
+            opts.max_failed_fraction
+        )
+        .into());
+    }
+    let mut reader = file_reader(path, &ReaderConfig::default(), true)?.skip_errors(true);
+    let recs = (&mut reader).collect::<Result<Vec<TaxBitExportRec>, Box<dyn Error>>>()?;
+    let failures = reader.take_skipped_rows();
+    let result = LenientReadResult {
+        rows: recs.len() + failures.len(),
+        recs,
+        failures,
+    };
+
+    let failed = Decimal::from(result.failed());
+    if let Some(first) = result
+        .failures
+        .first()
+        .filter(|_| failed > opts.max_failed_fraction * Decimal::from(result.rows))
+    {
+        return Err(format!(
+            "{} of {} rows failed to parse, more than {} allowed, the first: {}",
+            result.failed(),
+            result.rows,
+            opts.max_failed_fraction,
+            first.error
+        )
+        .into());
+    }
+
+    Ok(result)
+}
+
+// The reader of rdr, decompressing it if gzipped is set or it starts
+// with the gzip magic number
+fn reader_of<'a, R: Read + 'a>(
+    mut rdr: BufReader<R>,
+    gzipped: bool,
+    config: &ReaderConfig,
+    keep_raw: bool,
+) -> Result<TaxBitExportRecReader<Box<dyn Read + 'a>>, Box<dyn Error>> {
+    let rdr: Box<dyn Read + 'a> = if gzipped || rdr.fill_buf()?.starts_with(&[0x1f, 0x8b]) {
+        #[cfg(feature = "gzip")]
+        {
+            Box::new(crate::GzipReader::new(rdr))
+        }
+        #[cfg(not(feature = "gzip"))]
+        return Err("The input is gzip compressed, reading it requires the gzip feature".into());
+    } else {
+        Box::new(rdr)
+    };
+
+    TaxBitExportRecReader::new_reader(rdr, config.clone(), keep_raw)
+}
+
+#[cfg(test)]
+mod test {
+    #[cfg(feature = "std-fs")]
+    use std::fs;
+
+    use rust_decimal_macros::dec;
+    #[cfg(feature = "std-fs")]
+    use taxbitrec::TaxBitRecType;
+
+    use super::*;
+    #[cfg(feature = "std-fs")]
+    use crate::{write_tb_export_rec_file, ProgressEvent};
+
+    const CURRENT_CSV: &str = r#"Date,Transaction Type,Received Quantity,Received Currency,Sent Quantity,Sent Currency,Fee Currency,Fee Amount,Market Value,Source,Internal Transfer,External ID
+2020-03-02T07:32:05.000Z,Income,3e-7,BTC,,,,,0.0025979719720382955,BinanceUS,FALSE,2459217f-1a6f-4693-974c-d8d65f21abab
+2020-03-02T07:32:34.000Z,Income,0.0054,XRP,,,,,0.0012587400000000002,BinanceUS,TRUE,bf5cd6e1-64ec-4cb1-bbb2-502ac667561d
+"#;
+
+    const LEGACY_CSV: &str = r#"Date,Transaction Type,Received Quantity,Received Currency,Sent Quantity,Sent Currency,Fee Currency,Fee Amount,Market Value,Source,External ID
+2020-03-02T07:32:05.000Z,Income,3e-7,BTC,,,,,0.0025979719720382955,BinanceUS,2459217f-1a6f-4693-974c-d8d65f21abab
+2020-03-02T07:32:34.000Z,Income,0.0054,XRP,,,,,0.0012587400000000002,BinanceUS,bf5cd6e1-64ec-4cb1-bbb2-502ac667561d
+"#;
+
+    fn header_of(csv: &str) -> csv::StringRecord {
+        csv::Reader::from_reader(csv.as_bytes())
+            .headers()
+            .unwrap()
+            .clone()
+    }
+
+    #[cfg(feature = "std-fs")]
+    #[test]
+    fn test_read_from_reader() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("current.csv");
+        fs::write(&path, CURRENT_CSV).unwrap();
+
+        let cursor = std::io::Cursor::new(CURRENT_CSV.as_bytes().to_vec());
+        let recs = read_tb_export_recs_from_reader(cursor).unwrap();
+        assert_eq!(recs.len(), 2);
+        assert_eq!(recs, read_tb_export_rec_file(&path).unwrap());
+    }
+
+    #[test]
+    fn test_verify_header() {
+        assert_eq!(
+            verify_header(&header_of(CURRENT_CSV)).unwrap(),
+            TaxBitExportLayout::Current
+        );
+        assert_eq!(
+            verify_header(&header_of(LEGACY_CSV)).unwrap(),
+            TaxBitExportLayout::Legacy
+        );
+        assert!(verify_header(&header_of("Date,Transaction Type\n")).is_err());
+    }
+
+    #[cfg(feature = "std-fs")]
+    #[test]
+    fn test_read_current_layout() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("current.csv");
+        fs::write(&path, CURRENT_CSV).unwrap();
+
+        let recs = read_tb_export_rec_file(&path).unwrap();
+        assert_eq!(recs.len(), 2);
+        assert_eq!(recs[0].time, 1583134325000);
+        assert_eq!(recs[0].type_txs, TaxBitRecType::Income);
+        assert_eq!(recs[0].received_quantity, Some(dec!(0.0000003)));
+        assert!(!recs[0].internal_transfer);
+        assert!(recs[1].internal_transfer);
+    }
+
+    #[cfg(feature = "std-fs")]
+    #[test]
+    fn test_read_legacy_layout() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("legacy.csv");
+        fs::write(&path, LEGACY_CSV).unwrap();
+
+        let recs = read_tb_export_rec_file(&path).unwrap();
+        assert_eq!(recs.len(), 2);
+        assert_eq!(recs[0].received_currency, "BTC");
+        assert_eq!(recs[1].received_currency, "XRP");
+        assert_eq!(recs[1].external_id, "bf5cd6e1-64ec-4cb1-bbb2-502ac667561d");
+        assert!(recs.iter().all(|r| !r.internal_transfer));
+    }
+
+    #[cfg(feature = "std-fs")]
+    #[test]
+    fn test_read_legacy_write_current() {
+        let dir = tempfile::tempdir().unwrap();
+        let legacy_path = dir.path().join("legacy.csv");
+        let out_path = dir.path().join("out.csv");
+        fs::write(&legacy_path, LEGACY_CSV).unwrap();
+
+        let recs = read_tb_export_rec_file(&legacy_path).unwrap();
+        write_tb_export_rec_file(&out_path, &recs).unwrap();
+
+        let out = fs::read_to_string(&out_path).unwrap();
+        assert_eq!(
+            out.lines().next().unwrap(),
+            CURRENT_CSV.lines().next().unwrap()
+        );
+        assert_eq!(
+            verify_header(&header_of(&out)).unwrap(),
+            TaxBitExportLayout::Current
+        );
+
+        let recs_out = read_tb_export_rec_file(&out_path).unwrap();
+        assert_eq!(recs, recs_out);
+    }
+
+    const EXTRAS_CSV: &str = r#"Date,Transaction Type,Received Quantity,Received Currency,Sent Quantity,Sent Currency,Fee Currency,Fee Amount,Market Value,Source,Internal Transfer,External ID,Notes,Tx Hash
+2020-03-02T07:32:05.000Z,Income,3e-7,BTC,,,,,0.0025979719720382955,BinanceUS,FALSE,2459217f-1a6f-4693-974c-d8d65f21abab,"staking, weekly",0xabc
+2020-03-02T07:32:34.000Z,Income,0.0054,XRP,,,,,0.0012587400000000002,BinanceUS,FALSE,bf5cd6e1-64ec-4cb1-bbb2-502ac667561d,,0xdef
+"#;
+
+    #[test]
+    #[cfg(not(feature = "strict-parse"))]
+    fn test_read_extras() {
+        let reader = TaxBitExportRecReader::new(EXTRAS_CSV.as_bytes()).unwrap();
+        assert_eq!(reader.layout(), TaxBitExportLayout::Current);
+        assert_eq!(reader.extra_columns(), vec!["Notes", "Tx Hash"]);
+
+        let recs: Vec<TaxBitExportRec> = reader.map(|r| r.unwrap()).collect();
+        assert_eq!(recs.len(), 2);
+        assert_eq!(recs[0].extras["Notes"], "staking, weekly");
+        assert_eq!(recs[0].extras["Tx Hash"], "0xabc");
+        assert_eq!(recs[1].extras["Notes"], "");
+        assert_eq!(recs[1].extras["Tx Hash"], "0xdef");
+    }
+
+    #[test]
+    #[cfg(feature = "strict-parse")]
+    fn test_read_extras_strict_parse() {
+        assert!(TaxBitExportRecReader::new(EXTRAS_CSV.as_bytes()).is_err());
+    }
+
+    #[cfg(feature = "std-fs")]
+    #[test]
+    #[cfg(not(feature = "strict-parse"))]
+    fn test_extras_survive_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let in_path = dir.path().join("in.csv");
+        let out_path = dir.path().join("out.csv");
+        fs::write(&in_path, EXTRAS_CSV).unwrap();
+
+        let mut recs = read_tb_export_rec_file(&in_path).unwrap();
+        recs[1].market_value = Some(dec!(0.0013));
+        write_tb_export_rec_file(&out_path, &recs).unwrap();
+
+        let out = fs::read_to_string(&out_path).unwrap();
+        assert_eq!(
+            out.lines().next().unwrap(),
+            EXTRAS_CSV.lines().next().unwrap()
+        );
+
+        let recs_out = read_tb_export_rec_file(&out_path).unwrap();
+        assert_eq!(recs_out[1].market_value, Some(dec!(0.0013)));
+        for (rec, rec_out) in recs.iter().zip(recs_out.iter()) {
+            assert!(rec.eq_strict(rec_out));
+        }
+    }
+
+    #[cfg(feature = "std-fs")]
+    #[test]
+    #[cfg(not(feature = "gzip"))]
+    fn test_read_gzip_without_feature() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("current.csv");
+        fs::write(&path, [0x1f, 0x8b, 0x08, 0x00]).unwrap();
+        let e = read_tb_export_rec_file(&path).unwrap_err();
+        assert!(e.to_string().ends_with("requires the gzip feature"), "{e}");
+    }
+
+    const BAD_ROWS_CSV: &str = r#"Date,Transaction Type,Received Quantity,Received Currency,Sent Quantity,Sent Currency,Fee Currency,Fee Amount,Market Value,Source,Internal Transfer,External ID
+2020-03-02T07:32:05.000Z,Income,0.0054,XRP,,,,,0.00125874,BinanceUS,FALSE,id-1
+2020-03-02T07:32:34.000Z,Income,"1,5",XRP,,,,,0.00125874,BinanceUS,FALSE,id-2
+yesterday,Income,0.0054,XRP,,,,,0.00125874,BinanceUS,FALSE,id-3
+2020-03-02T07:33:34.000Z,Income,0.0054,XRP,,,,,0.00125874,BinanceUS,FALSE,id-4
+2020-03-02T07:34:34.000Z,Income,0.0054,XRP,,,,,0.00125874,BinanceUS,NO,id-5
+2020-03-02T07:35:34.000Z,Income,0.0054,XRP,,,,,0.00125874,BinanceUS,FALSE
+2020-03-02T07:36:34.000Z,Steal,0.0054,XRP,,,,,0.00125874,BinanceUS,FALSE,id-7
+"#;
+
+    #[test]
+    fn test_row_errors() {
+        let results: Vec<Result<TaxBitExportRec, Box<dyn Error>>> =
+            TaxBitExportRecReader::new(BAD_ROWS_CSV.as_bytes())
+                .unwrap()
+                .collect();
+        assert_eq!(results.len(), 7);
+        assert!(results[0].is_ok());
+        assert!(results[3].is_ok());
+
+        let row_error = |i: usize| {
+            results[i]
+                .as_ref()
+                .unwrap_err()
+                .downcast_ref::<RowError>()
+                .unwrap()
+        };
+
+        let e = row_error(1);
+        assert_eq!(e.row, 2);
+        assert_eq!(e.column.as_deref(), Some("Received Quantity"));
+        assert_eq!(e.raw, "1,5");
+        assert_eq!(
+            e.to_string(),
+            "row 2, column 'Received Quantity': cannot parse '1,5' as decimal"
+        );
+        assert!(e.source().is_some());
+
+        let e = row_error(2);
+        assert_eq!(e.row, 3);
+        assert_eq!(e.column.as_deref(), Some("Date"));
+        assert_eq!(e.raw, "yesterday");
+
+        let e = row_error(4);
+        assert_eq!(e.row, 5);
+        assert_eq!(e.column.as_deref(), Some("Internal Transfer"));
+        assert_eq!(e.raw, "NO");
+
+        // Too few fields, there's no column to blame
+        let e = row_error(5);
+        assert_eq!(e.row, 6);
+        assert_eq!(e.column, None);
+        assert!(e.raw.ends_with(",BinanceUS,FALSE"), "{}", e.raw);
+        assert!(e.to_string().starts_with("row 6: "), "{e}");
+
+        let e = row_error(6);
+        assert_eq!(e.row, 7);
+        assert_eq!(e.column.as_deref(), Some("Transaction Type"));
+        assert_eq!(e.raw, "Steal");
+    }
+
+    #[test]
+    fn test_read_lenient() {
+        let (recs, row_errors) = TaxBitExportRecReader::new(BAD_ROWS_CSV.as_bytes())
+            .unwrap()
+            .read_lenient()
+            .unwrap();
+        assert_eq!(
+            recs.iter()
+                .map(|r| r.external_id.as_str())
+                .collect::<Vec<_>>(),
+            vec!["id-1", "id-4"]
+        );
+        assert_eq!(
+            row_errors.iter().map(|e| e.row).collect::<Vec<_>>(),
+            vec![2, 3, 5, 6, 7]
+        );
+    }
+
+    #[test]
+    fn test_normalized_header() {
+        let rows =
+            "2022-01-01T00:00:00.000Z,Income,1,BTC,,,,,40000,Kraken,FALSE,a,0xabc,BTC,lot-1\n";
+        let read = |header: &str| {
+            let csv = format!("{header}\n{rows}");
+            read_tb_export_recs_from_reader(csv.as_bytes()).unwrap()
+        };
+        let mut canonical = TB_EXPORT_REC_V2_HEADER.to_vec();
+        canonical.push(TB_EXPORT_REC_LOT_ID_COLUMN);
+        let canonical = canonical.join(",");
+        let recs = read(&canonical);
+        assert_eq!(recs[0].lot_id(), Some("lot-1"));
+        assert_eq!(recs[0].extras["Transaction Hash"], "0xabc");
+
+        let lowercase = canonical.to_lowercase();
+        let snake_case = lowercase.replace(' ', "_");
+        assert_eq!(snake_case.split(',').nth(1), Some("transaction_type"));
+        for header in [&lowercase, &snake_case, &canonical.to_uppercase()] {
+            let recs_read = read(header);
+            assert_eq!(recs_read, recs, "{header}");
+            assert_eq!(recs_read[0].extras, recs[0].extras, "{header}");
+        }
+
+        // Written with the canonical names
+        let mut out: Vec<u8> = vec![];
+        crate::write_tb_export_recs_to_writer(&mut out, &read(&snake_case)).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let header = out.lines().next().unwrap();
+        assert!(
+            header.starts_with(&TB_EXPORT_REC_HEADER.join(",")),
+            "{header}"
+        );
+        assert!(header.contains(",Lot ID"), "{header}");
+        assert!(header.contains(",Transaction Hash"), "{header}");
+
+        assert_eq!(
+            canonical_column_name("received_QUANTITY"),
+            Some("Received Quantity")
+        );
+        assert_eq!(canonical_column_name("lot_id"), Some("Lot ID"));
+        assert_eq!(canonical_column_name("Quantity"), None);
+    }
+
+    #[test]
+    fn test_normalized_header_unknown() {
+        let header = header_of("date,txn_type,received_quantity,received_currency,sent_quantity,sent_currency,fee_currency,fee_amount,market_value,source,internal_transfer,external_id\n");
+        let e = verify_header(&header).unwrap_err().to_string();
+        assert_eq!(
+            e,
+            "Unexpected header, missing columns: Transaction Type; \
+            unknown columns, normalized: 'txn_type' (txntype)"
+        );
+    }
+
+    #[test]
+    fn test_read_scientific_notation() {
+        let csv = format!(
+            "{}\n\
+2022-01-01T00:00:00.000Z,Income,1.2E-7,BTC,,,BTC,3e-9,-3e2,Kraken,FALSE,sci
+2022-01-02T00:00:00.000Z,Income,1,BTC,,,BTC,1e-29,1,Kraken,FALSE,overflow
+",
+            TB_EXPORT_REC_HEADER.join(",")
+        );
+        let (recs, row_errors) = TaxBitExportRecReader::new(csv.as_bytes())
+            .unwrap()
+            .read_lenient()
+            .unwrap();
+        assert_eq!(recs.len(), 1);
+        assert_eq!(recs[0].received_quantity, Some(dec!(0.00000012)));
+        assert_eq!(recs[0].fee_amount, Some(dec!(0.000000003)));
+        assert_eq!(recs[0].market_value, Some(dec!(-300)));
+        assert_eq!(row_errors.len(), 1);
+        assert_eq!(row_errors[0].column.as_deref(), Some("Fee Amount"));
+
+        // Written in positional form and read back unchanged
+        let mut out: Vec<u8> = vec![];
+        crate::write_tb_export_recs_to_writer(&mut out, &recs).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(
+            out.contains(",0.00000012,BTC,,,BTC,0.000000003,-300,"),
+            "{out}"
+        );
+        let read_back = read_tb_export_recs_from_reader(out.as_bytes()).unwrap();
+        assert_eq!(read_back, recs);
+
+        // More digits than an f64 holds are exact
+        let csv = csv.replace("1.2E-7", "0.123456789012345678901");
+        let (recs, _) = TaxBitExportRecReader::new(csv.as_bytes())
+            .unwrap()
+            .read_lenient()
+            .unwrap();
+        assert_eq!(
+            recs[0].received_quantity,
+            Some(dec!(0.123456789012345678901))
+        );
+    }
+
+    #[test]
+    fn test_skip_errors() {
+        let mut reader = TaxBitExportRecReader::new(BAD_ROWS_CSV.as_bytes())
+            .unwrap()
+            .skip_errors(true);
+        let recs = (&mut reader)
+            .collect::<Result<Vec<TaxBitExportRec>, Box<dyn Error>>>()
+            .unwrap();
+        assert_eq!(recs.len(), 2);
+        let skipped = reader.skipped_rows();
+        assert_eq!(
+            skipped.iter().map(|s| s.row).collect::<Vec<_>>(),
+            vec![2, 3, 5, 6, 7]
+        );
+        assert_eq!(skipped[0].error.row, 2);
+        assert_eq!(
+            skipped[1].raw_line,
+            "yesterday,Income,0.0054,XRP,,,,,0.00125874,BinanceUS,FALSE,id-3"
+        );
+        assert_eq!(reader.take_skipped_rows().len(), 5);
+        assert!(reader.skipped_rows().is_empty());
+    }
+
+    // A file of good rows with bad rows at the indices in bad
+    #[cfg(feature = "std-fs")]
+    fn lenient_fixture(path: &Path, good: usize, bad: &[usize]) {
+        let mut csv = TB_EXPORT_REC_HEADER.join(",");
+        csv.push('\n');
+        for i in 0..good + bad.len() {
+            match bad.contains(&i) {
+                true => csv.push_str(&format!("garbage {i}\r\n")),
+                false => csv.push_str(&format!(
+                    "2022-01-01T00:00:00.000Z,Income,{i},BTC,,,,,,Kraken,FALSE,id-{i}\r\n"
+                )),
+            }
+        }
+        fs::write(path, csv).unwrap();
+    }
+
+    #[cfg(feature = "std-fs")]
+    #[test]
+    fn test_read_file_lenient() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mangled.csv");
+        lenient_fixture(&path, 50, &[0, 20, 52]);
+
+        let result = read_tb_export_rec_file_lenient(&path).unwrap();
+        assert_eq!(result.rows, 53);
+        assert_eq!(result.recs.len(), 50);
+        assert_eq!(result.failed(), 3);
+        assert_eq!(
+            result.failures.iter().map(|f| f.row).collect::<Vec<_>>(),
+            vec![1, 21, 53]
+        );
+        assert_eq!(result.failures[1].raw_line, "garbage 20");
+        assert!(result.failures[2].error.column.is_none());
+        assert_eq!(result.recs[0].external_id, "id-1");
+
+        // Not lenient enough
+        let opts = LenientOpts {
+            max_failed_fraction: dec!(0.05),
+        };
+        let e = read_tb_export_rec_file_lenient_with_opts(&path, &opts).unwrap_err();
+        assert!(e.to_string().starts_with("3 of 53 rows failed"), "{e}");
+    }
+
+    #[cfg(feature = "std-fs")]
+    #[test]
+    fn test_read_file_lenient_threshold() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("garbage.csv");
+        lenient_fixture(&path, 2, &(0..8).collect::<Vec<usize>>());
+
+        let e = read_tb_export_rec_file_lenient(&path).unwrap_err();
+        assert!(e.to_string().contains("8 of 10 rows failed"), "{e}");
+
+        // Everything passes when all failures are allowed
+        let opts = LenientOpts {
+            max_failed_fraction: dec!(1),
+        };
+        let result = read_tb_export_rec_file_lenient_with_opts(&path, &opts).unwrap();
+        assert_eq!(result.recs.len(), 2);
+
+        // An empty file has no failures
+        lenient_fixture(&path, 0, &[]);
+        let result = read_tb_export_rec_file_lenient(&path).unwrap();
+        assert_eq!(result.rows, 0);
+
+        // A fraction outside 0..=1 is rejected before reading
+        for max_failed_fraction in [dec!(-0.1), dec!(1.5)] {
+            let opts = LenientOpts {
+                max_failed_fraction,
+            };
+            let e = read_tb_export_rec_file_lenient_with_opts(&path, &opts).unwrap_err();
+            assert!(e.to_string().contains("between 0 and 1"), "{e}");
+        }
+    }
+
+    #[test]
+    fn test_normalize_zero_quantities_on_read() {
+        let csv = r#"Date,Transaction Type,Received Quantity,Received Currency,Sent Quantity,Sent Currency,Fee Currency,Fee Amount,Market Value,Source,Internal Transfer,External ID
+2021-01-02T10:00:00.000Z,Sale,0,,0.01,BTC,,0,330,Coinbase,FALSE,id-1
+2021-01-02T11:00:00.000Z,Income,0,BTC,,,,,0,Coinbase,FALSE,id-2
+"#;
+        let recs = read_tb_export_recs_from_reader(csv.as_bytes()).unwrap();
+        assert_eq!(recs[0].received_quantity, Some(dec!(0)));
+        assert_eq!(recs[0].fee_amount, Some(dec!(0)));
+
+        let config = ReaderConfig {
+            normalize_zero_quantities: true,
+            ..ReaderConfig::default()
+        };
+        let recs = read_tb_export_recs_from_reader_with_config(csv.as_bytes(), &config).unwrap();
+        assert_eq!(recs[0].received_quantity, None);
+        assert_eq!(recs[0].sent_quantity, Some(dec!(0.01)));
+        assert_eq!(recs[0].fee_amount, None);
+        assert_eq!(recs[0].validate(), Ok(()));
+        assert_eq!(recs[1].received_quantity, Some(dec!(0)));
+        assert_eq!(recs[1].sent_quantity, None);
+        assert_eq!(recs[1].market_value, Some(dec!(0)));
+    }
+
+    const COMMA_CSV: &str = r#"Date,Transaction Type,Received Quantity,Received Currency,Sent Quantity,Sent Currency,Fee Currency,Fee Amount,Market Value,Source,Internal Transfer,External ID
+2021-01-02T10:00:00.000Z,Buy,"0,00012345",BTC,"1.234,5",EUR,EUR,"1,25","1.234,5",Bitpanda,FALSE,id-1
+2021-01-03T10:00:00.000Z,Income,"1.234.567",ADA,,,,,12,Bitpanda,FALSE,id-2
+"#;
+
+    #[test]
+    fn test_comma_decimal_locale() {
+        let config = ReaderConfig {
+            decimal_locale: DecimalLocale::CommaDecimal,
+            ..ReaderConfig::default()
+        };
+        let recs =
+            read_tb_export_recs_from_reader_with_config(COMMA_CSV.as_bytes(), &config).unwrap();
+        assert_eq!(recs[0].received_quantity, Some(dec!(0.00012345)));
+        assert_eq!(recs[0].sent_quantity, Some(dec!(1234.5)));
+        assert_eq!(recs[0].fee_amount, Some(dec!(1.25)));
+        assert_eq!(recs[0].market_value, Some(dec!(1234.5)));
+        assert_eq!(recs[1].received_quantity, Some(dec!(1234567)));
+        assert_eq!(recs[1].market_value, Some(dec!(12)));
+
+        // Written with periods
+        let mut out: Vec<u8> = vec![];
+        crate::write_tb_export_recs_to_writer(&mut out, &recs).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains(",Buy,0.00012345,BTC,1234.5,EUR,EUR,1.25,1234.5,"));
+    }
+
+    #[test]
+    fn test_comma_decimal_locale_errors() {
+        // Ambiguous unless the locale is given
+        let e = read_tb_export_recs_from_reader(COMMA_CSV.as_bytes()).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "row 1, column 'Received Quantity': cannot parse '0,00012345' as decimal"
+        );
+
+        let config = ReaderConfig {
+            decimal_locale: DecimalLocale::CommaDecimal,
+            ..ReaderConfig::default()
+        };
+        for bad in ["1,2,3", "1.23,4", "0.5", "1,5.0"] {
+            let csv = COMMA_CSV.replace("\"1,25\"", &format!("\"{bad}\""));
+            let e = read_tb_export_recs_from_reader_with_config(csv.as_bytes(), &config)
+                .unwrap_err()
+                .downcast::<RowError>()
+                .unwrap();
+            assert_eq!(e.column.as_deref(), Some("Fee Amount"));
+            assert_eq!(e.raw, bad);
+        }
+    }
+
+    const DELIMITED: [(u8, &str); 3] = [
+        (
+            b',',
+            "Date,Transaction Type,Received Quantity,Received Currency,Sent Quantity,Sent Currency,Fee Currency,Fee Amount,Market Value,Source,Internal Transfer,External ID\n\
+            2021-01-02T10:00:00.000Z,Buy,0.03,BTC,990,USD,USD,1.5,990,\"Coinbase, Inc; Pro\",FALSE,id-1\n\
+            2021-01-03T10:00:00.000Z,Income,1.25,ADA,,,,,0.4,\"Coinbase, Inc; Pro\",FALSE,id-2\n",
+        ),
+        (
+            b';',
+            "Date;Transaction Type;Received Quantity;Received Currency;Sent Quantity;Sent Currency;Fee Currency;Fee Amount;Market Value;Source;Internal Transfer;External ID\n\
+            2021-01-02T10:00:00.000Z;Buy;0.03;BTC;990;USD;USD;1.5;990;\"Coinbase, Inc; Pro\";FALSE;id-1\n\
+            2021-01-03T10:00:00.000Z;Income;1.25;ADA;;;;;0.4;\"Coinbase, Inc; Pro\";FALSE;id-2\n",
+        ),
+        (
+            b'\t',
+            "Date\tTransaction Type\tReceived Quantity\tReceived Currency\tSent Quantity\tSent Currency\tFee Currency\tFee Amount\tMarket Value\tSource\tInternal Transfer\tExternal ID\n\
+            2021-01-02T10:00:00.000Z\tBuy\t0.03\tBTC\t990\tUSD\tUSD\t1.5\t990\tCoinbase, Inc; Pro\tFALSE\tid-1\n\
+            2021-01-03T10:00:00.000Z\tIncome\t1.25\tADA\t\t\t\t\t0.4\tCoinbase, Inc; Pro\tFALSE\tid-2\n",
+        ),
+    ];
+
+    #[test]
+    fn test_read_delimiters() {
+        let expected = read_tb_export_recs_from_reader(DELIMITED[0].1.as_bytes()).unwrap();
+        assert_eq!(expected.len(), 2);
+        assert_eq!(expected[0].source, "Coinbase, Inc; Pro");
+        assert_eq!(expected[0].fee_amount, Some(dec!(1.5)));
+
+        for (delimiter, csv) in DELIMITED {
+            let reader = TaxBitExportRecReader::new(csv.as_bytes()).unwrap();
+            assert_eq!(reader.delimiter(), delimiter);
+            let recs: Vec<TaxBitExportRec> = reader.collect::<Result<_, _>>().unwrap();
+            assert_eq!(recs, expected);
+
+            let config = ReaderConfig {
+                delimiter: Some(delimiter),
+                ..ReaderConfig::default()
+            };
+            let recs =
+                read_tb_export_recs_from_reader_with_config(csv.as_bytes(), &config).unwrap();
+            assert_eq!(recs, expected);
+        }
+
+        // The wrong delimiter finds no columns
+        let config = ReaderConfig {
+            delimiter: Some(b';'),
+            ..ReaderConfig::default()
+        };
+        assert!(TaxBitExportRecReader::new_with_config(DELIMITED[0].1.as_bytes(), config).is_err());
+    }
+
+    #[test]
+    fn test_write_delimiters() {
+        let recs = read_tb_export_recs_from_reader(DELIMITED[0].1.as_bytes()).unwrap();
+        for (delimiter, csv) in DELIMITED {
+            let config = crate::WriterConfig {
+                delimiter,
+                ..crate::WriterConfig::default()
+            };
+            let mut out: Vec<u8> = vec![];
+            crate::write_tb_export_recs_to_writer_with_config(&mut out, &recs, &config).unwrap();
+            assert_eq!(String::from_utf8(out).unwrap(), csv);
+        }
+    }
+
+    #[cfg(feature = "std-fs")]
+    #[test]
+    fn test_read_with_progress() {
+        use std::sync::{Arc, Mutex};
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("progress.csv");
+        let recs: Vec<TaxBitExportRec> = (0..250)
+            .map(|i| {
+                let mut rec = TaxBitExportRec::new();
+                rec.time = 1583134325000 + i * 1000;
+                rec.type_txs = TaxBitRecType::Income;
+                rec.received_quantity = Some(dec!(0.0054));
+                rec.received_currency = "XRP".to_owned();
+                rec.external_id = format!("id-{i}");
+                rec
+            })
+            .collect();
+        write_tb_export_rec_file(&path, &recs).unwrap();
+        let size = fs::metadata(&path).unwrap().len();
+
+        let events: Arc<Mutex<Vec<ProgressEvent>>> = Arc::default();
+        let sink = events.clone();
+        let progress = Progress::new(move |e| sink.lock().unwrap().push(e)).every_records(100);
+        let read = read_tb_export_rec_file_with_progress(&path, progress).unwrap();
+        assert_eq!(read, recs);
+
+        let events = events.lock().unwrap();
+        let done: Vec<u64> = events.iter().map(|e| e.records_done).collect();
+        assert_eq!(done, vec![100, 200, 250]);
+        assert_eq!(
+            *events.last().unwrap(),
+            ProgressEvent {
+                records_done: 250,
+                bytes_done: size,
+                total_bytes: Some(size),
+            }
+        );
+        assert!(events.windows(2).all(|w| w[0].bytes_done < w[1].bytes_done));
+    }
+
+    const RAW_CSV: &str = "Date,Transaction Type,Received Quantity,Received Currency,Sent Quantity,Sent Currency,Fee Currency,Fee Amount,Market Value,Source,Internal Transfer,External ID\r\n\
+        2020-03-02T07:32:05.000Z,Income,3e-7,BTC,,,,,0.0025979719720382955,BinanceUS,FALSE,\"a, daily\"\r\n\
+        \r\n\
+        2020-03-02T07:32:34.000Z,\"Income\",0.0054,XRP,,,,,0.00126,BinanceUS,TRUE,\"b\nc\"\r\n\
+        2020-03-02T07:33:00Z,Income,1,ETH,,,,,,\"Binance \"\"US\"\"\",FALSE,c\r\n";
+
+    #[test]
+    fn test_read_with_raw() {
+        let mut reader =
+            TaxBitExportRecReader::new_keeping_raw(RAW_CSV.as_bytes(), ReaderConfig::default())
+                .unwrap();
+        assert_eq!(
+            reader.raw_header(),
+            RAW_CSV.split_inclusive('\n').next().unwrap()
+        );
+
+        let mut raws: Vec<RawLine> = vec![];
+        while let Some(entry) = reader.read_with_raw() {
+            let entry = entry.unwrap();
+            // Derefs to the record
+            assert!(entry.received_quantity.is_some());
+            raws.push(entry.into_parts().1);
+        }
+        let texts: Vec<&str> = raws.iter().map(|r| r.text.as_str()).collect();
+        assert_eq!(
+            texts,
+            vec![
+                "2020-03-02T07:32:05.000Z,Income,3e-7,BTC,,,,,0.0025979719720382955,BinanceUS,FALSE,\"a, daily\"\r\n",
+                "2020-03-02T07:32:34.000Z,\"Income\",0.0054,XRP,,,,,0.00126,BinanceUS,TRUE,\"b\nc\"\r\n",
+                "2020-03-02T07:33:00Z,Income,1,ETH,,,,,,\"Binance \"\"US\"\"\",FALSE,c\r\n",
+            ]
+        );
+        assert_eq!(raws[2].line(), texts[2].trim_end());
+        for raw in &raws {
+            let offset = raw.offset as usize;
+            assert_eq!(&RAW_CSV[offset..offset + raw.text.len()], raw.text);
+        }
+        assert_eq!(
+            raws.iter().map(|r| r.row).collect::<Vec<usize>>(),
+            vec![1, 2, 3]
+        );
+
+        // A row error still returns the following records' raw text
+        let csv = CURRENT_CSV.replacen("Income,3e-7", "Income,x", 1);
+        let mut reader =
+            TaxBitExportRecReader::new_keeping_raw(csv.as_bytes(), ReaderConfig::default())
+                .unwrap();
+        assert!(reader.read_with_raw().unwrap().is_err());
+        let second = reader.read_with_raw().unwrap().unwrap();
+        assert_eq!(
+            second.raw.text,
+            CURRENT_CSV.lines().nth(2).unwrap().to_owned() + "\n"
+        );
+        assert!(reader.read_with_raw().is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "new_keeping_raw")]
+    fn test_read_with_raw_not_kept() {
+        let mut reader = TaxBitExportRecReader::new(CURRENT_CSV.as_bytes()).unwrap();
+        let _ = reader.read_with_raw();
+    }
+
+    #[test]
+    fn test_raw_pass_through() {
+        use crate::TaxBitExportRecWriter;
+
+        let csv = RAW_CSV.replace("\r\n\r\n", "\r\n");
+        let mut reader =
+            TaxBitExportRecReader::new_keeping_raw(csv.as_bytes(), ReaderConfig::default())
+                .unwrap();
+        let mut writer = TaxBitExportRecWriter::new(vec![]);
+        writer.write_raw_header(reader.raw_header()).unwrap();
+        assert!(writer.write_raw_header(reader.raw_header()).is_err());
+        while let Some(entry) = reader.read_with_raw() {
+            writer.write_raw(&entry.unwrap().raw).unwrap();
+        }
+        assert_eq!(
+            String::from_utf8(writer.into_inner().unwrap()).unwrap(),
+            csv
+        );
+
+        // Reads of 7 bytes split \r\n's across reads
+        struct Chunks<'a>(&'a [u8]);
+        impl Read for Chunks<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let n = self.0.len().min(buf.len()).min(7);
+                buf[..n].copy_from_slice(&self.0[..n]);
+                self.0 = &self.0[n..];
+                Ok(n)
+            }
+        }
+        let mut csv = CURRENT_CSV.replace('\n', "\r\n");
+        let row = csv.lines().nth(1).unwrap().to_owned();
+        for _ in 0..500 {
+            csv.push_str(&row);
+            csv.push_str("\r\n");
+        }
+        let mut reader =
+            TaxBitExportRecReader::new_keeping_raw(Chunks(csv.as_bytes()), ReaderConfig::default())
+                .unwrap();
+        let mut writer = TaxBitExportRecWriter::new(vec![]);
+        writer.write_raw_header(reader.raw_header()).unwrap();
+        while let Some(entry) = reader.read_with_raw() {
+            writer.write_raw(&entry.unwrap().raw).unwrap();
+        }
+        assert_eq!(
+            String::from_utf8(writer.into_inner().unwrap()).unwrap(),
+            csv
+        );
+
+        // Changed records are written by write_rec between raw lines
+        let mut reader =
+            TaxBitExportRecReader::new_keeping_raw(CURRENT_CSV.as_bytes(), ReaderConfig::default())
+                .unwrap();
+        let mut writer = TaxBitExportRecWriter::new(vec![]);
+        writer.write_raw_header(reader.raw_header()).unwrap();
+        let mut first = reader.read_with_raw().unwrap().unwrap();
+        first.source = "Binance.US".to_owned();
+        writer.write_rec(&first).unwrap();
+        writer
+            .write_raw(&reader.read_with_raw().unwrap().unwrap().raw)
+            .unwrap();
+        let out = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            out,
+            CURRENT_CSV.replacen(
+                "3e-7,BTC,,,,,0.0025979719720382955,BinanceUS,",
+                "0.0000003,BTC,,,,,0.0025979719720382955,Binance.US,",
+                1
+            )
+        );
+    }
+
+    #[test]
+    fn test_try_from_string_record() {
+        let csv = "2020-03-02T07:32:05.000Z,Income,3e-7,BTC,,,,,0.0026,\"Binance, US\",FALSE,a\n";
+        let record = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .from_reader(csv.as_bytes())
+            .records()
+            .next()
+            .unwrap()
+            .unwrap();
+        let rec = TaxBitExportRec::try_from(&record).unwrap();
+        assert_eq!(rec.time, 1583134325000);
+        assert_eq!(rec.received_quantity, Some(dec!(0.0000003)));
+        assert_eq!(rec.source, "Binance, US");
+        assert_eq!(rec.external_id, "a");
+
+        let record = rec.to_string_record().unwrap();
+        assert_eq!(record.get(2), Some("0.0000003"));
+        assert_eq!(record.get(9), Some("Binance, US"));
+        assert_eq!(TaxBitExportRec::try_from(&record).unwrap(), rec);
+
+        let bad: csv::StringRecord = record
+            .iter()
+            .map(|f| if f == "FALSE" { "maybe" } else { f })
+            .collect();
+        let err = TaxBitExportRec::try_from(&bad).unwrap_err();
+        let err = err.downcast::<RowError>().unwrap();
+        assert_eq!(err.column.as_deref(), Some("Internal Transfer"));
+        assert_eq!(err.raw, "maybe");
+
+        let short = csv::StringRecord::from(vec!["2020-03-02T07:32:05.000Z", "Income"]);
+        let err = TaxBitExportRec::try_from(&short).unwrap_err().to_string();
+        assert_eq!(err, "row 0: expected 12 fields, found 2");
+    }
+
+    #[test]
+    fn test_from_string_record_with_header() {
+        let header = header_of(LEGACY_CSV);
+        let mut reversed: Vec<&str> = header.iter().collect();
+        reversed.reverse();
+        let reversed = csv::StringRecord::from(reversed);
+
+        let expected = read_tb_export_recs_from_reader(LEGACY_CSV.as_bytes()).unwrap();
+        let mut rdr = csv::Reader::from_reader(LEGACY_CSV.as_bytes());
+        for (record, expected) in rdr.records().zip(&expected) {
+            let record = record.unwrap();
+            let rec = TaxBitExportRec::from_string_record_with_header(&header, &record).unwrap();
+            assert_eq!(&rec, expected);
+
+            let mut fields: Vec<&str> = record.iter().collect();
+            fields.reverse();
+            let record = csv::StringRecord::from(fields);
+            let rec = TaxBitExportRec::from_string_record_with_header(&reversed, &record).unwrap();
+            assert_eq!(&rec, expected);
+        }
+
+        let record = csv::StringRecord::from(vec!["x"; 11]);
+        let err = TaxBitExportRec::from_string_record_with_header(&header, &record).unwrap_err();
+        assert_eq!(
+            err.downcast::<RowError>().unwrap().column.as_deref(),
+            Some("Date")
+        );
+        let foreign = csv::StringRecord::from(vec!["Timestamp", "Amount"]);
+        assert!(TaxBitExportRec::from_string_record_with_header(&foreign, &record).is_err());
+    }
+}
@@ -0,0 +1,106 @@
+use rust_decimal::Decimal;
+use taxbitrec::TaxBitRecType;
+
+use crate::TaxBitExportRec;
+
+/// Convert negative fees, maker rebates, which TaxBit rejects into
+/// Income, returning the number converted.
+///
+/// For each record with a negative fee_amount an Income record is
+/// inserted after it receiving the rebate, the negated fee_amount, in
+/// the fee currency at the same time and source. Its external_id is the
+/// original's with "-rebate" appended and its market value is the rebate
+/// if the fee currency is USD, otherwise None. The original's fee amount
+/// and currency are cleared.
+pub fn convert_rebates(recs: &mut Vec<TaxBitExportRec>) -> usize {
+    let mut converted: Vec<TaxBitExportRec> = Vec::with_capacity(recs.len());
+    let mut count = 0;
+    for mut rec in recs.drain(..) {
+        let rebate = match rec.fee_amount {
+            Some(fee) if fee < Decimal::ZERO => {
+                let mut income = TaxBitExportRec::new();
+                income.time = rec.time;
+                income.type_txs = TaxBitRecType::Income;
+                income.received_quantity = Some(-fee);
+                income.received_currency = std::mem::take(&mut rec.fee_currency);
+                if income.received_currency == "USD" {
+                    income.market_value = Some(-fee);
+                }
+                income.source = rec.source.clone();
+                income.external_id = format!("{}-rebate", rec.external_id);
+                rec.fee_amount = None;
+                count += 1;
+                Some(income)
+            }
+            _ => None,
+        };
+        converted.push(rec);
+        converted.extend(rebate);
+    }
+    *recs = converted;
+
+    count
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::ValidationError;
+
+    fn trade(fee: Decimal, fee_currency: &str) -> TaxBitExportRec {
+        let mut rec = TaxBitExportRec::new();
+        rec.time = 1609581600000;
+        rec.type_txs = TaxBitRecType::Trade;
+        rec.sent_quantity = Some(dec!(0.35));
+        rec.sent_currency = "ETH".to_owned();
+        rec.received_quantity = Some(dec!(0.02314));
+        rec.received_currency = "BTC".to_owned();
+        rec.fee_amount = Some(fee);
+        rec.fee_currency = fee_currency.to_owned();
+        rec.source = "Kraken".to_owned();
+        rec.external_id = "T-1".to_owned();
+        rec
+    }
+
+    #[test]
+    fn test_negative_fee_is_invalid() {
+        assert_eq!(
+            trade(dec!(-0.0001), "BTC").validate(),
+            Err(vec![ValidationError::NegativeQuantity("Fee Amount")])
+        );
+    }
+
+    #[test]
+    fn test_convert_rebates() {
+        let mut recs = vec![
+            trade(dec!(0.5), "USD"),
+            trade(dec!(-0.0001), "BTC"),
+            trade(dec!(-0.25), "USD"),
+        ];
+        assert_eq!(convert_rebates(&mut recs), 2);
+        assert_eq!(recs.len(), 5);
+
+        assert_eq!(recs[0], trade(dec!(0.5), "USD"));
+        let original = &recs[1];
+        assert_eq!(original.fee_amount, None);
+        assert_eq!(original.fee_currency, "");
+        assert_eq!(original.validate(), Ok(()));
+
+        let income = &recs[2];
+        assert_eq!(income.type_txs, TaxBitRecType::Income);
+        assert_eq!(income.time, original.time);
+        assert_eq!(income.received_quantity, Some(dec!(0.0001)));
+        assert_eq!(income.received_currency, "BTC");
+        assert_eq!(income.market_value, None);
+        assert_eq!(income.source, "Kraken");
+        assert_eq!(income.external_id, "T-1-rebate");
+        assert_eq!(income.validate(), Ok(()));
+
+        assert_eq!(recs[4].received_currency, "USD");
+        assert_eq!(recs[4].market_value, Some(dec!(0.25)));
+
+        assert_eq!(convert_rebates(&mut recs), 0);
+    }
+}
@@ -0,0 +1,243 @@
+use std::{
+    collections::BTreeSet,
+    error::Error,
+    io::{Read, Write},
+};
+#[cfg(feature = "std-fs")]
+use std::{fs::File, io::BufReader, path::Path};
+
+use crate::{
+    TaxBitExportLayout, TaxBitExportRec, TaxBitExportRecReader, TaxBitExportRecWriter,
+    TB_EXPORT_REC_V2_HEADER,
+};
+
+const TRANSACTION_HASH_COLUMN: &str = TB_EXPORT_REC_V2_HEADER[12];
+const BLOCKCHAIN_COLUMN: &str = TB_EXPORT_REC_V2_HEADER[13];
+
+/// A record of TaxBit's newer export template, TB_EXPORT_REC_V2_HEADER,
+/// which adds "Transaction Hash" and "Blockchain" to TaxBitExportRec
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TaxBitExportRecV2 {
+    pub rec: TaxBitExportRec,
+    pub transaction_hash: Option<String>,
+    pub blockchain: Option<String>,
+}
+
+impl From<TaxBitExportRec> for TaxBitExportRecV2 {
+    /// The new fields are None
+    fn from(rec: TaxBitExportRec) -> Self {
+        TaxBitExportRecV2 {
+            rec,
+            transaction_hash: None,
+            blockchain: None,
+        }
+    }
+}
+
+impl From<TaxBitExportRecV2> for TaxBitExportRec {
+    /// Lossy, transaction_hash and blockchain are dropped
+    fn from(rec: TaxBitExportRecV2) -> Self {
+        rec.rec
+    }
+}
+
+// Move the V2 columns from the extras of a record read from a V2 file
+// to their fields, empty values are None
+fn rec_to_v2(mut rec: TaxBitExportRec) -> TaxBitExportRecV2 {
+    let mut take = |column: &str| rec.extras.remove(column).filter(|v| !v.is_empty());
+    let transaction_hash = take(TRANSACTION_HASH_COLUMN);
+    let blockchain = take(BLOCKCHAIN_COLUMN);
+    TaxBitExportRecV2 {
+        rec,
+        transaction_hash,
+        blockchain,
+    }
+}
+
+/// The records read by read_tb_export_recs in the layout of the file
+#[derive(Debug, Clone, PartialEq)]
+pub enum TaxBitExportRecs {
+    /// Read from a current or legacy layout file
+    V1(Vec<TaxBitExportRec>),
+
+    /// Read from a V2 layout file
+    V2(Vec<TaxBitExportRecV2>),
+}
+
+impl TaxBitExportRecs {
+    /// The records as TaxBitExportRec, V2 records lose their
+    /// transaction_hash and blockchain
+    pub fn into_v1(self) -> Vec<TaxBitExportRec> {
+        match self {
+            TaxBitExportRecs::V1(recs) => recs,
+            TaxBitExportRecs::V2(recs) => recs.into_iter().map(TaxBitExportRec::from).collect(),
+        }
+    }
+
+    /// The records as TaxBitExportRecV2, V1 records have no
+    /// transaction_hash or blockchain
+    pub fn into_v2(self) -> Vec<TaxBitExportRecV2> {
+        match self {
+            TaxBitExportRecs::V1(recs) => recs.into_iter().map(TaxBitExportRecV2::from).collect(),
+            TaxBitExportRecs::V2(recs) => recs,
+        }
+    }
+}
+
+/// Read TaxBit export records choosing the layout from the header, see
+/// verify_header. Files with the V2 layout return TaxBitExportRecs::V2
+/// all others TaxBitExportRecs::V1.
+pub fn read_tb_export_recs<R: Read>(rdr: R) -> Result<TaxBitExportRecs, Box<dyn Error>> {
+    let reader = TaxBitExportRecReader::new(rdr)?;
+    let layout = reader.layout();
+    let recs = reader.collect::<Result<Vec<TaxBitExportRec>, Box<dyn Error>>>()?;
+
+    Ok(match layout {
+        TaxBitExportLayout::V2 => TaxBitExportRecs::V2(recs.into_iter().map(rec_to_v2).collect()),
+        TaxBitExportLayout::Current | TaxBitExportLayout::Legacy => TaxBitExportRecs::V1(recs),
+    })
+}
+
+/// Read a TaxBit export file of any layout, see read_tb_export_recs
+#[cfg(feature = "std-fs")]
+pub fn read_tb_export_recs_file(path: &Path) -> Result<TaxBitExportRecs, Box<dyn Error>> {
+    read_tb_export_recs(BufReader::new(File::open(path)?))
+}
+
+/// Write the records to a file as write_tb_export_rec_v2_to_writer does
+#[cfg(feature = "std-fs")]
+pub fn write_tb_export_rec_v2_file(
+    path: &Path,
+    recs: &[TaxBitExportRecV2],
+) -> Result<(), Box<dyn Error>> {
+    write_tb_export_rec_v2_to_writer(File::create(path)?, recs)
+}
+
+/// Write the records to wtr using the V2 layout. As with
+/// write_tb_export_recs_to_writer the lot ID column is written if any
+/// record has a lot_id and extras are written as additional columns,
+/// both after the V2 columns.
+pub fn write_tb_export_rec_v2_to_writer<W: Write>(
+    wtr: W,
+    recs: &[TaxBitExportRecV2],
+) -> Result<(), Box<dyn Error>> {
+    let mut extra_columns = vec![
+        TRANSACTION_HASH_COLUMN.to_owned(),
+        BLOCKCHAIN_COLUMN.to_owned(),
+    ];
+    extra_columns.extend(
+        recs.iter()
+            .flat_map(|r| r.rec.extras.keys().cloned())
+            .filter(|c| !extra_columns.contains(c))
+            .collect::<BTreeSet<String>>(),
+    );
+
+    let mut writer = TaxBitExportRecWriter::new(wtr)
+        .with_lot_id_column(recs.iter().any(|r| r.rec.lot_id.is_some()))
+        .with_extra_columns(&extra_columns);
+    for rec_v2 in recs {
+        let mut rec = rec_v2.rec.clone();
+        rec.extras.insert(
+            TRANSACTION_HASH_COLUMN.to_owned(),
+            rec_v2.transaction_hash.clone().unwrap_or_default(),
+        );
+        rec.extras.insert(
+            BLOCKCHAIN_COLUMN.to_owned(),
+            rec_v2.blockchain.clone().unwrap_or_default(),
+        );
+        writer.write_rec(&rec)?;
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    #[cfg(feature = "std-fs")]
+    use std::fs;
+
+    use rust_decimal_macros::dec;
+    use taxbitrec::TaxBitRecType;
+
+    use super::*;
+    use crate::verify_header;
+    #[cfg(feature = "std-fs")]
+    use crate::write_tb_export_rec_file;
+
+    const V2_CSV: &str = r#"Date,Transaction Type,Received Quantity,Received Currency,Sent Quantity,Sent Currency,Fee Currency,Fee Amount,Market Value,Source,Internal Transfer,External ID,Transaction Hash,Blockchain
+2021-01-02T10:00:00.000Z,Transfer In,0.5,ETH,,,,,,Coinbase,FALSE,id-1,0xabc,Ethereum
+2021-01-03T10:00:00.000Z,Buy,0.03,BTC,990,USD,,,990,Coinbase,FALSE,id-2,,
+"#;
+
+    #[test]
+    fn test_read_v2() {
+        let header = csv::Reader::from_reader(V2_CSV.as_bytes())
+            .headers()
+            .unwrap()
+            .clone();
+        assert_eq!(verify_header(&header).unwrap(), TaxBitExportLayout::V2);
+
+        let recs = match read_tb_export_recs(V2_CSV.as_bytes()).unwrap() {
+            TaxBitExportRecs::V2(recs) => recs,
+            recs => panic!("Expected V2 records, got {recs:?}"),
+        };
+        assert_eq!(recs.len(), 2);
+        assert_eq!(recs[0].rec.type_txs, TaxBitRecType::TransferIn);
+        assert_eq!(recs[0].rec.received_quantity, Some(dec!(0.5)));
+        assert_eq!(recs[0].transaction_hash.as_deref(), Some("0xabc"));
+        assert_eq!(recs[0].blockchain.as_deref(), Some("Ethereum"));
+        assert!(recs[0].rec.extras.is_empty());
+        assert_eq!(recs[1].transaction_hash, None);
+        assert_eq!(recs[1].blockchain, None);
+    }
+
+    #[cfg(feature = "std-fs")]
+    #[test]
+    fn test_downconvert_upconvert() {
+        let recs_v2 = read_tb_export_recs(V2_CSV.as_bytes()).unwrap().into_v2();
+        let recs = read_tb_export_recs(V2_CSV.as_bytes()).unwrap().into_v1();
+        assert_eq!(recs.len(), 2);
+        assert_eq!(recs[0], recs_v2[0].rec);
+
+        let dir = tempfile::tempdir().unwrap();
+        let v1_path = dir.path().join("v1.csv");
+        write_tb_export_rec_file(&v1_path, &recs).unwrap();
+        let recs_v1 = match read_tb_export_recs_file(&v1_path).unwrap() {
+            TaxBitExportRecs::V1(recs) => recs,
+            recs => panic!("Expected V1 records, got {recs:?}"),
+        };
+        assert_eq!(recs_v1, recs);
+
+        let upconverted: Vec<TaxBitExportRecV2> =
+            recs_v1.into_iter().map(TaxBitExportRecV2::from).collect();
+        assert_eq!(upconverted[0].rec, recs_v2[0].rec);
+        assert_eq!(upconverted[0].transaction_hash, None);
+        assert_eq!(upconverted[0].blockchain, None);
+    }
+
+    #[cfg(feature = "std-fs")]
+    #[test]
+    fn test_write_v2_round_trip() {
+        let recs = read_tb_export_recs(V2_CSV.as_bytes()).unwrap().into_v2();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("v2.csv");
+        write_tb_export_rec_v2_file(&path, &recs).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), V2_CSV);
+        assert_eq!(
+            read_tb_export_recs_file(&path).unwrap(),
+            TaxBitExportRecs::V2(recs)
+        );
+    }
+
+    #[test]
+    fn test_v2_from_reader_to_writer() {
+        let cursor = std::io::Cursor::new(V2_CSV.as_bytes().to_vec());
+        let recs = read_tb_export_recs(cursor).unwrap().into_v2();
+
+        let mut out: Vec<u8> = vec![];
+        write_tb_export_rec_v2_to_writer(&mut out, &recs).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), V2_CSV);
+    }
+}
@@ -0,0 +1,270 @@
+use std::collections::BTreeMap;
+
+use rust_decimal::Decimal;
+use taxbitrec::TaxBitRecType;
+
+use crate::TaxBitExportRec;
+
+// The sign of a record's market value in a signed running total, None
+// for types which don't move value in or out such as Trade
+fn direction(type_txs: &TaxBitRecType) -> Option<Decimal> {
+    match type_txs {
+        TaxBitRecType::Buy
+        | TaxBitRecType::Income
+        | TaxBitRecType::TransferIn
+        | TaxBitRecType::GiftReceived => Some(Decimal::ONE),
+        TaxBitRecType::Sale
+        | TaxBitRecType::Expense
+        | TaxBitRecType::TransferOut
+        | TaxBitRecType::GiftSent => Some(Decimal::NEGATIVE_ONE),
+        TaxBitRecType::Trade | TaxBitRecType::Unknown | TaxBitRecType::Invalid => None,
+    }
+}
+
+/// Iterator of (time, cumulative market value), see RunningTotalsExt
+pub struct RunningTotals<I> {
+    iter: I,
+    signed: bool,
+    total: Decimal,
+    skipped: usize,
+    last_time: Option<i64>,
+}
+
+impl<I> RunningTotals<I> {
+    /// The number of records skipped so far as they have no market value
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+}
+
+impl<'a, I: Iterator<Item = &'a TaxBitExportRec>> Iterator for RunningTotals<I> {
+    type Item = (i64, Decimal);
+
+    fn next(&mut self) -> Option<(i64, Decimal)> {
+        for rec in self.iter.by_ref() {
+            debug_assert!(
+                !matches!(self.last_time, Some(t) if t > rec.time),
+                "running_totals requires records sorted by time"
+            );
+            self.last_time = Some(rec.time);
+
+            let sign = match self.signed {
+                true => match direction(&rec.type_txs) {
+                    Some(sign) => sign,
+                    None => continue,
+                },
+                false => Decimal::ONE,
+            };
+            match rec.market_value {
+                Some(mv) => {
+                    self.total += sign * mv;
+                    return Some((rec.time, self.total));
+                }
+                None => self.skipped += 1,
+            }
+        }
+
+        None
+    }
+}
+
+/// Running totals of each asset, see RunningTotalsExt::running_totals_by_asset
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AssetRunningTotals {
+    /// The (time, cumulative market value) series of each asset
+    pub series: BTreeMap<String, Vec<(i64, Decimal)>>,
+
+    /// The number of records skipped as they have no market value
+    pub skipped: usize,
+}
+
+/// Running totals of the market values of records sorted by time, for
+/// instance to chart the value flowing into a portfolio. Filter the
+/// records first to total only some types.
+pub trait RunningTotalsExt<'a>: Iterator<Item = &'a TaxBitExportRec> + Sized {
+    /// The time of each record and the sum of the market values of it
+    /// and the records before it. Records with no market value are
+    /// skipped and counted, see RunningTotals::skipped.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds if the records aren't sorted by time
+    fn running_totals(self) -> RunningTotals<Self> {
+        RunningTotals {
+            iter: self,
+            signed: false,
+            total: Decimal::ZERO,
+            skipped: 0,
+            last_time: None,
+        }
+    }
+
+    /// Running totals as running_totals with the market values of Buy,
+    /// Income, TransferIn and GiftReceived records added and of Sale,
+    /// Expense, TransferOut and GiftSent records subtracted. Other types
+    /// are passed over without being counted as skipped.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds if the records aren't sorted by time
+    fn signed_running_totals(self) -> RunningTotals<Self> {
+        RunningTotals {
+            signed: true,
+            ..self.running_totals()
+        }
+    }
+
+    /// The running totals of each asset, see TaxBitExportRec::try_get_asset,
+    /// as running_totals or, if signed, signed_running_totals computes
+    /// them. Records of Unknown type have no asset and are passed over.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds if the records aren't sorted by time
+    fn running_totals_by_asset(self, signed: bool) -> AssetRunningTotals {
+        let mut by_asset: BTreeMap<&str, Vec<&TaxBitExportRec>> = BTreeMap::new();
+        let mut last_time: Option<i64> = None;
+        for rec in self {
+            debug_assert!(
+                !matches!(last_time, Some(t) if t > rec.time),
+                "running_totals_by_asset requires records sorted by time"
+            );
+            last_time = Some(rec.time);
+            if let Some(asset) = rec.try_get_asset() {
+                by_asset.entry(asset).or_default().push(rec);
+            }
+        }
+
+        let mut totals = AssetRunningTotals::default();
+        for (asset, recs) in by_asset {
+            let mut running = RunningTotals {
+                signed,
+                ..recs.into_iter().running_totals()
+            };
+            let series: Vec<(i64, Decimal)> = running.by_ref().collect();
+            totals.skipped += running.skipped();
+            if !series.is_empty() {
+                totals.series.insert(asset.to_owned(), series);
+            }
+        }
+
+        totals
+    }
+}
+
+impl<'a, I: Iterator<Item = &'a TaxBitExportRec>> RunningTotalsExt<'a> for I {}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    // 2022-01-01T00:00:00Z
+    const T: i64 = 1640995200000;
+
+    fn rec(
+        time: i64,
+        type_txs: TaxBitRecType,
+        asset: &str,
+        market_value: Option<Decimal>,
+    ) -> TaxBitExportRec {
+        let mut rec = TaxBitExportRec::new();
+        rec.time = time;
+        rec.type_txs = type_txs;
+        rec.received_currency = asset.to_owned();
+        rec.sent_currency = asset.to_owned();
+        rec.market_value = market_value;
+        rec
+    }
+
+    fn history() -> Vec<TaxBitExportRec> {
+        vec![
+            rec(T, TaxBitRecType::Buy, "BTC", Some(dec!(4000.10))),
+            rec(T + 1, TaxBitRecType::Income, "ETH", Some(dec!(0.000001))),
+            rec(T + 2, TaxBitRecType::Income, "ETH", None),
+            rec(T + 3, TaxBitRecType::Trade, "ETH", Some(dec!(100))),
+            rec(T + 4, TaxBitRecType::Sale, "BTC", Some(dec!(2100.05))),
+            rec(T + 5, TaxBitRecType::Unknown, "", None),
+            rec(T + 6, TaxBitRecType::GiftSent, "ETH", Some(dec!(0.1))),
+        ]
+    }
+
+    #[test]
+    fn test_running_totals() {
+        let recs = history();
+        let mut totals = recs.iter().running_totals();
+        let series: Vec<(i64, Decimal)> = totals.by_ref().collect();
+        assert_eq!(
+            series,
+            vec![
+                (T, dec!(4000.10)),
+                (T + 1, dec!(4000.100001)),
+                (T + 3, dec!(4100.100001)),
+                (T + 4, dec!(6200.150001)),
+                (T + 6, dec!(6200.250001)),
+            ]
+        );
+        assert_eq!(totals.skipped(), 2);
+
+        // Only Income
+        let income: Vec<(i64, Decimal)> = recs
+            .iter()
+            .filter(|r| r.type_txs == TaxBitRecType::Income)
+            .running_totals()
+            .collect();
+        assert_eq!(income, vec![(T + 1, dec!(0.000001))]);
+    }
+
+    #[test]
+    fn test_signed_running_totals() {
+        let recs = history();
+        let mut totals = recs.iter().signed_running_totals();
+        let series: Vec<(i64, Decimal)> = totals.by_ref().collect();
+        assert_eq!(
+            series,
+            vec![
+                (T, dec!(4000.10)),
+                (T + 1, dec!(4000.100001)),
+                (T + 4, dec!(1900.050001)),
+                (T + 6, dec!(1899.950001)),
+            ]
+        );
+        assert_eq!(totals.skipped(), 1);
+    }
+
+    #[test]
+    fn test_running_totals_by_asset() {
+        let recs = history();
+        let totals = recs.iter().running_totals_by_asset(true);
+        assert_eq!(
+            totals.series,
+            BTreeMap::from([
+                (
+                    "BTC".to_owned(),
+                    vec![(T, dec!(4000.10)), (T + 4, dec!(1900.05))]
+                ),
+                (
+                    "ETH".to_owned(),
+                    vec![(T + 1, dec!(0.000001)), (T + 6, dec!(-0.099999))]
+                ),
+            ])
+        );
+        assert_eq!(totals.skipped, 1);
+
+        let totals = recs.iter().running_totals_by_asset(false);
+        assert_eq!(
+            totals.series["ETH"].last(),
+            Some(&(T + 6, dec!(100.100001)))
+        );
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "sorted by time")]
+    fn test_running_totals_unsorted() {
+        let mut recs = history();
+        recs.swap(0, 1);
+        recs.iter().running_totals().for_each(drop);
+    }
+}
@@ -0,0 +1,289 @@
+use std::collections::BTreeSet;
+
+#[cfg(feature = "clock")]
+use chrono::Utc;
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use taxbitrec::TaxBitRecType;
+
+use crate::{Severity, TaxBitExportRec};
+
+/// Options for sanity_check
+#[derive(Debug, Clone)]
+pub struct SanityOpts {
+    /// Times before this are reported, 2009-01-01T00:00:00Z by default
+    /// as there were no crypto assets before then
+    pub min_time_ms: i64,
+
+    /// The current time, None for the system clock with the clock
+    /// feature, without it None skips the "future-time" rule
+    pub now_ms: Option<i64>,
+
+    /// How far after now a time may be before it's reported, one day by
+    /// default so a time zone mistake in a recent record isn't
+    pub future_tolerance_ms: i64,
+
+    /// Market values over this are reported, 1,000,000,000 by default
+    pub max_market_value: Decimal,
+
+    /// Quantities with more decimal places than this, ignoring trailing
+    /// zeros, are reported, 18 by default
+    pub max_scale: u32,
+}
+
+impl Default for SanityOpts {
+    fn default() -> Self {
+        SanityOpts {
+            min_time_ms: 1230768000000,
+            now_ms: None,
+            future_tolerance_ms: 24 * 60 * 60 * 1000,
+            max_market_value: dec!(1_000_000_000),
+            max_scale: 18,
+        }
+    }
+}
+
+/// A suspicious value found by sanity_check
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SanityFinding {
+    pub severity: Severity,
+
+    /// The rule which found it, such as "future-time", see sanity_check
+    pub rule: &'static str,
+
+    /// Index of the record
+    pub index: usize,
+
+    /// The offending value as it would be written
+    pub value: String,
+
+    pub message: String,
+}
+
+/// Look for values which are valid, see TaxBitExportRec::validate, but
+/// are likely garbage, returning the findings ordered by record index.
+/// The rules are:
+///
+/// - "early-time", Error: a time before opts.min_time_ms
+/// - "future-time", Error: a time more than opts.future_tolerance_ms
+///   after now, see SanityOpts::now_ms
+/// - "huge-market-value", Warning: a Market Value over
+///   opts.max_market_value
+/// - "quantity-scale", Warning: a quantity or Fee Amount with more than
+///   opts.max_scale decimal places
+/// - "numeric-currency", Error: a currency which is a number, likely a
+///   quantity in the wrong column
+/// - "currency-source", Warning: a Source which is, ignoring case, a
+///   currency of any of the records
+/// - "same-currency-trade", Error: a Trade whose received and sent
+///   currencies are the same ignoring case
+pub fn sanity_check(recs: &[TaxBitExportRec], opts: SanityOpts) -> Vec<SanityFinding> {
+    #[cfg(feature = "clock")]
+    let now_ms = Some(opts.now_ms.unwrap_or_else(|| Utc::now().timestamp_millis()));
+    #[cfg(not(feature = "clock"))]
+    let now_ms = opts.now_ms;
+    let currencies: BTreeSet<String> = recs
+        .iter()
+        .flat_map(|r| [&r.received_currency, &r.sent_currency, &r.fee_currency])
+        .filter(|c| !c.is_empty())
+        .map(|c| c.to_uppercase())
+        .collect();
+
+    let mut findings = vec![];
+    for (index, rec) in recs.iter().enumerate() {
+        let mut finding =
+            |severity: Severity, rule: &'static str, value: String, message: String| {
+                findings.push(SanityFinding {
+                    severity,
+                    rule,
+                    index,
+                    value,
+                    message,
+                })
+            };
+        let time = || rec.time_utc().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string();
+
+        if rec.time < opts.min_time_ms {
+            finding(
+                Severity::Error,
+                "early-time",
+                time(),
+                "Time is before any crypto asset existed".to_owned(),
+            );
+        }
+        if now_ms.is_some_and(|now| rec.time > now.saturating_add(opts.future_tolerance_ms)) {
+            finding(
+                Severity::Error,
+                "future-time",
+                time(),
+                "Time is in the future".to_owned(),
+            );
+        }
+
+        if let Some(mv) = rec.market_value.filter(|mv| *mv > opts.max_market_value) {
+            finding(
+                Severity::Warning,
+                "huge-market-value",
+                mv.to_string(),
+                format!("Market Value is over {}", opts.max_market_value),
+            );
+        }
+
+        for (column, quantity) in [
+            ("Received Quantity", rec.received_quantity),
+            ("Sent Quantity", rec.sent_quantity),
+            ("Fee Amount", rec.fee_amount),
+        ] {
+            if let Some(q) = quantity.filter(|q| q.normalize().scale() > opts.max_scale) {
+                finding(
+                    Severity::Warning,
+                    "quantity-scale",
+                    q.to_string(),
+                    format!("{column} has more than {} decimal places", opts.max_scale),
+                );
+            }
+        }
+
+        for (column, currency) in [
+            ("Received Currency", &rec.received_currency),
+            ("Sent Currency", &rec.sent_currency),
+            ("Fee Currency", &rec.fee_currency),
+        ] {
+            if currency.trim().parse::<Decimal>().is_ok() {
+                finding(
+                    Severity::Error,
+                    "numeric-currency",
+                    currency.clone(),
+                    format!("{column} is a number"),
+                );
+            }
+        }
+
+        if currencies.contains(&rec.source.to_uppercase()) {
+            finding(
+                Severity::Warning,
+                "currency-source",
+                rec.source.clone(),
+                "Source is a currency".to_owned(),
+            );
+        }
+
+        if rec.type_txs == TaxBitRecType::Trade
+            && !rec.received_currency.is_empty()
+            && rec
+                .received_currency
+                .eq_ignore_ascii_case(&rec.sent_currency)
+        {
+            finding(
+                Severity::Error,
+                "same-currency-trade",
+                rec.received_currency.clone(),
+                "Trade receives the currency it sends".to_owned(),
+            );
+        }
+    }
+
+    findings
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // 2023-06-01T00:00:00Z
+    const NOW: i64 = 1685577600000;
+    const DAY: i64 = 24 * 60 * 60 * 1000;
+
+    fn trade(time: i64) -> TaxBitExportRec {
+        let mut rec = TaxBitExportRec::new();
+        rec.time = time;
+        rec.type_txs = TaxBitRecType::Trade;
+        rec.received_quantity = Some(dec!(1.5));
+        rec.received_currency = "ETH".to_owned();
+        rec.sent_quantity = Some(dec!(0.1));
+        rec.sent_currency = "BTC".to_owned();
+        rec.fee_amount = Some(dec!(0.001));
+        rec.fee_currency = "ETH".to_owned();
+        rec.market_value = Some(dec!(2700));
+        rec.source = "Kraken".to_owned();
+        rec.external_id = format!("{time}");
+        rec
+    }
+
+    fn opts() -> SanityOpts {
+        SanityOpts {
+            now_ms: Some(NOW),
+            ..SanityOpts::default()
+        }
+    }
+
+    #[test]
+    fn test_sanity_check_clean() {
+        let recs: Vec<TaxBitExportRec> = (0..3).map(|d| trade(NOW - d * DAY)).collect();
+        assert_eq!(sanity_check(&recs, opts()), vec![]);
+
+        // Within the future tolerance
+        assert_eq!(sanity_check(&[trade(NOW + DAY / 2)], opts()), vec![]);
+    }
+
+    #[test]
+    fn test_sanity_check_rules() {
+        let mut recs: Vec<TaxBitExportRec> = (0..7).map(|d| trade(NOW - d * DAY)).collect();
+        recs[0].time = 1230767999999;
+        recs[1].time = NOW + 2 * DAY;
+        recs[2].market_value = Some(dec!(5_000_000_000));
+        recs[3].sent_quantity = Some(dec!(0.0000000000000000001));
+        recs[4].fee_currency = "0.001".to_owned();
+        recs[5].source = "eth".to_owned();
+        recs[6].sent_currency = "eth".to_owned();
+
+        let findings = sanity_check(&recs, opts());
+        let rules: Vec<(usize, &str, Severity)> = findings
+            .iter()
+            .map(|f| (f.index, f.rule, f.severity))
+            .collect();
+        assert_eq!(
+            rules,
+            vec![
+                (0, "early-time", Severity::Error),
+                (1, "future-time", Severity::Error),
+                (2, "huge-market-value", Severity::Warning),
+                (3, "quantity-scale", Severity::Warning),
+                (4, "numeric-currency", Severity::Error),
+                (5, "currency-source", Severity::Warning),
+                (6, "same-currency-trade", Severity::Error),
+            ]
+        );
+        assert_eq!(findings[0].value, "2008-12-31T23:59:59.999Z");
+        assert_eq!(findings[2].value, "5000000000");
+        assert_eq!(findings[3].value, "0.0000000000000000001");
+        assert_eq!(
+            findings[3].message,
+            "Sent Quantity has more than 18 decimal places"
+        );
+        assert_eq!(findings[4].value, "0.001");
+        assert_eq!(findings[5].value, "eth");
+
+        // Trailing zeros don't count and the thresholds are configurable
+        recs[3].sent_quantity = Some(dec!(0.10000000000000000000));
+        let opts = SanityOpts {
+            max_market_value: dec!(10_000_000_000),
+            ..opts()
+        };
+        let findings = sanity_check(&recs, opts);
+        assert!(!findings
+            .iter()
+            .any(|f| f.rule == "huge-market-value" || f.rule == "quantity-scale"));
+        assert!(findings.iter().any(|f| f.severity == Severity::Error));
+    }
+
+    #[test]
+    fn test_sanity_check_system_clock() {
+        let findings = sanity_check(&[trade(i64::MAX / 2)], SanityOpts::default());
+        if cfg!(feature = "clock") {
+            assert_eq!(findings[0].rule, "future-time");
+        } else {
+            assert_eq!(findings, vec![]);
+        }
+    }
+}
@@ -0,0 +1,841 @@
+use std::{borrow::Borrow, cmp::Ordering, collections::BinaryHeap, path::PathBuf};
+#[cfg(feature = "std-fs")]
+use std::{
+    collections::BTreeSet,
+    error::Error,
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::Path,
+};
+
+use crate::TaxBitExportRec;
+#[cfg(feature = "std-fs")]
+use crate::{Progress, ProgressEvent, TaxBitExportRecReader, TaxBitExportRecWriter};
+
+/// Options for external_sort_file
+#[derive(Debug, Clone)]
+pub struct ExternalSortOpts {
+    /// Maximum number of records sorted in memory and written to each run
+    pub run_size: usize,
+
+    /// Directory for the temporary run files, the system temp directory if None
+    pub temp_dir: Option<PathBuf>,
+}
+
+impl Default for ExternalSortOpts {
+    fn default() -> Self {
+        ExternalSortOpts {
+            run_size: 200_000,
+            temp_dir: None,
+        }
+    }
+}
+
+/// Statistics of an external_sort_file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SortStats {
+    /// Number of sorted runs written to temporary files
+    pub runs: usize,
+
+    /// Number of records sorted
+    pub records: usize,
+
+    /// The most records held in memory at one time
+    pub peak_buffered: usize,
+}
+
+/// Sort a TaxBit export file too large for memory.
+///
+/// The input is streamed in runs of opts.run_size records, each is sorted
+/// and written to a temporary file and then the runs are k-way merged to
+/// output. The order is the same as sorting in memory. The temporary files
+/// are removed when done, even on error.
+#[cfg(feature = "std-fs")]
+pub fn external_sort_file(
+    input: &Path,
+    output: &Path,
+    opts: ExternalSortOpts,
+) -> Result<SortStats, Box<dyn Error>> {
+    sort_file(input, output, opts, None::<Progress>)
+}
+
+/// Sort a TaxBit export file as external_sort_file does reporting the
+/// progress of reading the input, the total bytes is the size of input
+/// unless progress has one. The last event is sent when all the input
+/// has been read, before the runs are merged.
+#[cfg(feature = "std-fs")]
+pub fn external_sort_file_with_progress<C: ?Sized + FnMut(ProgressEvent)>(
+    input: &Path,
+    output: &Path,
+    opts: ExternalSortOpts,
+    progress: Progress<C>,
+) -> Result<SortStats, Box<dyn Error>> {
+    let progress = match progress.has_total_bytes() {
+        true => progress,
+        false => progress.total_bytes(std::fs::metadata(input)?.len()),
+    };
+    sort_file(input, output, opts, Some(progress))
+}
+
+#[cfg(feature = "std-fs")]
+fn sort_file<C: ?Sized + FnMut(ProgressEvent)>(
+    input: &Path,
+    output: &Path,
+    opts: ExternalSortOpts,
+    progress: Option<Progress<C>>,
+) -> Result<SortStats, Box<dyn Error>> {
+    if opts.run_size == 0 {
+        return Err("ExternalSortOpts::run_size must be greater than 0".into());
+    }
+
+    let temp_dir = match &opts.temp_dir {
+        Some(dir) => tempfile::tempdir_in(dir)?,
+        None => tempfile::tempdir()?,
+    };
+
+    let mut stats = SortStats::default();
+    let mut extra_columns: BTreeSet<String> = BTreeSet::new();
+    let mut run_paths: Vec<PathBuf> = vec![];
+
+    let reader =
+        TaxBitExportRecReader::new(BufReader::new(File::open(input)?))?.with_progress_opt(progress);
+    let lot_id_column = reader.has_lot_id_column();
+    let mut run: Vec<TaxBitExportRec> = Vec::with_capacity(opts.run_size);
+    let mut reader = reader.peekable();
+    while reader.peek().is_some() {
+        run.clear();
+        for entry in reader.by_ref().take(opts.run_size) {
+            run.push(entry?);
+        }
+        stats.peak_buffered = stats.peak_buffered.max(run.len());
+        stats.records += run.len();
+        run.sort();
+
+        let run_columns: Vec<String> = run
+            .iter()
+            .flat_map(|r| r.extras.keys().cloned())
+            .collect::<BTreeSet<String>>()
+            .into_iter()
+            .collect();
+        extra_columns.extend(run_columns.iter().cloned());
+
+        let run_path = temp_dir.path().join(format!("run-{}.csv", run_paths.len()));
+        let mut writer = TaxBitExportRecWriter::new(BufWriter::new(File::create(&run_path)?))
+            .with_lot_id_column(run.iter().any(|r| r.lot_id.is_some()))
+            .with_extra_columns(&run_columns);
+        for rec in &run {
+            writer.write_rec(rec)?;
+        }
+        writer.flush()?;
+        run_paths.push(run_path);
+    }
+    drop(run);
+    stats.runs = run_paths.len();
+
+    let mut runs = run_paths
+        .iter()
+        .map(|p| TaxBitExportRecReader::new(BufReader::new(File::open(p)?)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let extra_columns: Vec<String> = extra_columns.into_iter().collect();
+    let mut writer = TaxBitExportRecWriter::new(BufWriter::new(File::create(output)?))
+        .with_lot_id_column(lot_id_column)
+        .with_extra_columns(&extra_columns);
+
+    let mut heap: BinaryHeap<MergeEntry<TaxBitExportRec>> = BinaryHeap::new();
+    for (input, run) in runs.iter_mut().enumerate() {
+        if let Some(entry) = run.next() {
+            heap.push(MergeEntry { rec: entry?, input });
+        }
+    }
+    stats.peak_buffered = stats.peak_buffered.max(heap.len());
+
+    while let Some(MergeEntry { rec, input }) = heap.pop() {
+        writer.write_rec(&rec)?;
+        if let Some(entry) = runs[input].next() {
+            heap.push(MergeEntry { rec: entry?, input });
+        }
+    }
+    writer.flush()?;
+
+    Ok(stats)
+}
+
+// The next record of an input of a k-way merge, ordered so a
+// BinaryHeap, a max heap, pops the least record first with ties going
+// to the lower input index
+struct MergeEntry<R: Borrow<TaxBitExportRec>> {
+    rec: R,
+    input: usize,
+}
+
+impl<R: Borrow<TaxBitExportRec>> Ord for MergeEntry<R> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .rec
+            .borrow()
+            .cmp(self.rec.borrow())
+            .then(other.input.cmp(&self.input))
+    }
+}
+
+impl<R: Borrow<TaxBitExportRec>> PartialOrd for MergeEntry<R> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<R: Borrow<TaxBitExportRec>> PartialEq for MergeEntry<R> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<R: Borrow<TaxBitExportRec>> Eq for MergeEntry<R> {}
+
+/// Iterator over sorted slices of records in sorted order, see
+/// interleave_sorted
+pub struct InterleaveSorted<'a> {
+    sets: Vec<&'a [TaxBitExportRec]>,
+    // The position of the next record of each set to push on the heap
+    next: Vec<usize>,
+    heap: BinaryHeap<MergeEntry<&'a TaxBitExportRec>>,
+}
+
+impl<'a> Iterator for InterleaveSorted<'a> {
+    type Item = &'a TaxBitExportRec;
+
+    fn next(&mut self) -> Option<&'a TaxBitExportRec> {
+        let MergeEntry { rec, input } = self.heap.pop()?;
+        if let Some(next) = self.sets[input].get(self.next[input]) {
+            self.heap.push(MergeEntry { rec: next, input });
+            self.next[input] += 1;
+        }
+
+        Some(rec)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.heap.len()
+            + self
+                .sets
+                .iter()
+                .zip(&self.next)
+                .map(|(set, next)| set.len() - next)
+                .sum::<usize>();
+        (remaining, Some(remaining))
+    }
+}
+
+/// Walk sorted slices of records in sorted order without concatenating
+/// and sorting them, the in memory analogue of merge_sorted_files. Equal
+/// records are returned in the order of their slices.
+///
+/// The slices aren't checked, if one isn't sorted neither is the
+/// output, see interleave_sorted_checked.
+pub fn interleave_sorted<'a>(sets: &[&'a [TaxBitExportRec]]) -> InterleaveSorted<'a> {
+    let mut heap = BinaryHeap::with_capacity(sets.len());
+    for (input, set) in sets.iter().enumerate() {
+        if let Some(rec) = set.first() {
+            heap.push(MergeEntry { rec, input });
+        }
+    }
+
+    InterleaveSorted {
+        sets: sets.to_vec(),
+        next: sets.iter().map(|s| s.len().min(1)).collect(),
+        heap,
+    }
+}
+
+/// Walk sorted slices of records as interleave_sorted does after
+/// checking each is sorted, an error names the first slice which isn't
+/// and the position of the first record before its predecessor
+pub fn interleave_sorted_checked<'a>(
+    sets: &[&'a [TaxBitExportRec]],
+) -> Result<InterleaveSorted<'a>, String> {
+    for (input, set) in sets.iter().enumerate() {
+        if let Some(i) = set.windows(2).position(|w| w[1] < w[0]) {
+            return Err(format!(
+                "Slice {input} isn't sorted, record {} is before the previous record",
+                i + 1
+            ));
+        }
+    }
+
+    Ok(interleave_sorted(sets))
+}
+
+/// Statistics of a merge_sorted_files
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MergeStats {
+    /// Number of records read from each input, in the order of the inputs
+    pub per_input: Vec<usize>,
+
+    /// Number of records written
+    pub total: usize,
+}
+
+// The next record of an input, an error if it's before the previous one
+#[cfg(feature = "std-fs")]
+fn next_sorted<R: std::io::Read>(
+    reader: &mut TaxBitExportRecReader<R>,
+    path: &Path,
+    previous: Option<&TaxBitExportRec>,
+) -> Result<Option<TaxBitExportRec>, Box<dyn Error>> {
+    let rec = match reader.next() {
+        Some(entry) => entry?,
+        None => return Ok(None),
+    };
+    if previous.is_some_and(|p| rec < *p) {
+        return Err(format!(
+            "{} line {}: record is before the previous record, the file isn't sorted",
+            path.display(),
+            reader.line()
+        )
+        .into());
+    }
+
+    Ok(Some(rec))
+}
+
+/// Merge sorted TaxBit export files into one sorted output without
+/// loading them into memory.
+///
+/// Each input is streamed and checked to be sorted as it's read, equal
+/// records are written in the order of their inputs. The output has the
+/// lot ID column if any input has it and the extra columns of all the
+/// inputs.
+#[cfg(feature = "std-fs")]
+pub fn merge_sorted_files(inputs: &[PathBuf], output: &Path) -> Result<MergeStats, Box<dyn Error>> {
+    merge_files(inputs, output, None::<Progress>)
+}
+
+/// Merge sorted TaxBit export files as merge_sorted_files does reporting
+/// the records written and the bytes read from all the inputs, the total
+/// bytes is the size of the inputs unless progress has one
+#[cfg(feature = "std-fs")]
+pub fn merge_sorted_files_with_progress<C: ?Sized + FnMut(ProgressEvent)>(
+    inputs: &[PathBuf],
+    output: &Path,
+    progress: Progress<C>,
+) -> Result<MergeStats, Box<dyn Error>> {
+    let progress = match progress.has_total_bytes() {
+        true => progress,
+        false => {
+            let mut total: u64 = 0;
+            for input in inputs {
+                total += std::fs::metadata(input)?.len();
+            }
+            progress.total_bytes(total)
+        }
+    };
+    merge_files(inputs, output, Some(progress))
+}
+
+#[cfg(feature = "std-fs")]
+fn merge_files<C: ?Sized + FnMut(ProgressEvent)>(
+    inputs: &[PathBuf],
+    output: &Path,
+    mut progress: Option<Progress<C>>,
+) -> Result<MergeStats, Box<dyn Error>> {
+    let mut readers = inputs
+        .iter()
+        .map(|p| TaxBitExportRecReader::new(BufReader::new(File::open(p)?)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let extra_columns: Vec<String> = readers
+        .iter()
+        .flat_map(|r| r.extra_columns())
+        .collect::<BTreeSet<String>>()
+        .into_iter()
+        .collect();
+    let mut writer = TaxBitExportRecWriter::new(BufWriter::new(File::create(output)?))
+        .with_lot_id_column(readers.iter().any(|r| r.has_lot_id_column()))
+        .with_extra_columns(&extra_columns);
+
+    let mut stats = MergeStats {
+        per_input: vec![0; inputs.len()],
+        total: 0,
+    };
+
+    let mut heap: BinaryHeap<MergeEntry<TaxBitExportRec>> = BinaryHeap::new();
+    for (input, reader) in readers.iter_mut().enumerate() {
+        if let Some(rec) = next_sorted(reader, &inputs[input], None)? {
+            heap.push(MergeEntry { rec, input });
+        }
+    }
+
+    while let Some(MergeEntry { rec, input }) = heap.pop() {
+        writer.write_rec(&rec)?;
+        stats.per_input[input] += 1;
+        stats.total += 1;
+        if let Some(rec) = next_sorted(&mut readers[input], &inputs[input], Some(&rec))? {
+            heap.push(MergeEntry { rec, input });
+        }
+        if let Some(progress) = &mut progress {
+            let bytes = readers.iter().map(|r| r.bytes_read()).sum();
+            progress.update(stats.total as u64, bytes);
+        }
+    }
+    writer.flush()?;
+    if let Some(progress) = &mut progress {
+        let bytes = readers.iter().map(|r| r.bytes_read()).sum();
+        progress.finish(stats.total as u64, bytes);
+    }
+
+    Ok(stats)
+}
+
+/// A field to sort by, see sort_by_keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    Time,
+    TypeTxs,
+    /// See TaxBitExportRec::try_get_asset, records with an Unknown type
+    /// sort last
+    Asset,
+    Source,
+    ReceivedCurrency,
+    SentCurrency,
+    /// Records without a market value sort first
+    MarketValue,
+    ExternalId,
+}
+
+// Compare a and b by key, ascending
+fn cmp_by_key(a: &TaxBitExportRec, b: &TaxBitExportRec, key: SortKey) -> Ordering {
+    match key {
+        SortKey::Time => a.time.cmp(&b.time),
+        SortKey::TypeTxs => a.type_txs.cmp(&b.type_txs),
+        SortKey::Asset => a.try_get_asset().cmp(&b.try_get_asset()),
+        SortKey::Source => a.source.cmp(&b.source),
+        SortKey::ReceivedCurrency => a.received_currency.cmp(&b.received_currency),
+        SortKey::SentCurrency => a.sent_currency.cmp(&b.sent_currency),
+        SortKey::MarketValue => a.market_value.cmp(&b.market_value),
+        SortKey::ExternalId => a.external_id.cmp(&b.external_id),
+    }
+}
+
+/// Sort the records by each of keys in turn, ascending or descending.
+/// The sort is stable so records equal in every key keep their order,
+/// with SortKey::Asset records with an Unknown type are last either way.
+pub fn sort_by_keys(recs: &mut [TaxBitExportRec], keys: &[SortKey], descending: bool) {
+    recs.sort_by(|a, b| {
+        for key in keys {
+            let ord = match (key, a.try_get_asset(), b.try_get_asset()) {
+                (SortKey::Asset, None, Some(_)) => Ordering::Greater,
+                (SortKey::Asset, Some(_), None) => Ordering::Less,
+                _ if descending => cmp_by_key(a, b, *key).reverse(),
+                _ => cmp_by_key(a, b, *key),
+            };
+            if ord != Ordering::Equal {
+                return ord;
+            }
+        }
+        Ordering::Equal
+    });
+}
+
+#[cfg(test)]
+mod test {
+    #[cfg(feature = "std-fs")]
+    use rust_decimal::Decimal;
+    use taxbitrec::TaxBitRecType;
+
+    use super::*;
+    #[cfg(feature = "std-fs")]
+    use crate::{read_tb_export_rec_file, write_tb_export_rec_file};
+
+    // Deterministic pseudo random records, times collide often
+    #[cfg(feature = "std-fs")]
+    fn shuffled_recs(count: usize) -> Vec<TaxBitExportRec> {
+        let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed
+        };
+
+        (0..count)
+            .map(|i| {
+                let mut rec = TaxBitExportRec::new();
+                rec.time = 1583134325000 + (next() % 1000) as i64 * 1000;
+                rec.type_txs = if next() % 2 == 0 {
+                    TaxBitRecType::Buy
+                } else {
+                    TaxBitRecType::Income
+                };
+                rec.received_quantity = Some(Decimal::new((next() % 100_000) as i64, 4));
+                rec.received_currency = ["BTC", "ETH", "ADA"][(next() % 3) as usize].to_owned();
+                rec.source = "BinanceUS".to_owned();
+                rec.external_id = format!("id-{i}");
+                rec
+            })
+            .collect()
+    }
+
+    #[cfg(feature = "std-fs")]
+    #[test]
+    fn test_external_sort_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in.csv");
+        let output = dir.path().join("out.csv");
+
+        let recs = shuffled_recs(10_000);
+        write_tb_export_rec_file(&input, &recs).unwrap();
+
+        let opts = ExternalSortOpts {
+            run_size: 100,
+            temp_dir: Some(dir.path().to_owned()),
+        };
+        let stats = external_sort_file(&input, &output, opts).unwrap();
+        assert_eq!(
+            stats,
+            SortStats {
+                runs: 100,
+                records: 10_000,
+                peak_buffered: 100,
+            }
+        );
+
+        let mut expected = recs;
+        expected.sort();
+        assert_eq!(read_tb_export_rec_file(&output).unwrap(), expected);
+
+        // Only in.csv and out.csv remain, the run files are removed
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[cfg(feature = "std-fs")]
+    #[test]
+    fn test_external_sort_file_lot_ids() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in.csv");
+        let output = dir.path().join("out.csv");
+
+        // Only some runs have a record with a lot ID
+        let mut recs = shuffled_recs(100);
+        for (i, rec) in recs.iter_mut().enumerate().filter(|(i, _)| i % 30 == 7) {
+            rec.lot_id = Some(format!("lot-{i}"));
+        }
+        write_tb_export_rec_file(&input, &recs).unwrap();
+
+        let opts = ExternalSortOpts {
+            run_size: 10,
+            temp_dir: Some(dir.path().to_owned()),
+        };
+        let stats = external_sort_file(&input, &output, opts).unwrap();
+        assert_eq!(stats.runs, 10);
+
+        let mut expected = recs;
+        expected.sort();
+        let sorted = read_tb_export_rec_file(&output).unwrap();
+        assert_eq!(sorted, expected);
+        assert_eq!(
+            sorted.iter().map(|r| r.lot_id.clone()).collect::<Vec<_>>(),
+            expected
+                .iter()
+                .map(|r| r.lot_id.clone())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(sorted.iter().filter(|r| r.lot_id.is_some()).count(), 4);
+    }
+
+    #[cfg(feature = "std-fs")]
+    #[test]
+    fn test_external_sort_file_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in.csv");
+        let output = dir.path().join("out.csv");
+        write_tb_export_rec_file(&input, &[]).unwrap();
+
+        let stats = external_sort_file(&input, &output, ExternalSortOpts::default()).unwrap();
+        assert_eq!(stats, SortStats::default());
+        assert!(read_tb_export_rec_file(&output).unwrap().is_empty());
+    }
+
+    #[cfg(feature = "std-fs")]
+    #[test]
+    fn test_external_sort_file_error_cleans_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let temp_dir = dir.path().join("temp");
+        std::fs::create_dir(&temp_dir).unwrap();
+        let input = dir.path().join("in.csv");
+        let output = dir.path().join("out.csv");
+
+        let mut writer = TaxBitExportRecWriter::new(vec![]);
+        for rec in shuffled_recs(10) {
+            writer.write_rec(&rec).unwrap();
+        }
+        let mut csv = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        csv.push_str("not a date,Buy,1,BTC,,,,,,,FALSE,bad\n");
+        std::fs::write(&input, csv).unwrap();
+
+        let opts = ExternalSortOpts {
+            run_size: 3,
+            temp_dir: Some(temp_dir.clone()),
+        };
+        assert!(external_sort_file(&input, &output, opts).is_err());
+        assert_eq!(std::fs::read_dir(&temp_dir).unwrap().count(), 0);
+    }
+
+    #[cfg(feature = "std-fs")]
+    fn write_sorted(path: &Path, recs: &[TaxBitExportRec]) -> Vec<TaxBitExportRec> {
+        let mut recs = recs.to_vec();
+        recs.sort();
+        write_tb_export_rec_file(path, &recs).unwrap();
+        recs
+    }
+
+    #[cfg(feature = "std-fs")]
+    #[test]
+    fn test_merge_sorted_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let recs = shuffled_recs(300);
+        let inputs: Vec<PathBuf> = (0..3)
+            .map(|i| dir.path().join(format!("in-{i}.csv")))
+            .collect();
+        write_sorted(&inputs[0], &recs[0..150]);
+        write_sorted(&inputs[1], &recs[150..250]);
+        write_sorted(&inputs[2], &recs[250..]);
+        let output = dir.path().join("out.csv");
+
+        let stats = merge_sorted_files(&inputs, &output).unwrap();
+        assert_eq!(
+            stats,
+            MergeStats {
+                per_input: vec![150, 100, 50],
+                total: 300,
+            }
+        );
+
+        let mut expected = recs;
+        expected.sort();
+        assert_eq!(read_tb_export_rec_file(&output).unwrap(), expected);
+    }
+
+    #[cfg(feature = "std-fs")]
+    #[test]
+    #[cfg(not(feature = "strict-parse"))]
+    fn test_merge_sorted_files_stable() {
+        let dir = tempfile::tempdir().unwrap();
+        // Equal records distinguished by their extras, which Ord ignores
+        let mut rec = shuffled_recs(1).remove(0);
+        let inputs: Vec<PathBuf> = (0..3)
+            .map(|i| {
+                let path = dir.path().join(format!("in-{i}.csv"));
+                rec.extras.insert("Input".to_owned(), i.to_string());
+                write_tb_export_rec_file(&path, &[rec.clone(), rec.clone()]).unwrap();
+                path
+            })
+            .collect();
+        let output = dir.path().join("out.csv");
+
+        merge_sorted_files(&inputs, &output).unwrap();
+        let order: Vec<String> = read_tb_export_rec_file(&output)
+            .unwrap()
+            .iter()
+            .map(|r| r.extras["Input"].clone())
+            .collect();
+        assert_eq!(order, vec!["0", "0", "1", "1", "2", "2"]);
+    }
+
+    #[cfg(feature = "std-fs")]
+    #[test]
+    fn test_merge_sorted_files_unsorted_input() {
+        let dir = tempfile::tempdir().unwrap();
+        let recs = shuffled_recs(30);
+        let inputs: Vec<PathBuf> = (0..3)
+            .map(|i| dir.path().join(format!("in-{i}.csv")))
+            .collect();
+        write_sorted(&inputs[0], &recs[0..10]);
+        let mut unsorted = write_sorted(&inputs[1], &recs[10..20]);
+        unsorted.swap(3, 4);
+        write_tb_export_rec_file(&inputs[1], &unsorted).unwrap();
+        write_sorted(&inputs[2], &recs[20..]);
+        let output = dir.path().join("out.csv");
+
+        let err = merge_sorted_files(&inputs, &output).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "{} line 6: record is before the previous record, the file isn't sorted",
+                inputs[1].display()
+            )
+        );
+    }
+
+    #[test]
+    fn test_sort_by_keys() {
+        let rec = |time: i64, source: &str, id: &str| {
+            let mut rec = TaxBitExportRec::new();
+            rec.time = time;
+            rec.type_txs = TaxBitRecType::Income;
+            rec.received_currency = "BTC".to_owned();
+            rec.source = source.to_owned();
+            rec.external_id = id.to_owned();
+            rec
+        };
+        let mut recs = vec![
+            rec(3, "Kraken", "k3"),
+            rec(1, "Kraken", "k1"),
+            rec(2, "Coinbase", "c2"),
+            rec(1, "Coinbase", "c1-a"),
+            rec(1, "Coinbase", "c1-b"),
+        ];
+        let ids = |recs: &[TaxBitExportRec]| -> Vec<String> {
+            recs.iter().map(|r| r.external_id.clone()).collect()
+        };
+
+        sort_by_keys(&mut recs, &[SortKey::Source, SortKey::Time], false);
+        assert_eq!(ids(&recs), vec!["c1-a", "c1-b", "c2", "k1", "k3"]);
+
+        sort_by_keys(&mut recs, &[SortKey::Source, SortKey::Time], true);
+        assert_eq!(ids(&recs), vec!["k3", "k1", "c2", "c1-a", "c1-b"]);
+
+        // Equal in every key so the order is kept
+        let unsorted = recs.clone();
+        sort_by_keys(&mut recs, &[SortKey::Asset, SortKey::MarketValue], false);
+        assert_eq!(recs, unsorted);
+        sort_by_keys(&mut recs, &[], true);
+        assert_eq!(recs, unsorted);
+    }
+
+    #[test]
+    fn test_sort_by_keys_asset() {
+        let rec = |type_txs: TaxBitRecType, asset: &str| {
+            let mut rec = TaxBitExportRec::new();
+            rec.external_id = format!("{type_txs:?}-{asset}");
+            rec.type_txs = type_txs;
+            rec.received_currency = asset.to_owned();
+            rec
+        };
+        let mut recs = vec![
+            rec(TaxBitRecType::Unknown, "AAA"),
+            rec(TaxBitRecType::Income, "ETH"),
+            rec(TaxBitRecType::Buy, "BTC"),
+        ];
+        let ids = |recs: &[TaxBitExportRec]| -> Vec<String> {
+            recs.iter().map(|r| r.external_id.clone()).collect()
+        };
+
+        sort_by_keys(&mut recs, &[SortKey::Asset], false);
+        assert_eq!(ids(&recs), vec!["Buy-BTC", "Income-ETH", "Unknown-AAA"]);
+        sort_by_keys(&mut recs, &[SortKey::Asset], true);
+        assert_eq!(ids(&recs), vec!["Income-ETH", "Buy-BTC", "Unknown-AAA"]);
+    }
+
+    #[cfg(feature = "std-fs")]
+    #[test]
+    fn test_sort_and_merge_with_progress() {
+        use std::sync::{Arc, Mutex};
+
+        use crate::ProgressEvent;
+
+        let recorder = || {
+            let events: Arc<Mutex<Vec<ProgressEvent>>> = Arc::default();
+            let sink = events.clone();
+            let progress = Progress::new(move |e| sink.lock().unwrap().push(e)).every_records(100);
+            (events, progress)
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let recs = shuffled_recs(300);
+        let input = dir.path().join("in.csv");
+        write_tb_export_rec_file(&input, &recs).unwrap();
+        let size = std::fs::metadata(&input).unwrap().len();
+
+        let (events, progress) = recorder();
+        let opts = ExternalSortOpts {
+            run_size: 50,
+            temp_dir: Some(dir.path().to_owned()),
+        };
+        let output = dir.path().join("sorted.csv");
+        external_sort_file_with_progress(&input, &output, opts, progress).unwrap();
+        let last = *events.lock().unwrap().last().unwrap();
+        assert_eq!(events.lock().unwrap().len(), 3);
+        assert_eq!(last.records_done, 300);
+        assert_eq!(last.bytes_done, size);
+        assert_eq!(last.total_bytes, Some(size));
+
+        let inputs: Vec<PathBuf> = (0..2)
+            .map(|i| dir.path().join(format!("in-{i}.csv")))
+            .collect();
+        write_sorted(&inputs[0], &recs[0..120]);
+        write_sorted(&inputs[1], &recs[120..]);
+        let total: u64 = inputs
+            .iter()
+            .map(|p| std::fs::metadata(p).unwrap().len())
+            .sum();
+        let (events, progress) = recorder();
+        let output = dir.path().join("merged.csv");
+        merge_sorted_files_with_progress(&inputs, &output, progress).unwrap();
+        let done: Vec<u64> = events
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|e| e.records_done)
+            .collect();
+        assert_eq!(done, vec![100, 200, 300]);
+        let last = *events.lock().unwrap().last().unwrap();
+        assert_eq!(last.bytes_done, total);
+        assert_eq!(last.total_bytes, Some(total));
+    }
+
+    #[test]
+    fn test_interleave_sorted() {
+        // Equal records distinguished by their extras, which Ord ignores
+        let rec = |time: i64, set: &str| {
+            let mut rec = TaxBitExportRec::new();
+            rec.time = time;
+            rec.type_txs = TaxBitRecType::Income;
+            rec.extras.insert("Set".to_owned(), set.to_owned());
+            rec
+        };
+        let a = vec![rec(1, "a"), rec(3, "a"), rec(3, "a"), rec(7, "a")];
+        let b = vec![rec(2, "b"), rec(3, "b"), rec(8, "b")];
+        let c = vec![rec(0, "c"), rec(3, "c"), rec(7, "c"), rec(9, "c")];
+        let sets: Vec<&[TaxBitExportRec]> = vec![&a, &[], &b, &c];
+
+        let mut expected: Vec<TaxBitExportRec> = sets.concat();
+        expected.sort();
+        let merged = interleave_sorted(&sets);
+        assert_eq!(merged.size_hint(), (11, Some(11)));
+        let merged: Vec<&TaxBitExportRec> = merged.collect();
+        assert_eq!(merged, expected.iter().collect::<Vec<_>>());
+        let order: Vec<(i64, &str)> = merged
+            .iter()
+            .map(|r| (r.time, r.extras["Set"].as_str()))
+            .collect();
+        assert_eq!(
+            order,
+            vec![
+                (0, "c"),
+                (1, "a"),
+                (2, "b"),
+                (3, "a"),
+                (3, "a"),
+                (3, "b"),
+                (3, "c"),
+                (7, "a"),
+                (7, "c"),
+                (8, "b"),
+                (9, "c"),
+            ]
+        );
+        assert_eq!(
+            interleave_sorted_checked(&sets).unwrap().count(),
+            expected.len()
+        );
+
+        let unsorted = vec![rec(1, "d"), rec(5, "d"), rec(4, "d")];
+        let err = interleave_sorted_checked(&[&a, &unsorted]).err().unwrap();
+        assert_eq!(
+            err,
+            "Slice 1 isn't sorted, record 2 is before the previous record"
+        );
+        assert_eq!(interleave_sorted(&[]).next(), None);
+    }
+}
@@ -0,0 +1,244 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    error::Error,
+    path::{Path, PathBuf},
+};
+
+use crate::{write_tb_export_rec_file, TaxBitExportRec};
+
+/// A file written when splitting records
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SplitFile {
+    pub path: PathBuf,
+
+    /// Number of records written to path
+    pub count: usize,
+}
+
+/// Partition the records by the UTC calendar year of their time and write
+/// each year, sorted, to `out_dir/stem-YYYY.csv`.
+///
+/// A record at exactly Jan 1 00:00:00.000Z belongs to the new year.
+pub fn split_by_year(
+    recs: &[TaxBitExportRec],
+    out_dir: &Path,
+    stem: &str,
+) -> Result<BTreeMap<u32, SplitFile>, Box<dyn Error>> {
+    let mut by_year: BTreeMap<u32, Vec<TaxBitExportRec>> = BTreeMap::new();
+    for rec in recs {
+        let year = rec.year();
+        let year = u32::try_from(year).map_err(|_| {
+            format!(
+                "Record with External ID {} has an invalid year {year}",
+                rec.external_id
+            )
+        })?;
+        by_year.entry(year).or_default().push(rec.clone());
+    }
+
+    let mut files: BTreeMap<u32, SplitFile> = BTreeMap::new();
+    for (year, mut year_recs) in by_year {
+        year_recs.sort();
+        let path = out_dir.join(format!("{stem}-{year}.csv"));
+        write_tb_export_rec_file(&path, &year_recs)?;
+        files.insert(
+            year,
+            SplitFile {
+                path,
+                count: year_recs.len(),
+            },
+        );
+    }
+
+    Ok(files)
+}
+
+// Replace the characters of a source which aren't ASCII alphanumeric,
+// '-' or '_' with '_' so it's a safe file name
+fn sanitize_source(source: &str) -> String {
+    source
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Partition the records by source and write each source, sorted, to
+/// `out_dir/<source>.csv` returning the path written for each source.
+///
+/// The file name is the source with characters other than ASCII
+/// alphanumerics, '-' and '_' replaced by '_'. Records with an empty
+/// source are written to "unknown-source.csv". Sources whose names
+/// collide, ignoring case, are disambiguated with a "-2", "-3", ...
+/// suffix in source order, "Binance US" is written to "Binance_US.csv"
+/// and "Binance/US" to "Binance_US-2.csv".
+pub fn split_by_source(
+    recs: &[TaxBitExportRec],
+    out_dir: &Path,
+) -> Result<BTreeMap<String, PathBuf>, Box<dyn Error>> {
+    let mut by_source: BTreeMap<&str, Vec<TaxBitExportRec>> = BTreeMap::new();
+    for rec in recs {
+        by_source
+            .entry(rec.source.as_str())
+            .or_default()
+            .push(rec.clone());
+    }
+
+    // The empty source sorts first so it always gets "unknown-source"
+    let mut names_used: BTreeSet<String> = BTreeSet::new();
+    let mut files: BTreeMap<String, PathBuf> = BTreeMap::new();
+    for (source, mut source_recs) in by_source {
+        let base = if source.is_empty() {
+            "unknown-source".to_owned()
+        } else {
+            sanitize_source(source)
+        };
+        let mut name = base.clone();
+        let mut n = 1;
+        while names_used.contains(&name.to_lowercase()) {
+            n += 1;
+            name = format!("{base}-{n}");
+        }
+        names_used.insert(name.to_lowercase());
+
+        source_recs.sort();
+        let path = out_dir.join(format!("{name}.csv"));
+        write_tb_export_rec_file(&path, &source_recs)?;
+        files.insert(source.to_owned(), path);
+    }
+
+    Ok(files)
+}
+
+#[cfg(test)]
+mod test {
+    use taxbitrec::TaxBitRecType;
+
+    use super::*;
+    use crate::read_tb_export_rec_file;
+
+    fn rec(time: i64, id: &str) -> TaxBitExportRec {
+        let mut rec = TaxBitExportRec::new();
+        rec.time = time;
+        rec.type_txs = TaxBitRecType::Income;
+        rec.received_currency = "BTC".to_owned();
+        rec.external_id = id.to_owned();
+        rec
+    }
+
+    #[test]
+    fn test_split_by_year() {
+        // 2023-01-01T00:00:00.000Z
+        let new_year = 1672531200000;
+        let recs = vec![
+            rec(new_year + 1000, "2023-b"),
+            rec(new_year - 1, "2022-last-ms"),
+            rec(new_year, "2023-first-ms"),
+            rec(new_year - 86_400_000, "2022-a"),
+        ];
+
+        let dir = tempfile::tempdir().unwrap();
+        let files = split_by_year(&recs, dir.path(), "taxbit").unwrap();
+        assert_eq!(
+            files.keys().copied().collect::<Vec<u32>>(),
+            vec![2022, 2023]
+        );
+
+        let f2022 = &files[&2022];
+        assert_eq!(f2022.path, dir.path().join("taxbit-2022.csv"));
+        assert_eq!(f2022.count, 2);
+        let recs2022 = read_tb_export_rec_file(&f2022.path).unwrap();
+        let ids: Vec<&str> = recs2022.iter().map(|r| r.external_id.as_str()).collect();
+        assert_eq!(ids, vec!["2022-a", "2022-last-ms"]);
+
+        let f2023 = &files[&2023];
+        assert_eq!(f2023.path, dir.path().join("taxbit-2023.csv"));
+        assert_eq!(f2023.count, 2);
+        let recs2023 = read_tb_export_rec_file(&f2023.path).unwrap();
+        let ids: Vec<&str> = recs2023.iter().map(|r| r.external_id.as_str()).collect();
+        assert_eq!(ids, vec!["2023-first-ms", "2023-b"]);
+    }
+
+    #[test]
+    fn test_split_by_year_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = split_by_year(&[], dir.path(), "taxbit").unwrap();
+        assert!(files.is_empty());
+    }
+
+    fn rec_from(source: &str, time: i64, id: &str) -> TaxBitExportRec {
+        let mut rec = rec(time, id);
+        rec.source = source.to_owned();
+        rec
+    }
+
+    #[test]
+    fn test_split_by_source() {
+        let recs = vec![
+            rec_from("Coinbase", 3000, "cb-2"),
+            rec_from("BinanceUS", 1000, "bu-1"),
+            rec_from("", 1000, "none-1"),
+            rec_from("Coinbase", 2000, "cb-1"),
+        ];
+
+        let dir = tempfile::tempdir().unwrap();
+        let files = split_by_source(&recs, dir.path()).unwrap();
+        assert_eq!(
+            files,
+            BTreeMap::from([
+                ("".to_owned(), dir.path().join("unknown-source.csv")),
+                ("BinanceUS".to_owned(), dir.path().join("BinanceUS.csv")),
+                ("Coinbase".to_owned(), dir.path().join("Coinbase.csv")),
+            ])
+        );
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 3);
+
+        let coinbase = read_tb_export_rec_file(&files["Coinbase"]).unwrap();
+        let ids: Vec<&str> = coinbase.iter().map(|r| r.external_id.as_str()).collect();
+        assert_eq!(ids, vec!["cb-1", "cb-2"]);
+        let unknown = read_tb_export_rec_file(&files[""]).unwrap();
+        assert_eq!(unknown.len(), 1);
+        assert_eq!(unknown[0].external_id, "none-1");
+    }
+
+    #[test]
+    fn test_split_by_source_collisions() {
+        let recs = vec![
+            rec_from("Binance US", 1000, "space"),
+            rec_from("Binance/US", 1000, "slash"),
+            rec_from("binance_us", 1000, "lower"),
+            rec_from("unknown source", 1000, "named-unknown"),
+            rec_from("", 1000, "empty"),
+        ];
+
+        let dir = tempfile::tempdir().unwrap();
+        let files = split_by_source(&recs, dir.path()).unwrap();
+        let names: BTreeMap<&str, String> = files
+            .iter()
+            .map(|(s, p)| {
+                (
+                    s.as_str(),
+                    p.file_name().unwrap().to_string_lossy().to_string(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            names,
+            BTreeMap::from([
+                ("", "unknown-source.csv".to_owned()),
+                ("Binance US", "Binance_US.csv".to_owned()),
+                ("Binance/US", "Binance_US-2.csv".to_owned()),
+                ("binance_us", "binance_us-3.csv".to_owned()),
+                ("unknown source", "unknown_source.csv".to_owned()),
+            ])
+        );
+
+        let slash = read_tb_export_rec_file(&files["Binance/US"]).unwrap();
+        assert_eq!(slash[0].external_id, "slash");
+    }
+}
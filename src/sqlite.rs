@@ -0,0 +1,241 @@
+use std::error::Error;
+
+use rusqlite::{params, Connection, Row};
+use rust_decimal::Decimal;
+
+use crate::TaxBitExportRec;
+
+// The columns of the table, the decimals are TEXT so they're exact
+const COLUMNS: [(&str, &str); 13] = [
+    ("time", "INTEGER NOT NULL"),
+    ("transaction_type", "TEXT NOT NULL"),
+    ("received_quantity", "TEXT"),
+    ("received_currency", "TEXT NOT NULL"),
+    ("sent_quantity", "TEXT"),
+    ("sent_currency", "TEXT NOT NULL"),
+    ("fee_currency", "TEXT NOT NULL"),
+    ("fee_amount", "TEXT"),
+    ("market_value", "TEXT"),
+    ("source", "TEXT NOT NULL"),
+    ("internal_transfer", "INTEGER NOT NULL"),
+    ("external_id", "TEXT NOT NULL"),
+    ("lot_id", "TEXT"),
+];
+
+/// Options for export_to_sqlite_with_opts
+#[derive(Debug, Clone, Default)]
+pub struct SqliteOpts {
+    /// Create a unique index on (source, external_id) so exporting a
+    /// record twice fails, records without an external_id aren't indexed
+    pub unique_source_external_id: bool,
+}
+
+// Quote a table or index name as an SQL identifier
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Export the records to a new table, see export_to_sqlite_with_opts
+pub fn export_to_sqlite(
+    conn: &mut Connection,
+    table: &str,
+    recs: &[TaxBitExportRec],
+) -> Result<(), Box<dyn Error>> {
+    export_to_sqlite_with_opts(conn, table, recs, &SqliteOpts::default())
+}
+
+/// Create table and insert the records into it in one transaction, so
+/// either all or none are exported. The time is INTEGER UTC milliseconds,
+/// the quantities, fee amount and market value are TEXT so they keep
+/// their precision, NULL if absent, and internal_transfer is INTEGER 0
+/// or 1. The extras aren't exported.
+///
+/// Fails if the table exists or, with opts.unique_source_external_id,
+/// if two records have the same source and non-empty external_id.
+pub fn export_to_sqlite_with_opts(
+    conn: &mut Connection,
+    table: &str,
+    recs: &[TaxBitExportRec],
+    opts: &SqliteOpts,
+) -> Result<(), Box<dyn Error>> {
+    let columns: Vec<String> = COLUMNS
+        .iter()
+        .map(|(name, decl)| format!("{name} {decl}"))
+        .collect();
+    let tx = conn.transaction()?;
+    tx.execute(
+        &format!("CREATE TABLE {} ({})", quote(table), columns.join(", ")),
+        [],
+    )?;
+    if opts.unique_source_external_id {
+        tx.execute(
+            &format!(
+                "CREATE UNIQUE INDEX {} ON {} (source, external_id) WHERE external_id <> ''",
+                quote(&format!("{table}_source_external_id")),
+                quote(table)
+            ),
+            [],
+        )?;
+    }
+
+    {
+        let names: Vec<&str> = COLUMNS.iter().map(|(name, _)| *name).collect();
+        let placeholders = vec!["?"; COLUMNS.len()].join(", ");
+        let mut insert = tx.prepare(&format!(
+            "INSERT INTO {} ({}) VALUES ({placeholders})",
+            quote(table),
+            names.join(", ")
+        ))?;
+        let text = |d: Option<Decimal>| d.map(|d| d.to_string());
+        for rec in recs {
+            let type_txs = serde_json::to_value(&rec.type_txs)?;
+            insert.execute(params![
+                rec.time,
+                type_txs.as_str().unwrap_or_default(),
+                text(rec.received_quantity),
+                rec.received_currency,
+                text(rec.sent_quantity),
+                rec.sent_currency,
+                rec.fee_currency,
+                text(rec.fee_amount),
+                text(rec.market_value),
+                rec.source,
+                rec.internal_transfer,
+                rec.external_id,
+                rec.lot_id,
+            ])?;
+        }
+    }
+    tx.commit()?;
+
+    Ok(())
+}
+
+/// Read the records of a table written by export_to_sqlite in the order
+/// they were inserted
+pub fn import_from_sqlite(
+    conn: &Connection,
+    table: &str,
+) -> Result<Vec<TaxBitExportRec>, Box<dyn Error>> {
+    let names: Vec<&str> = COLUMNS.iter().map(|(name, _)| *name).collect();
+    let mut select = conn.prepare(&format!(
+        "SELECT {} FROM {} ORDER BY rowid",
+        names.join(", "),
+        quote(table)
+    ))?;
+    let mut rows = select.query([])?;
+
+    let mut recs: Vec<TaxBitExportRec> = vec![];
+    while let Some(row) = rows.next()? {
+        recs.push(from_row(row)?);
+    }
+
+    Ok(recs)
+}
+
+fn from_row(row: &Row) -> Result<TaxBitExportRec, Box<dyn Error>> {
+    let decimal = |i: usize| -> Result<Option<Decimal>, Box<dyn Error>> {
+        match row.get::<_, Option<String>>(i)? {
+            Some(s) => Ok(Some(s.parse::<Decimal>().map_err(|e| {
+                format!("{} {s:?} isn't a decimal: {e}", COLUMNS[i].0)
+            })?)),
+            None => Ok(None),
+        }
+    };
+
+    let mut rec = TaxBitExportRec::new();
+    rec.time = row.get(0)?;
+    rec.type_txs = serde_json::from_value(serde_json::Value::String(row.get(1)?))?;
+    rec.received_quantity = decimal(2)?;
+    rec.received_currency = row.get(3)?;
+    rec.sent_quantity = decimal(4)?;
+    rec.sent_currency = row.get(5)?;
+    rec.fee_currency = row.get(6)?;
+    rec.fee_amount = decimal(7)?;
+    rec.market_value = decimal(8)?;
+    rec.source = row.get(9)?;
+    rec.internal_transfer = row.get(10)?;
+    rec.external_id = row.get(11)?;
+    rec.lot_id = row.get(12)?;
+
+    Ok(rec)
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::test_support::sample_buy_and_income;
+
+    // With more digits than an f64 holds and a lot ID
+    fn recs() -> Vec<TaxBitExportRec> {
+        let mut recs = sample_buy_and_income();
+        recs[0].received_quantity = Some(dec!(0.123456789012345678901));
+        recs[0].lot_id = Some("lot-1".to_owned());
+        recs
+    }
+
+    #[test]
+    fn test_sqlite_round_trip() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let recs = recs();
+        export_to_sqlite(&mut conn, "tb export", &recs).unwrap();
+        let read = import_from_sqlite(&conn, "tb export").unwrap();
+        assert_eq!(read, recs);
+        assert_eq!(read[0].fee_amount.unwrap().to_string(), "1.50");
+        assert_eq!(read[1].lot_id, None);
+
+        // Absent decimals are NULL and the values are queryable
+        let nulls: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM \"tb export\" WHERE sent_quantity IS NULL",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(nulls, 1);
+        let (time, transfer): (i64, i64) = conn
+            .query_row(
+                "SELECT time, internal_transfer FROM \"tb export\" WHERE transaction_type = 'Income'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!((time, transfer), (1640995200000, 1));
+
+        // The table already exists
+        assert!(export_to_sqlite(&mut conn, "tb export", &recs).is_err());
+
+        export_to_sqlite(&mut conn, "empty", &[]).unwrap();
+        assert_eq!(import_from_sqlite(&conn, "empty").unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_sqlite_unique_index() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let mut recs = recs();
+        recs.push(recs[0].clone());
+        export_to_sqlite(&mut conn, "dups", &recs).unwrap();
+        assert_eq!(import_from_sqlite(&conn, "dups").unwrap().len(), 3);
+
+        let opts = SqliteOpts {
+            unique_source_external_id: true,
+        };
+        let err = export_to_sqlite_with_opts(&mut conn, "unique", &recs, &opts).unwrap_err();
+        assert!(err.to_string().contains("UNIQUE"), "{err}");
+        // The transaction was rolled back so the table wasn't created
+        assert!(import_from_sqlite(&conn, "unique").is_err());
+
+        recs.pop();
+        export_to_sqlite_with_opts(&mut conn, "unique", &recs, &opts).unwrap();
+        assert_eq!(import_from_sqlite(&conn, "unique").unwrap(), recs);
+
+        // Records without an external_id are never duplicates
+        let mut blank = recs[0].clone();
+        blank.external_id = String::new();
+        let blanks = vec![blank.clone(), blank];
+        export_to_sqlite_with_opts(&mut conn, "blank", &blanks, &opts).unwrap();
+        assert_eq!(import_from_sqlite(&conn, "blank").unwrap(), blanks);
+    }
+}
@@ -0,0 +1,427 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Display,
+};
+#[cfg(feature = "std-fs")]
+use std::{error::Error, path::Path};
+
+use serde::Serialize;
+use taxbitrec::TaxBitRecType;
+use time_ms_conversions::time_ms_to_utc_string;
+
+use crate::TaxBitExportRec;
+#[cfg(feature = "std-fs")]
+use crate::{reader::rec_file_iter, Progress, ReaderConfig};
+
+/// Summary statistics of a set of records, see stats
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct RecStats {
+    pub total: usize,
+    pub by_type: BTreeMap<TaxBitRecType, usize>,
+    pub by_source: BTreeMap<String, usize>,
+
+    /// The distinct assets, see TaxBitExportRec::get_asset, records
+    /// with an Unknown type are skipped
+    pub assets: BTreeSet<String>,
+
+    pub earliest: Option<i64>,
+    pub latest: Option<i64>,
+    pub missing_market_value: usize,
+    pub empty_external_id: usize,
+}
+
+/// A count which differs between two RecStats, see RecStats::diff
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CountChange {
+    pub category: String,
+    pub before: usize,
+    pub after: usize,
+}
+
+impl Display for CountChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {} -> {} ({:+})",
+            self.category,
+            self.before,
+            self.after,
+            self.after as i128 - self.before as i128
+        )
+    }
+}
+
+/// Number of records of each TaxBitRecType
+pub fn count_by_type(recs: &[TaxBitExportRec]) -> BTreeMap<TaxBitRecType, usize> {
+    let mut counts: BTreeMap<TaxBitRecType, usize> = BTreeMap::new();
+    for rec in recs {
+        *counts.entry(rec.type_txs.clone()).or_default() += 1;
+    }
+
+    counts
+}
+
+/// Gather the RecStats of the records
+pub fn stats(recs: &[TaxBitExportRec]) -> RecStats {
+    let mut stats = RecStats::default();
+    for rec in recs {
+        stats.add(rec);
+    }
+
+    stats
+}
+
+impl RecStats {
+    pub(crate) fn add(&mut self, rec: &TaxBitExportRec) {
+        self.total += 1;
+        *self.by_type.entry(rec.type_txs.clone()).or_default() += 1;
+        *self.by_source.entry(rec.source.clone()).or_default() += 1;
+        if rec.type_txs != TaxBitRecType::Unknown {
+            self.assets.insert(rec.get_asset().to_owned());
+        }
+        self.earliest = Some(self.earliest.map_or(rec.time, |t| t.min(rec.time)));
+        self.latest = Some(self.latest.map_or(rec.time, |t| t.max(rec.time)));
+        if rec.market_value.is_none() {
+            self.missing_market_value += 1;
+        }
+        if rec.external_id.is_empty() {
+            self.empty_external_id += 1;
+        }
+    }
+
+    // All counts with their category names, a type or source missing
+    // from by_type or by_source has no entry
+    fn counts(&self) -> BTreeMap<String, usize> {
+        let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+        counts.insert("total".to_owned(), self.total);
+        counts.insert("assets".to_owned(), self.assets.len());
+        counts.insert("missing market value".to_owned(), self.missing_market_value);
+        counts.insert("empty external id".to_owned(), self.empty_external_id);
+        for (type_txs, count) in &self.by_type {
+            counts.insert(format!("type {type_txs:?}"), *count);
+        }
+        for (source, count) in &self.by_source {
+            counts.insert(format!("source {source}"), *count);
+        }
+
+        counts
+    }
+
+    /// The counts which changed from self to after. A type or source
+    /// present in only one of them is counted as zero in the other.
+    pub fn diff(&self, after: &RecStats) -> Vec<CountChange> {
+        let before_counts = self.counts();
+        let after_counts = after.counts();
+        let categories: BTreeSet<&String> =
+            before_counts.keys().chain(after_counts.keys()).collect();
+
+        categories
+            .into_iter()
+            .filter_map(|category| {
+                let before = before_counts.get(category).copied().unwrap_or(0);
+                let after = after_counts.get(category).copied().unwrap_or(0);
+                (before != after).then(|| CountChange {
+                    category: category.clone(),
+                    before,
+                    after,
+                })
+            })
+            .collect()
+    }
+}
+
+impl Display for RecStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let time = |t: Option<i64>| t.map_or("-".to_owned(), time_ms_to_utc_string);
+
+        writeln!(f, "{:<24}{}", "total", self.total)?;
+        writeln!(f, "{:<24}{}", "earliest", time(self.earliest))?;
+        writeln!(f, "{:<24}{}", "latest", time(self.latest))?;
+        writeln!(
+            f,
+            "{:<24}{}",
+            "missing market value", self.missing_market_value
+        )?;
+        writeln!(f, "{:<24}{}", "empty external id", self.empty_external_id)?;
+        for (type_txs, count) in &self.by_type {
+            writeln!(f, "{:<24}{}", format!("type {type_txs:?}"), count)?;
+        }
+        for (source, count) in &self.by_source {
+            writeln!(f, "{:<24}{}", format!("source {source}"), count)?;
+        }
+        let assets: Vec<&str> = self.assets.iter().map(|a| a.as_str()).collect();
+        write!(f, "{:<24}{}", "assets", assets.join(", "))
+    }
+}
+
+/// An overview of a file or set of records, see summarize_file
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct FileSummary {
+    pub total: usize,
+    pub earliest: Option<i64>,
+    pub latest: Option<i64>,
+    pub by_type: BTreeMap<TaxBitRecType, usize>,
+
+    /// The distinct assets, see TaxBitExportRec::try_get_asset, records
+    /// with an Unknown type are skipped
+    pub assets: BTreeSet<String>,
+
+    pub sources: BTreeSet<String>,
+    pub internal_transfers: usize,
+    pub missing_market_value: usize,
+}
+
+impl FileSummary {
+    fn add(&mut self, rec: &TaxBitExportRec) {
+        self.total += 1;
+        self.earliest = Some(self.earliest.map_or(rec.time, |t| t.min(rec.time)));
+        self.latest = Some(self.latest.map_or(rec.time, |t| t.max(rec.time)));
+        *self.by_type.entry(rec.type_txs.clone()).or_default() += 1;
+        if let Some(asset) = rec.try_get_asset() {
+            if !self.assets.contains(asset) {
+                self.assets.insert(asset.to_owned());
+            }
+        }
+        if !self.sources.contains(&rec.source) {
+            self.sources.insert(rec.source.clone());
+        }
+        if rec.internal_transfer {
+            self.internal_transfers += 1;
+        }
+        if rec.market_value.is_none() {
+            self.missing_market_value += 1;
+        }
+    }
+}
+
+impl Display for FileSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let time = |t: Option<i64>| t.map_or("-".to_owned(), time_ms_to_utc_string);
+        let join = |set: &BTreeSet<String>| {
+            set.iter()
+                .map(|s| s.as_str())
+                .collect::<Vec<&str>>()
+                .join(", ")
+        };
+
+        writeln!(f, "{:<24}{}", "total", self.total)?;
+        writeln!(f, "{:<24}{}", "earliest", time(self.earliest))?;
+        writeln!(f, "{:<24}{}", "latest", time(self.latest))?;
+        for (type_txs, count) in &self.by_type {
+            writeln!(f, "{:<24}{}", format!("type {type_txs:?}"), count)?;
+        }
+        writeln!(f, "{:<24}{}", "internal transfers", self.internal_transfers)?;
+        writeln!(
+            f,
+            "{:<24}{}",
+            "missing market value", self.missing_market_value
+        )?;
+        writeln!(f, "{:<24}{}", "sources", join(&self.sources))?;
+        write!(f, "{:<24}{}", "assets", join(&self.assets))
+    }
+}
+
+/// The FileSummary of the records
+pub fn summarize<'a, I>(recs: I) -> FileSummary
+where
+    I: IntoIterator<Item = &'a TaxBitExportRec>,
+{
+    let mut summary = FileSummary::default();
+    for rec in recs {
+        summary.add(rec);
+    }
+
+    summary
+}
+
+/// The FileSummary of a TaxBit export file read as
+/// read_tb_export_rec_file reads it. The records are read one at a time
+/// so memory use doesn't grow with the size of the file.
+#[cfg(feature = "std-fs")]
+pub fn summarize_file(path: &Path) -> Result<FileSummary, Box<dyn Error>> {
+    let mut summary = FileSummary::default();
+    for rec in rec_file_iter(path, &ReaderConfig::default(), None::<Progress>)? {
+        summary.add(&rec?);
+    }
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    fn rec(
+        time: i64,
+        type_txs: TaxBitRecType,
+        asset: &str,
+        source: &str,
+        id: &str,
+    ) -> TaxBitExportRec {
+        let mut rec = TaxBitExportRec::new();
+        rec.time = time;
+        rec.type_txs = type_txs;
+        match rec.type_txs {
+            TaxBitRecType::Sale | TaxBitRecType::Expense | TaxBitRecType::TransferOut => {
+                rec.sent_currency = asset.to_owned();
+                rec.sent_quantity = Some(dec!(1));
+            }
+            _ => {
+                rec.received_currency = asset.to_owned();
+                rec.received_quantity = Some(dec!(1));
+            }
+        }
+        rec.source = source.to_owned();
+        rec.external_id = id.to_owned();
+        rec.market_value = Some(dec!(10));
+        rec
+    }
+
+    // Hand counted:
+    //   total 5, Buy 2, Sale 1, Income 1, TransferOut 1
+    //   BinanceUS 3, Coinbase 2
+    //   assets BTC, ETH, XRP
+    //   earliest 1000, latest 5000
+    //   missing market value 1, empty external id 2
+    fn fixture() -> Vec<TaxBitExportRec> {
+        let mut recs = vec![
+            rec(3000, TaxBitRecType::Buy, "BTC", "BinanceUS", "a"),
+            rec(1000, TaxBitRecType::Buy, "ETH", "Coinbase", "b"),
+            rec(5000, TaxBitRecType::Sale, "BTC", "BinanceUS", ""),
+            rec(2000, TaxBitRecType::Income, "XRP", "BinanceUS", "d"),
+            rec(4000, TaxBitRecType::TransferOut, "ETH", "Coinbase", ""),
+        ];
+        recs[3].market_value = None;
+        recs
+    }
+
+    #[test]
+    fn test_count_by_type() {
+        let counts = count_by_type(&fixture());
+        assert_eq!(counts.len(), 4);
+        assert_eq!(counts[&TaxBitRecType::Buy], 2);
+        assert_eq!(counts[&TaxBitRecType::Sale], 1);
+        assert_eq!(counts[&TaxBitRecType::Income], 1);
+        assert_eq!(counts[&TaxBitRecType::TransferOut], 1);
+        assert!(count_by_type(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_stats() {
+        let s = stats(&fixture());
+        assert_eq!(s.total, 5);
+        assert_eq!(s.by_type, count_by_type(&fixture()));
+        assert_eq!(s.by_source["BinanceUS"], 3);
+        assert_eq!(s.by_source["Coinbase"], 2);
+        assert_eq!(
+            s.assets.iter().map(|a| a.as_str()).collect::<Vec<_>>(),
+            vec!["BTC", "ETH", "XRP"]
+        );
+        assert_eq!(s.earliest, Some(1000));
+        assert_eq!(s.latest, Some(5000));
+        assert_eq!(s.missing_market_value, 1);
+        assert_eq!(s.empty_external_id, 2);
+
+        assert_eq!(stats(&[]), RecStats::default());
+    }
+
+    #[test]
+    fn test_display() {
+        let s = stats(&fixture()).to_string();
+        let lines: Vec<&str> = s.lines().collect();
+        assert_eq!(lines[0], format!("{:<24}5", "total"));
+        assert!(lines.contains(&format!("{:<24}3", "source BinanceUS").as_str()));
+        assert!(lines.contains(&format!("{:<24}1", "missing market value").as_str()));
+        assert_eq!(
+            *lines.last().unwrap(),
+            format!("{:<24}BTC, ETH, XRP", "assets")
+        );
+    }
+
+    #[test]
+    fn test_serialize() {
+        let json = serde_json::to_value(stats(&fixture())).unwrap();
+        assert_eq!(json["total"], 5);
+        assert_eq!(json["by_source"]["Coinbase"], 2);
+        assert_eq!(json["earliest"], 1000);
+        assert_eq!(json["by_type"].as_object().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_diff() {
+        let recs = fixture();
+        let before = stats(&recs);
+        assert!(before.diff(&before).is_empty());
+
+        // Drop the Income record, the only XRP and missing market value record
+        let after = stats(&[&recs[..3], &recs[4..]].concat());
+        let changes = before.diff(&after);
+        let summary: Vec<String> = changes.iter().map(|c| c.to_string()).collect();
+        assert_eq!(
+            summary,
+            vec![
+                "assets: 3 -> 2 (-1)",
+                "missing market value: 1 -> 0 (-1)",
+                "source BinanceUS: 3 -> 2 (-1)",
+                "total: 5 -> 4 (-1)",
+                "type Income: 1 -> 0 (-1)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_summarize() {
+        let mut recs = fixture();
+        recs[4].internal_transfer = true;
+        let summary = summarize(&recs);
+        assert_eq!(
+            summary,
+            FileSummary {
+                total: 5,
+                earliest: Some(1000),
+                latest: Some(5000),
+                by_type: BTreeMap::from([
+                    (TaxBitRecType::Buy, 2),
+                    (TaxBitRecType::Sale, 1),
+                    (TaxBitRecType::Income, 1),
+                    (TaxBitRecType::TransferOut, 1),
+                ]),
+                assets: BTreeSet::from(["BTC".to_owned(), "ETH".to_owned(), "XRP".to_owned()]),
+                sources: BTreeSet::from(["BinanceUS".to_owned(), "Coinbase".to_owned()]),
+                internal_transfers: 1,
+                missing_market_value: 1,
+            }
+        );
+        assert_eq!(summarize(&[]), FileSummary::default());
+
+        let lines: Vec<String> = summary.to_string().lines().map(|l| l.to_owned()).collect();
+        assert_eq!(lines[0], format!("{:<24}5", "total"));
+        assert!(lines.contains(&format!("{:<24}1", "internal transfers")));
+        assert_eq!(
+            lines.last().unwrap(),
+            &format!("{:<24}BTC, ETH, XRP", "assets")
+        );
+
+        let json = serde_json::to_value(&summary).unwrap();
+        assert_eq!(json["total"], 5);
+        assert_eq!(json["sources"][1], "Coinbase");
+        assert_eq!(json["by_type"]["Buy"], 2);
+    }
+
+    #[cfg(feature = "std-fs")]
+    #[test]
+    fn test_summarize_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("recs.csv");
+        let mut recs = fixture();
+        recs[4].internal_transfer = true;
+        crate::write_tb_export_rec_file(&path, &recs).unwrap();
+        assert_eq!(summarize_file(&path).unwrap(), summarize(&recs));
+
+        std::fs::write(&path, "Date,Transaction Type\n").unwrap();
+        assert!(summarize_file(&path).is_err());
+        assert!(summarize_file(&dir.path().join("missing.csv")).is_err());
+    }
+}
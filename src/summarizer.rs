@@ -0,0 +1,184 @@
+use std::error::Error;
+
+use crate::{
+    income::add_income_by_month, IncomeByMonth, PivotTable, PivotValue, RecStats, RowError,
+    TaxBitExportRec,
+};
+
+/// The reports a Summarizer gathers, each equal to the one its function
+/// computes from all the records observed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CombinedReport {
+    /// The counts by type and source, the earliest and latest times and
+    /// the missing field counts, see stats
+    pub stats: RecStats,
+
+    /// The quantity of each asset per type, see pivot_by_asset_and_type
+    pub quantities: PivotTable,
+
+    /// The market value of each asset per type, see
+    /// pivot_by_asset_and_type_with_value
+    pub market_values: PivotTable,
+
+    /// See income_by_month
+    pub income_by_month: IncomeByMonth,
+
+    /// The number of rows which couldn't be parsed, see
+    /// Summarizer::observe_result
+    pub skipped_rows: usize,
+}
+
+/// Gathers a CombinedReport one record at a time so a file can be
+/// summarized in one pass without reading it into memory. Memory use
+/// grows with the number of types, sources, assets and months rather
+/// than records. Drive it from TaxBitExportRecReader with
+/// observe_result to skip, and count, the rows which don't parse.
+#[derive(Debug, Clone)]
+pub struct Summarizer {
+    report: CombinedReport,
+}
+
+impl Default for Summarizer {
+    fn default() -> Self {
+        Summarizer {
+            report: CombinedReport {
+                market_values: PivotTable {
+                    value: PivotValue::MarketValue,
+                    ..PivotTable::default()
+                },
+                ..CombinedReport::default()
+            },
+        }
+    }
+}
+
+impl Summarizer {
+    pub fn new() -> Summarizer {
+        Summarizer::default()
+    }
+
+    /// Add the record to the reports
+    pub fn observe(&mut self, rec: &TaxBitExportRec) {
+        let report = &mut self.report;
+        report.stats.add(rec);
+        report.quantities.add(rec);
+        report.market_values.add(rec);
+        add_income_by_month(&mut report.income_by_month, rec);
+    }
+
+    /// Observe an entry read by TaxBitExportRecReader, a RowError is
+    /// counted as a skipped row and other errors are returned
+    pub fn observe_result(
+        &mut self,
+        entry: Result<TaxBitExportRec, Box<dyn Error>>,
+    ) -> Result<(), Box<dyn Error>> {
+        match entry {
+            Ok(rec) => self.observe(&rec),
+            Err(e) if e.is::<RowError>() => self.report.skipped_rows += 1,
+            Err(e) => return Err(e),
+        }
+
+        Ok(())
+    }
+
+    pub fn finish(self) -> CombinedReport {
+        self.report
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal_macros::dec;
+    use taxbitrec::TaxBitRecType;
+
+    use super::*;
+    use crate::{
+        income_by_month, pivot_by_asset_and_type, pivot_by_asset_and_type_with_value, stats,
+        write_tb_export_recs_to_writer, TaxBitExportRecReader,
+    };
+
+    // 2022-01-01T00:00:00Z
+    const T: i64 = 1640995200000;
+    const DAY: i64 = 86_400_000;
+
+    fn fixture() -> Vec<TaxBitExportRec> {
+        let rec = |time: i64, type_txs: TaxBitRecType, asset: &str, source: &str| {
+            let mut rec = TaxBitExportRec::new();
+            rec.time = time;
+            rec.type_txs = type_txs.clone();
+            match type_txs {
+                TaxBitRecType::Sale | TaxBitRecType::TransferOut => {
+                    rec.sent_quantity = Some(dec!(0.5));
+                    rec.sent_currency = asset.to_owned();
+                }
+                _ => {
+                    rec.received_quantity = Some(dec!(0.25));
+                    rec.received_currency = asset.to_owned();
+                }
+            }
+            rec.market_value = Some(dec!(1000.01));
+            rec.source = source.to_owned();
+            rec.external_id = format!("{time}");
+            rec
+        };
+        let mut no_value = rec(T + 40 * DAY, TaxBitRecType::Income, "ETH", "Kraken");
+        no_value.market_value = None;
+        no_value.external_id = String::new();
+
+        vec![
+            rec(T, TaxBitRecType::Buy, "BTC", "Coinbase"),
+            rec(T + DAY, TaxBitRecType::Income, "ETH", "Kraken"),
+            rec(T + 2 * DAY, TaxBitRecType::Income, "ETH", "Kraken"),
+            no_value,
+            rec(T + 50 * DAY, TaxBitRecType::Sale, "BTC", "Coinbase"),
+            rec(T + 60 * DAY, TaxBitRecType::TransferOut, "ETH", "Kraken"),
+            rec(
+                T + 400 * DAY,
+                TaxBitRecType::GiftReceived,
+                "SOL",
+                "Coinbase",
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_summarizer_matches_reports() {
+        let mut csv: Vec<u8> = vec![];
+        write_tb_export_recs_to_writer(&mut csv, &fixture()).unwrap();
+        let mut csv = String::from_utf8(csv).unwrap();
+        let bad_row = csv.lines().nth(2).unwrap().replacen("2022", "20x2", 1);
+        csv.push_str(&bad_row);
+        csv.push('\n');
+
+        let mut summarizer = Summarizer::new();
+        for entry in TaxBitExportRecReader::new(csv.as_bytes()).unwrap() {
+            summarizer.observe_result(entry).unwrap();
+        }
+        let report = summarizer.finish();
+
+        let (recs, row_errors) = TaxBitExportRecReader::new(csv.as_bytes())
+            .unwrap()
+            .read_lenient()
+            .unwrap();
+        assert_eq!(recs, fixture());
+        assert_eq!(report.skipped_rows, row_errors.len());
+        assert_eq!(report.skipped_rows, 1);
+        assert_eq!(report.stats, stats(&recs));
+        assert_eq!(report.quantities, pivot_by_asset_and_type(&recs));
+        assert_eq!(
+            report.market_values,
+            pivot_by_asset_and_type_with_value(&recs, PivotValue::MarketValue)
+        );
+        assert_eq!(report.income_by_month, income_by_month(&recs));
+
+        assert_eq!(report.stats.total, 7);
+        assert_eq!(report.stats.missing_market_value, 1);
+        assert_eq!(report.stats.empty_external_id, 1);
+        assert_eq!(report.stats.earliest, Some(T));
+        assert_eq!(report.income_by_month[&(2022, 1)]["ETH"].count, 2);
+        assert_eq!(
+            report.market_values.cells["BTC"][&TaxBitRecType::Sale],
+            dec!(1000.01)
+        );
+    }
+}
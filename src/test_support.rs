@@ -0,0 +1,280 @@
+//! Helpers for testing code which produces TaxBit export records,
+//! enabled by the test-util feature
+//!
+//! assert_csv_roundtrip writes records to CSV and reads them back
+//! panicking with the fields which differ, sample_rec,
+//! sample_recs_all_types and sample_buy_and_income build valid records
+//! with realistic values.
+use std::collections::BTreeSet;
+
+use rust_decimal_macros::dec;
+use taxbitrec::TaxBitRecType;
+
+use crate::{TaxBitExportRec, TaxBitExportRecReader, TaxBitExportRecWriter};
+
+/// The fields which differ between expected and found as
+/// (field name, expected value, found value), extras included
+pub fn rec_field_diffs(
+    expected: &TaxBitExportRec,
+    found: &TaxBitExportRec,
+) -> Vec<(&'static str, String, String)> {
+    let mut diffs = vec![];
+    macro_rules! check {
+        ($field:ident) => {
+            if expected.$field != found.$field {
+                diffs.push((
+                    stringify!($field),
+                    format!("{:?}", expected.$field),
+                    format!("{:?}", found.$field),
+                ));
+            }
+        };
+    }
+    // Decimals are compared numerically so 1.0 equals 1
+    check!(time);
+    check!(type_txs);
+    check!(received_quantity);
+    check!(received_currency);
+    check!(sent_quantity);
+    check!(sent_currency);
+    check!(fee_currency);
+    check!(fee_amount);
+    check!(market_value);
+    check!(source);
+    check!(internal_transfer);
+    check!(external_id);
+    check!(lot_id);
+    check!(extras);
+
+    diffs
+}
+
+/// Write the records to CSV with TaxBitExportRecWriter, read them back
+/// with TaxBitExportRecReader and assert they're unchanged.
+///
+/// # Panics
+///
+/// If writing or reading fails, the number of records changes or a
+/// record changes, naming each field which differs with both values
+pub fn assert_csv_roundtrip(recs: &[TaxBitExportRec]) {
+    let extra_columns: Vec<String> = recs
+        .iter()
+        .flat_map(|r| r.extras.keys().cloned())
+        .collect::<BTreeSet<String>>()
+        .into_iter()
+        .collect();
+    let mut writer = TaxBitExportRecWriter::new(vec![])
+        .with_lot_id_column(recs.iter().any(|r| r.lot_id.is_some()))
+        .with_extra_columns(&extra_columns);
+    for (i, rec) in recs.iter().enumerate() {
+        if let Err(e) = writer.write_rec(rec) {
+            panic!("Writing record {i} failed: {e}");
+        }
+    }
+    let csv = match writer.into_inner() {
+        Ok(csv) => csv,
+        Err(e) => panic!("Writing failed: {e}"),
+    };
+
+    let reader = match TaxBitExportRecReader::new(csv.as_slice()) {
+        Ok(reader) => reader,
+        Err(e) => panic!("Reading the header failed: {e}"),
+    };
+    let found: Vec<TaxBitExportRec> = reader
+        .enumerate()
+        .map(|(i, entry)| match entry {
+            Ok(rec) => rec,
+            Err(e) => panic!("Reading record {i} failed: {e}"),
+        })
+        .collect();
+    assert_eq!(
+        found.len(),
+        recs.len(),
+        "Wrote {} records but read {}",
+        recs.len(),
+        found.len()
+    );
+
+    let mut mismatches: Vec<String> = vec![];
+    for (i, (expected, found)) in recs.iter().zip(found.iter()).enumerate() {
+        for (field, e, f) in rec_field_diffs(expected, found) {
+            mismatches.push(format!("record {i} {field}: expected {e} found {f}"));
+        }
+    }
+    if !mismatches.is_empty() {
+        panic!("CSV round trip changed records:\n{}", mismatches.join("\n"));
+    }
+}
+
+/// A valid record of type_txs with realistic values, Unknown and
+/// Invalid records have only the time, source and external_id set
+pub fn sample_rec(type_txs: TaxBitRecType) -> TaxBitExportRec {
+    let mut rec = TaxBitExportRec::new();
+    // 2022-03-01T14:35:06.123Z
+    rec.time = 1646145306123;
+    rec.source = "Coinbase".to_owned();
+    rec.external_id = format!("{type_txs:?}-0001");
+
+    let mut received = |q, c: &str| {
+        rec.received_quantity = Some(q);
+        rec.received_currency = c.to_owned();
+    };
+    match type_txs {
+        TaxBitRecType::Buy | TaxBitRecType::Trade => received(dec!(0.02314), "BTC"),
+        TaxBitRecType::Sale => received(dec!(1012.55), "USD"),
+        TaxBitRecType::TransferIn => received(dec!(1.5), "ETH"),
+        TaxBitRecType::Income => received(dec!(0.00421337), "ADA"),
+        TaxBitRecType::GiftReceived => received(dec!(0.1), "ETH"),
+        _ => (),
+    }
+    let mut sent = |q, c: &str| {
+        rec.sent_quantity = Some(q);
+        rec.sent_currency = c.to_owned();
+    };
+    match type_txs {
+        TaxBitRecType::Buy => sent(dec!(1000.00), "USD"),
+        TaxBitRecType::Trade => sent(dec!(0.35), "ETH"),
+        TaxBitRecType::Sale => sent(dec!(0.0234), "BTC"),
+        TaxBitRecType::TransferOut => sent(dec!(1.5), "ETH"),
+        TaxBitRecType::Expense => sent(dec!(0.0005), "BTC"),
+        TaxBitRecType::GiftSent => sent(dec!(0.25), "ETH"),
+        _ => (),
+    }
+    rec.market_value = match type_txs {
+        TaxBitRecType::Buy => Some(dec!(1000.00)),
+        TaxBitRecType::Trade => Some(dec!(1001.27)),
+        TaxBitRecType::Sale => Some(dec!(1012.55)),
+        TaxBitRecType::Income => Some(dec!(0.0041)),
+        TaxBitRecType::Expense => Some(dec!(21.63)),
+        TaxBitRecType::GiftReceived => Some(dec!(289.10)),
+        TaxBitRecType::GiftSent => Some(dec!(722.75)),
+        _ => None,
+    };
+    if matches!(
+        type_txs,
+        TaxBitRecType::Buy | TaxBitRecType::Sale | TaxBitRecType::Trade
+    ) {
+        rec.fee_amount = Some(dec!(14.95));
+        rec.fee_currency = "USD".to_owned();
+    }
+    if matches!(
+        type_txs,
+        TaxBitRecType::TransferIn | TaxBitRecType::TransferOut
+    ) {
+        rec.internal_transfer = true;
+    }
+    rec.type_txs = type_txs;
+
+    rec
+}
+
+/// One sample_rec of each TaxBitRecType except Unknown and Invalid, one
+/// millisecond apart in time order
+pub fn sample_recs_all_types() -> Vec<TaxBitExportRec> {
+    [
+        TaxBitRecType::Buy,
+        TaxBitRecType::Sale,
+        TaxBitRecType::Trade,
+        TaxBitRecType::TransferIn,
+        TaxBitRecType::TransferOut,
+        TaxBitRecType::Income,
+        TaxBitRecType::Expense,
+        TaxBitRecType::GiftReceived,
+        TaxBitRecType::GiftSent,
+    ]
+    .into_iter()
+    .enumerate()
+    .map(|(i, type_txs)| {
+        let mut rec = sample_rec(type_txs);
+        rec.time += i as i64;
+        rec
+    })
+    .collect()
+}
+
+/// A Buy with every field set and an internal transfer Income with
+/// only the received side, in different years, for tests of the file
+/// formats
+pub fn sample_buy_and_income() -> Vec<TaxBitExportRec> {
+    let mut buy = TaxBitExportRec::new();
+    // 2020-03-02T07:32:05.123Z
+    buy.time = 1583134325123;
+    buy.type_txs = TaxBitRecType::Buy;
+    buy.received_quantity = Some(dec!(0.12345678));
+    buy.received_currency = "BTC".to_owned();
+    buy.sent_quantity = Some(dec!(1000.10));
+    buy.sent_currency = "USD".to_owned();
+    buy.fee_amount = Some(dec!(1.50));
+    buy.fee_currency = "USD".to_owned();
+    buy.market_value = Some(dec!(1000.10));
+    buy.source = "Coinbase".to_owned();
+    buy.external_id = "a".to_owned();
+
+    let mut income = TaxBitExportRec::new();
+    // 2022-01-01T00:00:00Z
+    income.time = 1640995200000;
+    income.type_txs = TaxBitRecType::Income;
+    income.received_quantity = Some(dec!(0.0000003));
+    income.received_currency = "BTC".to_owned();
+    income.source = "Coinbase".to_owned();
+    income.internal_transfer = true;
+    income.external_id = "b".to_owned();
+
+    vec![buy, income]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sample_recs_are_valid() {
+        let recs = sample_recs_all_types();
+        assert_eq!(recs.len(), 9);
+        for rec in &recs {
+            assert_eq!(rec.validate(), Ok(()), "{rec}");
+        }
+        assert!(recs.windows(2).all(|w| w[0].time < w[1].time));
+    }
+
+    #[test]
+    fn test_assert_csv_roundtrip() {
+        let mut recs = sample_recs_all_types();
+        recs[0] = recs[0].with_lot_id("lot-1");
+        assert_csv_roundtrip(&recs);
+        assert_csv_roundtrip(&[]);
+    }
+
+    #[test]
+    fn test_rec_field_diffs() {
+        let expected = sample_rec(TaxBitRecType::Buy);
+        let mut found = expected.clone();
+        assert!(rec_field_diffs(&expected, &found).is_empty());
+
+        found.market_value = Some(dec!(1000.0));
+        assert!(rec_field_diffs(&expected, &found).is_empty());
+
+        found.market_value = Some(dec!(999.99));
+        found.source = "Kraken".to_owned();
+        assert_eq!(
+            rec_field_diffs(&expected, &found),
+            vec![
+                (
+                    "market_value",
+                    "Some(1000.00)".to_owned(),
+                    "Some(999.99)".to_owned()
+                ),
+                ("source", "\"Coinbase\"".to_owned(), "\"Kraken\"".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "record 1 lot_id: expected Some(\"\") found None")]
+    fn test_assert_csv_roundtrip_mismatch() {
+        // An empty lot ID is read back as None
+        let mut recs = sample_recs_all_types();
+        recs[1] = recs[1].with_lot_id("");
+        assert_csv_roundtrip(&recs);
+    }
+}
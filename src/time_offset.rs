@@ -0,0 +1,160 @@
+use crate::TaxBitExportRec;
+
+/// What apply_time_offset did, or preview_time_offset would do
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TimeOffsetReport {
+    /// The number of records shifted
+    pub shifted: usize,
+
+    /// The earliest and latest times of the shifted records after the
+    /// shift, None if no record was shifted
+    pub min_time: Option<i64>,
+    pub max_time: Option<i64>,
+
+    /// The records were sorted by time before the shift and aren't
+    /// after it, sort them again
+    pub order_broken: bool,
+}
+
+// The times of the records after the shift, None for the records not
+// shifted, or an error if a time overflows
+fn shifted_times(
+    recs: &[TaxBitExportRec],
+    offset_ms: i64,
+    source: Option<&str>,
+) -> Result<Vec<Option<i64>>, String> {
+    let mut times: Vec<Option<i64>> = Vec::with_capacity(recs.len());
+    for (i, rec) in recs.iter().enumerate() {
+        if source.is_some_and(|s| s != rec.source) {
+            times.push(None);
+            continue;
+        }
+        let time = rec.time.checked_add(offset_ms).ok_or_else(|| {
+            format!(
+                "Shifting the time {} of record {i} by {offset_ms} ms overflows",
+                rec.time
+            )
+        })?;
+        times.push(Some(time));
+    }
+
+    Ok(times)
+}
+
+/// What apply_time_offset would do without changing the records, to
+/// check the new dates before applying the offset
+pub fn preview_time_offset(
+    recs: &[TaxBitExportRec],
+    offset_ms: i64,
+    source: Option<&str>,
+) -> Result<TimeOffsetReport, String> {
+    let times = shifted_times(recs, offset_ms, source)?;
+
+    let mut report = TimeOffsetReport::default();
+    for time in times.iter().flatten() {
+        report.shifted += 1;
+        report.min_time = Some(report.min_time.map_or(*time, |t| t.min(*time)));
+        report.max_time = Some(report.max_time.map_or(*time, |t| t.max(*time)));
+    }
+    let was_sorted = recs.windows(2).all(|w| w[0].time <= w[1].time);
+    let new_times: Vec<i64> = times
+        .iter()
+        .zip(recs)
+        .map(|(time, rec)| time.unwrap_or(rec.time))
+        .collect();
+    report.order_broken = was_sorted && !new_times.windows(2).all(|w| w[0] <= w[1]);
+
+    Ok(report)
+}
+
+/// Add offset_ms to the time of each record, or only of those whose
+/// source is source, for instance to correct an export of local times
+/// labeled as UTC. Returns the number of records shifted and whether the
+/// shift broke their order, see TimeOffsetReport. The records aren't
+/// sorted again.
+///
+/// If any time would overflow an error is returned and no record is
+/// changed.
+pub fn apply_time_offset(
+    recs: &mut [TaxBitExportRec],
+    offset_ms: i64,
+    source: Option<&str>,
+) -> Result<TimeOffsetReport, String> {
+    let report = preview_time_offset(recs, offset_ms, source)?;
+    let times = shifted_times(recs, offset_ms, source).expect("SNH");
+    for (rec, time) in recs.iter_mut().zip(times) {
+        if let Some(time) = time {
+            rec.time = time;
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // 2022-01-01T00:00:00Z
+    const T: i64 = 1640995200000;
+    const HOURS_7: i64 = 7 * 60 * 60 * 1000;
+
+    fn rec(time: i64, source: &str) -> TaxBitExportRec {
+        let mut rec = TaxBitExportRec::new();
+        rec.time = time;
+        rec.source = source.to_owned();
+        rec
+    }
+
+    #[test]
+    fn test_apply_time_offset_source() {
+        let mut recs = vec![
+            rec(T, "Kraken"),
+            rec(T + 1000, "Coinbase"),
+            rec(T + 2000, "Kraken"),
+            rec(T + HOURS_7, "Coinbase"),
+        ];
+
+        let preview = preview_time_offset(&recs, HOURS_7, Some("Kraken")).unwrap();
+        assert_eq!(
+            preview,
+            TimeOffsetReport {
+                shifted: 2,
+                min_time: Some(T + HOURS_7),
+                max_time: Some(T + HOURS_7 + 2000),
+                order_broken: true,
+            }
+        );
+        assert_eq!(recs[0].time, T);
+
+        let report = apply_time_offset(&mut recs, HOURS_7, Some("Kraken")).unwrap();
+        assert_eq!(report, preview);
+        let times: Vec<i64> = recs.iter().map(|r| r.time).collect();
+        assert_eq!(
+            times,
+            vec![T + HOURS_7, T + 1000, T + HOURS_7 + 2000, T + HOURS_7]
+        );
+
+        assert_eq!(
+            apply_time_offset(&mut recs, HOURS_7, Some("Gemini")).unwrap(),
+            TimeOffsetReport::default()
+        );
+    }
+
+    #[test]
+    fn test_apply_time_offset_all() {
+        let mut recs = vec![rec(T, "Kraken"), rec(T + 1000, "Coinbase")];
+        let report = apply_time_offset(&mut recs, -HOURS_7, None).unwrap();
+        assert_eq!(report.shifted, 2);
+        assert!(!report.order_broken);
+        assert_eq!(report.min_time, Some(T - HOURS_7));
+        assert_eq!(report.max_time, Some(T - HOURS_7 + 1000));
+        assert_eq!(recs[1].time, T - HOURS_7 + 1000);
+
+        // An overflow changes nothing
+        recs.push(rec(i64::MAX - 1, "Coinbase"));
+        let err = apply_time_offset(&mut recs, 2, None).unwrap_err();
+        assert!(err.contains("record 2"), "{err}");
+        assert_eq!(recs[0].time, T - HOURS_7);
+    }
+}
@@ -0,0 +1,244 @@
+use std::collections::BTreeMap;
+#[cfg(feature = "std-fs")]
+use std::{error::Error, path::Path};
+
+use chrono::NaiveDate;
+use taxbitrec::TaxBitRecType;
+
+use crate::TaxBitExportRec;
+#[cfg(feature = "std-fs")]
+use crate::{reader::rec_file_iter, Progress, ReaderConfig};
+
+/// A range of UTC times in milliseconds including start and excluding end
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TimeRange {
+    pub start: i64,
+    pub end: i64,
+}
+
+impl TimeRange {
+    pub fn new(start: i64, end: i64) -> TimeRange {
+        TimeRange { start, end }
+    }
+
+    /// The UTC calendar year from Jan 1 00:00:00.000Z up to, but not
+    /// including, Jan 1 of the next year. None if chrono can't represent
+    /// the year.
+    pub fn utc_year(year: u32) -> Option<TimeRange> {
+        let jan_1 = |year: u32| -> Option<i64> {
+            let date = NaiveDate::from_ymd_opt(i32::try_from(year).ok()?, 1, 1)?;
+            Some(date.and_hms_opt(0, 0, 0)?.and_utc().timestamp_millis())
+        };
+
+        Some(TimeRange::new(jan_1(year)?, jan_1(year.checked_add(1)?)?))
+    }
+
+    pub fn contains(&self, time: i64) -> bool {
+        self.start <= time && time < self.end
+    }
+}
+
+/// Options for records_for_year_with_opts and read_file_for_year_with_opts
+#[derive(Debug, Clone, Default)]
+pub struct YearOpts {
+    /// Also return, for each asset the year's records send from a source,
+    /// the last TransferIn of that asset to that source before the year.
+    /// These are the deposits a cost basis calculation most likely needs
+    /// to know where the asset came from, they're not a complete history.
+    pub include_prior_transfers_in: bool,
+}
+
+// Collects the records of a year and, if wanted, the last TransferIn
+// before it of each (asset, source) without keeping the other records
+struct YearFilter {
+    range: TimeRange,
+    include_prior_transfers_in: bool,
+    recs: Vec<TaxBitExportRec>,
+    transfers_in: BTreeMap<(String, String), TaxBitExportRec>,
+}
+
+impl YearFilter {
+    fn new(year: u32, opts: &YearOpts) -> YearFilter {
+        YearFilter {
+            // An unrepresentable year has no records
+            range: TimeRange::utc_year(year).unwrap_or(TimeRange::new(0, 0)),
+            include_prior_transfers_in: opts.include_prior_transfers_in,
+            recs: vec![],
+            transfers_in: BTreeMap::new(),
+        }
+    }
+
+    fn push(&mut self, rec: &TaxBitExportRec) {
+        if self.range.contains(rec.time) {
+            self.recs.push(rec.clone());
+        } else if self.include_prior_transfers_in
+            && rec.time < self.range.start
+            && rec.type_txs == TaxBitRecType::TransferIn
+        {
+            let key = (rec.received_currency.clone(), rec.source.clone());
+            match self.transfers_in.get(&key) {
+                Some(last) if last.time > rec.time => {}
+                _ => {
+                    self.transfers_in.insert(key, rec.clone());
+                }
+            }
+        }
+    }
+
+    // The records sorted, with the prior TransferIns of the assets sent
+    fn finish(mut self) -> Vec<TaxBitExportRec> {
+        let mut prior: Vec<TaxBitExportRec> = vec![];
+        for rec in &self.recs {
+            if !rec.sent_currency.is_empty() {
+                let key = (rec.sent_currency.clone(), rec.source.clone());
+                prior.extend(self.transfers_in.remove(&key));
+            }
+        }
+        self.recs.append(&mut prior);
+        self.recs.sort();
+
+        self.recs
+    }
+}
+
+/// The records of the UTC calendar year, see TimeRange::utc_year, sorted
+pub fn records_for_year(recs: &[TaxBitExportRec], year: u32) -> Vec<TaxBitExportRec> {
+    records_for_year_with_opts(recs, year, &YearOpts::default())
+}
+
+/// The records of the UTC calendar year sorted, with the records opts
+/// asks for
+pub fn records_for_year_with_opts(
+    recs: &[TaxBitExportRec],
+    year: u32,
+    opts: &YearOpts,
+) -> Vec<TaxBitExportRec> {
+    let mut filter = YearFilter::new(year, opts);
+    for rec in recs {
+        filter.push(rec);
+    }
+
+    filter.finish()
+}
+
+/// Read the records of the UTC calendar year from a file as
+/// read_tb_export_rec_file reads it, sorted. The records of other years
+/// are dropped as they're read so only the year is kept in memory.
+#[cfg(feature = "std-fs")]
+pub fn read_file_for_year(path: &Path, year: u32) -> Result<Vec<TaxBitExportRec>, Box<dyn Error>> {
+    read_file_for_year_with_opts(path, year, &YearOpts::default())
+}
+
+/// Read the records of the UTC calendar year from a file sorted, with
+/// the records opts asks for
+#[cfg(feature = "std-fs")]
+pub fn read_file_for_year_with_opts(
+    path: &Path,
+    year: u32,
+    opts: &YearOpts,
+) -> Result<Vec<TaxBitExportRec>, Box<dyn Error>> {
+    let mut filter = YearFilter::new(year, opts);
+    for rec in rec_file_iter(path, &ReaderConfig::default(), None::<Progress>)? {
+        filter.push(&rec?);
+    }
+
+    Ok(filter.finish())
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    #[cfg(feature = "std-fs")]
+    use crate::write_tb_export_rec_file;
+
+    // 2022-01-01T00:00:00.000Z
+    const Y2022: i64 = 1640995200000;
+    // 2023-01-01T00:00:00.000Z
+    const Y2023: i64 = 1672531200000;
+
+    fn rec(time: i64, type_txs: TaxBitRecType, asset: &str, id: &str) -> TaxBitExportRec {
+        let mut rec = TaxBitExportRec::new();
+        rec.time = time;
+        rec.type_txs = type_txs.clone();
+        match type_txs {
+            TaxBitRecType::TransferIn | TaxBitRecType::Income => {
+                rec.received_quantity = Some(dec!(1));
+                rec.received_currency = asset.to_owned();
+            }
+            _ => {
+                rec.sent_quantity = Some(dec!(1));
+                rec.sent_currency = asset.to_owned();
+            }
+        }
+        rec.source = "Kraken".to_owned();
+        rec.external_id = id.to_owned();
+        rec
+    }
+
+    fn history() -> Vec<TaxBitExportRec> {
+        vec![
+            rec(Y2023, TaxBitRecType::Income, "BTC", "2023"),
+            rec(Y2022 - 1, TaxBitRecType::Income, "BTC", "2021-last"),
+            rec(Y2022 - 5000, TaxBitRecType::TransferIn, "ETH", "eth-in"),
+            rec(Y2022 - 9000, TaxBitRecType::TransferIn, "BTC", "btc-in-old"),
+            rec(Y2022 - 2000, TaxBitRecType::TransferIn, "BTC", "btc-in"),
+            rec(Y2023 - 1, TaxBitRecType::Sale, "BTC", "2022-last"),
+            rec(Y2022, TaxBitRecType::Income, "ETH", "2022-first"),
+        ]
+    }
+
+    fn ids(recs: &[TaxBitExportRec]) -> Vec<&str> {
+        recs.iter().map(|r| r.external_id.as_str()).collect()
+    }
+
+    #[test]
+    fn test_time_range_utc_year() {
+        let range = TimeRange::utc_year(2022).unwrap();
+        assert_eq!(range, TimeRange::new(Y2022, Y2023));
+        assert!(range.contains(Y2022));
+        assert!(range.contains(Y2023 - 1));
+        assert!(!range.contains(Y2023));
+        assert!(!range.contains(Y2022 - 1));
+        assert_eq!(TimeRange::utc_year(u32::MAX), None);
+    }
+
+    #[test]
+    fn test_records_for_year() {
+        let recs = history();
+        assert_eq!(
+            ids(&records_for_year(&recs, 2022)),
+            vec!["2022-first", "2022-last"]
+        );
+        assert_eq!(ids(&records_for_year(&recs, 2023)), vec!["2023"]);
+        assert_eq!(records_for_year(&recs, 2024), vec![]);
+
+        // Only the last BTC deposit before the year as ETH isn't sent
+        let opts = YearOpts {
+            include_prior_transfers_in: true,
+        };
+        assert_eq!(
+            ids(&records_for_year_with_opts(&recs, 2022, &opts)),
+            vec!["btc-in", "2022-first", "2022-last"]
+        );
+    }
+
+    #[cfg(feature = "std-fs")]
+    #[test]
+    fn test_read_file_for_year() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("archive.csv");
+        write_tb_export_rec_file(&path, &history()).unwrap();
+
+        let recs = read_file_for_year(&path, 2022).unwrap();
+        assert_eq!(ids(&recs), vec!["2022-first", "2022-last"]);
+        assert_eq!(recs[1].time_utc_string(), "2022-12-31 23:59:59.999 UTC");
+
+        let opts = YearOpts {
+            include_prior_transfers_in: true,
+        };
+        let recs = read_file_for_year_with_opts(&path, 2022, &opts).unwrap();
+        assert_eq!(ids(&recs), vec!["btc-in", "2022-first", "2022-last"]);
+    }
+}
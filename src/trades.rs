@@ -0,0 +1,601 @@
+use std::fmt::Display;
+
+use rust_decimal::{Decimal, RoundingStrategy};
+use rust_decimal_macros::dec;
+use taxbitrec::TaxBitRecType;
+
+use crate::{format_decimal, DecimalFormat, TaxBitExportRec, Traced};
+
+/// Split a crypto to crypto Trade into a Sale of the sent side for its
+/// market value in fiat and a Buy of the received side for the same
+/// value, for tools which don't accept Trades. The Sale has the fee and
+/// the external IDs are the Trade's with "-sale" and "-buy" appended,
+/// see merge_sale_buy_pairs for the inverse.
+pub fn split_trade(
+    rec: &TaxBitExportRec,
+    fiat: &str,
+) -> Result<(TaxBitExportRec, TaxBitExportRec), String> {
+    if rec.type_txs != TaxBitRecType::Trade {
+        return Err(format!(
+            "Record with External ID {} isn't a Trade",
+            rec.external_id
+        ));
+    }
+    let market_value = rec.market_value.ok_or_else(|| {
+        format!(
+            "Trade with External ID {} has no Market Value",
+            rec.external_id
+        )
+    })?;
+
+    let mut sale = rec.clone();
+    sale.type_txs = TaxBitRecType::Sale;
+    sale.received_quantity = Some(market_value);
+    sale.received_currency = fiat.to_owned();
+    sale.external_id = format!("{}-sale", rec.external_id);
+
+    let mut buy = rec.clone();
+    buy.type_txs = TaxBitRecType::Buy;
+    buy.sent_quantity = Some(market_value);
+    buy.sent_currency = fiat.to_owned();
+    buy.fee_amount = None;
+    buy.fee_currency = String::new();
+    buy.external_id = format!("{}-buy", rec.external_id);
+
+    Ok((sale, buy))
+}
+
+/// Split a Trade as split_trade does, the Sale and Buy both have the
+/// inputs of the Trade
+pub fn split_trade_traced(
+    traced: &Traced<TaxBitExportRec>,
+    fiat: &str,
+) -> Result<(Traced<TaxBitExportRec>, Traced<TaxBitExportRec>), String> {
+    let (sale, buy) = split_trade(&traced.rec, fiat)?;
+    let traced = |rec: TaxBitExportRec| Traced {
+        rec,
+        inputs: traced.inputs.clone(),
+    };
+
+    Ok((traced(sale), traced(buy)))
+}
+
+/// Options for merge_sale_buy_pairs_with_opts
+#[derive(Debug, Clone)]
+pub struct SaleBuyPairOpts {
+    /// The largest difference of the market values of a Sale and Buy as
+    /// a fraction of the larger of the two, 0.01 by default
+    pub market_value_tolerance: Decimal,
+}
+
+impl Default for SaleBuyPairOpts {
+    fn default() -> Self {
+        SaleBuyPairOpts {
+            market_value_tolerance: dec!(0.01),
+        }
+    }
+}
+
+/// A Sale and Buy merged into a Trade by merge_sale_buy_pairs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergeInfo {
+    /// Indices of the Sale and Buy in the records merged
+    pub sale: usize,
+    pub buy: usize,
+
+    /// Index of the Trade in the records returned
+    pub trade: usize,
+}
+
+/// Merge Sale and Buy pairs into Trades, see merge_sale_buy_pairs_with_opts
+pub fn merge_sale_buy_pairs(
+    recs: Vec<TaxBitExportRec>,
+    window_ms: i64,
+) -> Result<(Vec<TaxBitExportRec>, Vec<MergeInfo>), String> {
+    merge_sale_buy_pairs_with_opts(recs, window_ms, &SaleBuyPairOpts::default())
+}
+
+/// Merge each Sale with a Buy of the same source within window_ms of it
+/// which was paid for in the currency the Sale received and whose market
+/// value is within opts.market_value_tolerance of the Sale's. A Sale is
+/// paired with the closest such Buy in time, ties go to the lower index.
+///
+/// The Trade has the Sale's time, sent side, market value and other
+/// fields, the Buy's received side and the sum of their fees, it
+/// replaces whichever of the two is first. Its external_id is the one
+/// split_trade split if the Sale's and Buy's end in "-sale" and "-buy"
+/// and otherwise theirs joined with "+". The other records are
+/// unchanged, so this is the inverse of split_trade.
+///
+/// Returns an error if a pair have fees in different currencies.
+pub fn merge_sale_buy_pairs_with_opts(
+    recs: Vec<TaxBitExportRec>,
+    window_ms: i64,
+    opts: &SaleBuyPairOpts,
+) -> Result<(Vec<TaxBitExportRec>, Vec<MergeInfo>), String> {
+    let recs = recs.into_iter().map(Traced::untraced).collect();
+    let (merged, infos) = merge_sale_buy_pairs_traced(recs, window_ms, opts)?;
+
+    Ok((merged.into_iter().map(|t| t.rec).collect(), infos))
+}
+
+/// Merge the Sale and Buy pairs as merge_sale_buy_pairs_with_opts does,
+/// the inputs of a Trade are those of the Sale followed by those of the
+/// Buy
+pub fn merge_sale_buy_pairs_traced(
+    recs: Vec<Traced<TaxBitExportRec>>,
+    window_ms: i64,
+    opts: &SaleBuyPairOpts,
+) -> Result<(Vec<Traced<TaxBitExportRec>>, Vec<MergeInfo>), String> {
+    // The index of the Buy paired with each Sale
+    let mut pairs: Vec<(usize, usize)> = vec![];
+    let mut paired = vec![false; recs.len()];
+    for (s, sale) in recs.iter().enumerate() {
+        let sale = &sale.rec;
+        if sale.type_txs != TaxBitRecType::Sale {
+            continue;
+        }
+        let buy = recs
+            .iter()
+            .map(|t| &t.rec)
+            .enumerate()
+            .filter(|(b, buy)| !paired[*b] && is_pair(sale, buy, window_ms, opts))
+            .min_by_key(|(b, buy)| (buy.time.abs_diff(sale.time), *b))
+            .map(|(b, _)| b);
+        if let Some(b) = buy {
+            paired[s] = true;
+            paired[b] = true;
+            pairs.push((s, b));
+        }
+    }
+
+    // The Trade replacing the first of each pair, None for the second
+    let mut trades: Vec<Option<Traced<TaxBitExportRec>>> = vec![None; recs.len()];
+    for (s, b) in &pairs {
+        trades[*s.min(b)] = Some(Traced {
+            rec: merge(&recs[*s].rec, &recs[*b].rec)?,
+            inputs: [recs[*s].inputs.clone(), recs[*b].inputs.clone()].concat(),
+        });
+    }
+    let mut merged: Vec<Traced<TaxBitExportRec>> = Vec::with_capacity(recs.len() - pairs.len());
+    let mut trade_index = vec![0; recs.len()];
+    for (i, (rec, trade)) in recs.into_iter().zip(trades).enumerate() {
+        match (paired[i], trade) {
+            (true, Some(trade)) => {
+                trade_index[i] = merged.len();
+                merged.push(trade);
+            }
+            (true, None) => {}
+            (false, _) => merged.push(rec),
+        }
+    }
+    let infos = pairs
+        .into_iter()
+        .map(|(sale, buy)| MergeInfo {
+            sale,
+            buy,
+            trade: trade_index[sale.min(buy)],
+        })
+        .collect();
+
+    Ok((merged, infos))
+}
+
+// True if buy could be the other half of the trade sale is half of
+fn is_pair(
+    sale: &TaxBitExportRec,
+    buy: &TaxBitExportRec,
+    window_ms: i64,
+    opts: &SaleBuyPairOpts,
+) -> bool {
+    if buy.type_txs != TaxBitRecType::Buy
+        || buy.source != sale.source
+        || buy.sent_currency != sale.received_currency
+        || !i64::try_from(buy.time.abs_diff(sale.time)).is_ok_and(|d| d <= window_ms)
+    {
+        return false;
+    }
+    match (sale.market_value, buy.market_value) {
+        (Some(a), Some(b)) => (a - b).abs() <= opts.market_value_tolerance * a.abs().max(b.abs()),
+        _ => false,
+    }
+}
+
+/// The number of decimal places of TradePair::price
+pub const TRADE_PRICE_SCALE: u32 = 8;
+
+/// A Trade as a currency pair, the received asset is the base and the
+/// sent asset the quote, see TaxBitExportRec::trade_pair
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TradePair {
+    pub time: i64,
+    pub base: String,
+    pub quote: String,
+
+    /// The received quantity, never zero
+    pub base_quantity: Decimal,
+
+    /// The sent quantity
+    pub quote_quantity: Decimal,
+
+    pub external_id: String,
+}
+
+impl TradePair {
+    /// The pair as "BASE/QUOTE"
+    pub fn pair(&self) -> String {
+        format!("{}/{}", self.base, self.quote)
+    }
+
+    /// The price of one unit of base in quote rounded to
+    /// TRADE_PRICE_SCALE decimal places, see price_with_scale
+    pub fn price(&self) -> Result<Decimal, String> {
+        self.price_with_scale(TRADE_PRICE_SCALE)
+    }
+
+    /// The quote quantity divided by the base quantity rounded to scale
+    /// decimal places with midpoints away from zero. The division is
+    /// done to Decimal's 28 significant digits before rounding and it's
+    /// an error if the quotient is out of the range of a Decimal.
+    pub fn price_with_scale(&self, scale: u32) -> Result<Decimal, String> {
+        let price = self
+            .quote_quantity
+            .checked_div(self.base_quantity)
+            .ok_or_else(|| {
+                format!(
+                    "Price of {} {} for {} {} is out of the range of a Decimal",
+                    self.quote_quantity, self.quote, self.base_quantity, self.base
+                )
+            })?;
+
+        Ok(price.round_dp_with_strategy(scale, RoundingStrategy::MidpointAwayFromZero))
+    }
+}
+
+impl Display for TradePair {
+    /// "BASE/QUOTE @ PRICE" with trailing zeros of the price removed,
+    /// such as "ETH/USDC @ 1850.25", or "BASE/QUOTE" if there's no price
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.price() {
+            Ok(price) => write!(f, "{} @ {}", self.pair(), price.normalize()),
+            Err(_) => write!(f, "{}", self.pair()),
+        }
+    }
+}
+
+impl TaxBitExportRec {
+    /// The Trade as a currency pair, an error if it isn't a Trade, a
+    /// currency is empty, a quantity is missing or the received quantity
+    /// is zero
+    pub fn trade_pair(&self) -> Result<TradePair, String> {
+        if self.type_txs != TaxBitRecType::Trade {
+            return Err(format!(
+                "Record with External ID {} isn't a Trade",
+                self.external_id
+            ));
+        }
+        let missing =
+            |field: &str| format!("Trade with External ID {} has no {field}", self.external_id);
+        let base_quantity = self
+            .received_quantity
+            .ok_or_else(|| missing("Received Quantity"))?;
+        let quote_quantity = self.sent_quantity.ok_or_else(|| missing("Sent Quantity"))?;
+        if self.received_currency.is_empty() {
+            return Err(missing("Received Currency"));
+        }
+        if self.sent_currency.is_empty() {
+            return Err(missing("Sent Currency"));
+        }
+        if base_quantity.is_zero() {
+            return Err(format!(
+                "Trade with External ID {} has a zero Received Quantity",
+                self.external_id
+            ));
+        }
+
+        Ok(TradePair {
+            time: self.time,
+            base: self.received_currency.clone(),
+            quote: self.sent_currency.clone(),
+            base_quantity,
+            quote_quantity,
+            external_id: self.external_id.clone(),
+        })
+    }
+}
+
+/// The Trades as CSV with a header, one row per Trade sorted by time
+/// with the columns Time, Pair, Base Quantity, Quote Quantity, Price and
+/// External ID. Other records, and Trades trade_pair rejects, are left
+/// out, as is the Price of a Trade whose price is out of range.
+pub fn trades_to_pair_csv(recs: &[TaxBitExportRec]) -> String {
+    let mut pairs: Vec<TradePair> = recs.iter().filter_map(|r| r.trade_pair().ok()).collect();
+    pairs.sort_by_key(|p| p.time);
+
+    let format = DecimalFormat::default();
+    let mut wtr = csv::Writer::from_writer(vec![]);
+    wtr.write_record([
+        "Time",
+        "Pair",
+        "Base Quantity",
+        "Quote Quantity",
+        "Price",
+        "External ID",
+    ])
+    .expect("SNH");
+    for pair in &pairs {
+        let time = chrono::DateTime::from_timestamp_millis(pair.time).map_or(String::new(), |t| {
+            t.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()
+        });
+        wtr.write_record([
+            &time,
+            &pair.pair(),
+            &format_decimal(pair.base_quantity, &format),
+            &format_decimal(pair.quote_quantity, &format),
+            &pair
+                .price()
+                .map_or(String::new(), |p| format_decimal(p, &format)),
+            &pair.external_id,
+        ])
+        .expect("SNH");
+    }
+
+    String::from_utf8(wtr.into_inner().expect("SNH")).expect("SNH")
+}
+
+fn merge(sale: &TaxBitExportRec, buy: &TaxBitExportRec) -> Result<TaxBitExportRec, String> {
+    let mut trade = sale.clone();
+    trade.type_txs = TaxBitRecType::Trade;
+    trade.received_quantity = buy.received_quantity;
+    trade.received_currency = buy.received_currency.clone();
+
+    match (sale.fee_amount, buy.fee_amount) {
+        (Some(_), Some(_)) if sale.fee_currency != buy.fee_currency => {
+            return Err(format!(
+                "Sale {} and Buy {} have fees in different currencies, {} and {}",
+                sale.external_id, buy.external_id, sale.fee_currency, buy.fee_currency
+            ));
+        }
+        (Some(a), Some(b)) => trade.fee_amount = Some(a + b),
+        (None, Some(b)) => {
+            trade.fee_amount = Some(b);
+            trade.fee_currency = buy.fee_currency.clone();
+        }
+        (_, None) => {}
+    }
+
+    trade.external_id = match (
+        sale.external_id.strip_suffix("-sale"),
+        buy.external_id.strip_suffix("-buy"),
+    ) {
+        (Some(a), Some(b)) if a == b => a.to_owned(),
+        _ => format!("{}+{}", sale.external_id, buy.external_id),
+    };
+
+    Ok(trade)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // 2022-01-01T00:00:00Z
+    const T: i64 = 1640995200000;
+
+    fn trade(time: i64, id: &str) -> TaxBitExportRec {
+        let mut rec = TaxBitExportRec::new();
+        rec.time = time;
+        rec.type_txs = TaxBitRecType::Trade;
+        rec.received_quantity = Some(dec!(15.5));
+        rec.received_currency = "ETH".to_owned();
+        rec.sent_quantity = Some(dec!(1.25));
+        rec.sent_currency = "BTC".to_owned();
+        rec.fee_amount = Some(dec!(0.0001));
+        rec.fee_currency = "BTC".to_owned();
+        rec.market_value = Some(dec!(50000.25));
+        rec.source = "Kraken".to_owned();
+        rec.external_id = id.to_owned();
+        rec
+    }
+
+    #[test]
+    fn test_split_trade_round_trip() {
+        let mut income = TaxBitExportRec::new();
+        income.time = T + 1;
+        income.type_txs = TaxBitRecType::Income;
+        income.received_quantity = Some(dec!(1));
+        income.received_currency = "ETH".to_owned();
+        income.source = "Kraken".to_owned();
+        let trades = vec![trade(T, "a"), income, trade(T + 2, "b")];
+
+        let (sale, buy) = split_trade(&trades[0], "USD").unwrap();
+        assert_eq!(sale.type_txs, TaxBitRecType::Sale);
+        assert_eq!(sale.received_quantity, Some(dec!(50000.25)));
+        assert_eq!(sale.external_id, "a-sale");
+        assert_eq!(buy.sent_currency, "USD");
+        assert_eq!(buy.fee_amount, None);
+        assert!(split_trade(&trades[1], "USD").is_err());
+
+        // Both halves trace back to the Trade
+        let traced = crate::trace(trades.clone());
+        let (traced_sale, traced_buy) = split_trade_traced(&traced[2], "USD").unwrap();
+        assert_eq!(traced_sale.rec, split_trade(&trades[2], "USD").unwrap().0);
+        assert_eq!(traced_sale.inputs, traced[2].inputs);
+        assert_eq!(traced_buy.inputs[0].row, 2);
+
+        // Buys before the Sales
+        let mut split: Vec<TaxBitExportRec> = vec![];
+        for rec in &trades {
+            match split_trade(rec, "USD") {
+                Ok((sale, buy)) => split.extend([buy, sale]),
+                Err(_) => split.push(rec.clone()),
+            }
+        }
+        let (merged, infos) = merge_sale_buy_pairs(split.clone(), 0).unwrap();
+        assert_eq!(merged, trades);
+
+        // A merged Trade traces back to the Sale then the Buy
+        let (traced, traced_infos) =
+            merge_sale_buy_pairs_traced(crate::trace(split), 0, &SaleBuyPairOpts::default())
+                .unwrap();
+        assert_eq!(crate::untrace(traced.clone()), trades);
+        assert_eq!(traced_infos, infos);
+        let rows: Vec<Vec<usize>> = traced
+            .iter()
+            .map(|t| t.inputs.iter().map(|i| i.row).collect())
+            .collect();
+        assert_eq!(rows, vec![vec![1, 0], vec![2], vec![4, 3]]);
+        assert_eq!(
+            infos,
+            vec![
+                MergeInfo {
+                    sale: 1,
+                    buy: 0,
+                    trade: 0
+                },
+                MergeInfo {
+                    sale: 4,
+                    buy: 3,
+                    trade: 2
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge_sale_buy_pairs_unpaired() {
+        let (sale, buy) = split_trade(&trade(T, "a"), "USD").unwrap();
+
+        // Outside the window, another source and paid in another currency
+        let mut late = buy.clone();
+        late.time = T + 2000;
+        let mut other_source = buy.clone();
+        other_source.source = "Coinbase".to_owned();
+        let mut eur = buy.clone();
+        eur.sent_currency = "EUR".to_owned();
+        // Far enough apart to overflow a subtraction
+        let mut earliest = buy.clone();
+        earliest.time = i64::MIN;
+        let mut latest = sale.clone();
+        latest.time = i64::MAX;
+        let recs = vec![sale.clone(), late, other_source, eur, earliest, latest];
+        let (merged, infos) = merge_sale_buy_pairs(recs.clone(), 1000).unwrap();
+        assert_eq!(merged, recs);
+        assert_eq!(infos, vec![]);
+
+        // Market values 2% apart pair only with a larger tolerance
+        let mut cheap = buy.clone();
+        cheap.market_value = Some(dec!(49000));
+        cheap.external_id = "x".to_owned();
+        let recs = vec![sale.clone(), cheap];
+        let (merged, _) = merge_sale_buy_pairs(recs.clone(), 1000).unwrap();
+        assert_eq!(merged, recs);
+        let opts = SaleBuyPairOpts {
+            market_value_tolerance: dec!(0.03),
+        };
+        let (merged, infos) = merge_sale_buy_pairs_with_opts(recs, 1000, &opts).unwrap();
+        assert_eq!(merged.len(), 1);
+        assert_eq!(merged[0].external_id, "a-sale+x");
+        assert_eq!(merged[0].market_value, Some(dec!(50000.25)));
+        assert_eq!(infos.len(), 1);
+    }
+
+    #[test]
+    fn test_merge_sale_buy_pairs_fees() {
+        let (mut sale, mut buy) = split_trade(&trade(T, "a"), "USD").unwrap();
+        buy.fee_amount = Some(dec!(0.0002));
+        buy.fee_currency = "BTC".to_owned();
+        let (merged, _) = merge_sale_buy_pairs(vec![sale.clone(), buy.clone()], 0).unwrap();
+        assert_eq!(merged[0].fee_amount, Some(dec!(0.0003)));
+
+        buy.fee_currency = "ETH".to_owned();
+        let err = merge_sale_buy_pairs(vec![sale.clone(), buy.clone()], 0).unwrap_err();
+        assert!(err.contains("different currencies"), "{err}");
+
+        sale.fee_amount = None;
+        sale.fee_currency = String::new();
+        let (merged, _) = merge_sale_buy_pairs(vec![sale, buy], 0).unwrap();
+        assert_eq!(merged[0].fee_amount, Some(dec!(0.0002)));
+        assert_eq!(merged[0].fee_currency, "ETH");
+    }
+
+    #[test]
+    fn test_trade_pair() {
+        let mut rec = trade(T, "a");
+        rec.received_quantity = Some(dec!(2));
+        rec.received_currency = "ETH".to_owned();
+        rec.sent_quantity = Some(dec!(3700.50));
+        rec.sent_currency = "USDC".to_owned();
+        let pair = rec.trade_pair().unwrap();
+        assert_eq!(pair.base, "ETH");
+        assert_eq!(pair.quote, "USDC");
+        assert_eq!(pair.price(), Ok(dec!(1850.25)));
+        assert_eq!(pair.to_string(), "ETH/USDC @ 1850.25");
+
+        // 1/3 is rounded to the scale
+        rec.received_quantity = Some(dec!(3));
+        rec.sent_quantity = Some(dec!(1));
+        let pair = rec.trade_pair().unwrap();
+        assert_eq!(pair.price(), Ok(dec!(0.33333333)));
+        assert_eq!(pair.price_with_scale(2), Ok(dec!(0.33)));
+        assert_eq!(pair.price_with_scale(0), Ok(dec!(0)));
+    }
+
+    #[test]
+    fn test_trade_pair_tiny_base() {
+        let mut rec = trade(T, "dust");
+        rec.received_quantity = Some(dec!(0.000000000000000003));
+        rec.received_currency = "SHIB".to_owned();
+        rec.sent_quantity = Some(dec!(0.00000002));
+        rec.sent_currency = "BTC".to_owned();
+        let pair = rec.trade_pair().unwrap();
+        assert_eq!(pair.price(), Ok(dec!(6666666666.66666667)));
+        // More places than the quotient's 29 significant digits have
+        assert_eq!(
+            pair.price_with_scale(20),
+            Ok(dec!(6666666666.6666666666666666667))
+        );
+
+        // A quotient beyond Decimal's range is an error
+        rec.received_quantity = Some(dec!(0.0000000000000000000000000001));
+        rec.sent_quantity = Some(dec!(1000));
+        let pair = rec.trade_pair().unwrap();
+        assert!(pair.price().is_err());
+        assert_eq!(pair.to_string(), "SHIB/BTC");
+    }
+
+    #[test]
+    fn test_trade_pair_errors() {
+        let mut rec = trade(T, "a");
+        rec.type_txs = TaxBitRecType::Buy;
+        assert_eq!(
+            rec.trade_pair(),
+            Err("Record with External ID a isn't a Trade".to_owned())
+        );
+
+        let mut rec = trade(T, "b");
+        rec.received_quantity = Some(dec!(0));
+        assert_eq!(
+            rec.trade_pair(),
+            Err("Trade with External ID b has a zero Received Quantity".to_owned())
+        );
+        rec.sent_quantity = None;
+        assert_eq!(
+            rec.trade_pair(),
+            Err("Trade with External ID b has no Sent Quantity".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_trades_to_pair_csv() {
+        let mut buy = trade(T, "buy");
+        buy.type_txs = TaxBitRecType::Buy;
+        let recs = vec![trade(T + 1000, "late"), buy, trade(T, "early")];
+        assert_eq!(
+            trades_to_pair_csv(&recs),
+            "Time,Pair,Base Quantity,Quote Quantity,Price,External ID\n\
+            2022-01-01T00:00:00.000Z,ETH/BTC,15.5,1.25,0.08064516,early\n\
+            2022-01-01T00:00:01.000Z,ETH/BTC,15.5,1.25,0.08064516,late\n"
+        );
+    }
+}
@@ -0,0 +1,475 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use taxbitrec::TaxBitRecType;
+
+use crate::TaxBitExportRec;
+
+/// Options for pair_transfers
+#[derive(Debug, Clone)]
+pub struct PairOpts {
+    /// The TransferIn must be no more than this many milliseconds after
+    /// the TransferOut
+    pub window_ms: i64,
+
+    /// The largest network fee, sent quantity minus received quantity,
+    /// as a fraction of the sent quantity
+    pub fee_tolerance: Decimal,
+}
+
+impl Default for PairOpts {
+    /// A window of 3 hours and a fee tolerance of 1%
+    fn default() -> Self {
+        PairOpts {
+            window_ms: 3 * 60 * 60 * 1000,
+            fee_tolerance: dec!(0.01),
+        }
+    }
+}
+
+/// A TransferOut matched with its TransferIn
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferPair {
+    /// Index of the TransferOut record
+    pub out_index: usize,
+
+    /// Index of the TransferIn record
+    pub in_index: usize,
+
+    /// The sent quantity minus the received quantity, attributed to the
+    /// network fee
+    pub network_fee: Decimal,
+}
+
+/// The result of pair_transfers, the indices are of the records passed
+/// to pair_transfers and each list is in ascending index order
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TransferPairing {
+    pub pairs: Vec<TransferPair>,
+    pub unmatched_outs: Vec<usize>,
+    pub unmatched_ins: Vec<usize>,
+}
+
+/// The (source, external_id) of a record
+pub type TransferId = (String, String);
+
+impl TransferPairing {
+    /// The IDs of the TransferOut and TransferIn of each pair, so the
+    /// pairs can be marked with mark_internal_transfers_by_id in records
+    /// which have since been reordered or read again
+    ///
+    /// # Panics
+    ///
+    /// If a pair has an index which is out of bounds for recs
+    pub fn pair_ids(&self, recs: &[TaxBitExportRec]) -> Vec<(TransferId, TransferId)> {
+        let id = |i: usize| (recs[i].source.clone(), recs[i].external_id.clone());
+        self.pairs
+            .iter()
+            .map(|p| (id(p.out_index), id(p.in_index)))
+            .collect()
+    }
+}
+
+/// Match TransferOut records with TransferIn records of the same asset.
+///
+/// A TransferIn matches a TransferOut if it's no more than opts.window_ms
+/// after it and its received quantity is between the sent quantity less
+/// opts.fee_tolerance and the sent quantity. The closest in time candidates
+/// are paired first, ties go to the lower indices, and a record is in at
+/// most one pair.
+pub fn pair_transfers(recs: &[TaxBitExportRec], opts: PairOpts) -> TransferPairing {
+    let outs: Vec<usize> = (0..recs.len())
+        .filter(|i| recs[*i].type_txs == TaxBitRecType::TransferOut)
+        .collect();
+    let ins: Vec<usize> = (0..recs.len())
+        .filter(|i| recs[*i].type_txs == TaxBitRecType::TransferIn)
+        .collect();
+
+    // (time delta, out index, in index, network fee) of every possible pair
+    let mut candidates: Vec<(i64, usize, usize, Decimal)> = vec![];
+    for &o in &outs {
+        let out = &recs[o];
+        let sent = match out.sent_quantity {
+            Some(q) => q,
+            None => continue,
+        };
+        for &i in &ins {
+            let inn = &recs[i];
+            let received = match inn.received_quantity {
+                Some(q) => q,
+                None => continue,
+            };
+            // A difference which overflows is outside any window
+            let Some(delta) = inn.time.checked_sub(out.time) else {
+                continue;
+            };
+            let fee = sent - received;
+            if inn.received_currency == out.sent_currency
+                && (0..=opts.window_ms).contains(&delta)
+                && fee >= Decimal::ZERO
+                && fee <= sent * opts.fee_tolerance
+            {
+                candidates.push((delta, o, i, fee));
+            }
+        }
+    }
+    candidates.sort_by_key(|(delta, o, i, _)| (*delta, *o, *i));
+
+    let mut paired: BTreeSet<usize> = BTreeSet::new();
+    let mut pairs: Vec<TransferPair> = vec![];
+    for (_, o, i, fee) in candidates {
+        if !paired.contains(&o) && !paired.contains(&i) {
+            paired.insert(o);
+            paired.insert(i);
+            pairs.push(TransferPair {
+                out_index: o,
+                in_index: i,
+                network_fee: fee,
+            });
+        }
+    }
+    pairs.sort_by_key(|p| p.out_index);
+
+    TransferPairing {
+        pairs,
+        unmatched_outs: outs.into_iter().filter(|o| !paired.contains(o)).collect(),
+        unmatched_ins: ins.into_iter().filter(|i| !paired.contains(i)).collect(),
+    }
+}
+
+/// Options for mark_internal_transfers_with_opts
+#[derive(Debug, Clone, Default)]
+pub struct MarkOpts {
+    /// Clear internal_transfer on all records before marking the pairs
+    pub reset_first: bool,
+}
+
+/// Set internal_transfer on both records of each pair, a flag that's
+/// already set is never cleared. Returns the number of records whose
+/// flag was set by this call, so re-marking returns 0.
+///
+/// # Panics
+///
+/// If pairing has an index which is out of bounds for recs
+pub fn mark_internal_transfers(recs: &mut [TaxBitExportRec], pairing: &TransferPairing) -> usize {
+    mark_internal_transfers_with_opts(recs, pairing, &MarkOpts::default())
+}
+
+/// Set internal_transfer on both records of each pair as
+/// mark_internal_transfers does, if opts.reset_first is set it's first
+/// cleared on all records.
+///
+/// # Panics
+///
+/// If pairing has an index which is out of bounds for recs
+pub fn mark_internal_transfers_with_opts(
+    recs: &mut [TaxBitExportRec],
+    pairing: &TransferPairing,
+    opts: &MarkOpts,
+) -> usize {
+    let indices: Vec<usize> = pairing
+        .pairs
+        .iter()
+        .flat_map(|p| [p.out_index, p.in_index])
+        .collect();
+    mark(recs, &indices, opts)
+}
+
+/// Set internal_transfer on both records of each pair, identified by
+/// their (source, external_id), as mark_internal_transfers_with_opts
+/// does, see TransferPairing::pair_ids.
+///
+/// It's an error, and the records are unchanged, if an ID isn't that of
+/// exactly one record.
+pub fn mark_internal_transfers_by_id(
+    recs: &mut [TaxBitExportRec],
+    pairs: &[(TransferId, TransferId)],
+    opts: &MarkOpts,
+) -> Result<usize, String> {
+    let mut by_id: BTreeMap<(&str, &str), Vec<usize>> = BTreeMap::new();
+    for (i, rec) in recs.iter().enumerate() {
+        by_id
+            .entry((&rec.source, &rec.external_id))
+            .or_default()
+            .push(i);
+    }
+
+    let mut indices = vec![];
+    for (source, external_id) in pairs.iter().flat_map(|(o, i)| [o, i]) {
+        match by_id
+            .get(&(source.as_str(), external_id.as_str()))
+            .map(|v| v.as_slice())
+        {
+            Some([i]) => indices.push(*i),
+            found => {
+                return Err(format!(
+                    "{} records have Source {source} and External ID {external_id}, expected 1",
+                    found.map_or(0, |v| v.len())
+                ))
+            }
+        }
+    }
+
+    Ok(mark(recs, &indices, opts))
+}
+
+// Set internal_transfer on the records at indices, first clearing it on
+// all of them if opts.reset_first is set, returning the number newly set
+fn mark(recs: &mut [TaxBitExportRec], indices: &[usize], opts: &MarkOpts) -> usize {
+    if opts.reset_first {
+        for rec in recs.iter_mut() {
+            rec.internal_transfer = false;
+        }
+    }
+
+    let mut count = 0;
+    for &i in indices {
+        if !recs[i].internal_transfer {
+            recs[i].internal_transfer = true;
+            count += 1;
+        }
+    }
+
+    count
+}
+
+/// Pair the transfers, see pair_transfers, and mark the pairs, see
+/// mark_internal_transfers, returning the pairing
+pub fn auto_mark_internal_transfers(
+    recs: &mut [TaxBitExportRec],
+    opts: PairOpts,
+) -> TransferPairing {
+    let pairing = pair_transfers(recs, opts);
+    mark_internal_transfers(recs, &pairing);
+
+    pairing
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // 2021-01-02T10:00:00Z
+    const T: i64 = 1609581600000;
+    const MINUTE: i64 = 60 * 1000;
+
+    fn out(time: i64, quantity: Decimal, asset: &str) -> TaxBitExportRec {
+        let mut rec = TaxBitExportRec::new();
+        rec.time = time;
+        rec.type_txs = TaxBitRecType::TransferOut;
+        rec.sent_quantity = Some(quantity);
+        rec.sent_currency = asset.to_owned();
+        rec
+    }
+
+    fn inn(time: i64, quantity: Decimal, asset: &str) -> TaxBitExportRec {
+        let mut rec = TaxBitExportRec::new();
+        rec.time = time;
+        rec.type_txs = TaxBitRecType::TransferIn;
+        rec.received_quantity = Some(quantity);
+        rec.received_currency = asset.to_owned();
+        rec
+    }
+
+    #[test]
+    fn test_pair_transfers_exact() {
+        let recs = vec![out(T, dec!(1), "BTC"), inn(T + 10 * MINUTE, dec!(1), "BTC")];
+        let pairing = pair_transfers(&recs, PairOpts::default());
+        assert_eq!(
+            pairing.pairs,
+            vec![TransferPair {
+                out_index: 0,
+                in_index: 1,
+                network_fee: dec!(0),
+            }]
+        );
+        assert!(pairing.unmatched_outs.is_empty());
+        assert!(pairing.unmatched_ins.is_empty());
+    }
+
+    #[test]
+    fn test_pair_transfers_fee_delta() {
+        let recs = vec![
+            inn(T + 10 * MINUTE, dec!(0.9995), "BTC"),
+            out(T, dec!(1), "BTC"),
+            // More than the 1% tolerance
+            out(T, dec!(2), "ETH"),
+            inn(T + 10 * MINUTE, dec!(1.9), "ETH"),
+        ];
+        let pairing = pair_transfers(&recs, PairOpts::default());
+        assert_eq!(
+            pairing.pairs,
+            vec![TransferPair {
+                out_index: 1,
+                in_index: 0,
+                network_fee: dec!(0.0005),
+            }]
+        );
+        assert_eq!(pairing.unmatched_outs, vec![2]);
+        assert_eq!(pairing.unmatched_ins, vec![3]);
+    }
+
+    #[test]
+    fn test_pair_transfers_nearest_wins() {
+        let recs = vec![
+            out(T, dec!(1), "BTC"),
+            inn(T + 90 * MINUTE, dec!(1), "BTC"),
+            inn(T + 20 * MINUTE, dec!(1), "BTC"),
+            // Before the out and after the window
+            inn(T - MINUTE, dec!(1), "BTC"),
+            inn(T + 4 * 60 * MINUTE, dec!(1), "BTC"),
+        ];
+        let pairing = pair_transfers(&recs, PairOpts::default());
+        assert_eq!(pairing.pairs.len(), 1);
+        assert_eq!(pairing.pairs[0].in_index, 2);
+        assert_eq!(pairing.unmatched_ins, vec![1, 3, 4]);
+    }
+
+    #[test]
+    fn test_pair_transfers_no_double_assignment() {
+        // Both outs are closest to the same in, the second out gets the other in
+        let recs = vec![
+            out(T, dec!(1), "BTC"),
+            out(T + 5 * MINUTE, dec!(1), "BTC"),
+            inn(T + 10 * MINUTE, dec!(1), "BTC"),
+            inn(T + 30 * MINUTE, dec!(1), "BTC"),
+        ];
+        let pairing = pair_transfers(&recs, PairOpts::default());
+        let pairs: Vec<(usize, usize)> = pairing
+            .pairs
+            .iter()
+            .map(|p| (p.out_index, p.in_index))
+            .collect();
+        assert_eq!(pairs, vec![(0, 3), (1, 2)]);
+    }
+
+    #[test]
+    fn test_pair_transfers_unmatched_withdrawal() {
+        let recs = vec![out(T, dec!(1), "BTC"), inn(T + MINUTE, dec!(1), "ETH")];
+        let pairing = pair_transfers(&recs, PairOpts::default());
+        assert!(pairing.pairs.is_empty());
+        assert_eq!(pairing.unmatched_outs, vec![0]);
+        assert_eq!(pairing.unmatched_ins, vec![1]);
+    }
+
+    #[test]
+    fn test_pair_transfers_extreme_times() {
+        let recs = vec![
+            out(i64::MIN, dec!(1), "BTC"),
+            inn(i64::MAX, dec!(1), "BTC"),
+            out(i64::MAX - MINUTE, dec!(1), "BTC"),
+        ];
+        let opts = PairOpts {
+            window_ms: i64::MAX,
+            ..PairOpts::default()
+        };
+        let pairing = pair_transfers(&recs, opts);
+        assert_eq!(
+            pairing
+                .pairs
+                .iter()
+                .map(|p| (p.out_index, p.in_index))
+                .collect::<Vec<_>>(),
+            vec![(2, 1)]
+        );
+        assert_eq!(pairing.unmatched_outs, vec![0]);
+    }
+
+    fn flagged(recs: &[TaxBitExportRec]) -> Vec<usize> {
+        (0..recs.len())
+            .filter(|i| recs[*i].internal_transfer)
+            .collect()
+    }
+
+    #[test]
+    fn test_mark_internal_transfers() {
+        let mut recs = vec![
+            out(T, dec!(1), "BTC"),
+            out(T, dec!(1), "ETH"),
+            inn(T + MINUTE, dec!(1), "BTC"),
+            inn(T + MINUTE, dec!(5), "ADA"),
+        ];
+        recs[3].internal_transfer = true;
+        let pairing = pair_transfers(&recs, PairOpts::default());
+
+        assert_eq!(mark_internal_transfers(&mut recs, &pairing), 2);
+        assert_eq!(flagged(&recs), vec![0, 2, 3]);
+
+        // Idempotent
+        assert_eq!(mark_internal_transfers(&mut recs, &pairing), 0);
+        assert_eq!(flagged(&recs), vec![0, 2, 3]);
+
+        let opts = MarkOpts { reset_first: true };
+        assert_eq!(
+            mark_internal_transfers_with_opts(&mut recs, &pairing, &opts),
+            2
+        );
+        assert_eq!(flagged(&recs), vec![0, 2]);
+    }
+
+    #[test]
+    fn test_mark_internal_transfers_by_id() {
+        let mut recs = vec![
+            out(T, dec!(1), "BTC"),
+            out(T, dec!(1), "ETH"),
+            inn(T + MINUTE, dec!(1), "BTC"),
+            inn(T + MINUTE, dec!(1), "ETH"),
+        ];
+        for (i, rec) in recs.iter_mut().enumerate() {
+            rec.source = "Coinbase".to_owned();
+            rec.external_id = format!("id-{i}");
+        }
+        let pairing = pair_transfers(&recs, PairOpts::default());
+        let ids = pairing.pair_ids(&recs);
+        assert_eq!(
+            ids[0],
+            (
+                ("Coinbase".to_owned(), "id-0".to_owned()),
+                ("Coinbase".to_owned(), "id-2".to_owned())
+            )
+        );
+
+        // The records are marked after being reordered
+        recs.reverse();
+        let opts = MarkOpts::default();
+        assert_eq!(
+            mark_internal_transfers_by_id(&mut recs, &ids[..1], &opts),
+            Ok(2)
+        );
+        assert_eq!(flagged(&recs), vec![1, 3]);
+        assert_eq!(mark_internal_transfers_by_id(&mut recs, &ids, &opts), Ok(2));
+        assert_eq!(flagged(&recs), vec![0, 1, 2, 3]);
+        assert_eq!(mark_internal_transfers_by_id(&mut recs, &ids, &opts), Ok(0));
+
+        // A missing or shared ID changes nothing
+        let opts = MarkOpts { reset_first: true };
+        recs[0].external_id = "id-0".to_owned();
+        let before = recs.clone();
+        let e = mark_internal_transfers_by_id(&mut recs, &ids, &opts).unwrap_err();
+        assert_eq!(
+            e,
+            "2 records have Source Coinbase and External ID id-0, expected 1"
+        );
+        recs[0].external_id = "other".to_owned();
+        let e = mark_internal_transfers_by_id(&mut recs, &ids, &opts).unwrap_err();
+        assert!(e.starts_with("0 records have"), "{e}");
+        assert_eq!(recs[1..], before[1..]);
+    }
+
+    #[test]
+    fn test_auto_mark_internal_transfers() {
+        let mut recs = vec![
+            inn(T + MINUTE, dec!(0.999), "BTC"),
+            out(T, dec!(1), "ETH"),
+            out(T, dec!(1), "BTC"),
+        ];
+        let pairing = auto_mark_internal_transfers(&mut recs, PairOpts::default());
+        assert_eq!(pairing.unmatched_outs, vec![1]);
+        assert_eq!(flagged(&recs), vec![0, 2]);
+
+        let before = recs.clone();
+        auto_mark_internal_transfers(&mut recs, PairOpts::default());
+        assert_eq!(recs, before);
+    }
+}
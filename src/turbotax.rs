@@ -0,0 +1,229 @@
+//! Write TaxBit export records in the TurboTax crypto import CSV format
+//!
+//! | TaxBitRecType | TurboTax Type |
+//! |---------------|---------------|
+//! | Buy           | Buy           |
+//! | Sale          | Sale          |
+//! | Trade         | Convert       |
+//! | TransferIn    | Deposit       |
+//! | TransferOut   | Withdrawal    |
+//! | Income        | Income        |
+//! | Expense       | Expense       |
+//!
+//! Other types have no TurboTax equivalent. Dates are written in UTC as
+//! MM/DD/YYYY HH:MM:SS, source is written to Description and external_id
+//! to Transaction ID. There is no transaction hash so it's always empty.
+use std::{error::Error, io::Write};
+
+use dec_utils::dec_to_string_or_empty;
+use taxbitrec::TaxBitRecType;
+
+use crate::TaxBitExportRec;
+
+/// Column names of the TurboTax crypto import format
+pub const TURBOTAX_HEADER: [&str; 12] = [
+    "Date",
+    "Type",
+    "Sent Asset",
+    "Sent Amount",
+    "Received Asset",
+    "Received Amount",
+    "Fee Asset",
+    "Fee Amount",
+    "Market Value",
+    "Description",
+    "Transaction Hash",
+    "Transaction ID",
+];
+
+fn turbotax_type(rec: &TaxBitExportRec) -> Result<&'static str, String> {
+    Ok(match rec.type_txs {
+        TaxBitRecType::Buy => "Buy",
+        TaxBitRecType::Sale => "Sale",
+        TaxBitRecType::Trade => "Convert",
+        TaxBitRecType::TransferIn => "Deposit",
+        TaxBitRecType::TransferOut => "Withdrawal",
+        TaxBitRecType::Income => "Income",
+        TaxBitRecType::Expense => "Expense",
+        _ => {
+            return Err(format!(
+                "Record with External ID {} at {} has type {:?} which has no TurboTax equivalent",
+                rec.external_id,
+                rec.time_utc().format("%Y-%m-%dT%H:%M:%S%.3fZ"),
+                rec.type_txs
+            ))
+        }
+    })
+}
+
+fn rec_to_turbotax_fields(rec: &TaxBitExportRec) -> Result<Vec<String>, String> {
+    Ok(vec![
+        rec.time_utc().format("%m/%d/%Y %H:%M:%S").to_string(),
+        turbotax_type(rec)?.to_owned(),
+        rec.sent_currency.clone(),
+        dec_to_string_or_empty(rec.sent_quantity),
+        rec.received_currency.clone(),
+        dec_to_string_or_empty(rec.received_quantity),
+        rec.fee_currency.clone(),
+        dec_to_string_or_empty(rec.fee_amount),
+        dec_to_string_or_empty(rec.market_value),
+        rec.source.clone(),
+        "".to_owned(),
+        rec.external_id.clone(),
+    ])
+}
+
+/// Write the records in the TurboTax crypto import format. All records
+/// are converted before anything is written so an error, for a type with
+/// no TurboTax equivalent, leaves w untouched.
+pub fn write_turbotax_csv<W: Write>(w: W, recs: &[TaxBitExportRec]) -> Result<(), Box<dyn Error>> {
+    let rows = recs
+        .iter()
+        .map(rec_to_turbotax_fields)
+        .collect::<Result<Vec<Vec<String>>, String>>()?;
+
+    let mut writer = csv::Writer::from_writer(w);
+    writer.write_record(TURBOTAX_HEADER)?;
+    for row in rows {
+        writer.write_record(&row)?;
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    fn rec(
+        time: i64,
+        type_txs: TaxBitRecType,
+        sent: (Option<Decimal>, &str),
+        received: (Option<Decimal>, &str),
+        id: &str,
+    ) -> TaxBitExportRec {
+        let mut rec = TaxBitExportRec::new();
+        rec.time = time;
+        rec.type_txs = type_txs;
+        rec.sent_quantity = sent.0;
+        rec.sent_currency = sent.1.to_owned();
+        rec.received_quantity = received.0;
+        rec.received_currency = received.1.to_owned();
+        rec.source = "BinanceUS".to_owned();
+        rec.external_id = id.to_owned();
+        rec
+    }
+
+    #[test]
+    fn test_write_turbotax_csv() {
+        // 2021-01-02T10:00:00Z
+        let t = 1609581600000;
+        let mut recs = vec![
+            rec(
+                t,
+                TaxBitRecType::Buy,
+                (Some(dec!(990)), "USD"),
+                (Some(dec!(0.03)), "BTC"),
+                "id-buy",
+            ),
+            rec(
+                t + 1000,
+                TaxBitRecType::Sale,
+                (Some(dec!(0.01)), "BTC"),
+                (Some(dec!(350)), "USD"),
+                "id-sale",
+            ),
+            rec(
+                t + 2000,
+                TaxBitRecType::Trade,
+                (Some(dec!(0.01)), "BTC"),
+                (Some(dec!(0.3)), "ETH"),
+                "id-trade",
+            ),
+            rec(
+                t + 3000,
+                TaxBitRecType::TransferIn,
+                (None, ""),
+                (Some(dec!(0.5)), "ETH"),
+                "id-in",
+            ),
+            rec(
+                t + 4000,
+                TaxBitRecType::TransferOut,
+                (Some(dec!(0.5)), "ETH"),
+                (None, ""),
+                "id-out",
+            ),
+            rec(
+                t + 5000,
+                TaxBitRecType::Income,
+                (None, ""),
+                (Some(dec!(1.25)), "ADA"),
+                "id-income",
+            ),
+            rec(
+                t + 6000,
+                TaxBitRecType::Expense,
+                (Some(dec!(0.001)), "ETH"),
+                (None, ""),
+                "id-expense",
+            ),
+        ];
+        recs[0].fee_amount = Some(dec!(1.5));
+        recs[0].fee_currency = "USD".to_owned();
+        recs[0].market_value = Some(dec!(990));
+        recs[5].market_value = Some(dec!(0.5));
+
+        let mut out: Vec<u8> = vec![];
+        write_turbotax_csv(&mut out, &recs).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            r#"Date,Type,Sent Asset,Sent Amount,Received Asset,Received Amount,Fee Asset,Fee Amount,Market Value,Description,Transaction Hash,Transaction ID
+01/02/2021 10:00:00,Buy,USD,990,BTC,0.03,USD,1.5,990,BinanceUS,,id-buy
+01/02/2021 10:00:01,Sale,BTC,0.01,USD,350,,,,BinanceUS,,id-sale
+01/02/2021 10:00:02,Convert,BTC,0.01,ETH,0.3,,,,BinanceUS,,id-trade
+01/02/2021 10:00:03,Deposit,,,ETH,0.5,,,,BinanceUS,,id-in
+01/02/2021 10:00:04,Withdrawal,ETH,0.5,,,,,,BinanceUS,,id-out
+01/02/2021 10:00:05,Income,,,ADA,1.25,,,0.5,BinanceUS,,id-income
+01/02/2021 10:00:06,Expense,ETH,0.001,,,,,,BinanceUS,,id-expense
+"#
+        );
+    }
+
+    #[test]
+    fn test_write_turbotax_csv_no_equivalent() {
+        for type_txs in [
+            TaxBitRecType::GiftSent,
+            TaxBitRecType::GiftReceived,
+            TaxBitRecType::Invalid,
+            TaxBitRecType::Unknown,
+        ] {
+            let recs = vec![
+                rec(
+                    0,
+                    TaxBitRecType::Income,
+                    (None, ""),
+                    (Some(dec!(1)), "BTC"),
+                    "ok",
+                ),
+                rec(
+                    1609581600000,
+                    type_txs,
+                    (None, ""),
+                    (Some(dec!(1)), "BTC"),
+                    "id-1",
+                ),
+            ];
+            let mut out: Vec<u8> = vec![];
+            let err = write_turbotax_csv(&mut out, &recs).unwrap_err();
+            assert!(err
+                .to_string()
+                .starts_with("Record with External ID id-1 at 2021-01-02T10:00:00.000Z"));
+            assert!(out.is_empty());
+        }
+    }
+}
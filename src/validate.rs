@@ -0,0 +1,370 @@
+use std::fmt::Display;
+
+use rust_decimal::Decimal;
+use taxbitrec::TaxBitRecType;
+
+use crate::{RowError, TaxBitExportRec, TaxBitExportRecReader};
+
+/// A reason a TaxBitExportRec is not valid
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationError {
+    /// type_txs is Unknown or Invalid
+    InvalidType,
+
+    /// The type requires a received quantity and currency, or only one of them is present
+    MissingReceivedSide,
+
+    /// The type requires a sent quantity and currency, or only one of them is present
+    MissingSentSide,
+
+    /// The type must not have a received quantity or currency
+    UnexpectedReceivedSide,
+
+    /// The type must not have a sent quantity or currency
+    UnexpectedSentSide,
+
+    /// A quantity is negative, the column name is included
+    NegativeQuantity(&'static str),
+
+    NegativeMarketValue,
+
+    /// Only one of fee amount and fee currency is present
+    IncompleteFee,
+}
+
+impl Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValidationError::InvalidType => write!(f, "Transaction Type is Unknown or Invalid"),
+            ValidationError::MissingReceivedSide => {
+                write!(f, "Received Quantity and Received Currency are required")
+            }
+            ValidationError::MissingSentSide => {
+                write!(f, "Sent Quantity and Sent Currency are required")
+            }
+            ValidationError::UnexpectedReceivedSide => {
+                write!(f, "Received Quantity and Received Currency must be empty")
+            }
+            ValidationError::UnexpectedSentSide => {
+                write!(f, "Sent Quantity and Sent Currency must be empty")
+            }
+            ValidationError::NegativeQuantity(column) => write!(f, "{column} is negative"),
+            ValidationError::NegativeMarketValue => write!(f, "Market Value is negative"),
+            ValidationError::IncompleteFee => {
+                write!(
+                    f,
+                    "Fee Amount and Fee Currency must both be present or empty"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+// Whether a side is (required, allowed) for a type
+fn sides(type_txs: &TaxBitRecType) -> ((bool, bool), (bool, bool)) {
+    match type_txs {
+        TaxBitRecType::Buy => ((true, true), (false, true)),
+        TaxBitRecType::Sale => ((false, true), (true, true)),
+        TaxBitRecType::Trade => ((true, true), (true, true)),
+        TaxBitRecType::TransferIn | TaxBitRecType::Income | TaxBitRecType::GiftReceived => {
+            ((true, true), (false, false))
+        }
+        TaxBitRecType::TransferOut | TaxBitRecType::Expense | TaxBitRecType::GiftSent => {
+            ((false, false), (true, true))
+        }
+        TaxBitRecType::Invalid | TaxBitRecType::Unknown => ((false, true), (false, true)),
+    }
+}
+
+impl TaxBitExportRec {
+    /// Validate the record returning all of the reasons it isn't valid.
+    ///
+    /// The received and sent sides must be consistent with the type,
+    /// each side and the fee must have both a quantity and a currency
+    /// or neither, and quantities, including the fee amount, and market
+    /// value must not be negative, see convert_rebates for negative fees.
+    pub fn validate(&self) -> Result<(), Vec<ValidationError>> {
+        let mut errors = vec![];
+
+        if matches!(
+            self.type_txs,
+            TaxBitRecType::Unknown | TaxBitRecType::Invalid
+        ) {
+            errors.push(ValidationError::InvalidType);
+        }
+
+        let ((received_required, received_allowed), (sent_required, sent_allowed)) =
+            sides(&self.type_txs);
+
+        let received_present =
+            self.received_quantity.is_some() || !self.received_currency.is_empty();
+        let received_complete =
+            self.received_quantity.is_some() && !self.received_currency.is_empty();
+        if received_present && !received_allowed {
+            errors.push(ValidationError::UnexpectedReceivedSide);
+        } else if (received_required || received_present) && !received_complete {
+            errors.push(ValidationError::MissingReceivedSide);
+        }
+
+        let sent_present = self.sent_quantity.is_some() || !self.sent_currency.is_empty();
+        let sent_complete = self.sent_quantity.is_some() && !self.sent_currency.is_empty();
+        if sent_present && !sent_allowed {
+            errors.push(ValidationError::UnexpectedSentSide);
+        } else if (sent_required || sent_present) && !sent_complete {
+            errors.push(ValidationError::MissingSentSide);
+        }
+
+        if self.received_quantity.is_some_and(|q| q < Decimal::ZERO) {
+            errors.push(ValidationError::NegativeQuantity("Received Quantity"));
+        }
+        if self.sent_quantity.is_some_and(|q| q < Decimal::ZERO) {
+            errors.push(ValidationError::NegativeQuantity("Sent Quantity"));
+        }
+        if self.fee_amount.is_some_and(|q| q < Decimal::ZERO) {
+            errors.push(ValidationError::NegativeQuantity("Fee Amount"));
+        }
+        if self.market_value.is_some_and(|v| v < Decimal::ZERO) {
+            errors.push(ValidationError::NegativeMarketValue);
+        }
+
+        if self.fee_amount.is_some() == self.fee_currency.is_empty() {
+            errors.push(ValidationError::IncompleteFee);
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+/// A record which parsed but isn't valid, see TaxBitExportRec::validate
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidRow {
+    /// The 1-based number of the data row, as RowError::row
+    pub row: usize,
+
+    pub errors: Vec<ValidationError>,
+}
+
+/// The result of validate_csv_bytes
+#[derive(Debug, Default)]
+pub struct ValidationReport {
+    /// The number of data rows read
+    pub rows: usize,
+
+    /// The rows which couldn't be parsed
+    pub row_errors: Vec<RowError>,
+
+    /// The rows which parsed but aren't valid
+    pub invalid: Vec<InvalidRow>,
+
+    /// An error which stopped reading, such as a missing or unknown
+    /// header, the rows before it are reported
+    pub error: Option<String>,
+}
+
+impl ValidationReport {
+    /// True if every row parsed and is valid
+    pub fn is_valid(&self) -> bool {
+        self.row_errors.is_empty() && self.invalid.is_empty() && self.error.is_none()
+    }
+}
+
+/// Parse and validate a TaxBit export CSV in memory, reporting every
+/// row which doesn't parse or isn't valid rather than stopping at the
+/// first. Doesn't need the std-fs feature or panic on bad input, so it
+/// can check a file in a browser before it's uploaded.
+pub fn validate_csv_bytes(bytes: &[u8]) -> ValidationReport {
+    let mut report = ValidationReport::default();
+    let reader = match TaxBitExportRecReader::new(bytes) {
+        Ok(reader) => reader,
+        Err(e) => {
+            report.error = Some(e.to_string());
+            return report;
+        }
+    };
+
+    for (i, entry) in reader.enumerate() {
+        let row = i + 1;
+        match entry {
+            Ok(rec) => {
+                if let Err(errors) = rec.validate() {
+                    report.invalid.push(InvalidRow { row, errors });
+                }
+            }
+            Err(e) => match e.downcast::<RowError>() {
+                Ok(row_error) => report.row_errors.push(*row_error),
+                Err(e) => {
+                    report.error = Some(e.to_string());
+                    break;
+                }
+            },
+        }
+        report.rows = row;
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    fn rec(type_txs: TaxBitRecType, received: Option<&str>, sent: Option<&str>) -> TaxBitExportRec {
+        let mut r = TaxBitExportRec::new();
+        r.type_txs = type_txs;
+        if let Some(c) = received {
+            r.received_quantity = Some(dec!(1));
+            r.received_currency = c.to_owned();
+        }
+        if let Some(c) = sent {
+            r.sent_quantity = Some(dec!(2));
+            r.sent_currency = c.to_owned();
+        }
+        r
+    }
+
+    #[test]
+    fn test_valid() {
+        assert_eq!(
+            rec(TaxBitRecType::Buy, Some("BTC"), Some("USD")).validate(),
+            Ok(())
+        );
+        assert_eq!(
+            rec(TaxBitRecType::Buy, Some("BTC"), None).validate(),
+            Ok(())
+        );
+        assert_eq!(
+            rec(TaxBitRecType::Sale, Some("USD"), Some("BTC")).validate(),
+            Ok(())
+        );
+        assert_eq!(
+            rec(TaxBitRecType::Trade, Some("ETH"), Some("BTC")).validate(),
+            Ok(())
+        );
+        for t in [
+            TaxBitRecType::TransferIn,
+            TaxBitRecType::Income,
+            TaxBitRecType::GiftReceived,
+        ] {
+            assert_eq!(rec(t, Some("BTC"), None).validate(), Ok(()));
+        }
+        for t in [
+            TaxBitRecType::TransferOut,
+            TaxBitRecType::Expense,
+            TaxBitRecType::GiftSent,
+        ] {
+            assert_eq!(rec(t, None, Some("BTC")).validate(), Ok(()));
+        }
+
+        let mut r = rec(TaxBitRecType::Income, Some("BTC"), None);
+        r.fee_amount = Some(dec!(0.1));
+        r.fee_currency = "BNB".to_owned();
+        r.market_value = Some(dec!(0));
+        assert_eq!(r.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert_eq!(
+            rec(TaxBitRecType::Unknown, Some("BTC"), None).validate(),
+            Err(vec![ValidationError::InvalidType])
+        );
+        assert_eq!(
+            rec(TaxBitRecType::Trade, Some("ETH"), None).validate(),
+            Err(vec![ValidationError::MissingSentSide])
+        );
+        assert_eq!(
+            rec(TaxBitRecType::Income, Some("ETH"), Some("USD")).validate(),
+            Err(vec![ValidationError::UnexpectedSentSide])
+        );
+        assert_eq!(
+            rec(TaxBitRecType::Expense, Some("ETH"), None).validate(),
+            Err(vec![
+                ValidationError::UnexpectedReceivedSide,
+                ValidationError::MissingSentSide
+            ])
+        );
+
+        let mut r = rec(TaxBitRecType::Buy, Some("BTC"), None);
+        r.received_currency = "".to_owned();
+        r.sent_currency = "USD".to_owned();
+        assert_eq!(
+            r.validate(),
+            Err(vec![
+                ValidationError::MissingReceivedSide,
+                ValidationError::MissingSentSide
+            ])
+        );
+
+        let mut r = rec(TaxBitRecType::Trade, Some("ETH"), Some("BTC"));
+        r.received_quantity = Some(dec!(-1));
+        r.sent_quantity = Some(dec!(-1));
+        r.market_value = Some(dec!(-1));
+        r.fee_amount = Some(dec!(1));
+        let errors = r.validate().unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                ValidationError::NegativeQuantity("Received Quantity"),
+                ValidationError::NegativeQuantity("Sent Quantity"),
+                ValidationError::NegativeMarketValue,
+                ValidationError::IncompleteFee,
+            ]
+        );
+        assert_eq!(errors[0].to_string(), "Received Quantity is negative");
+    }
+
+    const HEADER: &str = "Date,Transaction Type,Received Quantity,Received Currency,Sent Quantity,Sent Currency,Fee Currency,Fee Amount,Market Value,Source,Internal Transfer,External ID\n";
+
+    #[test]
+    fn test_validate_csv_bytes() {
+        let csv = format!(
+            "{HEADER}\
+2022-01-01T00:00:00.000Z,Income,1,BTC,,,,,40000,Kraken,FALSE,a
+2022-01-02T00:00:00.000Z,Income,1,BTC,2,ETH,,,40000,Kraken,FALSE,b
+20x2-01-03T00:00:00.000Z,Income,1,BTC,,,,,40000,Kraken,FALSE,c
+2022-01-04T00:00:00.000Z,Sale,,,1,BTC,BTC,,40000,Kraken,FALSE,d
+"
+        );
+        let report = validate_csv_bytes(csv.as_bytes());
+        assert!(!report.is_valid());
+        assert_eq!(report.rows, 4);
+        assert_eq!(report.error, None);
+        assert_eq!(report.row_errors.len(), 1);
+        assert_eq!(report.row_errors[0].row, 3);
+        assert_eq!(
+            report.invalid,
+            vec![
+                InvalidRow {
+                    row: 2,
+                    errors: vec![ValidationError::UnexpectedSentSide],
+                },
+                InvalidRow {
+                    row: 4,
+                    errors: vec![ValidationError::IncompleteFee],
+                },
+            ]
+        );
+
+        let report = validate_csv_bytes(HEADER.as_bytes());
+        assert!(report.is_valid());
+        assert_eq!(report.rows, 0);
+    }
+
+    #[test]
+    fn test_validate_csv_bytes_bad_header() {
+        for csv in ["", "Date,Type\n2022-01-01T00:00:00.000Z,Income\n"] {
+            let report = validate_csv_bytes(csv.as_bytes());
+            assert!(!report.is_valid());
+            assert_eq!(report.rows, 0);
+            assert!(report.error.is_some(), "{csv:?}");
+        }
+    }
+}
@@ -0,0 +1,1331 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    error::Error,
+    fmt::Display,
+    io::{self, Write},
+};
+#[cfg(feature = "std-fs")]
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use rust_decimal::Decimal;
+use serde_utc_time_ms::se_time_ms_to_utc_z_string;
+
+use crate::{
+    find_duplicate_ids, format_decimal, se_bool_to_uppercase_string_true_false, DecimalFormat,
+    Progress, ProgressEvent, RawLine, TaxBitExportRec, ValidationError, TB_EXPORT_REC_HEADER,
+    TB_EXPORT_REC_LOT_ID_COLUMN,
+};
+
+/// The precision of the Date column when writing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TimestampPrecision {
+    /// Always write milliseconds, "2022-03-01T14:35:06.000Z"
+    #[default]
+    Milliseconds,
+
+    /// Never write milliseconds, "2022-03-01T14:35:06Z", they are truncated
+    Seconds,
+
+    /// Write milliseconds only when they aren't zero
+    Auto,
+}
+
+/// How TaxBitExportRecWriter::open opens a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OpenMode {
+    /// Create the file, truncating it if it exists
+    #[default]
+    Create,
+
+    /// Append to the file, creating it if it doesn't exist. If it has a
+    /// header it must be the header the writer would write, otherwise
+    /// the header is written.
+    Append,
+}
+
+/// Options used when writing TaxBit export records
+#[derive(Debug, Clone)]
+pub struct WriterConfig {
+    /// The field delimiter, b',' by default, b';' and b'\t' are also
+    /// common
+    pub delimiter: u8,
+
+    pub timestamp_precision: TimestampPrecision,
+
+    /// Refuse to write records sharing a (source, external_id), see
+    /// find_duplicate_ids
+    pub reject_duplicate_ids: bool,
+
+    /// Write absent quantities, fee amounts and market values as zero,
+    /// "0" with the default formats, rather than empty. They're read
+    /// back as Some(0) unless ReaderConfig::zero_decimal_as_none is set.
+    pub none_decimal_as_zero: bool,
+
+    /// Format of the received, sent and fee quantities
+    pub quantity_format: DecimalFormat,
+
+    /// Format of the market value
+    pub market_value_format: DecimalFormat,
+
+    /// Validate every record, see TaxBitExportRec::validate, and check
+    /// for duplicate (source, external_id)s before writing anything,
+    /// returning a WriteRejected with every violation. Files are written
+    /// to a temporary file which replaces the target only once all the
+    /// records are written. TaxBitExportRecWriter checks each record
+    /// before writing it.
+    pub strict: bool,
+}
+
+/// A reason a record was rejected when writing with WriterConfig::strict
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WriteViolation {
+    /// The record at index isn't valid
+    Invalid {
+        index: usize,
+        errors: Vec<ValidationError>,
+    },
+
+    /// The record at index has the (source, external_id) of the earlier
+    /// record at first. TaxBitExportRecWriter counts the indices from
+    /// the first record it wrote.
+    DuplicateId {
+        index: usize,
+        first: usize,
+        source: String,
+        external_id: String,
+    },
+}
+
+impl WriteViolation {
+    /// The index of the record rejected
+    pub fn index(&self) -> usize {
+        match self {
+            WriteViolation::Invalid { index, .. } | WriteViolation::DuplicateId { index, .. } => {
+                *index
+            }
+        }
+    }
+}
+
+impl Display for WriteViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WriteViolation::Invalid { index, errors } => {
+                let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+                write!(f, "record {index}: {}", errors.join(", "))
+            }
+            WriteViolation::DuplicateId {
+                index,
+                first,
+                source,
+                external_id,
+            } => write!(
+                f,
+                "record {index}: Duplicate External ID {external_id} for Source {source} of record {first}"
+            ),
+        }
+    }
+}
+
+/// The records weren't written as some violate WriterConfig::strict,
+/// the violations are ordered by record index
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WriteRejected {
+    pub violations: Vec<WriteViolation>,
+}
+
+impl Display for WriteRejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let violations: Vec<String> = self.violations.iter().map(|v| v.to_string()).collect();
+        write!(f, "Write rejected: {}", violations.join("; "))
+    }
+}
+
+impl Error for WriteRejected {}
+
+impl Default for WriterConfig {
+    fn default() -> Self {
+        WriterConfig {
+            delimiter: b',',
+            timestamp_precision: TimestampPrecision::default(),
+            reject_duplicate_ids: false,
+            none_decimal_as_zero: false,
+            quantity_format: DecimalFormat::default(),
+            market_value_format: DecimalFormat::default(),
+            strict: false,
+        }
+    }
+}
+
+/// Streaming writer of TaxBit export records using the current layout.
+///
+/// The header is written before the first record or when flushed. The
+/// TB_EXPORT_REC_LOT_ID_COLUMN, enabled with `with_lot_id_column`, and
+/// extra columns, see TaxBitExportRec::extras, declared with
+/// `with_extra_columns`, must be set up front and are written after the
+/// TaxBit columns. Use `with_write_header(false)` to write only records,
+/// for instance the second part of a multi-part output.
+pub struct TaxBitExportRecWriter<W: Write, C: ?Sized = dyn FnMut(ProgressEvent) + Send> {
+    // wtr until the csv writer is created, when the config is known, or
+    // while raw lines are written
+    wtr: Option<CountingWriter<W>>,
+    writer: Option<csv::Writer<CountingWriter<W>>>,
+    config: WriterConfig,
+    lot_id_column: bool,
+    extra_columns: Vec<String>,
+    header_written: bool,
+    // The header line of a file opened with OpenMode::Append, checked
+    // instead of writing the header
+    existing_header: Option<String>,
+    // The appended file doesn't end with a line terminator, one is
+    // written once the header is checked
+    partial_last_line: bool,
+    // The (source, external_id)s written, when strict or rejecting
+    // duplicate IDs, and the index of the record with each
+    ids_written: BTreeMap<(String, String), usize>,
+    records_written: u64,
+    progress: Option<Progress<C>>,
+}
+
+// Counts the bytes the csv writer writes to W
+struct CountingWriter<W: Write> {
+    wtr: W,
+    bytes: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.wtr.write(buf)?;
+        self.bytes += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.wtr.flush()
+    }
+}
+
+impl<W: Write> TaxBitExportRecWriter<W> {
+    pub fn new(wtr: W) -> TaxBitExportRecWriter<W> {
+        TaxBitExportRecWriter {
+            wtr: Some(CountingWriter { wtr, bytes: 0 }),
+            writer: None,
+            config: WriterConfig::default(),
+            lot_id_column: false,
+            extra_columns: vec![],
+            header_written: false,
+            existing_header: None,
+            partial_last_line: false,
+            ids_written: BTreeMap::new(),
+            records_written: 0,
+            progress: None,
+        }
+    }
+}
+
+impl<W: Write, C: ?Sized + FnMut(ProgressEvent)> TaxBitExportRecWriter<W, C> {
+    pub fn with_config(mut self, config: WriterConfig) -> TaxBitExportRecWriter<W, C> {
+        self.config = config;
+        self
+    }
+
+    /// Write the lot ID column after the TaxBit columns
+    pub fn with_lot_id_column(mut self, enabled: bool) -> TaxBitExportRecWriter<W, C> {
+        self.lot_id_column = enabled;
+        self
+    }
+
+    /// Set the extra columns written after the TaxBit and lot ID columns
+    pub fn with_extra_columns(mut self, columns: &[String]) -> TaxBitExportRecWriter<W, C> {
+        self.extra_columns = columns.to_vec();
+        self
+    }
+
+    /// Write the header, the default, or write only the records
+    pub fn with_write_header(mut self, enabled: bool) -> TaxBitExportRecWriter<W, C> {
+        self.header_written = !enabled;
+        self
+    }
+
+    /// Report the records written and the bytes written to the
+    /// underlying writer to progress, the bytes lag as the output is
+    /// buffered. The last event is sent by flush or into_inner.
+    pub fn with_progress<D: ?Sized + FnMut(ProgressEvent)>(
+        self,
+        progress: Progress<D>,
+    ) -> TaxBitExportRecWriter<W, D> {
+        TaxBitExportRecWriter {
+            wtr: self.wtr,
+            writer: self.writer,
+            config: self.config,
+            lot_id_column: self.lot_id_column,
+            extra_columns: self.extra_columns,
+            header_written: self.header_written,
+            existing_header: self.existing_header,
+            partial_last_line: self.partial_last_line,
+            ids_written: self.ids_written,
+            records_written: self.records_written,
+            progress: Some(progress),
+        }
+    }
+
+    fn csv_writer(&mut self) -> &mut csv::Writer<CountingWriter<W>> {
+        if let Some(wtr) = self.wtr.take() {
+            self.writer = Some(
+                csv::WriterBuilder::new()
+                    .has_headers(false)
+                    .delimiter(self.config.delimiter)
+                    .from_writer(wtr),
+            );
+        }
+        self.writer.as_mut().unwrap()
+    }
+
+    fn write_header(&mut self) -> Result<(), Box<dyn Error>> {
+        if !self.header_written {
+            let mut header: Vec<String> =
+                TB_EXPORT_REC_HEADER.iter().map(|c| c.to_string()).collect();
+            if self.lot_id_column {
+                header.push(TB_EXPORT_REC_LOT_ID_COLUMN.to_owned());
+            }
+            header.extend(self.extra_columns.iter().cloned());
+            match self.existing_header.take() {
+                Some(line) => {
+                    let existing = csv::ReaderBuilder::new()
+                        .has_headers(false)
+                        .delimiter(self.config.delimiter)
+                        .from_reader(line.as_bytes())
+                        .records()
+                        .next()
+                        .transpose()?
+                        .unwrap_or_default();
+                    if existing.iter().ne(header.iter()) {
+                        return Err(format!(
+                            "Can't append, the existing header \"{line}\" isn't \"{}\"",
+                            header.join(&(self.config.delimiter as char).to_string())
+                        )
+                        .into());
+                    }
+                }
+                None => self.csv_writer().write_record(&header)?,
+            }
+            self.header_written = true;
+        }
+        if self.partial_last_line {
+            self.partial_last_line = false;
+            self.write_verbatim("\n")?;
+        }
+
+        Ok(())
+    }
+
+    pub fn write_rec(&mut self, rec: &TaxBitExportRec) -> Result<(), Box<dyn Error>> {
+        self.write_header()?;
+
+        if let Some(name) = rec.extras.keys().find(|k| !self.extra_columns.contains(k)) {
+            return Err(format!(
+                "Record with External ID {} has extra column {name} not in the header",
+                rec.external_id
+            )
+            .into());
+        }
+
+        if rec.lot_id.is_some() && !self.lot_id_column {
+            return Err(format!(
+                "Record with External ID {} has a lot ID but the lot ID column isn't enabled",
+                rec.external_id
+            )
+            .into());
+        }
+
+        let index = self.records_written as usize;
+        let id = (!rec.external_id.is_empty()
+            && (self.config.strict || self.config.reject_duplicate_ids))
+            .then(|| (rec.source.clone(), rec.external_id.clone()));
+        let first = id.as_ref().and_then(|id| self.ids_written.get(id).copied());
+
+        if self.config.strict {
+            let mut violations = vec![];
+            if let Err(errors) = rec.validate() {
+                violations.push(WriteViolation::Invalid { index, errors });
+            }
+            if let Some(first) = first {
+                violations.push(WriteViolation::DuplicateId {
+                    index,
+                    first,
+                    source: rec.source.clone(),
+                    external_id: rec.external_id.clone(),
+                });
+            }
+            if !violations.is_empty() {
+                return Err(WriteRejected { violations }.into());
+            }
+        }
+
+        if self.config.reject_duplicate_ids && first.is_some() {
+            return Err(format!(
+                "Duplicate External ID {} for Source {}",
+                rec.external_id, rec.source
+            )
+            .into());
+        }
+
+        let mut fields = rec_to_csv_fields(rec, &self.config)?;
+        if self.lot_id_column {
+            fields.push(rec.lot_id.clone().unwrap_or_default());
+        }
+        for column in &self.extra_columns {
+            fields.push(rec.extras.get(column).cloned().unwrap_or_default());
+        }
+        self.csv_writer().write_record(&fields)?;
+        // Only a record which is written claims its ID
+        if let Some(id) = id {
+            self.ids_written.insert(id, index);
+        }
+        self.records_written += 1;
+        if self.progress.is_some() {
+            let bytes = self.csv_writer().get_ref().bytes;
+            if let Some(progress) = &mut self.progress {
+                progress.update(self.records_written, bytes);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write the text of a header read by TaxBitExportRecReader verbatim
+    /// rather than the writer's header, see
+    /// TaxBitExportRecReader::raw_header. It's an error if the header has
+    /// already been written.
+    pub fn write_raw_header(&mut self, header: &str) -> Result<(), Box<dyn Error>> {
+        if self.header_written {
+            return Err("The header has already been written".into());
+        }
+        self.write_verbatim(header)?;
+        self.header_written = true;
+
+        Ok(())
+    }
+
+    /// Write the text of a record read by
+    /// TaxBitExportRecReader::read_with_raw verbatim, so records which
+    /// weren't changed are passed through byte for byte. A line
+    /// terminator is added if the text has none. Its columns must be
+    /// those of the header, see write_raw_header.
+    ///
+    /// The text isn't buffered, wrap an unbuffered W, such as a File, in
+    /// a BufWriter.
+    pub fn write_raw(&mut self, raw: &RawLine) -> Result<(), Box<dyn Error>> {
+        self.write_header()?;
+        self.write_verbatim(&raw.text)?;
+        if !raw.text.ends_with('\n') {
+            self.write_verbatim("\n")?;
+        }
+        self.records_written += 1;
+        if let (Some(progress), Some(wtr)) = (&mut self.progress, &self.wtr) {
+            progress.update(self.records_written, wtr.bytes);
+        }
+
+        Ok(())
+    }
+
+    // Write text to W after what the csv writer has buffered, the csv
+    // writer is recreated when it's next needed
+    fn write_verbatim(&mut self, text: &str) -> Result<(), Box<dyn Error>> {
+        if let Some(writer) = self.writer.take() {
+            self.wtr = Some(writer.into_inner().map_err(|e| e.into_error())?);
+        }
+        self.wtr.as_mut().unwrap().write_all(text.as_bytes())?;
+
+        Ok(())
+    }
+
+    /// Flush the writer, writing the header if it hasn't been written yet
+    pub fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        self.write_header()?;
+        self.csv_writer().flush()?;
+        if self.progress.is_some() {
+            let bytes = self.csv_writer().get_ref().bytes;
+            if let Some(progress) = &mut self.progress {
+                progress.finish(self.records_written, bytes);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Flush and return the underlying writer
+    pub fn into_inner(mut self) -> Result<W, Box<dyn Error>> {
+        self.flush()?;
+        match self.writer.take().unwrap().into_inner() {
+            Ok(w) => Ok(w.wtr),
+            Err(e) => Err(e.into_error().into()),
+        }
+    }
+}
+
+#[cfg(feature = "std-fs")]
+impl TaxBitExportRecWriter<File> {
+    /// Open path for writing records, see OpenMode.
+    ///
+    /// When appending the existing header is checked before the first
+    /// record is written, or when flushed, once the configuration and
+    /// columns are known. The file isn't changed if the check fails,
+    /// a line terminator is added to a partial last line only after it.
+    pub fn open(
+        path: &Path,
+        mode: OpenMode,
+    ) -> Result<TaxBitExportRecWriter<File>, Box<dyn Error>> {
+        let file = match mode {
+            OpenMode::Create => return Ok(TaxBitExportRecWriter::new(File::create(path)?)),
+            OpenMode::Append => OpenOptions::new()
+                .read(true)
+                .append(true)
+                .create(true)
+                .open(path)?,
+        };
+
+        let len = file.metadata()?.len();
+        let mut existing_header = None;
+        let mut partial_last_line = false;
+        if len > 0 {
+            let mut line = String::new();
+            BufReader::new(&file).read_line(&mut line)?;
+            existing_header = Some(line.trim_end_matches(['\r', '\n']).to_owned());
+
+            // Don't append to a partial last line
+            let mut last = [0u8];
+            (&file).seek(SeekFrom::Start(len - 1))?;
+            (&file).read_exact(&mut last)?;
+            partial_last_line = last[0] != b'\n';
+        }
+
+        let mut writer = TaxBitExportRecWriter::new(file);
+        writer.existing_header = existing_header;
+        writer.partial_last_line = partial_last_line;
+        Ok(writer)
+    }
+}
+
+impl TaxBitExportRec {
+    /// The TaxBit columns, TB_EXPORT_REC_HEADER, as the writer writes
+    /// them, the lot ID and extras aren't included. Fails if writing the
+    /// record with the default WriterConfig would.
+    pub fn to_string_record(&self) -> Result<csv::StringRecord, Box<dyn Error>> {
+        Ok(csv::StringRecord::from(rec_to_csv_fields(
+            self,
+            &WriterConfig::default(),
+        )?))
+    }
+}
+
+fn to_string(v: serde_json::Value) -> String {
+    match v {
+        serde_json::Value::String(s) => s,
+        v => v.to_string(),
+    }
+}
+
+fn time_to_csv_field(
+    rec: &TaxBitExportRec,
+    precision: TimestampPrecision,
+) -> Result<String, Box<dyn Error>> {
+    let millis = rec.time.rem_euclid(1000);
+    Ok(match precision {
+        TimestampPrecision::Seconds => rec.time_utc().format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+        TimestampPrecision::Auto if millis == 0 => {
+            rec.time_utc().format("%Y-%m-%dT%H:%M:%SZ").to_string()
+        }
+        TimestampPrecision::Milliseconds | TimestampPrecision::Auto => to_string(
+            se_time_ms_to_utc_z_string(&rec.time, serde_json::value::Serializer)?,
+        ),
+    })
+}
+
+fn decimal_to_csv_field(
+    d: Option<Decimal>,
+    format: &DecimalFormat,
+    config: &WriterConfig,
+) -> String {
+    match d {
+        Some(d) => format_decimal(d, format),
+        None if config.none_decimal_as_zero => format_decimal(Decimal::ZERO, format),
+        None => "".to_owned(),
+    }
+}
+
+// The values of the TaxBit columns. The time and decimals are formatted
+// as configured, the decimals with format_decimal using the quantity or
+// market value DecimalFormat, the type and internal transfer with the
+// serializers the TaxBitExportRec Serialize implementation uses.
+pub(crate) fn rec_to_csv_fields(
+    rec: &TaxBitExportRec,
+    config: &WriterConfig,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    Ok(vec![
+        time_to_csv_field(rec, config.timestamp_precision)?,
+        to_string(serde_json::to_value(&rec.type_txs)?),
+        decimal_to_csv_field(rec.received_quantity, &config.quantity_format, config),
+        rec.received_currency.clone(),
+        decimal_to_csv_field(rec.sent_quantity, &config.quantity_format, config),
+        rec.sent_currency.clone(),
+        rec.fee_currency.clone(),
+        decimal_to_csv_field(rec.fee_amount, &config.quantity_format, config),
+        decimal_to_csv_field(rec.market_value, &config.market_value_format, config),
+        rec.source.clone(),
+        to_string(se_bool_to_uppercase_string_true_false(
+            &rec.internal_transfer,
+            serde_json::value::Serializer,
+        )?),
+        rec.external_id.clone(),
+    ])
+}
+
+/// Write the records to wtr using the current TaxBit export layout.
+///
+/// The header is always written, even if there are no records. The lot
+/// ID column is written if any record has a lot_id and any extras are
+/// written as additional columns sorted by name.
+pub fn write_tb_export_recs_to_writer<W: Write>(
+    wtr: W,
+    recs: &[TaxBitExportRec],
+) -> Result<(), Box<dyn Error>> {
+    write_tb_export_recs_to_writer_with_config(wtr, recs, &WriterConfig::default())
+}
+
+/// Write the records to wtr as write_tb_export_recs_to_writer does using
+/// config.
+///
+/// If config.reject_duplicate_ids is set and there are duplicates, or
+/// config.strict is set and a record is rejected, nothing is written.
+pub fn write_tb_export_recs_to_writer_with_config<W: Write>(
+    wtr: W,
+    recs: &[TaxBitExportRec],
+    config: &WriterConfig,
+) -> Result<(), Box<dyn Error>> {
+    check_strict(recs, config)?;
+    check_duplicate_ids(recs, config)?;
+    write_recs(wtr, recs, config)?;
+
+    Ok(())
+}
+
+/// Write the records to a file as write_tb_export_recs_to_writer does
+#[cfg(feature = "std-fs")]
+pub fn write_tb_export_rec_file(
+    path: &Path,
+    recs: &[TaxBitExportRec],
+) -> Result<(), Box<dyn Error>> {
+    write_tb_export_rec_file_with_config(path, recs, &WriterConfig::default())
+}
+
+/// Write the records to a file as write_tb_export_recs_to_writer_with_config
+/// does.
+///
+/// If config.reject_duplicate_ids is set and there are duplicates, or
+/// config.strict is set and a record is rejected, the file isn't created
+/// or changed. When strict the records are written to a temporary file
+/// in the same directory which is renamed to path once complete, so a
+/// failed write leaves an existing file as it was.
+#[cfg(feature = "std-fs")]
+pub fn write_tb_export_rec_file_with_config(
+    path: &Path,
+    recs: &[TaxBitExportRec],
+    config: &WriterConfig,
+) -> Result<(), Box<dyn Error>> {
+    check_strict(recs, config)?;
+    check_duplicate_ids(recs, config)?;
+    if config.strict {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let tmp = write_recs(tempfile::NamedTempFile::new_in(dir)?, recs, config)?;
+        tmp.as_file().sync_all()?;
+        tmp.persist(path)?;
+    } else {
+        write_recs(File::create(path)?, recs, config)?;
+    }
+
+    Ok(())
+}
+
+// Every WriteViolation of the records if config.strict is set
+fn check_strict(recs: &[TaxBitExportRec], config: &WriterConfig) -> Result<(), WriteRejected> {
+    if !config.strict {
+        return Ok(());
+    }
+
+    let mut violations = vec![];
+    let mut ids: BTreeMap<(&str, &str), usize> = BTreeMap::new();
+    for (index, rec) in recs.iter().enumerate() {
+        if let Err(errors) = rec.validate() {
+            violations.push(WriteViolation::Invalid { index, errors });
+        }
+        if rec.external_id.is_empty() {
+            continue;
+        }
+        match ids.get(&(rec.source.as_str(), rec.external_id.as_str())) {
+            Some(first) => violations.push(WriteViolation::DuplicateId {
+                index,
+                first: *first,
+                source: rec.source.clone(),
+                external_id: rec.external_id.clone(),
+            }),
+            None => {
+                ids.insert((&rec.source, &rec.external_id), index);
+            }
+        }
+    }
+
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(WriteRejected { violations })
+    }
+}
+
+fn check_duplicate_ids(
+    recs: &[TaxBitExportRec],
+    config: &WriterConfig,
+) -> Result<(), Box<dyn Error>> {
+    if config.reject_duplicate_ids {
+        if let Some(((source, id), indices)) = find_duplicate_ids(recs).into_iter().next() {
+            return Err(format!(
+                "Duplicate External ID {id} for Source {source} at records {indices:?}"
+            )
+            .into());
+        }
+    }
+
+    Ok(())
+}
+
+// Write the records to wtr as write_tb_export_recs_to_writer_with_config
+// does, except duplicates aren't checked up front, and return wtr
+pub(crate) fn write_recs<W: Write>(
+    wtr: W,
+    recs: &[TaxBitExportRec],
+    config: &WriterConfig,
+) -> Result<W, Box<dyn Error>> {
+    let extra_columns: Vec<String> = recs
+        .iter()
+        .flat_map(|r| r.extras.keys().cloned())
+        .collect::<BTreeSet<String>>()
+        .into_iter()
+        .collect();
+
+    let mut writer = TaxBitExportRecWriter::new(wtr)
+        .with_config(config.clone())
+        .with_lot_id_column(recs.iter().any(|r| r.lot_id.is_some()))
+        .with_extra_columns(&extra_columns);
+    for rec in recs {
+        writer.write_rec(rec)?;
+    }
+
+    writer.into_inner()
+}
+
+#[cfg(test)]
+mod test {
+    #[cfg(feature = "std-fs")]
+    use std::fs;
+
+    use rust_decimal_macros::dec;
+    use taxbitrec::TaxBitRecType;
+
+    use super::*;
+    use crate::{
+        read_tb_export_recs_from_reader_with_config,
+        test_support::{assert_csv_roundtrip, rec_field_diffs, sample_recs_all_types},
+        ReaderConfig, TaxBitExportRecReader,
+    };
+
+    #[cfg(feature = "std-fs")]
+    #[test]
+    fn test_write_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("empty.csv");
+
+        write_tb_export_rec_file(&path, &[]).unwrap();
+        let out = fs::read_to_string(&path).unwrap();
+        assert_eq!(out, format!("{}\n", TB_EXPORT_REC_HEADER.join(",")));
+    }
+
+    #[test]
+    fn test_writer_matches_serialize() {
+        let mut rec = TaxBitExportRec::new();
+        rec.time = 1583134325000;
+        rec.type_txs = TaxBitRecType::Income;
+        rec.received_quantity = Some(dec!(0.0000003));
+        rec.received_currency = "BTC".to_owned();
+        rec.market_value = Some(dec!(0.0025979719720382955));
+        rec.source = "BinanceUS".to_owned();
+        rec.external_id = "2459217f-1a6f-4693-974c-d8d65f21abab".to_owned();
+
+        let mut serialized = csv::WriterBuilder::new()
+            .has_headers(false)
+            .from_writer(vec![]);
+        serialized.serialize(&rec).unwrap();
+        let serialized = String::from_utf8(serialized.into_inner().unwrap()).unwrap();
+
+        let mut writer = TaxBitExportRecWriter::new(vec![]);
+        writer.write_rec(&rec).unwrap();
+        let written = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+
+        assert_eq!(written.lines().nth(1).unwrap(), serialized.trim_end());
+    }
+
+    #[test]
+    fn test_writer_undeclared_extra_column() {
+        let mut rec = TaxBitExportRec::new();
+        rec.type_txs = TaxBitRecType::Income;
+        rec.extras.insert("Notes".to_owned(), "a".to_owned());
+
+        let mut writer = TaxBitExportRecWriter::new(vec![]);
+        assert!(writer.write_rec(&rec).is_err());
+
+        let mut writer =
+            TaxBitExportRecWriter::new(vec![]).with_extra_columns(&["Notes".to_owned()]);
+        writer.write_rec(&rec).unwrap();
+        let written = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        assert!(written.lines().next().unwrap().ends_with(",Notes"));
+        assert!(written.lines().nth(1).unwrap().ends_with(",a"));
+    }
+
+    fn date_column(time: i64, precision: TimestampPrecision) -> String {
+        let mut rec = TaxBitExportRec::new();
+        rec.time = time;
+        rec.type_txs = TaxBitRecType::Income;
+
+        let config = WriterConfig {
+            timestamp_precision: precision,
+            ..WriterConfig::default()
+        };
+        let mut writer = TaxBitExportRecWriter::new(vec![]).with_config(config);
+        writer.write_rec(&rec).unwrap();
+        let written = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        let row = written.lines().nth(1).unwrap().to_owned();
+        row.split(',').next().unwrap().to_owned()
+    }
+
+    #[test]
+    fn test_timestamp_precision() {
+        // 2022-03-01T14:35:06.000Z
+        let whole = 1646145306000;
+        let fraction = whole + 789;
+
+        assert_eq!(
+            date_column(whole, TimestampPrecision::Milliseconds),
+            "2022-03-01T14:35:06.000Z"
+        );
+        assert_eq!(
+            date_column(fraction, TimestampPrecision::Milliseconds),
+            "2022-03-01T14:35:06.789Z"
+        );
+        assert_eq!(
+            date_column(whole, TimestampPrecision::Seconds),
+            "2022-03-01T14:35:06Z"
+        );
+        assert_eq!(
+            date_column(fraction, TimestampPrecision::Seconds),
+            "2022-03-01T14:35:06Z"
+        );
+        assert_eq!(
+            date_column(whole, TimestampPrecision::Auto),
+            "2022-03-01T14:35:06Z"
+        );
+        assert_eq!(
+            date_column(fraction, TimestampPrecision::Auto),
+            "2022-03-01T14:35:06.789Z"
+        );
+    }
+
+    #[cfg(feature = "std-fs")]
+    #[test]
+    fn test_timestamp_precision_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut rec = TaxBitExportRec::new();
+        rec.time = 1646145306789;
+        rec.type_txs = TaxBitRecType::Income;
+        let recs = vec![rec];
+
+        for (precision, expected) in [
+            (TimestampPrecision::Milliseconds, 1646145306789),
+            (TimestampPrecision::Seconds, 1646145306000),
+            (TimestampPrecision::Auto, 1646145306789),
+        ] {
+            let path = dir.path().join("out.csv");
+            let config = WriterConfig {
+                timestamp_precision: precision,
+                ..WriterConfig::default()
+            };
+            write_tb_export_rec_file_with_config(&path, &recs, &config).unwrap();
+            let recs_read = crate::read_tb_export_rec_file(&path).unwrap();
+            assert_eq!(recs_read[0].time, expected);
+        }
+    }
+
+    #[cfg(feature = "std-fs")]
+    #[test]
+    fn test_lot_id_column() {
+        let mut rec = TaxBitExportRec::new();
+        rec.type_txs = TaxBitRecType::Buy;
+        let rec = rec.with_lot_id("lot-1");
+
+        let mut writer = TaxBitExportRecWriter::new(vec![]);
+        assert!(writer.write_rec(&rec).is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("lots.csv");
+        let recs = vec![rec, TaxBitExportRec::new()];
+        write_tb_export_rec_file(&path, &recs).unwrap();
+
+        let out = fs::read_to_string(&path).unwrap();
+        assert!(out.lines().next().unwrap().ends_with(",External ID,Lot ID"));
+        assert!(out.lines().nth(1).unwrap().ends_with(",lot-1"));
+
+        let recs_read = crate::read_tb_export_rec_file(&path).unwrap();
+        assert_eq!(recs_read, recs);
+        assert!(recs_read[0].extras.is_empty());
+    }
+
+    #[cfg(feature = "std-fs")]
+    #[test]
+    fn test_reject_duplicate_ids() {
+        let rec = |source: &str, id: &str| {
+            let mut rec = TaxBitExportRec::new();
+            rec.type_txs = TaxBitRecType::Income;
+            rec.source = source.to_owned();
+            rec.external_id = id.to_owned();
+            rec
+        };
+        let config = WriterConfig {
+            reject_duplicate_ids: true,
+            ..WriterConfig::default()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.csv");
+
+        // Different sources and empty ids are fine
+        let recs = vec![rec("a", "1"), rec("b", "1"), rec("a", ""), rec("a", "")];
+        write_tb_export_rec_file_with_config(&path, &recs, &config).unwrap();
+
+        let recs = vec![rec("a", "1"), rec("b", "2"), rec("a", "1")];
+        fs::remove_file(&path).unwrap();
+        let err = write_tb_export_rec_file_with_config(&path, &recs, &config).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Duplicate External ID 1 for Source a at records [0, 2]"
+        );
+        assert!(!path.exists());
+        write_tb_export_rec_file(&path, &recs).unwrap();
+
+        let mut writer = TaxBitExportRecWriter::new(vec![]).with_config(config);
+        writer.write_rec(&recs[0]).unwrap();
+        writer.write_rec(&recs[1]).unwrap();
+        assert!(writer.write_rec(&recs[2]).is_err());
+    }
+
+    #[test]
+    fn test_write_all_types_roundtrip() {
+        assert_csv_roundtrip(&sample_recs_all_types());
+    }
+
+    #[test]
+    fn test_none_decimal_as_zero() {
+        let mut rec = TaxBitExportRec::new();
+        rec.time = 1646145306000;
+        rec.type_txs = TaxBitRecType::Income;
+        rec.received_quantity = Some(dec!(1.5));
+        rec.received_currency = "ADA".to_owned();
+
+        let write = |config: WriterConfig| {
+            let mut writer = TaxBitExportRecWriter::new(vec![]).with_config(config);
+            writer.write_rec(&rec).unwrap();
+            String::from_utf8(writer.into_inner().unwrap()).unwrap()
+        };
+        let header = TB_EXPORT_REC_HEADER.join(",");
+        assert_eq!(
+            write(WriterConfig::default()),
+            format!("{header}\n2022-03-01T14:35:06.000Z,Income,1.5,ADA,,,,,,,FALSE,\n")
+        );
+        let zeros = write(WriterConfig {
+            none_decimal_as_zero: true,
+            ..WriterConfig::default()
+        });
+        assert_eq!(
+            zeros,
+            format!("{header}\n2022-03-01T14:35:06.000Z,Income,1.5,ADA,0,,,0,0,,FALSE,\n")
+        );
+
+        let read: TaxBitExportRec = TaxBitExportRecReader::new(zeros.as_bytes())
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(read.received_quantity, Some(dec!(1.5)));
+        assert_eq!(read.sent_quantity, Some(dec!(0)));
+        assert_eq!(read.fee_amount, Some(dec!(0)));
+        assert_eq!(read.market_value, Some(dec!(0)));
+
+        let config = ReaderConfig {
+            zero_decimal_as_none: true,
+            ..ReaderConfig::default()
+        };
+        let read = read_tb_export_recs_from_reader_with_config(zeros.as_bytes(), &config).unwrap();
+        assert_eq!(read, vec![rec]);
+    }
+
+    #[test]
+    fn test_decimal_format_padding_round_trip() {
+        let recs = sample_recs_all_types();
+        let config = WriterConfig {
+            quantity_format: DecimalFormat::fixed(8),
+            market_value_format: DecimalFormat::fixed(2),
+            ..WriterConfig::default()
+        };
+        let mut writer = TaxBitExportRecWriter::new(vec![]).with_config(config);
+        for rec in &recs {
+            writer.write_rec(rec).unwrap();
+        }
+        let csv = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+
+        // Buy
+        assert!(csv
+            .lines()
+            .nth(1)
+            .unwrap()
+            .contains(",Buy,0.02314000,BTC,1000.00000000,USD,USD,14.95000000,1000.00,"));
+        // Income, market value 0.0041 is rounded
+        assert!(csv
+            .lines()
+            .nth(6)
+            .unwrap()
+            .contains(",Income,0.00421337,ADA,,,,,0.00,"));
+
+        let read: Vec<TaxBitExportRec> = TaxBitExportRecReader::new(csv.as_bytes())
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        for (i, (expected, found)) in recs.iter().zip(read.iter()).enumerate() {
+            let diffs = rec_field_diffs(expected, found);
+            if expected.type_txs == TaxBitRecType::Income {
+                assert_eq!(diffs.len(), 1);
+                assert_eq!(diffs[0].0, "market_value");
+                assert_eq!(found.market_value, Some(dec!(0)));
+            } else {
+                assert!(diffs.is_empty(), "record {i}: {diffs:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_decimal_format_no_exponent() {
+        let mut rec = TaxBitExportRec::new();
+        rec.time = 1646145306000;
+        rec.type_txs = TaxBitRecType::Income;
+        rec.received_quantity = Some(Decimal::from_scientific("1E-8").unwrap());
+        rec.received_currency = "BTC".to_owned();
+
+        let mut writer = TaxBitExportRecWriter::new(vec![]);
+        writer.write_rec(&rec).unwrap();
+        let csv = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        assert!(csv
+            .lines()
+            .nth(1)
+            .unwrap()
+            .contains(",Income,0.00000001,BTC,"));
+    }
+
+    #[cfg(feature = "std-fs")]
+    #[test]
+    fn test_write_to_writer_matches_file() {
+        let mut recs = sample_recs_all_types();
+        recs[0] = recs[0].with_lot_id("lot-1");
+        recs[1].extras.insert("Notes".to_owned(), "a, b".to_owned());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.csv");
+        write_tb_export_rec_file(&path, &recs).unwrap();
+
+        let mut out: Vec<u8> = vec![];
+        write_tb_export_recs_to_writer(&mut out, &recs).unwrap();
+        assert_eq!(out, fs::read(&path).unwrap());
+
+        let config = WriterConfig {
+            reject_duplicate_ids: true,
+            ..WriterConfig::default()
+        };
+        recs.push(recs[0].clone());
+        let mut out: Vec<u8> = vec![];
+        assert!(write_tb_export_recs_to_writer_with_config(&mut out, &recs, &config).is_err());
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn test_writer_progress() {
+        use std::sync::{Arc, Mutex};
+
+        use crate::ProgressEvent;
+
+        let events: Arc<Mutex<Vec<ProgressEvent>>> = Arc::default();
+        let sink = events.clone();
+        let progress = Progress::new(move |e| sink.lock().unwrap().push(e))
+            .every_records(0)
+            .every_bytes(1000);
+        let mut writer = TaxBitExportRecWriter::new(vec![]).with_progress(progress);
+        let recs = sample_recs_all_types();
+        for _ in 0..20 {
+            for rec in &recs {
+                writer.write_rec(rec).unwrap();
+            }
+        }
+        let out = writer.into_inner().unwrap();
+
+        let events = events.lock().unwrap();
+        assert!(events.len() > 1);
+        let last = events.last().unwrap();
+        assert_eq!(last.records_done, 20 * recs.len() as u64);
+        assert_eq!(last.bytes_done, out.len() as u64);
+        assert_eq!(last.total_bytes, None);
+    }
+
+    fn batch(day: i64, count: i64) -> Vec<TaxBitExportRec> {
+        (0..count)
+            .map(|i| {
+                let mut rec = TaxBitExportRec::new();
+                rec.time = 1640995200000 + day * 86_400_000 + i * 1000;
+                rec.type_txs = TaxBitRecType::Income;
+                rec.received_quantity = Some(dec!(0.0054));
+                rec.received_currency = "XRP".to_owned();
+                rec.source = "BinanceUS".to_owned();
+                rec.external_id = format!("{day}-{i}");
+                rec
+            })
+            .collect()
+    }
+
+    #[cfg(feature = "std-fs")]
+    fn append(path: &Path, recs: &[TaxBitExportRec]) -> Result<(), Box<dyn Error>> {
+        let mut writer = TaxBitExportRecWriter::open(path, OpenMode::Append)?;
+        for rec in recs {
+            writer.write_rec(rec)?;
+        }
+        writer.flush()
+    }
+
+    #[cfg(feature = "std-fs")]
+    #[test]
+    fn test_append_existing() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("daily.csv");
+        write_tb_export_rec_file(&path, &batch(0, 2)).unwrap();
+        append(&path, &batch(1, 3)).unwrap();
+        append(&path, &[]).unwrap();
+
+        let out = fs::read_to_string(&path).unwrap();
+        assert_eq!(out.matches("Date,").count(), 1);
+        let mut expected = batch(0, 2);
+        expected.extend(batch(1, 3));
+        assert_eq!(crate::read_tb_export_rec_file(&path).unwrap(), expected);
+
+        // A missing final newline is added rather than joining two rows
+        fs::write(&path, out.trim_end()).unwrap();
+        append(&path, &batch(2, 1)).unwrap();
+        expected.extend(batch(2, 1));
+        assert_eq!(crate::read_tb_export_rec_file(&path).unwrap(), expected);
+    }
+
+    #[cfg(feature = "std-fs")]
+    #[test]
+    fn test_append_empty_or_missing() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing.csv");
+        append(&missing, &batch(0, 2)).unwrap();
+        let empty = dir.path().join("empty.csv");
+        fs::write(&empty, "").unwrap();
+        append(&empty, &batch(0, 2)).unwrap();
+
+        let created = dir.path().join("created.csv");
+        write_tb_export_rec_file(&created, &batch(0, 2)).unwrap();
+        let expected = fs::read_to_string(&created).unwrap();
+        assert_eq!(fs::read_to_string(&missing).unwrap(), expected);
+        assert_eq!(fs::read_to_string(&empty).unwrap(), expected);
+    }
+
+    #[cfg(feature = "std-fs")]
+    #[test]
+    fn test_append_foreign_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("foreign.csv");
+        let foreign = "Timestamp,Type,Asset,Amount\n2022-01-01,Buy,BTC,1\n";
+        fs::write(&path, foreign).unwrap();
+        let err = append(&path, &batch(0, 1)).unwrap_err();
+        assert!(err.to_string().starts_with("Can't append"), "{err}");
+        assert_eq!(fs::read_to_string(&path).unwrap(), foreign);
+
+        // Nor is a line terminator added to its partial last line
+        let foreign = foreign.trim_end();
+        fs::write(&path, foreign).unwrap();
+        assert!(append(&path, &batch(0, 1)).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), foreign);
+
+        // A TaxBit header with other columns doesn't match either
+        let path = dir.path().join("lot_id.csv");
+        let mut rec = batch(0, 1).remove(0);
+        rec.lot_id = Some("lot-1".to_owned());
+        write_tb_export_rec_file(&path, &[rec]).unwrap();
+        assert!(append(&path, &batch(1, 1)).is_err());
+    }
+
+    #[test]
+    fn test_without_header() {
+        let mut first = TaxBitExportRecWriter::new(vec![]);
+        let mut second = TaxBitExportRecWriter::new(vec![]).with_write_header(false);
+        let recs = batch(0, 4);
+        for rec in &recs[..2] {
+            first.write_rec(rec).unwrap();
+        }
+        for rec in &recs[2..] {
+            second.write_rec(rec).unwrap();
+        }
+        let mut out = first.into_inner().unwrap();
+        out.extend(second.into_inner().unwrap());
+        assert_eq!(
+            crate::read_tb_export_recs_from_reader(out.as_slice()).unwrap(),
+            recs
+        );
+
+        let empty = TaxBitExportRecWriter::new(vec![])
+            .with_write_header(false)
+            .into_inner()
+            .unwrap();
+        assert!(empty.is_empty());
+    }
+
+    #[cfg(feature = "std-fs")]
+    #[test]
+    fn test_strict_rejects_before_writing() {
+        let income = |id: &str| {
+            let mut rec = TaxBitExportRec::new();
+            rec.type_txs = TaxBitRecType::Income;
+            rec.received_quantity = Some(dec!(1));
+            rec.received_currency = "BTC".to_owned();
+            rec.source = "Kraken".to_owned();
+            rec.external_id = id.to_owned();
+            rec
+        };
+        let config = WriterConfig {
+            strict: true,
+            ..WriterConfig::default()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.csv");
+
+        let mut invalid = income("2");
+        invalid.sent_currency = "USD".to_owned();
+        invalid.fee_amount = Some(dec!(-1));
+        invalid.fee_currency = "BTC".to_owned();
+        let recs = vec![income("1"), invalid, income("3"), income("1")];
+        let err = write_tb_export_rec_file_with_config(&path, &recs, &config).unwrap_err();
+        assert!(!path.exists());
+        let rejected = err.downcast_ref::<WriteRejected>().unwrap();
+        assert_eq!(
+            rejected.violations,
+            vec![
+                WriteViolation::Invalid {
+                    index: 1,
+                    errors: vec![
+                        ValidationError::UnexpectedSentSide,
+                        ValidationError::NegativeQuantity("Fee Amount"),
+                    ],
+                },
+                WriteViolation::DuplicateId {
+                    index: 3,
+                    first: 0,
+                    source: "Kraken".to_owned(),
+                    external_id: "1".to_owned(),
+                },
+            ]
+        );
+        assert_eq!(
+            rejected
+                .violations
+                .iter()
+                .map(|v| v.index())
+                .collect::<Vec<_>>(),
+            vec![1, 3]
+        );
+        assert!(
+            err.to_string()
+                .contains("record 3: Duplicate External ID 1"),
+            "{err}"
+        );
+
+        // An existing file is left as it was
+        write_tb_export_rec_file(&path, &recs[..1]).unwrap();
+        let before = fs::read(&path).unwrap();
+        assert!(write_tb_export_rec_file_with_config(&path, &recs, &config).is_err());
+        assert_eq!(fs::read(&path).unwrap(), before);
+
+        // A valid batch replaces it and leaves no temporary file
+        let recs = vec![income("1"), income("2"), income("3")];
+        write_tb_export_rec_file_with_config(&path, &recs, &config).unwrap();
+        assert_eq!(crate::read_tb_export_rec_file(&path).unwrap(), recs);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        // Not strict the invalid record is written
+        let mut unvalidated = income("4");
+        unvalidated.received_quantity = None;
+        write_tb_export_rec_file(&path, &[unvalidated]).unwrap();
+    }
+
+    #[test]
+    fn test_strict_streaming_writer() {
+        let mut rec = TaxBitExportRec::new();
+        rec.type_txs = TaxBitRecType::Income;
+        rec.received_quantity = Some(dec!(1));
+        rec.received_currency = "BTC".to_owned();
+        rec.external_id = "1".to_owned();
+        let config = WriterConfig {
+            strict: true,
+            ..WriterConfig::default()
+        };
+
+        let mut writer = TaxBitExportRecWriter::new(vec![]).with_config(config);
+        writer.write_rec(&rec).unwrap();
+        let mut invalid = rec.clone();
+        invalid.external_id = "2".to_owned();
+        invalid.received_currency = String::new();
+        let err = writer.write_rec(&invalid).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<WriteRejected>().unwrap().violations,
+            vec![WriteViolation::Invalid {
+                index: 1,
+                errors: vec![ValidationError::MissingReceivedSide],
+            }]
+        );
+        let err = writer.write_rec(&rec).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Write rejected: record 1: Duplicate External ID 1 for Source  of record 0"
+        );
+
+        // The rejected record didn't claim its ID so the corrected one is
+        // written
+        invalid.received_currency = "BTC".to_owned();
+        writer.write_rec(&invalid).unwrap();
+        let err = writer.write_rec(&invalid).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Write rejected: record 2: Duplicate External ID 2 for Source  of record 1"
+        );
+        let csv = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        assert_eq!(csv.lines().count(), 3);
+    }
+}
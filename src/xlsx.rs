@@ -0,0 +1,227 @@
+use std::{collections::BTreeMap, error::Error, path::Path};
+
+use rust_decimal::{prelude::ToPrimitive, Decimal};
+use rust_xlsxwriter::{Format, Workbook, Worksheet};
+use taxbitrec::TaxBitRecType;
+
+use crate::{pivot_by_asset_and_type, TaxBitExportRec, TB_EXPORT_REC_HEADER};
+
+// The Excel serial date of the Unix epoch, 1970-01-01
+const EXCEL_UNIX_EPOCH: f64 = 25569.0;
+const MS_PER_DAY: f64 = 86_400_000.0;
+
+// The columns of the summary sheet after Year and Asset
+const SUMMARY_COLUMNS: [(TaxBitRecType, &str); 9] = [
+    (TaxBitRecType::Buy, "Buy"),
+    (TaxBitRecType::Sale, "Sale"),
+    (TaxBitRecType::Trade, "Trade"),
+    (TaxBitRecType::TransferIn, "Transfer In"),
+    (TaxBitRecType::TransferOut, "Transfer Out"),
+    (TaxBitRecType::Income, "Income"),
+    (TaxBitRecType::Expense, "Expense"),
+    (TaxBitRecType::GiftReceived, "Gift Received"),
+    (TaxBitRecType::GiftSent, "Gift Sent"),
+];
+
+/// Options for write_tb_export_rec_xlsx
+#[derive(Debug, Clone, Default)]
+pub struct XlsxOpts {
+    /// Add a "Summary" sheet with the quantity of each asset per
+    /// transaction type for each UTC year, see pivot_by_asset_and_type
+    pub include_summary: bool,
+
+    /// Write the quantities, fee amounts and market values as text so
+    /// they're exact rather than as numbers
+    pub decimals_as_text: bool,
+}
+
+/// Write the records to an Excel workbook with a "Records" sheet of the
+/// TaxBit columns, the lot ID and extras aren't written. The header row
+/// is bold and frozen and the Date is an Excel date time, UTC, with
+/// milliseconds.
+///
+/// Unless opts.decimals_as_text is set the quantities, fee amounts and
+/// market values, including the summary's, are numbers. Excel numbers
+/// are f64 so a Decimal is rounded to the nearest f64, a relative error
+/// of at most 2^-53, and Excel shows at most 15 significant digits.
+pub fn write_tb_export_rec_xlsx(
+    path: &Path,
+    recs: &[TaxBitExportRec],
+    opts: &XlsxOpts,
+) -> Result<(), Box<dyn Error>> {
+    let mut workbook = Workbook::new();
+    let bold = Format::new().set_bold();
+    let date = Format::new().set_num_format("yyyy-mm-dd hh:mm:ss.000");
+    let quantity = Format::new().set_num_format("0.##########");
+    let value = Format::new().set_num_format("#,##0.00");
+
+    let sheet = workbook.add_worksheet().set_name("Records")?;
+    for (col, name) in TB_EXPORT_REC_HEADER.iter().enumerate() {
+        sheet.write_string_with_format(0, col as u16, *name, &bold)?;
+    }
+    sheet.set_freeze_panes(1, 0)?;
+    sheet.set_column_width(0, 24)?;
+    for (i, rec) in recs.iter().enumerate() {
+        let row = i as u32 + 1;
+        let serial = EXCEL_UNIX_EPOCH + rec.time as f64 / MS_PER_DAY;
+        sheet.write_number_with_format(row, 0, serial, &date)?;
+        let type_txs = serde_json::to_value(&rec.type_txs)?;
+        sheet.write_string(row, 1, type_txs.as_str().unwrap_or_default())?;
+        let cells = [
+            (2, rec.received_quantity, &quantity),
+            (4, rec.sent_quantity, &quantity),
+            (7, rec.fee_amount, &quantity),
+            (8, rec.market_value, &value),
+        ];
+        for (col, d, format) in cells {
+            if let Some(d) = d {
+                write_decimal(sheet, row, col, d, format, opts)?;
+            }
+        }
+        let strings = [
+            (3, &rec.received_currency),
+            (5, &rec.sent_currency),
+            (6, &rec.fee_currency),
+            (9, &rec.source),
+            (11, &rec.external_id),
+        ];
+        for (col, s) in strings {
+            if !s.is_empty() {
+                sheet.write_string(row, col, s)?;
+            }
+        }
+        sheet.write_boolean(row, 10, rec.internal_transfer)?;
+    }
+
+    if opts.include_summary {
+        let sheet = workbook.add_worksheet().set_name("Summary")?;
+        sheet.write_string_with_format(0, 0, "Year", &bold)?;
+        sheet.write_string_with_format(0, 1, "Asset", &bold)?;
+        for (i, (_, name)) in SUMMARY_COLUMNS.iter().enumerate() {
+            sheet.write_string_with_format(0, i as u16 + 2, *name, &bold)?;
+        }
+        sheet.set_freeze_panes(1, 0)?;
+
+        let mut by_year: BTreeMap<i32, Vec<TaxBitExportRec>> = BTreeMap::new();
+        for rec in recs {
+            by_year.entry(rec.year()).or_default().push(rec.clone());
+        }
+        let mut row = 1;
+        for (year, recs) in by_year {
+            for (asset, sums) in pivot_by_asset_and_type(&recs).cells {
+                sheet.write_number(row, 0, year)?;
+                sheet.write_string(row, 1, &asset)?;
+                for (i, (type_txs, _)) in SUMMARY_COLUMNS.iter().enumerate() {
+                    if let Some(sum) = sums.get(type_txs) {
+                        write_decimal(sheet, row, i as u16 + 2, *sum, &quantity, opts)?;
+                    }
+                }
+                row += 1;
+            }
+        }
+    }
+
+    workbook.save(path)?;
+
+    Ok(())
+}
+
+fn write_decimal(
+    sheet: &mut Worksheet,
+    row: u32,
+    col: u16,
+    d: Decimal,
+    format: &Format,
+    opts: &XlsxOpts,
+) -> Result<(), Box<dyn Error>> {
+    if opts.decimals_as_text {
+        sheet.write_string(row, col, d.to_string())?;
+    } else {
+        let n = d
+            .to_f64()
+            .ok_or_else(|| format!("{d} isn't representable as f64"))?;
+        sheet.write_number_with_format(row, col, n, format)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use calamine::{open_workbook, Data, Reader, Xlsx};
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::test_support::sample_buy_and_income;
+
+    #[test]
+    fn test_write_xlsx() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("recs.xlsx");
+        let opts = XlsxOpts {
+            include_summary: true,
+            ..XlsxOpts::default()
+        };
+        write_tb_export_rec_xlsx(&path, &sample_buy_and_income(), &opts).unwrap();
+
+        let mut workbook: Xlsx<_> = open_workbook(&path).unwrap();
+        assert_eq!(workbook.sheet_names(), vec!["Records", "Summary"]);
+        let sheet = workbook.worksheet_range("Records").unwrap();
+        assert_eq!(sheet.get_size(), (3, 12));
+        let header: Vec<String> = (0..12)
+            .map(|c| sheet.get((0, c)).unwrap().to_string())
+            .collect();
+        assert_eq!(header, TB_EXPORT_REC_HEADER);
+
+        match sheet.get((1, 0)).unwrap() {
+            Data::DateTime(dt) => {
+                let ms = ((dt.as_f64() - EXCEL_UNIX_EPOCH) * MS_PER_DAY).round() as i64;
+                assert_eq!(ms, 1583134325123);
+            }
+            other => panic!("Date isn't a date time: {other:?}"),
+        }
+        assert_eq!(sheet.get((1, 1)), Some(&Data::String("Buy".to_owned())));
+        assert_eq!(sheet.get((1, 2)), Some(&Data::Float(0.12345678)));
+        assert_eq!(sheet.get((1, 8)), Some(&Data::Float(1000.10)));
+        assert_eq!(sheet.get((1, 10)), Some(&Data::Bool(false)));
+        assert_eq!(sheet.get((2, 2)), Some(&Data::Float(0.0000003)));
+        assert_eq!(sheet.get((2, 4)), Some(&Data::Empty));
+        assert_eq!(sheet.get((2, 8)), Some(&Data::Empty));
+        assert_eq!(sheet.get((2, 10)), Some(&Data::Bool(true)));
+
+        let summary = workbook.worksheet_range("Summary").unwrap();
+        let rows: Vec<Vec<String>> = summary
+            .rows()
+            .map(|r| r.iter().map(|c| c.to_string()).collect())
+            .collect();
+        assert_eq!(rows[0][..4], ["Year", "Asset", "Buy", "Sale"]);
+        assert_eq!(rows[1][..3], ["2020", "BTC", "0.12345678"]);
+        assert_eq!(rows[2][..2], ["2022", "BTC"]);
+        assert_eq!(rows[2][7], "0.0000003");
+        assert_eq!(rows.len(), 3);
+    }
+
+    #[test]
+    fn test_write_xlsx_decimals_as_text() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("recs.xlsx");
+        let mut recs = sample_buy_and_income();
+        recs[0].received_quantity = Some(dec!(0.123456789012345678901));
+        let opts = XlsxOpts {
+            decimals_as_text: true,
+            ..XlsxOpts::default()
+        };
+        write_tb_export_rec_xlsx(&path, &recs, &opts).unwrap();
+
+        let mut workbook: Xlsx<_> = open_workbook(&path).unwrap();
+        assert_eq!(workbook.sheet_names(), vec!["Records"]);
+        let sheet = workbook.worksheet_range("Records").unwrap();
+        let text = |row, col| match sheet.get((row, col)) {
+            Some(Data::String(s)) => s.parse::<Decimal>().unwrap(),
+            other => panic!("not text: {other:?}"),
+        };
+        assert_eq!(text(1, 2), dec!(0.123456789012345678901));
+        assert_eq!(text(1, 8), dec!(1000.10));
+        assert_eq!(text(2, 2), dec!(0.0000003));
+    }
+}